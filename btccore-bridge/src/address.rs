@@ -0,0 +1,350 @@
+//! Payout addresses and the scriptPubKey of the coinbase output paying them.
+//!
+//! Legacy addresses are base58check with a version byte per network,
+//! segwit addresses bech32 (version 0, BIP 173) or bech32m (version 1 and
+//! up, BIP 350) with a human readable part per network.
+
+use anyhow::{bail, ensure, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::Network;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_ALPHABET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+// Checksum constants of bech32 and bech32m
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+
+// Longest bech32 string BIP 173 allows
+const BECH32_MAX_LEN: usize = 90;
+
+// Script opcodes used by output scripts
+const OP_DUP: u8 = 0x76;
+const OP_HASH160: u8 = 0xa9;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_1: u8 = 0x51;
+
+/// What an address pays to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    PubkeyHash([u8; 20]),
+    ScriptHash([u8; 20]),
+    /// Segwit output of a version from 0 to 16
+    Witness {
+        version: u8,
+        program: Vec<u8>,
+    },
+}
+
+/// Address parsed for a network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    network: Network,
+    payload: Payload,
+}
+
+impl Address {
+    /// Parses a base58check or bech32(m) address
+    /// Fails if the address is valid but belongs to another network.
+    pub fn parse(address: &str, network: Network) -> Result<Self> {
+        let payload = if is_bech32(address) {
+            parse_segwit(address, network)?
+        } else {
+            parse_base58(address, network)?
+        };
+        Ok(Self { network, payload })
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    /// Output script paying this address, for the coinbase
+    pub fn script_pubkey(&self) -> Vec<u8> {
+        match &self.payload {
+            Payload::PubkeyHash(hash) => [
+                &[OP_DUP, OP_HASH160, 20][..],
+                hash,
+                &[OP_EQUALVERIFY, OP_CHECKSIG],
+            ]
+            .concat(),
+            Payload::ScriptHash(hash) => [&[OP_HASH160, 20][..], hash, &[OP_EQUAL]].concat(),
+            Payload::Witness { version, program } => {
+                let op = if *version == 0 { 0 } else { OP_1 + version - 1 };
+                [&[op, program.len() as u8][..], program].concat()
+            }
+        }
+    }
+}
+
+// Segwit addresses start with a known prefix and a 1, base58 has no 1 past
+// its leading zeros
+fn is_bech32(address: &str) -> bool {
+    let lower = address.to_ascii_lowercase();
+    ["bc1", "tb1", "bcrt1"]
+        .iter()
+        .any(|prefix| lower.starts_with(prefix))
+}
+
+fn parse_base58(address: &str, network: Network) -> Result<Payload> {
+    let bytes = base58_decode(address)?;
+    ensure!(
+        bytes.len() == 25,
+        "{address} decodes to {} bytes instead of 25",
+        bytes.len()
+    );
+    let (data, checksum) = bytes.split_at(21);
+    ensure!(
+        Sha256::digest(Sha256::digest(data))[..4] == *checksum,
+        "{address} has a bad checksum"
+    );
+
+    let hash: [u8; 20] = data[1..].try_into().unwrap();
+    let (pubkey_hash, script_hash) = network.base58_versions();
+    match data[0] {
+        version if version == pubkey_hash => Ok(Payload::PubkeyHash(hash)),
+        version if version == script_hash => Ok(Payload::ScriptHash(hash)),
+        0x00 | 0x05 => bail!("{address} is a mainnet address, not {network}"),
+        0x6f | 0xc4 => bail!("{address} is a testnet address, not {network}"),
+        version => bail!("{address} has unknown version byte {version:#04x}"),
+    }
+}
+
+fn base58_decode(address: &str) -> Result<Vec<u8>> {
+    // Big-endian base 256 digits of the number
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in address.bytes() {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|&digit| digit == c)
+            .with_context(|| format!("{address} has {:?}, which isn't base58", c as char))?
+            as u32;
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    // Every leading 1 is a zero byte
+    let zeros = address.bytes().take_while(|&c| c == b'1').count();
+    Ok([vec![0; zeros], bytes].concat())
+}
+
+fn parse_segwit(address: &str, network: Network) -> Result<Payload> {
+    ensure!(
+        address.len() <= BECH32_MAX_LEN,
+        "{address} is longer than {BECH32_MAX_LEN} characters"
+    );
+    ensure!(
+        address.to_ascii_lowercase() == address || address.to_ascii_uppercase() == address,
+        "{address} mixes upper and lower case"
+    );
+    let address_lower = address.to_ascii_lowercase();
+    let (hrp, data) = address_lower
+        .rsplit_once('1')
+        .context("Bech32 address without separator")?;
+    if hrp != network.bech32_hrp() {
+        let found = Network::ALL
+            .into_iter()
+            .find(|other| other.bech32_hrp() == hrp);
+        match found {
+            Some(found) => bail!("{address} is a {found} address, not {network}"),
+            None => bail!("{address} has unknown prefix {hrp}"),
+        }
+    }
+
+    let values = data
+        .bytes()
+        .map(|c| {
+            BECH32_ALPHABET
+                .iter()
+                .position(|&digit| digit == c)
+                .map(|value| value as u8)
+                .with_context(|| format!("{address} has {:?}, which isn't bech32", c as char))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    // Witness version, program and 6 checksum values
+    ensure!(values.len() > 7, "{address} is too short");
+    let version = values[0];
+    ensure!(version <= 16, "{address} has witness version {version}");
+
+    // Version 0 uses the bech32 checksum, later versions bech32m
+    let expected = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let checksum = bech32_polymod(hrp_expand(hrp).into_iter().chain(values.iter().copied()));
+    ensure!(checksum == expected, "{address} has a bad checksum");
+
+    let program = convert_bits(&values[1..values.len() - 6], 5, 8)
+        .with_context(|| format!("{address} has invalid padding"))?;
+    let valid_len = match version {
+        0 => program.len() == 20 || program.len() == 32,
+        _ => (2..=40).contains(&program.len()),
+    };
+    ensure!(
+        valid_len,
+        "{address} has a {} byte witness program",
+        program.len()
+    );
+    Ok(Payload::Witness { version, program })
+}
+
+// Human readable part as checksummed by bech32
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    hrp.bytes()
+        .map(|c| c >> 5)
+        .chain([0])
+        .chain(hrp.bytes().map(|c| c & 31))
+        .collect()
+}
+
+fn bech32_polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    values.into_iter().fold(1, |checksum, value| {
+        let top = checksum >> 25;
+        let checksum = ((checksum & 0x1ff_ffff) << 5) ^ value as u32;
+        (0..5)
+            .filter(|i| (top >> i) & 1 == 1)
+            .fold(checksum, |checksum, i| checksum ^ GENERATOR[i])
+    })
+}
+
+// Regroups 5-bit values into bytes, None if the padding isn't zero bits
+fn convert_bits(values: &[u8], from: u32, to: u32) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut bytes = Vec::new();
+    for &value in values {
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    (bits < from && acc & ((1 << bits) - 1) == 0).then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(address: &str, network: Network) -> String {
+        hex::encode(Address::parse(address, network).unwrap().script_pubkey())
+    }
+
+    #[test]
+    fn scripts_of_every_address_type() {
+        assert_eq!(
+            script("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Network::Mainnet),
+            "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac"
+        );
+        assert_eq!(
+            script("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", Network::Mainnet),
+            "a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87"
+        );
+        assert_eq!(
+            script("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn", Network::Regtest),
+            "76a914243f1394f44554f4ce3fd68649c19adc483ce92488ac"
+        );
+        assert_eq!(
+            script("2MzQwSSnBHWHqSAqtTVQ6v47XtaisrJa1Vc", Network::Testnet),
+            "a9144e9f39ca4688ff102128ea4ccda34105324305b087"
+        );
+
+        // BIP 173 and BIP 350 vectors
+        assert_eq!(
+            script(
+                "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+                Network::Mainnet
+            ),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        assert_eq!(
+            script(
+                "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+                Network::Mainnet
+            ),
+            "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"
+        );
+        assert_eq!(
+            script(
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                Network::Mainnet
+            ),
+            "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert_eq!(
+            script(
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+                Network::Testnet
+            ),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        assert_eq!(
+            script(
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+                Network::Regtest
+            ),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+    }
+
+    #[test]
+    fn bad_and_foreign_addresses_are_rejected() {
+        let error =
+            |address: &str, network| Address::parse(address, network).unwrap_err().to_string();
+        assert!(
+            error("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Network::Regtest)
+                .contains("mainnet address, not regtest")
+        );
+        assert!(
+            error("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn", Network::Mainnet)
+                .contains("testnet address, not mainnet")
+        );
+        assert!(error(
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            Network::Mainnet
+        )
+        .contains("testnet address, not mainnet"));
+
+        // One character changed breaks the checksum
+        assert!(error("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb", Network::Mainnet).contains("checksum"));
+        assert!(error(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+            Network::Mainnet
+        )
+        .contains("checksum"));
+        // Version 1 with a bech32 instead of a bech32m checksum
+        assert!(error(
+            "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7k7grplx",
+            Network::Mainnet
+        )
+        .contains("checksum"));
+        assert!(error(
+            "bc1qW508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            Network::Mainnet
+        )
+        .contains("mixes"));
+        assert!(error("0OIl", Network::Mainnet).contains("base58"));
+    }
+}
@@ -0,0 +1,213 @@
+//! Choosing the template transactions that go into a block.
+//!
+//! The template lists more than fits once the coinbase is added, or a
+//! node may hand out limits below what it filled. Transactions are kept
+//! in template order while the block stays within sizelimit, weightlimit
+//! and sigoplimit. A transaction whose parent was dropped is dropped too.
+//!
+//! Parents have to come before the transactions spending them. Bitcoin
+//! Core lists them that way, anything else is moved as little as needed
+//! going by depends.
+
+use anyhow::{bail, Result};
+
+use crate::{coinbase::write_compact_size, Transaction};
+
+/// Limits of a block as getblocktemplate gives them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLimits {
+    /// Serialized size in bytes
+    pub size: u64,
+    /// Weight units
+    pub weight: u64,
+    /// Signature operations, in the segwit cost units of the template
+    pub sigops: u64,
+}
+
+// Template transactions split into the ones in the block and the rest
+pub(crate) struct Selection {
+    pub(crate) kept: Vec<Transaction>,
+    pub(crate) dropped: Vec<Transaction>,
+}
+
+// Keeps the transactions that fit next to the header and coinbase, with
+// parents ahead of their children
+pub(crate) fn select(
+    coinbase: &Transaction,
+    transactions: Vec<Transaction>,
+    limits: &BlockLimits,
+) -> Result<Selection> {
+    let order = parents_first(&transactions)?;
+
+    // The count only shrinks as transactions are dropped, so its length
+    // for all of them bounds the final one
+    let mut count = Vec::new();
+    write_compact_size(&mut count, transactions.len() as u64 + 1);
+    let base = 80 + count.len() as u64;
+
+    let mut size = base + coinbase.raw().len() as u64;
+    let mut weight = base * 4 + coinbase.weight();
+    let mut sigops = coinbase.sigops();
+
+    let mut included = vec![false; transactions.len()];
+    let mut selection = Selection {
+        kept: Vec::new(),
+        dropped: Vec::new(),
+    };
+    let mut transactions: Vec<Option<Transaction>> = transactions.into_iter().map(Some).collect();
+    for i in order {
+        let Some(tx) = transactions[i].take() else {
+            continue;
+        };
+        // depends counts from 1, every parent was seen already
+        let parents_kept = tx.depends().iter().all(|&parent| included[parent - 1]);
+        let fits = size + tx.raw().len() as u64 <= limits.size
+            && weight + tx.weight() <= limits.weight
+            && sigops + tx.sigops() <= limits.sigops;
+
+        if parents_kept && fits {
+            size += tx.raw().len() as u64;
+            weight += tx.weight();
+            sigops += tx.sigops();
+            included[i] = true;
+            selection.kept.push(tx);
+        } else {
+            selection.dropped.push(tx);
+        }
+    }
+    Ok(selection)
+}
+
+// Template positions with every transaction after its parents, else in
+// template order
+fn parents_first(transactions: &[Transaction]) -> Result<Vec<usize>> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Visit {
+        New,
+        Open,
+        Done,
+    }
+
+    fn visit(
+        i: usize,
+        transactions: &[Transaction],
+        visits: &mut [Visit],
+        order: &mut Vec<usize>,
+    ) -> Result<()> {
+        match visits[i] {
+            Visit::Done => return Ok(()),
+            Visit::Open => bail!("Template transaction {} is its own ancestor", i + 1),
+            Visit::New => visits[i] = Visit::Open,
+        }
+        for &parent in transactions[i].depends() {
+            if !(1..=transactions.len()).contains(&parent) {
+                bail!(
+                    "Template transaction {} depends on {parent}, out of {}",
+                    i + 1,
+                    transactions.len()
+                );
+            }
+            visit(parent - 1, transactions, visits, order)?;
+        }
+        visits[i] = Visit::Done;
+        order.push(i);
+        Ok(())
+    }
+
+    let mut visits = vec![Visit::New; transactions.len()];
+    let mut order = Vec::with_capacity(transactions.len());
+    for i in 0..transactions.len() {
+        visit(i, transactions, &mut visits, &mut order)?;
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(len: usize, depends: Vec<usize>) -> Transaction {
+        Transaction::from_raw(vec![len as u8; len])
+            .unwrap()
+            .with_depends(depends)
+    }
+
+    #[test]
+    fn transactions_past_the_limits_are_dropped_with_their_children() {
+        let coinbase = tx(100, Vec::new());
+        let transactions = vec![
+            tx(50, Vec::new()),
+            tx(60, Vec::new()),
+            tx(30, vec![2]),
+            tx(20, Vec::new()),
+        ];
+        let unlimited = BlockLimits {
+            size: u64::MAX,
+            weight: u64::MAX,
+            sigops: u64::MAX,
+        };
+        let all = select(&coinbase, transactions.clone(), &unlimited).unwrap();
+        assert_eq!(all.kept, transactions);
+        assert!(all.dropped.is_empty());
+
+        // Room for the header, count, coinbase and 80 more bytes: the
+        // second doesn't fit, which takes its child along
+        let limits = BlockLimits {
+            size: 81 + 100 + 80,
+            ..unlimited
+        };
+        let trimmed = select(&coinbase, transactions.clone(), &limits).unwrap();
+        assert_eq!(
+            trimmed.kept,
+            vec![transactions[0].clone(), transactions[3].clone()]
+        );
+        assert_eq!(
+            trimmed.dropped,
+            vec![transactions[1].clone(), transactions[2].clone()]
+        );
+
+        let limits = BlockLimits {
+            weight: (81 + 100) * 4,
+            ..unlimited
+        };
+        assert!(select(&coinbase, transactions, &limits)
+            .unwrap()
+            .kept
+            .is_empty());
+    }
+
+    #[test]
+    fn parents_are_moved_ahead_of_their_children() {
+        let coinbase = tx(100, Vec::new());
+        let limits = BlockLimits {
+            size: u64::MAX,
+            weight: u64::MAX,
+            sigops: u64::MAX,
+        };
+        // The first spends the third, which spends the fourth
+        let transactions = vec![
+            tx(10, vec![3]),
+            tx(20, Vec::new()),
+            tx(30, vec![4]),
+            tx(40, Vec::new()),
+        ];
+        let selection = select(&coinbase, transactions.clone(), &limits).unwrap();
+        let lens: Vec<usize> = selection.kept.iter().map(|tx| tx.raw().len()).collect();
+        assert_eq!(lens, vec![40, 30, 10, 20]);
+
+        // A dropped parent takes the child along wherever it is listed
+        let limits = BlockLimits {
+            size: 81 + 100 + 35,
+            ..limits
+        };
+        let selection = select(&coinbase, transactions, &limits).unwrap();
+        let kept: Vec<usize> = selection.kept.iter().map(|tx| tx.raw().len()).collect();
+        let dropped: Vec<usize> = selection.dropped.iter().map(|tx| tx.raw().len()).collect();
+        assert_eq!((kept, dropped), (vec![20], vec![40, 30, 10]));
+
+        let cycle = vec![tx(10, vec![2]), tx(20, vec![1])];
+        assert!(select(&coinbase, cycle, &limits).is_err());
+        let missing = vec![tx(10, vec![5])];
+        assert!(select(&coinbase, missing, &limits).is_err());
+    }
+}
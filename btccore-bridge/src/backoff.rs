@@ -0,0 +1,103 @@
+//! Waiting between retries when the node can't be reached.
+//!
+//! Delays double with every failure up to a cap, and each is shortened by
+//! a random part so miners sharing a node don't all retry at once.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Default delay after the first failure
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+// Default cap of the delay
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Jittered exponential backoff
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+    // xorshift state for the jitter, never zero
+    rng: u64,
+}
+
+impl Backoff {
+    /// Waits about initial after the first failure, never more than max
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Self {
+            initial,
+            max: max.max(initial),
+            attempts: 0,
+            rng: seed | 1,
+        }
+    }
+
+    /// Getter for the delay after the first failure
+    pub fn get_initial(&self) -> Duration {
+        self.initial
+    }
+
+    /// Getter for the longest delay
+    pub fn get_max(&self) -> Duration {
+        self.max
+    }
+
+    /// Failures since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Delay before the next retry, between half and all of initial
+    /// doubled per earlier failure
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self
+            .initial
+            .saturating_mul(1 << self.attempts.min(31))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let jitter = (self.rng >> 11) as f64 / (1u64 << 53) as f64;
+        base.mul_f64(0.5 + 0.5 * jitter)
+    }
+
+    /// Starts over at initial, after a success
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(DEFAULT_INITIAL_DELAY, DEFAULT_MAX_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_cap_with_jitter() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+        assert_eq!(backoff.attempts(), 6);
+
+        let bases = [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis);
+        for (delay, base) in delays.iter().zip(bases) {
+            assert!(
+                *delay >= base / 2 && *delay <= base,
+                "{delay:?} for {base:?}"
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+        assert_eq!(backoff.get_max(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,350 @@
+//! Coinbase transaction of a block built from a template.
+//!
+//! The coinbase pays the block reward to the payout address and, once
+//! segwit is active, commits to the wtxids of the block (BIP 141) in an
+//! OP_RETURN output. Its witness then holds the reserved value.
+
+use std::ops::Range;
+
+use anyhow::{ensure, Result};
+
+use crate::merkle::{merkle_root, sha256d};
+
+// OP_RETURN, a 36 byte push and the commitment header aa21a9ed
+const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Witness of the coinbase input, all zero as Bitcoin Core uses it
+const WITNESS_RESERVED_VALUE: [u8; 32] = [0u8; 32];
+
+// Largest scriptSig of a coinbase
+const MAX_COINBASE_SCRIPT_SIG: usize = 100;
+
+// Longest push of push_data, more than a coinbase ever needs
+const MAX_PUSH: usize = 75;
+
+/// Longest OP_RETURN data nodes relay by default (-datacarriersize)
+pub const MAX_OP_RETURN_DATA: usize = 80;
+
+/// Serialized coinbase with its txid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coinbase {
+    /// Consensus serialization, with the witness if it has one
+    pub raw: Vec<u8>,
+    /// Hash of the serialization without witness
+    pub txid: [u8; 32],
+    /// Where the extranonce is in raw
+    pub extranonce: Range<usize>,
+    /// Where the script of the witness commitment output is in raw
+    pub commitment: Option<Range<usize>>,
+}
+
+/// Output paying part of the coinbase value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinbaseOutput {
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+impl Coinbase {
+    /// Pays value to script_pubkey at height, with extranonce after the
+    /// height in the scriptSig
+    /// witness_commitment is the script of the commitment output.
+    pub fn new(
+        height: u32,
+        value: u64,
+        script_pubkey: &[u8],
+        extranonce: &[u8],
+        witness_commitment: Option<&[u8]>,
+    ) -> Result<Self> {
+        let output = CoinbaseOutput {
+            value,
+            script_pubkey: script_pubkey.to_vec(),
+        };
+        Self::with_outputs(height, &[], &[output], extranonce, witness_commitment)
+    }
+
+    /// Like new, paying each of outputs in order, with tag pushed between
+    /// the height and the extranonce unless it is empty
+    pub fn with_outputs(
+        height: u32,
+        tag: &[u8],
+        outputs: &[CoinbaseOutput],
+        extranonce: &[u8],
+        witness_commitment: Option<&[u8]>,
+    ) -> Result<Self> {
+        ensure!(!outputs.is_empty(), "Coinbase without outputs.");
+        ensure!(
+            tag.len() <= MAX_PUSH && extranonce.len() <= MAX_PUSH,
+            "Coinbase tag and extranonce are limited to {MAX_PUSH} bytes each."
+        );
+        let mut script_sig = push_height(height);
+        if !tag.is_empty() {
+            push_data(&mut script_sig, tag);
+        }
+        push_data(&mut script_sig, extranonce);
+        ensure!(
+            (2..=MAX_COINBASE_SCRIPT_SIG).contains(&script_sig.len()),
+            "Coinbase scriptSig of {} bytes, should be 2 to {MAX_COINBASE_SCRIPT_SIG}",
+            script_sig.len()
+        );
+
+        let mut input = Vec::new();
+        input.extend_from_slice(&[0u8; 32]);
+        input.extend_from_slice(&u32::MAX.to_le_bytes());
+        write_compact_size(&mut input, script_sig.len() as u64);
+        input.extend_from_slice(&script_sig);
+        input.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut output_count = outputs.len() as u64;
+        let outputs = {
+            let mut serialized = Vec::new();
+            for output in outputs {
+                write_output(&mut serialized, output.value, &output.script_pubkey);
+            }
+            if let Some(commitment) = witness_commitment {
+                write_output(&mut serialized, 0, commitment);
+                output_count += 1;
+            }
+            serialized
+        };
+
+        // Version, marker and flag if there is a witness, inputs, outputs,
+        // witness, lock time
+        let serialize = |with_witness: bool| {
+            let mut tx = Vec::new();
+            tx.extend_from_slice(&2u32.to_le_bytes());
+            if with_witness {
+                tx.extend_from_slice(&[0x00, 0x01]);
+            }
+            write_compact_size(&mut tx, 1);
+            tx.extend_from_slice(&input);
+            write_compact_size(&mut tx, output_count);
+            tx.extend_from_slice(&outputs);
+            if with_witness {
+                write_compact_size(&mut tx, 1);
+                write_compact_size(&mut tx, WITNESS_RESERVED_VALUE.len() as u64);
+                tx.extend_from_slice(&WITNESS_RESERVED_VALUE);
+            }
+            tx.extend_from_slice(&0u32.to_le_bytes());
+            tx
+        };
+
+        // The extranonce ends the scriptSig, only the sequence follows in
+        // the input. Version, marker and flag and the input count precede it.
+        let marker = if witness_commitment.is_some() { 2 } else { 0 };
+        let end = 4 + marker + 1 + (input.len() - 4);
+
+        let stripped = serialize(false);
+        let raw = match witness_commitment {
+            Some(_) => serialize(true),
+            None => stripped.clone(),
+        };
+        // The commitment is the last output, the witness and lock time
+        // follow it
+        let commitment = witness_commitment.map(|script| {
+            let end = raw.len() - 4 - (2 + WITNESS_RESERVED_VALUE.len());
+            end - script.len()..end
+        });
+        Ok(Self {
+            txid: sha256d(&stripped),
+            extranonce: end - extranonce.len()..end,
+            commitment,
+            raw,
+        })
+    }
+}
+
+/// Script of the witness commitment output for the wtxids of every
+/// transaction but the coinbase, whose wtxid counts as zero
+pub fn witness_commitment(wtxids: &[[u8; 32]]) -> Vec<u8> {
+    let hashes: Vec<[u8; 32]> = std::iter::once([0u8; 32])
+        .chain(wtxids.iter().copied())
+        .collect();
+    let root = merkle_root(&hashes);
+    let commitment = sha256d(&[root, WITNESS_RESERVED_VALUE].concat());
+    [&WITNESS_COMMITMENT_PREFIX[..], &commitment].concat()
+}
+
+/// Appends a Bitcoin CompactSize, the variable length integer in front of
+/// scripts and lists
+pub(crate) fn write_compact_size(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+fn write_output(out: &mut Vec<u8>, value: u64, script_pubkey: &[u8]) {
+    out.extend_from_slice(&value.to_le_bytes());
+    write_compact_size(out, script_pubkey.len() as u64);
+    out.extend_from_slice(script_pubkey);
+}
+
+// Height as BIP 34 wants it, the way Bitcoin Core's CScript << height
+// pushes a number: OP_1 to OP_16 for small heights, else minimal bytes
+fn push_height(height: u32) -> Vec<u8> {
+    match height {
+        0 => vec![0x00],
+        1..=16 => vec![0x50 + height as u8],
+        _ => {
+            let mut bytes: Vec<u8> = height.to_le_bytes().to_vec();
+            while bytes.last() == Some(&0) {
+                bytes.pop();
+            }
+            // The top bit is the sign of a script number
+            if bytes.last().is_some_and(|&byte| byte & 0x80 != 0) {
+                bytes.push(0);
+            }
+            let mut script = Vec::new();
+            push_data(&mut script, &bytes);
+            script
+        }
+    }
+}
+
+/// Output of no value carrying data after an OP_RETURN
+pub fn op_return(data: &[u8]) -> Result<CoinbaseOutput> {
+    ensure!(
+        data.len() <= MAX_OP_RETURN_DATA,
+        "OP_RETURN data of {} bytes, nodes relay at most {MAX_OP_RETURN_DATA}.",
+        data.len()
+    );
+    let mut script_pubkey = vec![0x6a];
+    // OP_PUSHDATA1 past what a plain push holds
+    if data.len() > MAX_PUSH {
+        script_pubkey.push(0x4c);
+    }
+    script_pubkey.push(data.len() as u8);
+    script_pubkey.extend_from_slice(data);
+    Ok(CoinbaseOutput {
+        value: 0,
+        script_pubkey,
+    })
+}
+
+// Pushes up to 75 bytes, more than a coinbase ever has
+fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    debug_assert!(data.len() <= MAX_PUSH);
+    script.push(data.len() as u8);
+    script.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_blocks_commit_to_the_known_value() {
+        // Default commitment of bitcoind for a block without transactions
+        assert_eq!(
+            hex::encode(witness_commitment(&[])),
+            "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9"
+        );
+        assert_ne!(witness_commitment(&[[1; 32]]), witness_commitment(&[]));
+    }
+
+    #[test]
+    fn coinbase_pays_and_commits() {
+        let script_pubkey = [0x00, 0x14]
+            .into_iter()
+            .chain([0xab; 20])
+            .collect::<Vec<_>>();
+        let commitment = witness_commitment(&[]);
+        let coinbase = Coinbase::new(
+            102,
+            5_000_000_000,
+            &script_pubkey,
+            &[0; 8],
+            Some(&commitment),
+        )
+        .unwrap();
+        let raw = &coinbase.raw;
+
+        // Version 2, segwit marker and flag, one null input
+        assert_eq!(raw[..6], [2, 0, 0, 0, 0x00, 0x01]);
+        assert_eq!(raw[6], 1);
+        assert_eq!(raw[7..39], [0; 32]);
+        assert_eq!(raw[39..43], [0xff; 4]);
+        // scriptSig: height 102 as a one byte push, then the extranonce
+        assert_eq!(raw[43..47], [11, 1, 102, 8]);
+        assert_eq!(coinbase.extranonce, 47..55);
+        assert_eq!(raw[coinbase.commitment.clone().unwrap()], commitment);
+        assert!(raw
+            .windows(8)
+            .any(|window| window == 5_000_000_000u64.to_le_bytes()));
+        // Witness with the reserved value, then lock time 0
+        assert_eq!(
+            raw[raw.len() - 38..raw.len() - 4],
+            [[1, 32].as_slice(), &[0; 32]].concat()
+        );
+
+        // The txid leaves the witness out
+        let legacy = Coinbase::new(102, 5_000_000_000, &script_pubkey, &[0; 8], None).unwrap();
+        assert_ne!(legacy.raw, coinbase.raw);
+        assert_eq!(sha256d(&legacy.raw), legacy.txid);
+        assert_ne!(legacy.txid, coinbase.txid);
+        assert_eq!(legacy.extranonce, 45..53);
+        assert_eq!(legacy.commitment, None);
+        assert_eq!(
+            legacy.raw.len(),
+            coinbase.raw.len() - 2 - 34 - 8 - 1 - commitment.len()
+        );
+    }
+
+    #[test]
+    fn tags_and_op_returns_are_embedded() {
+        let outputs = [
+            CoinbaseOutput {
+                value: 50,
+                script_pubkey: vec![0x51],
+            },
+            op_return(b"hello").unwrap(),
+        ];
+        let coinbase =
+            Coinbase::with_outputs(102, b"/harvester/", &outputs, &[0; 4], None).unwrap();
+        let raw = &coinbase.raw;
+        // Height, the tag, then the extranonce
+        assert_eq!(raw[41..45], [2 + 12 + 5, 1, 102, 11]);
+        assert_eq!(raw[45..56], *b"/harvester/");
+        assert_eq!(raw[coinbase.extranonce.start - 1], 4);
+        assert_eq!(coinbase.extranonce, 57..61);
+        let data_output = [&[0u8; 8][..], &[7, 0x6a, 5], b"hello"].concat();
+        assert!(raw
+            .windows(data_output.len())
+            .any(|window| window == data_output));
+
+        assert_eq!(
+            op_return(&[1; 80]).unwrap().script_pubkey[..3],
+            [0x6a, 0x4c, 80]
+        );
+        assert!(op_return(&[1; 81]).is_err());
+        // Too long for the scriptSig together
+        assert!(Coinbase::with_outputs(102, &[b'x'; 75], &outputs, &[0; 32], None).is_err());
+        assert!(Coinbase::with_outputs(102, &[b'x'; 76], &outputs, &[], None).is_err());
+    }
+
+    #[test]
+    fn heights_are_pushed_as_script_numbers() {
+        assert_eq!(push_height(1), [0x51]);
+        assert_eq!(push_height(16), [0x60]);
+        assert_eq!(push_height(17), [1, 17]);
+        assert_eq!(push_height(128), [2, 0x80, 0x00]);
+        assert_eq!(push_height(840_000), [3, 0x40, 0xd1, 0x0c]);
+
+        let mut size = Vec::new();
+        write_compact_size(&mut size, 0xfd);
+        write_compact_size(&mut size, 0x1_0000);
+        assert_eq!(size, [0xfd, 0xfd, 0x00, 0xfe, 0x00, 0x00, 0x01, 0x00]);
+    }
+}
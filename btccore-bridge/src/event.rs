@@ -0,0 +1,53 @@
+//! Events the bridge sends to the mining side.
+//!
+//! ZMQ announcements, long polls and failures all end up on the channel
+//! of the Bridge, so the orchestrator can tell a new tip, which makes the
+//! current work worthless, from a template that merely got better.
+
+use std::fmt;
+
+use crate::BlockTemplate;
+
+/// Hash of a block in display order, as ZMQ and RPC give it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockHash(pub [u8; 32]);
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Something the miner may have to react to
+#[derive(Debug)]
+pub enum BridgeEvent {
+    /// A block was connected, work on the old tip is stale
+    NewTip(BlockHash),
+    /// Long polling returned a newer template, see Bridge::use_template
+    NewTemplate(Box<BlockTemplate>),
+    /// The tip is unchanged but a new template would pay more
+    TemplateExpired,
+    /// ZMQ or RPC failed and is being retried, the current work may be
+    /// out of date
+    ConnectionLost(String),
+    /// The node answers again after ConnectionLost
+    ConnectionRestored,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_hashes_display_as_hex() {
+        let mut hash = [0u8; 32];
+        hash[0] = 0x5f;
+        hash[31] = 0x46;
+        let hex = BlockHash(hash).to_string();
+        assert_eq!(hex.len(), 64);
+        assert!(hex.starts_with("5f00") && hex.ends_with("0046"));
+    }
+}
@@ -0,0 +1,77 @@
+//! When the work of the miner is too old to keep mining.
+//!
+//! Templates age even without new blocks: transactions keep paying fees
+//! and curtime falls behind. Once the node moved to another tip the work
+//! is stale outright, a solved block would be an orphan.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::{
+    sync::mpsc::Sender,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::{send, BlockHash, BridgeEvent};
+
+/// How long a template is used before fetching another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreshnessPolicy {
+    /// Age at which a new template is worth fetching
+    pub refresh_interval: Duration,
+    /// Age at which the work is stale even on the right tip
+    pub stale_after: Duration,
+}
+
+impl Default for FreshnessPolicy {
+    fn default() -> Self {
+        Self {
+            refresh_interval: Duration::from_secs(30),
+            stale_after: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Tip of the node as the events reported it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tip {
+    pub hash: BlockHash,
+    /// Only known from templates, ZMQ announces the hash alone
+    pub height: Option<u32>,
+}
+
+/// Sends TemplateExpired every interval indefinitely, so the template
+/// picks up new fees and time without waiting for a block. Only returns
+/// when the channel is closed.
+pub async fn refresh_templates(sender: Sender<BridgeEvent>, interval: Duration) -> Result<()> {
+    let mut ticks = time::interval_at(Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        send(&sender, BridgeEvent::TemplateExpired).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn templates_expire_every_interval() {
+        let (sender, mut events) = mpsc::channel(8);
+        let start = Instant::now();
+        let task = tokio::spawn(refresh_templates(sender, Duration::from_millis(20)));
+
+        for _ in 0..2 {
+            assert!(matches!(
+                events.recv().await,
+                Some(BridgeEvent::TemplateExpired)
+            ));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        drop(events);
+        assert!(task.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Append-only journal of solved blocks.
+//!
+//! Every block is written to disk before it is submitted, and the node's
+//! response after. A block without a response never reached the node, so
+//! it is submitted again on the next start. Records are JSON lines, flushed
+//! to disk one by one; a crash can at most cut off the last line.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{encode_hex, SubmitResult};
+
+/// Solved block that is still to be submitted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundBlock {
+    pub header: [u8; 80],
+    /// Serialized block as submitblock takes it
+    pub hex: String,
+    /// Seconds since the Unix epoch
+    pub found_at: u64,
+}
+
+/// Journal file of solved blocks
+#[derive(Debug)]
+pub struct BlockJournal {
+    path: PathBuf,
+}
+
+// One line of the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Found {
+        header: String,
+        block: String,
+        found_at: u64,
+    },
+    Submitted {
+        header: String,
+        response: String,
+        submitted_at: u64,
+    },
+}
+
+impl BlockJournal {
+    /// Journal at path, created with the first block
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Getter for the path of the journal
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes a block down before it is submitted, hex as submitblock
+    /// takes it
+    pub fn record_found(&self, header: &[u8; 80], hex: &str) -> Result<()> {
+        self.append(&Record::Found {
+            header: encode_hex(header),
+            block: hex.to_string(),
+            found_at: now(),
+        })
+    }
+
+    /// Writes down what the node made of the block with header
+    pub fn record_response(&self, header: &[u8; 80], result: &SubmitResult) -> Result<()> {
+        let response = match result {
+            SubmitResult::Accepted => "accepted".to_string(),
+            SubmitResult::Rejected(reason) => format!("rejected: {reason}"),
+        };
+        self.append(&Record::Submitted {
+            header: encode_hex(header),
+            response,
+            submitted_at: now(),
+        })
+    }
+
+    /// Blocks found without a response, oldest first
+    pub fn pending(&self) -> Result<Vec<FoundBlock>> {
+        let journal = match std::fs::read_to_string(&self.path) {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).context("Couldn't read the block journal."),
+        };
+
+        let mut pending: Vec<FoundBlock> = Vec::new();
+        let lines: Vec<&str> = journal.lines().collect();
+        for (number, line) in lines.iter().enumerate() {
+            let record = match serde_json::from_str(line) {
+                Ok(record) => record,
+                // Cut off by a crash while it was written
+                Err(_) if number + 1 == lines.len() && !journal.ends_with('\n') => break,
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("Line {} of the block journal is corrupt.", number + 1)
+                    })
+                }
+            };
+            match record {
+                Record::Found {
+                    header,
+                    block,
+                    found_at,
+                } => {
+                    let header = crate::decode_hex(&header)?
+                        .try_into()
+                        .map_err(|_| anyhow::anyhow!("Journal header isn't 80 bytes."))?;
+                    pending.push(FoundBlock {
+                        header,
+                        hex: block,
+                        found_at,
+                    });
+                }
+                Record::Submitted { header, .. } => {
+                    pending.retain(|found| encode_hex(&found.header) != header);
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    // Appends record as a line and waits for it to reach the disk
+    fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Couldn't open the block journal {}.", self.path.display()))?;
+        // A line cut off by a crash goes, it would corrupt this one
+        let journal = std::fs::read(&self.path)?;
+        if !journal.is_empty() && !journal.ends_with(b"\n") {
+            let end = journal.iter().rposition(|&byte| byte == b'\n');
+            file.set_len(end.map_or(0, |end| end as u64 + 1))?;
+        }
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+            .context("Couldn't flush the block journal.")?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_stay_pending_until_the_node_answers() {
+        let path = std::env::temp_dir().join(format!("harvester-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journal = BlockJournal::new(&path);
+        assert!(journal.pending().unwrap().is_empty());
+
+        let record = |header: [u8; 80]| Record::Found {
+            header: encode_hex(&header),
+            block: encode_hex(&header),
+            found_at: 1,
+        };
+        journal.append(&record([1; 80])).unwrap();
+        journal.append(&record([2; 80])).unwrap();
+        journal
+            .record_response(&[1; 80], &SubmitResult::Rejected("duplicate".to_string()))
+            .unwrap();
+        let pending = journal.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].header, [2; 80]);
+        assert_eq!(pending[0].hex, encode_hex(&[2; 80]));
+
+        // A line the crash cut off is ignored and replaced, damage
+        // elsewhere isn't
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"record":"found","hea"#).unwrap();
+        assert_eq!(journal.pending().unwrap().len(), 1);
+        journal
+            .record_response(&[2; 80], &SubmitResult::Accepted)
+            .unwrap();
+        assert!(journal.pending().unwrap().is_empty());
+        file.write_all(b"{}\n").unwrap();
+        assert!(journal.pending().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,72 @@
-use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    ops::{Range, RangeInclusive},
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
-type Transaction = Vec<u8>;
+mod address;
+mod assembly;
+mod backoff;
+mod client;
+mod coinbase;
+mod event;
+mod freshness;
+mod journal;
+mod merkle;
+mod network;
+mod payout;
+mod pool;
+#[cfg(feature = "test-support")]
+pub mod regtest;
+mod rpc;
+mod signet;
+mod socks;
+mod stratum;
+mod subscriber;
+#[cfg(feature = "tls")]
+mod tls;
+mod transaction;
+
+pub use address::{Address, Payload};
+pub use assembly::BlockLimits;
+pub use backoff::Backoff;
+#[cfg(unix)]
+pub use client::UnixTransport;
+pub use client::{NodeClient, TcpTransport, Transport};
+pub use coinbase::{op_return, witness_commitment, Coinbase, CoinbaseOutput, MAX_OP_RETURN_DATA};
+pub use event::{BlockHash, BridgeEvent};
+pub use freshness::{refresh_templates, FreshnessPolicy, Tip};
+pub use journal::{BlockJournal, FoundBlock};
+pub use network::Network;
+pub use payout::PayoutSplit;
+pub use pool::{Share, ShareStats, StratumClient, StratumEvent};
+pub use rpc::{check_rules, JsonRpcRequest, TemplateRequest, SUPPORTED_RULES};
+pub use signet::{SignetSolution, SIGNET_HEADER};
+pub use socks::Socks5Transport;
+pub use stratum::{Extranonce, StratumJob, StratumWork};
+pub use subscriber::{TemplateRefresh, ZmqSubscriber, HASHBLOCK_TOPIC, HASHTX_TOPIC};
+#[cfg(feature = "tls")]
+pub use tls::TlsTransport;
+pub use transaction::{strip_witness, Transaction};
+
+// Default bytes the coinbase scriptSig leaves for an extranonce
+const DEFAULT_EXTRANONCE_SIZE: usize = 8;
+
+/// Longest extranonce the coinbase scriptSig takes
+pub const MAX_EXTRANONCE_SIZE: usize = 32;
+
+/// Longest tag the coinbase scriptSig takes
+pub const MAX_COINBASE_TAG: usize = 75;
+
+// Default retries of a failed getblocktemplate in update_block
+const DEFAULT_RPC_RETRIES: u32 = 3;
 
+#[allow(dead_code)]
 #[derive(Debug, Default, Deserialize, Serialize)]
 struct CoinbaseTransaction;
 
@@ -12,6 +74,174 @@ struct CoinbaseTransaction;
 pub struct Block {
     header: [u8; 80],
     transactions: Vec<Transaction>,
+    dropped: Vec<Transaction>,
+    target: [u8; 32],
+    height: u32,
+    previousblockhash: BlockHash,
+    created: Instant,
+    // Hashes that lead from the coinbase txid to the merkle root
+    merkle_branch: Vec<[u8; 32]>,
+    // Where the extranonce is in the raw coinbase
+    extranonce: Range<usize>,
+    // Where the script of the commitment output is in the raw coinbase
+    commitment: Option<Range<usize>>,
+    signet_challenge: Option<Vec<u8>>,
+    signet_solution: Option<SignetSolution>,
+}
+
+impl Block {
+    /// Getter for the 80 byte header
+    pub fn header(&self) -> &[u8; 80] {
+        &self.header
+    }
+
+    /// Replaces the header with the one the miner found, whose nonce,
+    /// time and version may differ from the template
+    pub fn set_header(&mut self, header: [u8; 80]) {
+        self.header = header;
+    }
+
+    /// Big-endian 256-bit target the block hash has to meet, what the
+    /// miner should be given
+    pub fn target(&self) -> &[u8; 32] {
+        &self.target
+    }
+
+    /// Serialized transactions, the coinbase first
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Template transactions left out to stay within the limits
+    pub fn dropped(&self) -> &[Transaction] {
+        &self.dropped
+    }
+
+    /// Height the block would have
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Tip the block builds on
+    pub fn previousblockhash(&self) -> &BlockHash {
+        &self.previousblockhash
+    }
+
+    /// Time since the block was built from its template
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Extranonce in the scriptSig of the coinbase, all zero at first
+    pub fn extranonce(&self) -> &[u8] {
+        &self.transactions[0].raw()[self.extranonce.clone()]
+    }
+
+    /// Counts the extranonce up as a little-endian number and puts the new
+    /// merkle root in the header, for when the miner ran out of nonces.
+    /// Fails once every extranonce was used, or once a signet block is
+    /// signed.
+    pub fn roll_extranonce(&mut self) -> Result<()> {
+        ensure!(
+            self.signet_solution.is_none(),
+            "The signature covers the extranonce, update_block for a new block."
+        );
+        let mut raw = self.transactions[0].raw().to_vec();
+        let extranonce = &mut raw[self.extranonce.clone()];
+        // Stops at the first byte that doesn't carry
+        let rolled = extranonce.iter_mut().any(|byte| {
+            *byte = byte.wrapping_add(1);
+            *byte != 0
+        });
+        ensure!(
+            rolled,
+            "All extranonces of {} bytes are used.",
+            extranonce.len()
+        );
+
+        let coinbase = Transaction::from_raw(raw)?;
+        let root = merkle::root_from_branch(coinbase.txid(), &self.merkle_branch);
+        self.header[36..68].copy_from_slice(&root);
+        self.transactions[0] = coinbase;
+        Ok(())
+    }
+
+    /// Script a signet block has to be signed for, None off signet
+    pub fn signet_challenge(&self) -> Option<&[u8]> {
+        self.signet_challenge.as_deref()
+    }
+
+    /// Solution the block was signed with, None until then
+    pub fn signet_solution(&self) -> Option<&SignetSolution> {
+        self.signet_solution.as_ref()
+    }
+
+    /// Base64 PSBT for a wallet holding the keys of the signet challenge,
+    /// e.g. through walletprocesspsbt, see SignetSolution::from_psbt
+    pub fn signet_psbt(&self) -> Result<String> {
+        let challenge = self
+            .signet_challenge
+            .as_ref()
+            .context("Only signet blocks are signed.")?;
+        ensure!(self.signet_solution.is_none(), "Block is signed already.");
+        Ok(signet::signing_psbt(&self.header, challenge))
+    }
+
+    /// Pushes solution after the witness commitment and puts the new
+    /// merkle root in the header. Version and time then have to stay as
+    /// they are, the miner may only roll the nonce.
+    pub fn set_signet_solution(&mut self, solution: SignetSolution) -> Result<()> {
+        ensure!(
+            self.signet_challenge.is_some(),
+            "Only signet blocks are signed."
+        );
+        let commitment = self
+            .commitment
+            .clone()
+            .context("Signet block without a witness commitment.")?;
+        let (raw, commitment) =
+            signet::with_solution(self.transactions[0].raw(), commitment, &solution)?;
+
+        let coinbase = Transaction::from_raw(raw)?;
+        let root = merkle::root_from_branch(coinbase.txid(), &self.merkle_branch);
+        self.header[36..68].copy_from_slice(&root);
+        self.transactions[0] = coinbase;
+        self.commitment = Some(commitment);
+        self.signet_solution = Some(solution);
+        Ok(())
+    }
+
+    /// Fees the transactions pay on top of the subsidy
+    pub fn fees(&self) -> u64 {
+        self.transactions.iter().map(Transaction::fee).sum()
+    }
+
+    /// Weight of the block, at most 4M for a valid one
+    pub fn weight(&self) -> u64 {
+        let mut count = Vec::new();
+        coinbase::write_compact_size(&mut count, self.transactions.len() as u64);
+        let base = (self.header.len() + count.len()) as u64 * 4;
+        base + self
+            .transactions
+            .iter()
+            .map(Transaction::weight)
+            .sum::<u64>()
+    }
+
+    /// Consensus serialization: header, transaction count, transactions
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut block = self.header.to_vec();
+        coinbase::write_compact_size(&mut block, self.transactions.len() as u64);
+        for tx in &self.transactions {
+            block.extend_from_slice(tx.raw());
+        }
+        block
+    }
+
+    /// Hex of to_bytes, as submitblock takes it
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.to_bytes())
+    }
 }
 
 /// Block template as per BIP 0022
@@ -20,75 +250,662 @@ pub struct Block {
 pub struct BlockTemplate {
     bits: String,
     curtime: u32,
+    // Older templates may leave the minimum time out
+    #[serde(default)]
+    mintime: u32,
     height: u32,
     previousblockhash: String,
+    // Big-endian hex of the exact target, bits can only approximate it
+    #[serde(default)]
+    target: Option<String>,
     sigoplimit: u32,
     sizelimit: u32,
+    // Missing before segwit, where sizelimit is what binds
+    #[serde(default)]
+    weightlimit: Option<u32>,
     transactions: Vec<Transaction>,
     version: u32,
     // coinbaseaux is ignored for this implementation
     //coinbasetxn: CoinbaseTransaction,
     coinbasevalue: u64,
+    // Script of the witness commitment output, missing before segwit
+    #[serde(default)]
+    default_witness_commitment: Option<String>,
+    // Parts of the template the miner may change, "time" for rolling
+    #[serde(default)]
+    mutable: Vec<String>,
+    // Hex of the lowest and highest nonce, both big-endian
+    #[serde(default)]
+    noncerange: Option<String>,
+    // Missing if the node doesn't do long polling
+    #[serde(default)]
+    longpollid: Option<String>,
+    // Script blocks have to be signed for, only on signet
+    #[serde(default)]
+    signet_challenge: Option<String>,
+    // Deployments the block follows, ! if the miner has to know them
+    #[serde(default)]
+    rules: Vec<String>,
+    // Pending deployments by name, with the version bit each signals on
+    #[serde(default)]
+    vbavailable: BTreeMap<String, u8>,
     // workid is ignored for this implementation
 }
 
+impl BlockTemplate {
+    /// Current time of the node, in Unix seconds
+    pub fn curtime(&self) -> u32 {
+        self.curtime
+    }
+
+    /// Earliest time a block on this template may have
+    pub fn mintime(&self) -> u32 {
+        self.mintime
+    }
+
+    /// Height of the block on top of previousblockhash
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Fields the miner may change, e.g. "time" or "transactions"
+    pub fn mutable(&self) -> &[String] {
+        &self.mutable
+    }
+
+    /// True if the template lists field as mutable
+    pub fn is_mutable(&self, field: &str) -> bool {
+        self.mutable.iter().any(|mutable| mutable == field)
+    }
+
+    /// Nonces the header may use, all of them if the node leaves it out
+    pub fn noncerange(&self) -> Result<RangeInclusive<u32>> {
+        let Some(range) = &self.noncerange else {
+            return Ok(0..=u32::MAX);
+        };
+        let parse = |hex: Option<&str>| {
+            hex.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .with_context(|| format!("Invalid noncerange {range}."))
+        };
+        ensure!(range.len() == 16, "Invalid noncerange {range}.");
+        let (start, end) = (parse(range.get(..8))?, parse(range.get(8..))?);
+        ensure!(start <= end, "Empty noncerange {range}.");
+        Ok(start..=end)
+    }
+
+    /// Id to pass back to getblocktemplate to long poll for a new template
+    pub fn longpollid(&self) -> Option<&str> {
+        self.longpollid.as_deref()
+    }
+
+    /// Deployment rules of the block, such as "segwit"
+    /// A leading ! marks rules a miner must understand to use the template.
+    pub fn rules(&self) -> &[String] {
+        &self.rules
+    }
+
+    /// Deployments being signalled, with their version bit
+    pub fn vbavailable(&self) -> &BTreeMap<String, u8> {
+        &self.vbavailable
+    }
+
+    /// Big-endian 256-bit target of the block
+    /// Parsed from the target field if the node sent one, else expanded
+    /// from bits.
+    pub fn target(&self) -> Result<[u8; 32]> {
+        match &self.target {
+            Some(target) => decode_hex(target)
+                .and_then(|bytes| {
+                    bytes
+                        .try_into()
+                        .map_err(|bytes: Vec<u8>| anyhow::anyhow!("{} bytes", bytes.len()))
+                })
+                .context("Invalid target."),
+            None => bits_to_target(self.bits()?),
+        }
+    }
+
+    /// Size, weight and sigop limits the block has to stay within
+    pub fn limits(&self) -> BlockLimits {
+        BlockLimits {
+            size: self.sizelimit as u64,
+            weight: self.weightlimit.map_or(u64::MAX, u64::from),
+            sigops: self.sigoplimit as u64,
+        }
+    }
+
+    /// Script of the witness commitment output for the coinbase
+    /// Taken from default_witness_commitment, else computed if a
+    /// transaction has a witness. None if nothing needs committing.
+    pub fn witness_commitment(&self) -> Result<Option<Vec<u8>>> {
+        if let Some(commitment) = &self.default_witness_commitment {
+            return decode_hex(commitment)
+                .map(Some)
+                .context("Invalid default_witness_commitment.");
+        }
+        if !self.transactions.iter().any(Transaction::has_witness) {
+            return Ok(None);
+        }
+        let wtxids: Vec<[u8; 32]> = self.transactions.iter().map(|tx| *tx.wtxid()).collect();
+        Ok(Some(witness_commitment(&wtxids)))
+    }
+
+    /// Script a block of this template has to be signed for, None unless
+    /// the node runs a signet
+    pub fn signet_challenge(&self) -> Result<Option<Vec<u8>>> {
+        self.signet_challenge
+            .as_deref()
+            .map(decode_hex)
+            .transpose()
+            .context("Invalid signet_challenge.")
+    }
+
+    /// Serialized 80 byte header for this template with a zero nonce
+    /// merkle_root is in SHA256 byte order, previousblockhash and bits come
+    /// as the big-endian hex bitcoind prints and are swapped to match.
+    pub fn to_header(&self, merkle_root: &[u8; 32]) -> Result<[u8; 80]> {
+        let prev_hash = self.previousblockhash()?;
+        let bits = self.bits()?;
+
+        let mut header = [0u8; 80];
+        header[..4].copy_from_slice(&self.version.to_le_bytes());
+        header[4..36].copy_from_slice(&prev_hash);
+        header[4..36].reverse();
+        header[36..68].copy_from_slice(merkle_root);
+        header[68..72].copy_from_slice(&self.curtime.to_le_bytes());
+        header[72..76].copy_from_slice(&bits.to_le_bytes());
+        Ok(header)
+    }
+
+    /// Hash of the block this one builds on, in the display order ZMQ
+    /// hashblock messages use
+    pub fn previousblockhash(&self) -> Result<[u8; 32]> {
+        decode_hex(&self.previousblockhash)
+            .and_then(|bytes| {
+                bytes
+                    .try_into()
+                    .map_err(|bytes: Vec<u8>| anyhow::anyhow!("{} bytes", bytes.len()))
+            })
+            .context("Invalid previousblockhash.")
+    }
+
+    // Compact target of the header
+    fn bits(&self) -> Result<u32> {
+        u32::from_str_radix(&self.bits, 16).context("Invalid bits.")
+    }
+}
+
+// Expands compact bits into a big-endian 256-bit target
+pub(crate) fn bits_to_target(bits: u32) -> Result<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    ensure!(
+        bits & 0x0080_0000 == 0 || mantissa == 0,
+        "Bits {bits:08x} encode a negative target."
+    );
+
+    // The three mantissa bytes start exponent bytes from the end
+    let mut target = [0u8; 32];
+    for (i, &byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+        match (32 + i).checked_sub(exponent) {
+            Some(index) if index < 32 => target[index] = byte,
+            Some(_) => {}
+            None => ensure!(byte == 0, "Bits {bits:08x} overflow 256 bits."),
+        }
+    }
+    Ok(target)
+}
+
+// Decodes hex without pulling in a crate for it
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("Odd number of hex digits.");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .context("Not a hex digit.")
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
 /// Trait for dependency injection and mocking
 #[async_trait]
 pub trait RpcClient {
-    async fn getblocktemplate(&self) -> Result<BlockTemplate>;
+    /// Sends request as the only parameter, see JsonRpcRequest
+    async fn getblocktemplate(&self, request: &TemplateRequest) -> Result<BlockTemplate>;
+
+    /// Sends the hex of a block, see SubmitResult::from_response
+    async fn submitblock(&self, block_hex: &str) -> Result<SubmitResult>;
+
+    /// Sends the hex of a block to getblocktemplate in proposal mode, see
+    /// JsonRpcRequest::proposal. The answer has the shape of submitblock.
+    async fn proposeblock(&self, block_hex: &str) -> Result<SubmitResult>;
+
+    /// Has the wallet of the node sign a base64 PSBT and returns it
+    /// finalized, see JsonRpcRequest::walletprocesspsbt. Only signets
+    /// need it.
+    async fn walletprocesspsbt(&self, psbt: &str) -> Result<String>;
 }
 
-/// Struct to parse the response from JSON-RPC getblocktemplate
+/// Struct to parse the response from JSON-RPC getblocktemplate, or from
+/// submitblock with T = Option<String>
 #[derive(Debug, Deserialize)]
-pub struct JsonRpcResponse {
-    result: BlockTemplate,
-    error: Option<serde_json::Value>,
-    id: String,
+pub struct JsonRpcResponse<T = BlockTemplate> {
+    pub result: T,
+    pub error: Option<serde_json::Value>,
+    pub id: String,
+}
+
+/// What the node made of a submitted block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitResult {
+    Accepted,
+    /// Reason the node gave, such as "high-hash" or "duplicate"
+    Rejected(String),
+}
+
+impl SubmitResult {
+    /// submitblock returns null for an accepted block and the reason as a
+    /// string otherwise, errors are for malformed requests
+    pub fn from_response(response: JsonRpcResponse<Option<String>>) -> Result<Self> {
+        if let Some(error) = response.error.filter(|error| !error.is_null()) {
+            anyhow::bail!("submitblock failed: {error}");
+        }
+        Ok(match response.result {
+            None => SubmitResult::Accepted,
+            Some(reason) => SubmitResult::Rejected(reason),
+        })
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        *self == SubmitResult::Accepted
+    }
 }
 
 /// Using a trait allows us to mock the zmq_receiver
 #[async_trait]
 pub trait ZmqReceiver {
-    async fn recv(&self) -> Result<[u8; 32]>;
+    async fn recv(&self) -> Result<BridgeEvent>;
 }
 
 /// Bridge between Bitcoin Core and a tokio channel
 pub struct Bridge<T: RpcClient> {
     block: Option<Block>,
+    network: Network,
     rpc_client: T,
-    sender: Sender<[u8; 32]>,
+    sender: Sender<BridgeEvent>,
+    backoff: Backoff,
+    rpc_retries: u32,
+    freshness: FreshnessPolicy,
+    tip: Option<Tip>,
+    propose_blocks: bool,
+    extranonce_size: usize,
+    splits: Vec<PayoutSplit>,
+    coinbase_tag: String,
+    op_return: Option<CoinbaseOutput>,
+    journal: Option<BlockJournal>,
 }
 
 impl<T: RpcClient> Bridge<T> {
     /// Returns a Bridge and a receiver for new headers
-    pub fn new(rpc_client: T) -> (Self, Receiver<[u8; 32]>) {
+    pub fn new(rpc_client: T) -> (Self, Receiver<BridgeEvent>) {
         let (sender, receiver) = mpsc::channel(8);
 
         (
             Bridge {
                 block: None,
+                network: Network::default(),
                 rpc_client,
                 sender,
+                backoff: Backoff::default(),
+                rpc_retries: DEFAULT_RPC_RETRIES,
+                freshness: FreshnessPolicy::default(),
+                tip: None,
+                propose_blocks: false,
+                extranonce_size: DEFAULT_EXTRANONCE_SIZE,
+                splits: Vec::new(),
+                coinbase_tag: String::new(),
+                op_return: None,
+                journal: None,
             },
             receiver,
         )
     }
 
     /// Updates internal block
+    /// Failed RPC calls are retried with backoff, the health of the node
+    /// goes to the channel as ConnectionLost and ConnectionRestored. A
+    /// template that doesn't build on the tip observe last saw is fetched
+    /// again the same way. If every retry fails the last good block is
+    /// kept. Signet blocks are signed, see sign_block. With
+    /// set_propose_blocks the node validates the block first.
     pub async fn update_block(&mut self, payout_address: &str) -> Result<()> {
-        let template = self
-            .rpc_client
-            .getblocktemplate()
-            .await
-            .context("Couldn't get block template.")?;
+        let mut backoff = self.backoff.clone();
+        backoff.reset();
+        let mut lost = false;
+        let template = loop {
+            let result = self
+                .rpc_client
+                .getblocktemplate(&TemplateRequest::for_network(self.network))
+                .await;
+            // Whoever reads the channel may be the one waiting here
+            let err = match result {
+                Ok(template) => {
+                    if std::mem::take(&mut lost) {
+                        let _ = self.sender.try_send(BridgeEvent::ConnectionRestored);
+                    }
+                    match self.check_tip(&template) {
+                        Ok(()) => break template,
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => {
+                    let err = err.context("Couldn't get block template.");
+                    if !std::mem::replace(&mut lost, true) {
+                        let _ = self
+                            .sender
+                            .try_send(BridgeEvent::ConnectionLost(format!("{err:#}")));
+                    }
+                    err
+                }
+            };
+            if backoff.attempts() >= self.rpc_retries {
+                return Err(err);
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+        };
 
-        let block = construct_block(template, payout_address);
-        let header = block.header;
+        let mut block = self.build_block(template, payout_address)?;
+        Self::sign(&self.rpc_client, &mut block).await?;
+        if self.propose_blocks {
+            let verdict = self
+                .rpc_client
+                .proposeblock(&block.to_hex())
+                .await
+                .context("Couldn't propose block.")?;
+            if let SubmitResult::Rejected(reason) = verdict {
+                anyhow::bail!("Node rejected the assembled block: {reason}.");
+            }
+        }
+        self.block = Some(block);
+        Ok(())
+    }
+
+    // Fails if the template builds on another block than the known tip,
+    // the node may not have caught up with its own announcement yet
+    fn check_tip(&self, template: &BlockTemplate) -> Result<()> {
+        let Some(tip) = &self.tip else {
+            return Ok(());
+        };
+        let previous = BlockHash(template.previousblockhash()?);
+        ensure!(
+            previous == tip.hash,
+            "Template builds on {previous}, not on the tip {}.",
+            tip.hash
+        );
+        Ok(())
+    }
+
+    /// Signs the current block for its signet challenge, with an empty
+    /// solution for OP_TRUE and else by the wallet of the node. update_block
+    /// does so itself, use_template leaves it to this.
+    pub async fn sign_block(&mut self) -> Result<()> {
+        let block = self
+            .block
+            .as_mut()
+            .context("No block to sign, update_block first.")?;
+        Self::sign(&self.rpc_client, block).await
+    }
+
+    // Signs block unless it is off signet or signed already
+    async fn sign(rpc_client: &T, block: &mut Block) -> Result<()> {
+        let Some(challenge) = block.signet_challenge() else {
+            return Ok(());
+        };
+        if block.signet_solution().is_some() {
+            return Ok(());
+        }
+        let solution = if challenge == [signet::OP_TRUE] {
+            SignetSolution::default()
+        } else {
+            let signed = rpc_client
+                .walletprocesspsbt(&block.signet_psbt()?)
+                .await
+                .context("Couldn't sign the signet block.")?;
+            SignetSolution::from_psbt(&signed)?
+        };
+        block.set_signet_solution(solution)
+    }
+
+    /// Builds the internal block from a template fetched elsewhere, such
+    /// as the one of a BridgeEvent::NewTemplate
+    pub fn use_template(&mut self, template: BlockTemplate, payout_address: &str) -> Result<()> {
+        let block = self.build_block(template, payout_address)?;
         self.block = Some(block);
 
         Ok(())
     }
 
+    // Checks the template fits the bridge before assembling it
+    fn build_block(&self, template: BlockTemplate, payout_address: &str) -> Result<Block> {
+        check_rules(template.rules())?;
+        // Targets compare as big-endian numbers
+        ensure!(
+            template.target()? <= self.network.pow_limit(),
+            "Template target is above the {} limit, is the node on another network?",
+            self.network
+        );
+
+        construct_block(
+            template,
+            payout_address,
+            self.network,
+            &CoinbaseConfig {
+                extranonce_size: self.extranonce_size,
+                splits: &self.splits,
+                tag: self.coinbase_tag.as_bytes(),
+                op_return: self.op_return.as_ref(),
+            },
+        )
+    }
+
+    /// Rolls the extranonce of the current block, see
+    /// Block::roll_extranonce, and returns the new header
+    pub fn roll_extranonce(&mut self) -> Result<&[u8; 80]> {
+        let block = self
+            .block
+            .as_mut()
+            .context("No block to roll, update_block first.")?;
+        block.roll_extranonce()?;
+        Ok(block.header())
+    }
+
+    /// Getter for the shares of other addresses
+    pub fn get_payout_splits(&self) -> &[PayoutSplit] {
+        &self.splits
+    }
+
+    /// Pays each split its percentage of the coinbase value in new blocks,
+    /// the payout address gets the rest. Splits have to leave it a share.
+    pub fn set_payout_splits(&mut self, splits: Vec<PayoutSplit>) -> Result<()> {
+        payout::check_splits(&splits)?;
+        self.splits = splits;
+        Ok(())
+    }
+
+    /// Getter for the tag in the coinbase scriptSig
+    pub fn get_coinbase_tag(&self) -> &str {
+        &self.coinbase_tag
+    }
+
+    /// Sets an ASCII tag such as "/harvester/" for the scriptSig of new
+    /// coinbases, empty for none. Tag, height and extranonce share 100
+    /// bytes, which new blocks check.
+    pub fn set_coinbase_tag(&mut self, tag: &str) -> Result<()> {
+        ensure!(
+            tag.chars()
+                .all(|char| char.is_ascii_graphic() || char == ' '),
+            "Coinbase tag {tag:?} should be printable ASCII."
+        );
+        ensure!(
+            tag.len() <= MAX_COINBASE_TAG,
+            "Coinbase tag of {} bytes, at most {MAX_COINBASE_TAG} fit.",
+            tag.len()
+        );
+        self.coinbase_tag = tag.to_string();
+        Ok(())
+    }
+
+    /// Getter for the OP_RETURN output of new coinbases
+    pub fn get_op_return(&self) -> Option<&CoinbaseOutput> {
+        self.op_return.as_ref()
+    }
+
+    /// Adds an OP_RETURN output carrying data to new coinbases, None to
+    /// leave it out
+    pub fn set_op_return(&mut self, data: Option<&[u8]>) -> Result<()> {
+        self.op_return = data.map(op_return).transpose()?;
+        Ok(())
+    }
+
+    /// Getter for the extranonce size of new blocks
+    pub fn get_extranonce_size(&self) -> usize {
+        self.extranonce_size
+    }
+
+    /// Sets the bytes new blocks reserve for an extranonce, 8 by default.
+    /// Each byte multiplies the 2^32 nonces of a header by 256.
+    pub fn set_extranonce_size(&mut self, size: usize) -> Result<()> {
+        ensure!(
+            size <= MAX_EXTRANONCE_SIZE,
+            "Extranonce of {size} bytes, at most {MAX_EXTRANONCE_SIZE} fit the coinbase."
+        );
+        self.extranonce_size = size;
+        Ok(())
+    }
+
+    /// Asks the node whether the current block is valid apart from its
+    /// proof of work, which catches assembly bugs before mining
+    pub async fn propose_block(&self) -> Result<SubmitResult> {
+        let block = self
+            .block
+            .as_ref()
+            .context("No block to propose, update_block first.")?;
+        self.rpc_client
+            .proposeblock(&block.to_hex())
+            .await
+            .context("Couldn't propose block.")
+    }
+
+    /// Getter for the validation of new blocks
+    pub fn get_propose_blocks(&self) -> bool {
+        self.propose_blocks
+    }
+
+    /// Has update_block propose every block before using it, a block the
+    /// node rejects then fails the update. Off by default.
+    pub fn set_propose_blocks(&mut self, propose: bool) {
+        self.propose_blocks = propose;
+    }
+
+    /// Getter for the backoff between RPC retries
+    pub fn get_backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// Sets the backoff between RPC retries
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Getter for the retries of a failed getblocktemplate
+    pub fn get_rpc_retries(&self) -> u32 {
+        self.rpc_retries
+    }
+
+    /// Sets how often update_block retries getblocktemplate, 0 to fail at once
+    pub fn set_rpc_retries(&mut self, retries: u32) {
+        self.rpc_retries = retries;
+    }
+
+    /// Getter for the freshness policy
+    pub fn get_freshness(&self) -> FreshnessPolicy {
+        self.freshness
+    }
+
+    /// Sets when blocks need refreshing and when they are stale
+    pub fn set_freshness(&mut self, freshness: FreshnessPolicy) {
+        self.freshness = freshness;
+    }
+
+    /// Getter for the node tip the last events reported
+    pub fn get_tip(&self) -> Option<&Tip> {
+        self.tip.as_ref()
+    }
+
+    /// Records the tip a NewTip or NewTemplate reports, pass every event
+    /// so is_stale knows when the node moved on
+    pub fn observe(&mut self, event: &BridgeEvent) {
+        match event {
+            BridgeEvent::NewTip(hash) => {
+                self.tip = Some(Tip {
+                    hash: *hash,
+                    height: None,
+                })
+            }
+            BridgeEvent::NewTemplate(template) => {
+                if let Ok(hash) = template.previousblockhash() {
+                    self.tip = Some(Tip {
+                        hash: BlockHash(hash),
+                        height: template.height().checked_sub(1),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// True without a block, once the node tip differs from the one the
+    /// block builds on, or after stale_after
+    pub fn is_stale(&self) -> bool {
+        let Some(block) = &self.block else {
+            return true;
+        };
+        let moved_on = self.tip.is_some_and(|tip| {
+            tip.hash != block.previousblockhash
+                || tip
+                    .height
+                    .is_some_and(|height| height.checked_add(1) != Some(block.height))
+        });
+        moved_on || block.age() >= self.freshness.stale_after
+    }
+
+    /// True if the block is stale or older than refresh_interval
+    pub fn needs_refresh(&self) -> bool {
+        self.is_stale()
+            || self
+                .block
+                .as_ref()
+                .is_some_and(|block| block.age() >= self.freshness.refresh_interval)
+    }
+
+    /// Getter for the network payout addresses are parsed for
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    /// Sets the network of the node, mainnet by default
+    pub fn set_network(&mut self, network: Network) {
+        self.network = network;
+    }
+
     /// Getter for block
     pub fn get_block(&self) -> Option<&Block> {
         self.block.as_ref()
@@ -102,47 +919,270 @@ impl<T: RpcClient> Bridge<T> {
         }
     }
 
+    /// Block with the header the miner found, ready for submitblock
+    pub fn solved_block(&mut self, header: [u8; 80]) -> Option<&Block> {
+        let block = self.block.as_mut()?;
+        block.set_header(header);
+        Some(block)
+    }
+
+    /// Submits the current block with the header the miner found
+    /// With a journal the block is written down first, and stays pending
+    /// there if the node can't be reached.
+    pub async fn submit_block(&mut self, header: [u8; 80]) -> Result<SubmitResult> {
+        let block = self
+            .solved_block(header)
+            .context("No block to submit, update_block first.")?;
+        let hex = block.to_hex();
+        if let Some(journal) = &self.journal {
+            journal.record_found(&header, &hex)?;
+        }
+        self.submit_hex(&header, &hex).await
+    }
+
+    /// Submits the blocks the journal has no response for, such as those
+    /// found before the node went down. Stops at the first that can't be
+    /// submitted, it stays pending with the rest.
+    pub async fn resubmit_pending(&self) -> Result<Vec<SubmitResult>> {
+        let Some(journal) = &self.journal else {
+            return Ok(Vec::new());
+        };
+        let mut results = Vec::new();
+        for found in journal.pending()? {
+            results.push(self.submit_hex(&found.header, &found.hex).await?);
+        }
+        Ok(results)
+    }
+
+    // Submits and journals the response
+    async fn submit_hex(&self, header: &[u8; 80], hex: &str) -> Result<SubmitResult> {
+        let result = self
+            .rpc_client
+            .submitblock(hex)
+            .await
+            .context("Couldn't submit block.")?;
+        if let Some(journal) = &self.journal {
+            journal.record_response(header, &result)?;
+        }
+        Ok(result)
+    }
+
+    /// Getter for the journal of solved blocks
+    pub fn get_journal(&self) -> Option<&BlockJournal> {
+        self.journal.as_ref()
+    }
+
+    /// Journals every solved block before submitting it, see
+    /// resubmit_pending. Mainnet blocks are too valuable to lose on a
+    /// failed RPC call.
+    pub fn set_journal(&mut self, journal: Option<BlockJournal>) {
+        self.journal = journal;
+    }
+
     /// Get a clone of the sender
-    pub fn get_sender(&self) -> Sender<[u8; 32]> {
+    pub fn get_sender(&self) -> Sender<BridgeEvent> {
         self.sender.clone()
     }
 }
 
 /// Listens for new block indefinitely.
+/// If ZMQ fails, sends ConnectionLost and retries with backoff, then
+/// ConnectionRestored once a message arrives. Only returns when the
+/// channel is closed.
 pub async fn listen_for_new_block(
-    sender: Sender<[u8; 32]>,
+    sender: Sender<BridgeEvent>,
     zmq_receiver: impl ZmqReceiver,
 ) -> Result<()> {
+    let mut backoff = Backoff::default();
     loop {
-        let prev_hash = zmq_receiver
-            .recv()
-            .await
-            .context("Failed to receive ZMQ message.")?;
-        sender
-            .send(prev_hash)
-            .await
-            .context("Failed to send message through channel.")?;
+        match zmq_receiver.recv().await {
+            Ok(event) => {
+                if backoff.attempts() > 0 {
+                    backoff.reset();
+                    send(&sender, BridgeEvent::ConnectionRestored).await?;
+                }
+                send(&sender, event).await?;
+            }
+            Err(err) => {
+                if backoff.attempts() == 0 {
+                    let err = err.context("Failed to receive ZMQ message.");
+                    send(&sender, BridgeEvent::ConnectionLost(format!("{err:#}"))).await?;
+                }
+                tokio::time::sleep(backoff.next_delay()).await;
+            }
+        }
     }
 }
 
-// Constructs a full block (header + transactions)
-fn construct_block(template: BlockTemplate, payout_address: &str) -> Block {
-    Block {
-        header: [0u8; 80],
-        transactions: vec![],
+/// Long polls getblocktemplate indefinitely, without needing ZMQ.
+/// Each template the node answers with is sent as NewTemplate, for new
+/// transactions as well as for a new tip. Failed calls are retried like
+/// in listen_for_new_block, a node without long polling is an error.
+pub async fn longpoll_for_templates(
+    sender: Sender<BridgeEvent>,
+    rpc_client: impl RpcClient,
+) -> Result<()> {
+    let mut request = TemplateRequest::default();
+    let mut backoff = Backoff::default();
+    loop {
+        let template = match rpc_client.getblocktemplate(&request).await {
+            Ok(template) => template,
+            Err(err) => {
+                if backoff.attempts() == 0 {
+                    let err = err.context("Failed to long poll for a block template.");
+                    send(&sender, BridgeEvent::ConnectionLost(format!("{err:#}"))).await?;
+                }
+                tokio::time::sleep(backoff.next_delay()).await;
+                continue;
+            }
+        };
+        if backoff.attempts() > 0 {
+            backoff.reset();
+            send(&sender, BridgeEvent::ConnectionRestored).await?;
+        }
+        let longpollid = template
+            .longpollid()
+            .context("Node doesn't support long polling.")?;
+        request.longpollid = Some(longpollid.to_string());
+        send(&sender, BridgeEvent::NewTemplate(Box::new(template))).await?;
     }
 }
 
+// Fails only once the receiver is dropped
+pub(crate) async fn send(sender: &Sender<BridgeEvent>, event: BridgeEvent) -> Result<()> {
+    sender
+        .send(event)
+        .await
+        .context("Failed to send message through channel.")
+}
+
+// What the Bridge adds to the coinbase besides the payout
+struct CoinbaseConfig<'a> {
+    extranonce_size: usize,
+    splits: &'a [PayoutSplit],
+    tag: &'a [u8],
+    op_return: Option<&'a CoinbaseOutput>,
+}
+
+// Constructs a full block (header + transactions)
+// The coinbase comes first and pays the coinbasevalue, less the fees of
+// the transactions dropped to stay within the limits, split between the
+// payout address and the splits. The commitment stays the last output,
+// on signet with the header the solution goes behind.
+fn construct_block(
+    mut template: BlockTemplate,
+    payout_address: &str,
+    network: Network,
+    config: &CoinbaseConfig,
+) -> Result<Block> {
+    let address = Address::parse(payout_address, network).context("Invalid payout address.")?;
+    let extranonce = vec![0u8; config.extranonce_size];
+    let build_coinbase = |value: u64, commitment: Option<&[u8]>| {
+        let mut outputs = payout::split_value(value, &address, config.splits, network)?;
+        outputs.extend(config.op_return.cloned());
+        Coinbase::with_outputs(
+            template.height,
+            config.tag,
+            &outputs,
+            &extranonce,
+            commitment,
+        )
+    };
+
+    let signet_challenge = template.signet_challenge()?;
+    let with_header = |commitment: Vec<u8>| match signet_challenge {
+        Some(_) => signet::with_header(&commitment),
+        None => commitment,
+    };
+    let commitment = template.witness_commitment()?.map(with_header);
+    ensure!(
+        signet_challenge.is_none() || commitment.is_some(),
+        "Signet template without a witness commitment."
+    );
+    let coinbase = build_coinbase(template.coinbasevalue, commitment.as_deref())?;
+    // A rebuilt coinbase has them in the same place
+    let extranonce = coinbase.extranonce.clone();
+    let commitment_script = coinbase.commitment.clone();
+    let coinbase = Transaction::from_raw(coinbase.raw)?;
+    let selection = assembly::select(
+        &coinbase,
+        std::mem::take(&mut template.transactions),
+        &template.limits(),
+    )?;
+
+    // The commitment has the same length either way, so the coinbase
+    // keeps the weight it was selected with
+    let coinbase = if selection.dropped.is_empty() {
+        coinbase
+    } else {
+        let fees: u64 = selection.dropped.iter().map(Transaction::fee).sum();
+        let commitment = commitment.map(|_| {
+            let wtxids: Vec<[u8; 32]> = selection.kept.iter().map(|tx| *tx.wtxid()).collect();
+            with_header(witness_commitment(&wtxids))
+        });
+        let coinbase = build_coinbase(
+            template.coinbasevalue.saturating_sub(fees),
+            commitment.as_deref(),
+        )?;
+        Transaction::from_raw(coinbase.raw)?
+    };
+
+    let txids: Vec<[u8; 32]> = std::iter::once(&coinbase)
+        .chain(&selection.kept)
+        .map(|tx| *tx.txid())
+        .collect();
+    let header = template.to_header(&merkle::merkle_root(&txids))?;
+    let merkle_branch = merkle::merkle_branch(&txids);
+    let target = template.target()?;
+    let previousblockhash = BlockHash(template.previousblockhash()?);
+
+    let mut transactions = vec![coinbase];
+    transactions.extend(selection.kept);
+    Ok(Block {
+        header,
+        transactions,
+        dropped: selection.dropped,
+        target,
+        height: template.height,
+        previousblockhash,
+        created: Instant::now(),
+        merkle_branch,
+        extranonce,
+        commitment: commitment_script,
+        signet_challenge,
+        signet_solution: None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
 
     struct MockClient;
     struct MockReceiver;
+    // Fails until the counter runs out
+    struct FlakyClient(AtomicU32);
+    struct FlakyReceiver(AtomicU32);
+    // Builds on another tip until the counter runs out
+    struct ForkClient(AtomicU32);
+    // Records the longpollid of every request
+    struct LongpollClient(Arc<Mutex<Vec<Option<String>>>>);
 
     #[async_trait]
     impl RpcClient for MockClient {
-        async fn getblocktemplate(&self) -> anyhow::Result<BlockTemplate> {
+        async fn getblocktemplate(
+            &self,
+            request: &TemplateRequest,
+        ) -> anyhow::Result<BlockTemplate> {
+            // Bitcoin Core refuses requests without segwit
+            anyhow::ensure!(request.rules.iter().any(|rule| rule == "segwit"));
             // Example JSON-RPC response for getblocktemplate
             let raw = r#"
             {
@@ -181,12 +1221,133 @@ mod tests {
 
             Ok(template)
         }
+
+        // Accepts blocks with a nonzero nonce
+        async fn submitblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            let result = match &block_hex[152..160] {
+                "00000000" => r#""high-hash""#,
+                _ => "null",
+            };
+            let raw = format!(r#"{{"result":{result},"error":null,"id":"curltest"}}"#);
+            let response: JsonRpcResponse<Option<String>> = serde_json::from_str(&raw)?;
+            SubmitResult::from_response(response)
+        }
+
+        // Templates have no transactions, so the coinbase txid has to be
+        // the merkle root
+        async fn proposeblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            let bytes = decode_hex(block_hex)?;
+            let coinbase = Transaction::from_raw(bytes[81..].to_vec())?;
+            Ok(match bytes[36..68] == *coinbase.txid() {
+                true => SubmitResult::Accepted,
+                false => SubmitResult::Rejected("bad-txnmrklroot".to_string()),
+            })
+        }
+
+        async fn walletprocesspsbt(&self, _psbt: &str) -> anyhow::Result<String> {
+            anyhow::bail!("No wallet is loaded.")
+        }
+    }
+
+    #[async_trait]
+    impl RpcClient for LongpollClient {
+        async fn getblocktemplate(
+            &self,
+            request: &TemplateRequest,
+        ) -> anyhow::Result<BlockTemplate> {
+            self.0.lock().unwrap().push(request.longpollid.clone());
+            MockClient.getblocktemplate(request).await
+        }
+
+        async fn submitblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            MockClient.submitblock(block_hex).await
+        }
+
+        async fn proposeblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            MockClient.proposeblock(block_hex).await
+        }
+
+        async fn walletprocesspsbt(&self, psbt: &str) -> anyhow::Result<String> {
+            MockClient.walletprocesspsbt(psbt).await
+        }
+    }
+
+    #[async_trait]
+    impl RpcClient for FlakyClient {
+        async fn getblocktemplate(
+            &self,
+            request: &TemplateRequest,
+        ) -> anyhow::Result<BlockTemplate> {
+            let failures = self.0.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.0.store(failures - 1, Ordering::Relaxed);
+                anyhow::bail!("Connection refused");
+            }
+            MockClient.getblocktemplate(request).await
+        }
+
+        async fn submitblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            let failures = self.0.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.0.store(failures - 1, Ordering::Relaxed);
+                anyhow::bail!("Connection refused");
+            }
+            MockClient.submitblock(block_hex).await
+        }
+
+        async fn proposeblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            MockClient.proposeblock(block_hex).await
+        }
+
+        async fn walletprocesspsbt(&self, psbt: &str) -> anyhow::Result<String> {
+            MockClient.walletprocesspsbt(psbt).await
+        }
+    }
+
+    #[async_trait]
+    impl RpcClient for ForkClient {
+        async fn getblocktemplate(
+            &self,
+            request: &TemplateRequest,
+        ) -> anyhow::Result<BlockTemplate> {
+            let mut template = MockClient.getblocktemplate(request).await?;
+            let forks = self.0.load(Ordering::Relaxed);
+            if forks > 0 {
+                self.0.store(forks - 1, Ordering::Relaxed);
+                template.previousblockhash = "11".repeat(32);
+            }
+            Ok(template)
+        }
+
+        async fn submitblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            MockClient.submitblock(block_hex).await
+        }
+
+        async fn proposeblock(&self, block_hex: &str) -> anyhow::Result<SubmitResult> {
+            MockClient.proposeblock(block_hex).await
+        }
+
+        async fn walletprocesspsbt(&self, psbt: &str) -> anyhow::Result<String> {
+            MockClient.walletprocesspsbt(psbt).await
+        }
+    }
+
+    #[async_trait]
+    impl ZmqReceiver for FlakyReceiver {
+        async fn recv(&self) -> anyhow::Result<BridgeEvent> {
+            let failures = self.0.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.0.store(failures - 1, Ordering::Relaxed);
+                anyhow::bail!("Socket closed");
+            }
+            MockReceiver.recv().await
+        }
     }
 
     #[async_trait]
     impl ZmqReceiver for MockReceiver {
-        async fn recv(&self) -> anyhow::Result<[u8; 32]> {
-            Ok([0u8; 32])
+        async fn recv(&self) -> anyhow::Result<BridgeEvent> {
+            Ok(BridgeEvent::NewTip(BlockHash([0u8; 32])))
         }
     }
 
@@ -203,9 +1364,211 @@ mod tests {
     async fn parsing_template_from_json_works() {
         let mock_client = MockClient;
 
-        let res = mock_client.getblocktemplate().await;
+        let res = mock_client
+            .getblocktemplate(&TemplateRequest::default())
+            .await;
 
         assert!(res.is_ok());
+        let legacy = TemplateRequest {
+            rules: Vec::new(),
+            ..Default::default()
+        };
+        assert!(mock_client.getblocktemplate(&legacy).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn header_from_template_swaps_to_little_endian() {
+        let template = MockClient
+            .getblocktemplate(&TemplateRequest::default())
+            .await
+            .unwrap();
+        let merkle_root = [0xAB; 32];
+        let header = template.to_header(&merkle_root).unwrap();
+        assert_eq!(
+            (template.mintime(), template.curtime()),
+            (1747616695, 1747695629)
+        );
+
+        assert_eq!(header[..4], 536870912u32.to_le_bytes());
+        // Displayed hash 5f12...1746 is stored reversed
+        assert_eq!(header[4], 0x46);
+        assert_eq!(header[35], 0x5f);
+        assert_eq!(header[36..68], merkle_root);
+        assert_eq!(header[68..72], 1747695629u32.to_le_bytes());
+        assert_eq!(header[72..76], [0xff, 0xff, 0x7f, 0x20]);
+        assert_eq!(header[76..], [0; 4]);
+
+        let bad = BlockTemplate {
+            previousblockhash: "5f12".to_string(),
+            ..Default::default()
+        };
+        assert!(bad.to_header(&merkle_root).is_err());
+    }
+
+    #[tokio::test]
+    async fn every_template_field_is_parsed() {
+        let mut template = MockClient
+            .getblocktemplate(&TemplateRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(template.height(), 102);
+        assert_eq!(template.mutable(), ["time", "transactions", "prevblock"]);
+        assert!(template.is_mutable("time"));
+        assert!(!template.is_mutable("version/force"));
+        assert_eq!(template.noncerange().unwrap(), 0..=u32::MAX);
+        assert_eq!(
+            template.longpollid(),
+            Some("5f127e4316a7cfe0b9c86c251c49bf94517007705091cb5f38f5db1f9f2217460")
+        );
+        assert_eq!(template.rules(), ["csv", "!segwit", "taproot"]);
+        assert!(template.vbavailable().is_empty());
+        assert_eq!(template.limits().weight, 4_000_000);
+        assert!(template.default_witness_commitment.is_some());
+
+        template.noncerange = Some("0000ffff00000001".to_string());
+        assert!(template.noncerange().is_err());
+        template.noncerange = Some("00000100".to_string());
+        assert!(template.noncerange().is_err());
+        template.noncerange = Some("000001000000ffff".to_string());
+        assert_eq!(template.noncerange().unwrap(), 0x100..=0xffff);
+
+        let json = r#"{"bits":"207fffff","curtime":0,"height":1,"previousblockhash":"",
+            "sigoplimit":0,"sizelimit":0,"transactions":[],"version":0,"coinbasevalue":0,
+            "vbavailable":{"testdummy":28}}"#;
+        let template: BlockTemplate = serde_json::from_str(json).unwrap();
+        assert_eq!(template.vbavailable()["testdummy"], 28);
+        assert_eq!(template.noncerange().unwrap(), 0..=u32::MAX);
+        assert_eq!(template.longpollid(), None);
+        assert_eq!(template.limits().weight, u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn target_field_is_preferred_over_bits() {
+        let mut template = MockClient
+            .getblocktemplate(&TemplateRequest::default())
+            .await
+            .unwrap();
+        let mut regtest = [0u8; 32];
+        regtest[..3].copy_from_slice(&[0x7f, 0xff, 0xff]);
+        assert_eq!(template.target().unwrap(), regtest);
+
+        // The field wins where bits would round
+        let mut exact = regtest;
+        exact[31] = 0x01;
+        template.target = Some(encode_hex(&exact));
+        assert_eq!(template.target().unwrap(), exact);
+        template.target = Some("7fffff".to_string());
+        assert!(template.target().is_err());
+
+        // Without one bits are expanded
+        template.target = None;
+        assert_eq!(template.target().unwrap(), regtest);
+        template.bits = "1d00ffff".to_string();
+        assert_eq!(template.target().unwrap()[4..6], [0xff, 0xff]);
+        template.bits = "04923456".to_string();
+        assert!(template.target().is_err());
+        template.bits = "ff123456".to_string();
+        assert!(template.target().is_err());
+    }
+
+    #[test]
+    fn blocks_serialize_for_submitblock() {
+        let mut block = Block {
+            header: [0x11; 80],
+            transactions: vec![
+                Transaction::from_raw(vec![0xaa; 3]).unwrap(),
+                Transaction::from_raw(vec![0xbb; 2]).unwrap(),
+            ],
+            dropped: Vec::new(),
+            target: [0xff; 32],
+            height: 1,
+            previousblockhash: BlockHash([0; 32]),
+            created: Instant::now(),
+            merkle_branch: Vec::new(),
+            extranonce: 0..0,
+            commitment: None,
+            signet_challenge: None,
+            signet_solution: None,
+        };
+        let mut header = [0x11; 80];
+        header[76..].copy_from_slice(&42u32.to_le_bytes());
+        block.set_header(header);
+
+        let bytes = block.to_bytes();
+        assert_eq!(bytes.len(), 80 + 1 + 5);
+        assert_eq!(bytes[..80], header);
+        assert_eq!(bytes[80..], [2, 0xaa, 0xaa, 0xaa, 0xbb, 0xbb]);
+        assert_eq!(block.to_hex(), encode_hex(&bytes));
+        assert!(block.to_hex().ends_with("2a00000002aaaaaabbbb"));
+        assert_eq!(decode_hex(&block.to_hex()).unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn submitted_blocks_report_the_nodes_verdict() {
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        assert!(bridge.submit_block([0u8; 80]).await.is_err());
+
+        bridge
+            .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .await
+            .unwrap();
+        let mut header = *bridge.get_current_header().unwrap();
+        assert_eq!(
+            bridge.submit_block(header).await.unwrap(),
+            SubmitResult::Rejected("high-hash".to_string())
+        );
+        header[76..].copy_from_slice(&7u32.to_le_bytes());
+        assert!(bridge.submit_block(header).await.unwrap().is_accepted());
+        assert_eq!(bridge.get_current_header(), Some(&header));
+
+        // Proposals skip the proof of work but not the merkle root
+        assert!(bridge.propose_block().await.unwrap().is_accepted());
+        header[36] ^= 1;
+        bridge.solved_block(header);
+        assert_eq!(
+            bridge.propose_block().await.unwrap(),
+            SubmitResult::Rejected("bad-txnmrklroot".to_string())
+        );
+        bridge.set_propose_blocks(true);
+        bridge
+            .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .await
+            .unwrap();
+        assert!(bridge.propose_block().await.unwrap().is_accepted());
+
+        let failed =
+            r#"{"result":null,"error":{"code":-22,"message":"Block decode failed"},"id":"1"}"#;
+        assert!(SubmitResult::from_response(serde_json::from_str(failed).unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn longpolls_pass_the_previous_longpollid() {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn(longpoll_for_templates(sender, LongpollClient(ids.clone())));
+
+        let Some(BridgeEvent::NewTemplate(template)) = receiver.recv().await else {
+            panic!("Long poll didn't send the template");
+        };
+        assert_eq!(template.previousblockhash().unwrap()[0], 0x5f);
+        receiver.recv().await.unwrap();
+        task.abort();
+
+        // The bridge mines on what the long poll found
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        bridge
+            .use_template(*template, "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .unwrap();
+        assert!(bridge.get_block().is_some());
+
+        let ids = ids.lock().unwrap();
+        assert_eq!(ids[0], None);
+        assert_eq!(
+            ids[1].as_deref(),
+            Some("5f127e4316a7cfe0b9c86c251c49bf94517007705091cb5f38f5db1f9f2217460")
+        );
     }
 
     #[tokio::test]
@@ -218,13 +1581,371 @@ mod tests {
 
         let task = tokio::spawn(listen_for_new_block(sender, mock_receiver));
 
-        while let Some(_) = hash_rx.recv().await {
-            let res = bridge.update_block("").await;
+        if let Some(BridgeEvent::NewTip(tip)) = hash_rx.recv().await {
+            assert_eq!(tip, BlockHash([0u8; 32]));
+            // The mock template is regtest, so is the payout address
+            assert!(bridge.update_block("").await.is_err());
+            let mainnet = bridge
+                .update_block("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+                .await;
+            assert!(mainnet.unwrap_err().to_string().contains("another network"));
+            bridge.set_network(Network::Regtest);
+            let res = bridge
+                .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+                .await;
             assert!(res.is_ok());
             let header = bridge.get_current_header().unwrap();
             assert_eq!(header.len(), 80);
-            task.abort();
-            break;
+
+            // Only the coinbase, so it is the merkle root
+            let block = bridge.get_block().unwrap();
+            assert_eq!(block.transactions.len(), 1);
+            assert_eq!(header[36..68], *block.transactions[0].txid());
+            assert_eq!(block.fees(), 0);
+            assert!(block.dropped().is_empty());
+            assert_eq!(block.target()[..3], [0x7f, 0xff, 0xff]);
+            assert!(block.weight() > 80 * 4);
+            let commitment = MockClient
+                .getblocktemplate(&TemplateRequest::default())
+                .await
+                .unwrap()
+                .witness_commitment()
+                .unwrap()
+                .unwrap();
+            assert_eq!(commitment, witness_commitment(&[]));
+
+            let mut solved = *header;
+            solved[76..].copy_from_slice(&7u32.to_le_bytes());
+            let block = bridge.solved_block(solved).unwrap();
+            assert_eq!(block.to_bytes()[..80], solved);
+            assert_eq!(block.to_bytes()[80], 1);
         }
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn rpc_failures_are_retried_and_keep_the_last_block() {
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let (mut bridge, mut events) = Bridge::new(FlakyClient(AtomicU32::new(2)));
+        bridge.set_network(Network::Regtest);
+        bridge.set_backoff(Backoff::new(
+            Duration::from_millis(1),
+            Duration::from_millis(4),
+        ));
+        assert_eq!(bridge.get_rpc_retries(), DEFAULT_RPC_RETRIES);
+
+        bridge.update_block(address).await.unwrap();
+        let Ok(BridgeEvent::ConnectionLost(reason)) = events.try_recv() else {
+            panic!("The failure wasn't reported");
+        };
+        assert!(reason.contains("Connection refused"));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(BridgeEvent::ConnectionRestored)
+        ));
+        assert!(events.try_recv().is_err());
+
+        // Out of retries, the miner keeps the old work
+        let header = *bridge.get_current_header().unwrap();
+        bridge.rpc_client.0.store(2, Ordering::Relaxed);
+        bridge.set_rpc_retries(1);
+        assert!(bridge.update_block(address).await.is_err());
+        assert_eq!(bridge.get_current_header(), Some(&header));
+    }
+
+    #[tokio::test]
+    async fn listening_survives_zmq_failures() {
+        let (sender, mut events) = mpsc::channel(8);
+        let task = tokio::spawn(listen_for_new_block(
+            sender,
+            FlakyReceiver(AtomicU32::new(3)),
+        ));
+
+        // One report for the whole outage
+        assert!(matches!(
+            events.recv().await,
+            Some(BridgeEvent::ConnectionLost(_))
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(BridgeEvent::ConnectionRestored)
+        ));
+        assert!(matches!(events.recv().await, Some(BridgeEvent::NewTip(_))));
+
+        // Only a closed channel ends the loop
+        drop(events);
+        assert!(task.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn blocks_go_stale_when_the_tip_moves_or_they_age() {
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        assert!(bridge.is_stale());
+        bridge
+            .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .await
+            .unwrap();
+        assert!(!bridge.is_stale() && !bridge.needs_refresh());
+        let block = bridge.get_block().unwrap();
+        assert_eq!(block.height(), 102);
+        assert_eq!(block.previousblockhash().0[0], 0x5f);
+
+        // A block on top of the one mined on
+        bridge.observe(&BridgeEvent::NewTip(BlockHash([0x11; 32])));
+        assert!(bridge.is_stale());
+
+        let request = TemplateRequest::default();
+        let fetch = || MockClient.getblocktemplate(&request);
+        let template = fetch().await.unwrap();
+        bridge.observe(&BridgeEvent::NewTemplate(Box::new(template)));
+        assert_eq!(bridge.get_tip().unwrap().height, Some(101));
+        assert!(!bridge.is_stale());
+        // Same hash, yet the node reports another height
+        let mut template = fetch().await.unwrap();
+        template.height = 103;
+        bridge.observe(&BridgeEvent::NewTemplate(Box::new(template)));
+        assert!(bridge.is_stale());
+
+        bridge.observe(&BridgeEvent::NewTip(
+            *bridge.get_block().unwrap().previousblockhash(),
+        ));
+        bridge.set_freshness(FreshnessPolicy {
+            refresh_interval: Duration::ZERO,
+            stale_after: Duration::from_secs(60),
+        });
+        assert!(!bridge.is_stale() && bridge.needs_refresh());
+        bridge.set_freshness(FreshnessPolicy {
+            refresh_interval: Duration::ZERO,
+            stale_after: Duration::ZERO,
+        });
+        assert!(bridge.is_stale());
+    }
+
+    #[tokio::test]
+    async fn templates_off_the_announced_tip_are_fetched_again() {
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let (mut bridge, mut events) = Bridge::new(ForkClient(AtomicU32::new(2)));
+        bridge.set_network(Network::Regtest);
+        bridge.set_backoff(Backoff::new(
+            Duration::from_millis(1),
+            Duration::from_millis(4),
+        ));
+        let tip = MockClient
+            .getblocktemplate(&TemplateRequest::default())
+            .await
+            .unwrap()
+            .previousblockhash()
+            .unwrap();
+        bridge.observe(&BridgeEvent::NewTip(BlockHash(tip)));
+
+        bridge.update_block(address).await.unwrap();
+        assert_eq!(bridge.rpc_client.0.load(Ordering::Relaxed), 0);
+        assert_eq!(bridge.get_block().unwrap().previousblockhash().0, tip);
+        // The node answered every time
+        assert!(events.try_recv().is_err());
+
+        // A tip the node never catches up with
+        bridge.observe(&BridgeEvent::NewTip(BlockHash([0x22; 32])));
+        let err = bridge.update_block(address).await.unwrap_err();
+        assert!(err.to_string().contains("not on the tip"));
+        assert_eq!(bridge.get_block().unwrap().previousblockhash().0, tip);
+    }
+
+    #[tokio::test]
+    async fn rolled_extranonces_update_the_merkle_root() {
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        assert!(bridge.roll_extranonce().is_err());
+        bridge.update_block(address).await.unwrap();
+        assert_eq!(bridge.get_block().unwrap().extranonce(), [0; 8]);
+
+        let before = *bridge.get_current_header().unwrap();
+        let after = *bridge.roll_extranonce().unwrap();
+        assert_ne!(before[36..68], after[36..68]);
+        assert_eq!(before[..36], after[..36]);
+        let block = bridge.get_block().unwrap();
+        assert_eq!(block.extranonce(), [1, 0, 0, 0, 0, 0, 0, 0]);
+        // The node agrees on the new root
+        assert!(bridge.propose_block().await.unwrap().is_accepted());
+
+        assert!(bridge.set_extranonce_size(MAX_EXTRANONCE_SIZE + 1).is_err());
+        bridge.set_extranonce_size(1).unwrap();
+        bridge.update_block(address).await.unwrap();
+        for _ in 0..255 {
+            bridge.roll_extranonce().unwrap();
+        }
+        assert_eq!(bridge.get_block().unwrap().extranonce(), [0xff]);
+        assert!(bridge.roll_extranonce().is_err());
+        assert_eq!(bridge.get_block().unwrap().extranonce(), [0xff]);
+    }
+
+    #[tokio::test]
+    async fn the_coinbase_value_is_split_between_addresses() {
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        let payout_address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let fee_address = "bcrt1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qzf4jry";
+        let split = |percent| PayoutSplit {
+            address: fee_address.to_string(),
+            percent,
+        };
+        assert!(bridge
+            .set_payout_splits(vec![split(1.0), split(99.0)])
+            .is_err());
+        bridge.set_payout_splits(vec![split(1.0)]).unwrap();
+        bridge.update_block(payout_address).await.unwrap();
+
+        // Value, script length and script of each output
+        let coinbase = bridge.get_block().unwrap().transactions()[0].raw();
+        let pays = |value: u64, address: &str| {
+            let script = Address::parse(address, Network::Regtest)
+                .unwrap()
+                .script_pubkey();
+            let output = [&value.to_le_bytes()[..], &[script.len() as u8], &script].concat();
+            coinbase
+                .windows(output.len())
+                .any(|window| window == output)
+        };
+        assert!(pays(50_000_000, fee_address));
+        assert!(pays(4_950_000_000, payout_address));
+    }
+
+    #[tokio::test]
+    async fn coinbases_carry_the_tag_and_data() {
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        assert!(bridge.set_coinbase_tag("/h\u{e4}rvester/").is_err());
+        assert!(bridge.set_coinbase_tag(&"x".repeat(76)).is_err());
+        assert!(bridge.set_op_return(Some(&[0; 81])).is_err());
+        bridge.set_coinbase_tag("/harvester/").unwrap();
+        bridge.set_op_return(Some(b"gm")).unwrap();
+        bridge
+            .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .await
+            .unwrap();
+
+        let block = bridge.get_block().unwrap();
+        let coinbase = block.transactions()[0].raw();
+        let contains = |bytes: &[u8]| coinbase.windows(bytes.len()).any(|window| window == bytes);
+        assert!(contains(b"/harvester/"));
+        assert!(contains(&bridge.get_op_return().unwrap().script_pubkey));
+        assert_eq!(block.extranonce(), [0; 8]);
+        // The commitment is still found
+        assert!(contains(&witness_commitment(&[])));
+        bridge.roll_extranonce().unwrap();
+        assert!(bridge.propose_block().await.unwrap().is_accepted());
+
+        // Too much for the 100 bytes of the scriptSig
+        bridge.set_coinbase_tag(&"x".repeat(75)).unwrap();
+        bridge.set_extranonce_size(32).unwrap();
+        assert!(bridge
+            .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn unsubmitted_blocks_are_resubmitted_from_the_journal() {
+        let path =
+            std::env::temp_dir().join(format!("harvester-bridge-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut bridge, _) = Bridge::new(FlakyClient(AtomicU32::new(0)));
+        bridge.set_network(Network::Regtest);
+        bridge
+            .update_block("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .await
+            .unwrap();
+        bridge.set_journal(Some(BlockJournal::new(&path)));
+
+        // The node is down when the block is found
+        let mut header = *bridge.get_current_header().unwrap();
+        header[76..].copy_from_slice(&7u32.to_le_bytes());
+        bridge.rpc_client.0.store(1, Ordering::Relaxed);
+        assert!(bridge.submit_block(header).await.is_err());
+        let journal = bridge.get_journal().unwrap();
+        let pending = journal.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].header, header);
+        assert_eq!(pending[0].hex, bridge.get_block().unwrap().to_hex());
+
+        // After a restart
+        bridge.set_journal(Some(BlockJournal::new(&path)));
+        assert_eq!(
+            bridge.resubmit_pending().await.unwrap(),
+            [SubmitResult::Accepted]
+        );
+        assert!(bridge.get_journal().unwrap().pending().unwrap().is_empty());
+        assert!(bridge.submit_block(header).await.unwrap().is_accepted());
+        assert!(bridge.resubmit_pending().await.unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn signet_blocks_are_signed_for_their_challenge() {
+        let (mut bridge, _) = Bridge::new(MockClient);
+        bridge.set_network(Network::Regtest);
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        async fn signet_template(challenge: &str) -> BlockTemplate {
+            let mut template = MockClient
+                .getblocktemplate(&TemplateRequest::default())
+                .await
+                .unwrap();
+            template.signet_challenge = Some(challenge.to_string());
+            // Bitcoin Core requires the rule on signet
+            template.rules.push("!signet".to_string());
+            template
+        }
+
+        bridge
+            .use_template(signet_template("51").await, address)
+            .unwrap();
+        let block = bridge.get_block().unwrap();
+        assert_eq!(block.signet_challenge(), Some(&[0x51][..]));
+        assert!(block.signet_solution().is_none());
+        let unsigned = block.transactions()[0].raw().to_vec();
+        assert!(unsigned
+            .windows(5)
+            .any(|window| window == [4, 0xec, 0xc7, 0xda, 0xa2]));
+        assert!(block.signet_psbt().is_ok());
+
+        // OP_TRUE takes the empty solution, the wallet isn't asked
+        bridge.sign_block().await.unwrap();
+        let block = bridge.get_block().unwrap();
+        let coinbase = &block.transactions()[0];
+        let txid = *coinbase.txid();
+        assert!(coinbase
+            .raw()
+            .windows(7)
+            .any(|window| window == [6, 0xec, 0xc7, 0xda, 0xa2, 0, 0]));
+        assert_eq!(block.header()[36..68], txid);
+        assert_eq!(
+            bridge.propose_block().await.unwrap(),
+            SubmitResult::Accepted
+        );
+        assert!(bridge.get_block().unwrap().signet_psbt().is_err());
+        assert!(bridge.roll_extranonce().is_err());
+        // Signing twice changes nothing
+        bridge.sign_block().await.unwrap();
+        assert_eq!(bridge.get_block().unwrap().header()[36..68], txid);
+
+        // Other challenges need a wallet, the mock has none
+        let multisig = format!("5121{}51ae", "02".repeat(33));
+        bridge
+            .use_template(signet_template(&multisig).await, address)
+            .unwrap();
+        assert!(bridge.sign_block().await.is_err());
+
+        let mut template = signet_template("51").await;
+        template.default_witness_commitment = None;
+        assert!(bridge.use_template(template, address).is_err());
+        assert!(MockClient
+            .getblocktemplate(&TemplateRequest::default())
+            .await
+            .unwrap()
+            .signet_challenge()
+            .unwrap()
+            .is_none());
     }
 }
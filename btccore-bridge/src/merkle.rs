@@ -0,0 +1,64 @@
+//! Double SHA256 and merkle roots for assembling blocks.
+//!
+//! The miner crate has its own, the crates don't depend on each other.
+//! Hashes are in SHA256 byte order, reversed from how bitcoind displays
+//! them.
+
+use sha2::{Digest, Sha256};
+
+pub(crate) fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+// Root of the hashes, pairing the last hash of odd levels with itself
+// All zero without hashes, like Bitcoin Core.
+pub(crate) fn merkle_root(hashes: &[[u8; 32]]) -> [u8; 32] {
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| sha256d(&[pair[0], *pair.last().unwrap()].concat()))
+            .collect();
+    }
+    level.first().copied().unwrap_or_default()
+}
+
+// Hashes the first leaf meets on its way up, for root_from_branch
+pub(crate) fn merkle_branch(hashes: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let mut branch = Vec::new();
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        branch.push(level[1]);
+        level = level
+            .chunks(2)
+            .map(|pair| sha256d(&[pair[0], *pair.last().unwrap()].concat()))
+            .collect();
+    }
+    branch
+}
+
+// Root of the tree the branch came from, with first as the first leaf
+pub(crate) fn root_from_branch(first: &[u8; 32], branch: &[[u8; 32]]) -> [u8; 32] {
+    branch
+        .iter()
+        .fold(*first, |hash, sibling| sha256d(&[hash, *sibling].concat()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches_lead_to_the_root() {
+        for count in 1..=7u8 {
+            let mut hashes: Vec<[u8; 32]> = (0..count).map(|i| [i; 32]).collect();
+            let branch = merkle_branch(&hashes);
+            assert_eq!(root_from_branch(&hashes[0], &branch), merkle_root(&hashes));
+
+            // Only the first leaf changes, as when the extranonce rolls
+            hashes[0] = [0xff; 32];
+            assert_eq!(root_from_branch(&hashes[0], &branch), merkle_root(&hashes));
+        }
+        assert!(merkle_branch(&[[1; 32]]).is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+//! Chains the bridge can be pointed at.
+//!
+//! Besides address prefixes, each network has its own RPC port and limit
+//! on the target. A template above the limit means the node runs another
+//! network than the bridge was set up for.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use crate::bits_to_target;
+
+/// Chain an address belongs to
+/// Signet uses the testnet prefixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// Every network, mainnet first
+    pub const ALL: [Network; 4] = [
+        Network::Mainnet,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ];
+
+    /// Port bitcoind serves RPC on unless -rpcport says otherwise
+    pub fn default_rpc_port(self) -> u16 {
+        match self {
+            Network::Mainnet => 8332,
+            Network::Testnet => 18332,
+            Network::Signet => 38332,
+            Network::Regtest => 18443,
+        }
+    }
+
+    /// Compact bits of the easiest target blocks may have
+    pub fn pow_limit_bits(self) -> u32 {
+        match self {
+            Network::Mainnet | Network::Testnet => 0x1d00_ffff,
+            Network::Signet => 0x1e03_77ae,
+            Network::Regtest => 0x207f_ffff,
+        }
+    }
+
+    /// Easiest big-endian 256-bit target blocks may have
+    pub fn pow_limit(self) -> [u8; 32] {
+        bits_to_target(self.pow_limit_bits()).expect("Limits are valid bits")
+    }
+
+    // Version bytes of P2PKH and P2SH addresses
+    pub(crate) fn base58_versions(self) -> (u8, u8) {
+        match self {
+            Network::Mainnet => (0x00, 0x05),
+            Network::Testnet | Network::Signet | Network::Regtest => (0x6f, 0xc4),
+        }
+    }
+
+    pub(crate) fn bech32_hrp(self) -> &'static str {
+        match self {
+            Network::Mainnet => "bc",
+            Network::Testnet | Network::Signet => "tb",
+            Network::Regtest => "bcrt",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Parses the names Display prints, or the chain names of bitcoind
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "mainnet" | "main" | "bitcoin" => Network::Mainnet,
+            "testnet" | "test" => Network::Testnet,
+            "signet" => Network::Signet,
+            "regtest" => Network::Regtest,
+            _ => bail!("Unknown network {name}, expected mainnet, testnet, signet or regtest."),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_parse_and_have_their_limits() {
+        for network in Network::ALL {
+            assert_eq!(network.to_string().parse::<Network>().unwrap(), network);
+        }
+        assert_eq!("main".parse::<Network>().unwrap(), Network::Mainnet);
+        assert_eq!("Regtest".parse::<Network>().unwrap(), Network::Regtest);
+        assert!("testnet3x".parse::<Network>().is_err());
+
+        assert_eq!(Network::Signet.default_rpc_port(), 38332);
+        assert_eq!(Network::Mainnet.pow_limit()[..6], [0, 0, 0, 0, 0xff, 0xff]);
+        assert_eq!(Network::Signet.pow_limit()[..5], [0, 0, 0x03, 0x77, 0xae]);
+        assert_eq!(Network::Regtest.pow_limit()[..3], [0x7f, 0xff, 0xff]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Sharing the coinbase value between addresses.
+//!
+//! Splits give a percentage of the value to other addresses, e.g. a dev
+//! fee or the owners of a shared rig. Each share is rounded down and the
+//! payout address gets the rest, so the outputs add up to the
+//! coinbasevalue exactly.
+
+use anyhow::{ensure, Context, Result};
+
+use crate::{coinbase::CoinbaseOutput, Address, Network};
+
+/// Percentage of the coinbase value paid to address
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayoutSplit {
+    pub address: String,
+    pub percent: f64,
+}
+
+// Every split takes a positive share and leaves some for the payout address
+pub(crate) fn check_splits(splits: &[PayoutSplit]) -> Result<()> {
+    for split in splits {
+        ensure!(
+            split.percent.is_finite() && split.percent > 0.0,
+            "Split of {}% to {}, should be above 0.",
+            split.percent,
+            split.address
+        );
+    }
+    let total: f64 = splits.iter().map(|split| split.percent).sum();
+    ensure!(
+        total < 100.0,
+        "Splits take {total}%, the payout address needs a share."
+    );
+    Ok(())
+}
+
+// Outputs of the coinbase, the payout address first
+pub(crate) fn split_value(
+    value: u64,
+    payout_address: &Address,
+    splits: &[PayoutSplit],
+    network: Network,
+) -> Result<Vec<CoinbaseOutput>> {
+    check_splits(splits)?;
+    let mut outputs = vec![CoinbaseOutput {
+        value,
+        script_pubkey: payout_address.script_pubkey(),
+    }];
+    for split in splits {
+        let address = Address::parse(&split.address, network)
+            .with_context(|| format!("Invalid split address {}.", split.address))?;
+        // Below 2^53 satoshis f64 is exact
+        let share = (value as f64 * split.percent / 100.0).floor() as u64;
+        outputs[0].value -= share;
+        outputs.push(CoinbaseOutput {
+            value: share,
+            script_pubkey: address.script_pubkey(),
+        });
+    }
+
+    let total: u64 = outputs.iter().map(|output| output.value).sum();
+    ensure!(
+        total == value,
+        "Coinbase outputs pay {total}, not the coinbasevalue {value}."
+    );
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_add_up_to_the_value() {
+        let network = Network::Regtest;
+        let payout =
+            Address::parse("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080", network).unwrap();
+        let split = |percent| PayoutSplit {
+            address: "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080".to_string(),
+            percent,
+        };
+
+        let outputs = split_value(5_000_000_001, &payout, &[split(1.0)], network).unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[1].value, 50_000_000);
+        assert_eq!(outputs[0].value, 4_950_000_001);
+
+        let outputs = split_value(7, &payout, &[split(33.3), split(33.3)], network).unwrap();
+        assert_eq!(
+            outputs
+                .iter()
+                .map(|output| output.value)
+                .collect::<Vec<_>>(),
+            [3, 2, 2]
+        );
+        assert_eq!(split_value(7, &payout, &[], network).unwrap()[0].value, 7);
+
+        assert!(check_splits(&[split(60.0), split(40.0)]).is_err());
+        assert!(check_splits(&[split(0.0)]).is_err());
+        assert!(check_splits(&[split(f64::NAN)]).is_err());
+        let mainnet = PayoutSplit {
+            address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+            percent: 1.0,
+        };
+        assert!(split_value(7, &payout, &[mainnet], network).is_err());
+    }
+}
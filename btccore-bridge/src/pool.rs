@@ -0,0 +1,876 @@
+//! Connection to a stratum pool.
+//!
+//! Stratum is line-delimited JSON-RPC over any Transport: the client
+//! subscribes for its extranonce, authorizes the worker and then gets jobs
+//! (mining.notify) and the share difficulty (mining.set_difficulty) pushed.
+//! Shares go back with mining.submit, the pool answers each by id.
+//! With version rolling (BIP 310) mining.configure comes first and asks for
+//! version bits the miner may roll, shares then carry their version bits.
+//! next_event is cancel-safe, so it can sit in a select! next to mining.
+//! It also reconnects with backoff when the connection drops or the pool
+//! sends client.reconnect. Jobs of the old session are dropped then and
+//! shares only ever go out once.
+//! NiceHash wants more: mining.extranonce.subscribe, so it can move the
+//! extranonce with mining.set_extranonce, and new difficulties only count
+//! from the next job on. set_nicehash turns both on.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::{json, Value};
+use tokio::{
+    io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
+    time::Instant,
+};
+
+use crate::{encode_hex, Backoff, Extranonce, StratumJob, Transport};
+
+// Sent with mining.subscribe
+const USER_AGENT: &str = concat!("harvester/", env!("CARGO_PKG_VERSION"));
+
+// Error code of mining.submit for a job the pool no longer knows
+const JOB_NOT_FOUND: i64 = 21;
+
+// Fewest version bits worth rolling, pools allowing less refuse
+const MIN_VERSION_BITS: u32 = 2;
+
+/// Nonce found for a job, what mining.submit sends
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Share {
+    pub job_id: String,
+    pub extranonce2: Vec<u8>,
+    pub ntime: u32,
+    pub nonce: u32,
+    /// Bits of the version within the version mask, None unless rolled
+    pub version_bits: Option<u32>,
+}
+
+/// Shares the pool answered, and the difficulty it credited
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShareStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Shares for jobs the pool had replaced, dropped before submitting
+    /// or turned down by the pool
+    pub stale: u64,
+    /// Shares sent right before the connection dropped, never answered
+    pub lost: u64,
+    /// Sum of the difficulties of accepted shares, 2^32 hashes each on
+    /// average
+    pub accepted_difficulty: f64,
+}
+
+/// Something the pool sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum StratumEvent {
+    /// Work to switch to, everything older is stale if clean_jobs is set
+    Job(StratumJob),
+    /// New share difficulty, 1 means the target of difficulty 1
+    /// With NiceHash it counts from the next job on.
+    Difficulty(f64),
+    /// Extranonce the pool moved the session to, wait for a job
+    Extranonce(Extranonce),
+    ShareAccepted,
+    /// Reason the pool gave
+    ShareRejected(String),
+    /// Version bits the pool allows from now on (mining.set_version_mask)
+    VersionMask(u32),
+    /// Connection dropped or the pool asked to reconnect, the jobs are gone
+    Disconnected(String),
+    /// Subscribed and authorized again, wait for a job
+    Reconnected,
+}
+
+// Reading and writing halves of the connection
+struct Connection<S> {
+    lines: Lines<BufReader<ReadHalf<S>>>,
+    writer: WriteHalf<S>,
+}
+
+/// Stratum client of a worker
+pub struct StratumClient<T: Transport> {
+    transport: T,
+    user: String,
+    password: String,
+    connection: Option<Connection<T::Stream>>,
+    // Subscribed and authorized on the connection
+    ready: bool,
+    backoff: Backoff,
+    // When to try connecting again, None for right away
+    retry_at: Option<Instant>,
+    extranonce: Option<Extranonce>,
+    // Version bits asked for and the ones the pool allowed of them
+    requested_mask: Option<u32>,
+    version_mask: Option<u32>,
+    difficulty: f64,
+    nicehash: bool,
+    // NiceHash difficulty waiting for the next job
+    next_difficulty: Option<f64>,
+    job: Option<StratumJob>,
+    // Jobs shares are still taken for, reset by clean_jobs
+    live_jobs: Vec<String>,
+    // Shares of the live jobs that went out already
+    submitted: HashSet<Share>,
+    next_id: u64,
+    // mining.submit ids waiting for an answer, with the difficulty the
+    // share was found at
+    pending: HashMap<u64, f64>,
+    stats: ShareStats,
+    // Events that arrived while a request waited for its answer
+    queued: VecDeque<StratumEvent>,
+}
+
+impl<T: Transport> StratumClient<T> {
+    /// Client for worker user, not connected until connect
+    pub fn new(transport: T, user: &str, password: &str) -> Self {
+        Self {
+            transport,
+            user: user.to_string(),
+            password: password.to_string(),
+            connection: None,
+            ready: false,
+            backoff: Backoff::default(),
+            retry_at: None,
+            extranonce: None,
+            requested_mask: None,
+            version_mask: None,
+            difficulty: 1.0,
+            nicehash: false,
+            next_difficulty: None,
+            job: None,
+            live_jobs: Vec::new(),
+            submitted: HashSet::new(),
+            next_id: 0,
+            pending: HashMap::new(),
+            stats: ShareStats::default(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Asks the pool on connect to allow rolling the version bits of mask,
+    /// usually VERSION_ROLLING_MASK. None to mine the versions of the jobs.
+    pub fn set_version_rolling(&mut self, mask: Option<u32>) {
+        self.requested_mask = mask;
+    }
+
+    /// Getter for NiceHash compatibility
+    pub fn get_nicehash(&self) -> bool {
+        self.nicehash
+    }
+
+    /// Follows NiceHash's rules: subscribes to extranonce changes on
+    /// connect and applies difficulties with the next job
+    pub fn set_nicehash(&mut self, nicehash: bool) {
+        self.nicehash = nicehash;
+    }
+
+    /// Getter for the backoff between reconnects
+    pub fn get_backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// Sets the backoff between reconnects
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Connects, negotiates version rolling if asked to, subscribes and
+    /// authorizes the worker
+    pub async fn connect(&mut self) -> Result<()> {
+        self.ready = false;
+        let stream = self.transport.connect().await?;
+        let (reader, writer) = split(stream);
+        self.connection = Some(Connection {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        });
+
+        self.version_mask = None;
+        if let Some(mask) = self.requested_mask {
+            let params = json!([
+                ["version-rolling"],
+                {
+                    "version-rolling.mask": format!("{mask:08x}"),
+                    "version-rolling.min-bit-count": MIN_VERSION_BITS,
+                }
+            ]);
+            // Pools without BIP 310 answer with an error, they get mined
+            // without rolling
+            if let Ok(result) = self.request("mining.configure", params).await {
+                if result["version-rolling"] == Value::Bool(true) {
+                    let allowed = parse_mask(&result["version-rolling.mask"])?;
+                    self.version_mask = Some(allowed & mask).filter(|mask| *mask != 0);
+                }
+            }
+        }
+
+        let subscription = self
+            .request("mining.subscribe", json!([USER_AGENT]))
+            .await
+            .context("Pool refused mining.subscribe.")?;
+        self.extranonce = Some(Extranonce::from_subscribe(&subscription)?);
+
+        let authorized = self
+            .request("mining.authorize", json!([self.user, self.password]))
+            .await
+            .with_context(|| format!("Pool refused worker {}.", self.user))?;
+        ensure!(
+            authorized == Value::Bool(true),
+            "Pool refused worker {}.",
+            self.user
+        );
+        if self.nicehash {
+            let subscribed = self
+                .request("mining.extranonce.subscribe", json!([]))
+                .await
+                .context("Pool refused mining.extranonce.subscribe.")?;
+            ensure!(
+                subscribed == Value::Bool(true),
+                "Pool refused mining.extranonce.subscribe."
+            );
+        }
+        self.ready = true;
+        Ok(())
+    }
+
+    /// Waits for the next message of the pool that matters to the miner,
+    /// reconnecting first if the connection is gone
+    pub async fn next_event(&mut self) -> Result<StratumEvent> {
+        loop {
+            if let Some(event) = self.queued.pop_front() {
+                return Ok(event);
+            }
+            if !self.ready {
+                return self.reconnect().await;
+            }
+            match self.read_message().await {
+                Ok(message) => {
+                    if let Some(event) = self.handle(message)? {
+                        return Ok(event);
+                    }
+                }
+                Err(err) => self.disconnect(format!("{err:#}")),
+            }
+        }
+    }
+
+    /// Sends share unless its job is stale or it went out already, the
+    /// answer comes as an event. Returns whether it was sent.
+    pub async fn submit(&mut self, share: &Share) -> Result<bool> {
+        if let Some(bits) = share.version_bits {
+            let mask = self
+                .version_mask
+                .context("Share with version bits, but the pool allows no version rolling.")?;
+            ensure!(
+                bits & !mask == 0,
+                "Version bits {bits:08x} outside the mask {mask:08x} of the pool."
+            );
+        }
+        if !self.ready || !self.live_jobs.contains(&share.job_id) {
+            self.stats.stale += 1;
+            return Ok(false);
+        }
+        if self.submitted.contains(share) {
+            return Ok(false);
+        }
+        let mut params = json!([
+            self.user,
+            share.job_id,
+            encode_hex(&share.extranonce2),
+            format!("{:08x}", share.ntime),
+            format!("{:08x}", share.nonce),
+        ]);
+        if let (Some(bits), Some(params)) = (share.version_bits, params.as_array_mut()) {
+            params.push(json!(format!("{bits:08x}")));
+        }
+        let id = match self.send("mining.submit", params).await {
+            Ok(id) => id,
+            Err(err) => {
+                self.stats.lost += 1;
+                self.disconnect(format!("{err:#}"));
+                return Ok(false);
+            }
+        };
+        self.submitted.insert(share.clone());
+        self.pending.insert(id, self.difficulty);
+        Ok(true)
+    }
+
+    /// Getter for extranonce1 and the extranonce2 size, None until
+    /// connected
+    pub fn get_extranonce(&self) -> Option<&Extranonce> {
+        self.extranonce.as_ref()
+    }
+
+    /// Version bits the pool allows to roll, None without version rolling
+    pub fn get_version_mask(&self) -> Option<u32> {
+        self.version_mask
+    }
+
+    /// Latest job of the pool
+    pub fn get_job(&self) -> Option<&StratumJob> {
+        self.job.as_ref()
+    }
+
+    /// Getter for the share difficulty of the current job, 1 until the
+    /// pool sets it
+    pub fn get_difficulty(&self) -> f64 {
+        self.difficulty
+    }
+
+    /// Getter for the worker name
+    pub fn get_user(&self) -> &str {
+        &self.user
+    }
+
+    /// Getter for the share counters
+    pub fn stats(&self) -> &ShareStats {
+        &self.stats
+    }
+
+    // Drops the session, next_event reconnects
+    fn disconnect(&mut self, reason: String) {
+        self.ready = false;
+        self.connection = None;
+        self.job = None;
+        self.next_difficulty = None;
+        self.live_jobs.clear();
+        self.submitted.clear();
+        self.stats.lost += self.pending.len() as u64;
+        self.pending.clear();
+        self.queued.push_back(StratumEvent::Disconnected(reason));
+    }
+
+    // Connects again once retry_at passed, backing off while it fails.
+    // Cancel-safe, an interrupted handshake starts over.
+    async fn reconnect(&mut self) -> Result<StratumEvent> {
+        loop {
+            if let Some(retry_at) = self.retry_at {
+                tokio::time::sleep_until(retry_at).await;
+            }
+            match self.connect().await {
+                Ok(()) => {
+                    self.backoff.reset();
+                    self.retry_at = None;
+                    return Ok(StratumEvent::Reconnected);
+                }
+                Err(_) => self.retry_at = Some(Instant::now() + self.backoff.next_delay()),
+            }
+        }
+    }
+
+    // Sends a request and waits for its answer, queueing what else comes
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.send(method, params).await?;
+        loop {
+            let message = self.read_message().await?;
+            if message.get("id").and_then(Value::as_u64) == Some(id) {
+                if let Some(error) = message.get("error").filter(|error| !error.is_null()) {
+                    bail!("{method} failed: {error}");
+                }
+                return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+            }
+            if let Some(event) = self.handle(message)? {
+                self.queued.push_back(event);
+            }
+        }
+    }
+
+    async fn send(&mut self, method: &str, params: Value) -> Result<u64> {
+        let connection = self
+            .connection
+            .as_mut()
+            .context("Not connected, connect first.")?;
+        self.next_id += 1;
+        let mut line = json!({"id": self.next_id, "method": method, "params": params}).to_string();
+        line.push('\n');
+        connection.writer.write_all(line.as_bytes()).await?;
+        connection.writer.flush().await?;
+        Ok(self.next_id)
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        let connection = self
+            .connection
+            .as_mut()
+            .context("Not connected, connect first.")?;
+        loop {
+            let line = connection
+                .lines
+                .next_line()
+                .await
+                .context("Lost the pool connection.")?
+                .context("Pool closed the connection.")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return serde_json::from_str(&line)
+                .with_context(|| format!("Pool sent invalid JSON: {line}"));
+        }
+    }
+
+    // Applies a notification or an answer to a share
+    fn handle(&mut self, message: Value) -> Result<Option<StratumEvent>> {
+        let params = &message["params"];
+        match message.get("method").and_then(Value::as_str) {
+            Some("mining.notify") => {
+                let job = StratumJob::from_notify(params)?;
+                if job.clean_jobs() {
+                    self.live_jobs.clear();
+                    self.submitted.clear();
+                }
+                self.live_jobs.push(job.job_id().to_string());
+                self.job = Some(job.clone());
+                if let Some(difficulty) = self.next_difficulty.take() {
+                    self.difficulty = difficulty;
+                }
+                Ok(Some(StratumEvent::Job(job)))
+            }
+            Some("mining.set_difficulty") => {
+                let difficulty = params[0]
+                    .as_f64()
+                    .filter(|difficulty| *difficulty > 0.0)
+                    .context("mining.set_difficulty without a positive difficulty.")?;
+                match self.nicehash && self.job.is_some() {
+                    true => self.next_difficulty = Some(difficulty),
+                    false => self.difficulty = difficulty,
+                }
+                Ok(Some(StratumEvent::Difficulty(difficulty)))
+            }
+            // Jobs built on the old extranonce are worthless, NiceHash
+            // follows up with a fresh one
+            Some("mining.set_extranonce") if self.nicehash => {
+                let extranonce = Extranonce::from_set_extranonce(params)?;
+                self.extranonce = Some(extranonce.clone());
+                self.job = None;
+                self.live_jobs.clear();
+                self.submitted.clear();
+                Ok(Some(StratumEvent::Extranonce(extranonce)))
+            }
+            Some("mining.set_version_mask") => {
+                let Some(requested) = self.requested_mask else {
+                    return Ok(None);
+                };
+                let mask = parse_mask(&params[0])? & requested;
+                self.version_mask = Some(mask).filter(|mask| *mask != 0);
+                Ok(Some(StratumEvent::VersionMask(mask)))
+            }
+            // Same pool only, the transport decides where to connect
+            Some("client.reconnect") => {
+                let wait = params[2].as_u64().unwrap_or(0);
+                self.disconnect("Pool asked to reconnect.".to_string());
+                self.retry_at = Some(Instant::now() + Duration::from_secs(wait));
+                Ok(self.queued.pop_back())
+            }
+            // Nothing the miner needs
+            Some(_) => Ok(None),
+            None => Ok(self.answer(&message)),
+        }
+    }
+
+    // Counts the answer to a share, None for other answers
+    fn answer(&mut self, message: &Value) -> Option<StratumEvent> {
+        let id = message.get("id").and_then(Value::as_u64)?;
+        let difficulty = self.pending.remove(&id)?;
+        let error = message.get("error").filter(|error| !error.is_null());
+        if error.is_none() && message["result"] == Value::Bool(true) {
+            self.stats.accepted += 1;
+            self.stats.accepted_difficulty += difficulty;
+            return Some(StratumEvent::ShareAccepted);
+        }
+
+        // Errors are [code, message, traceback]
+        let reason = match error {
+            Some(error) => error[1].as_str().unwrap_or("no reason").to_string(),
+            None => "no reason".to_string(),
+        };
+        let stale = error.and_then(|error| error[0].as_i64()) == Some(JOB_NOT_FOUND)
+            || reason.to_lowercase().contains("stale");
+        match stale {
+            true => self.stats.stale += 1,
+            false => self.stats.rejected += 1,
+        }
+        Some(StratumEvent::ShareRejected(reason))
+    }
+}
+
+// Version mask as hex, like 1fffe000
+fn parse_mask(mask: &Value) -> Result<u32> {
+    let mask = mask.as_str().context("Version mask isn't a string.")?;
+    u32::from_str_radix(mask, 16).with_context(|| format!("Invalid version mask {mask}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TcpTransport;
+    use tokio::net::TcpListener;
+
+    fn notify(job_id: &str, clean_jobs: bool) -> String {
+        json!({"id": null, "method": "mining.notify", "params": [
+            job_id,
+            "00".repeat(32),
+            "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704",
+            "ffffffff0100f2052a01000000015100000000",
+            [],
+            "20000000",
+            "1d00ffff",
+            "4966bc61",
+            clean_jobs
+        ]})
+        .to_string()
+            + "\n"
+    }
+
+    #[tokio::test]
+    async fn shares_are_submitted_and_counted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let pool = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = split(stream);
+            let mut lines = BufReader::new(reader).lines();
+
+            let subscribe = request(&mut lines).await;
+            assert_eq!(subscribe["method"], "mining.subscribe");
+            // Pools like to push work before the subscription is answered
+            let reply = format!(
+                "{{\"id\":null,\"method\":\"mining.set_difficulty\",\"params\":[2]}}\n{}\
+                 {{\"id\":1,\"result\":[[],\"08000002\",4],\"error\":null}}\n",
+                notify("a", true)
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            let authorize = request(&mut lines).await;
+            assert_eq!(authorize["params"], json!(["rig.1", "x"]));
+            writer
+                .write_all(b"{\"id\":2,\"result\":true,\"error\":null}\n")
+                .await
+                .unwrap();
+
+            let submit = request(&mut lines).await;
+            assert_eq!(
+                submit["params"],
+                json!(["rig.1", "a", "01000000", "4966bc61", "0000002a"])
+            );
+            let reply = format!(
+                "{{\"id\":{},\"result\":true,\"error\":null}}\n",
+                submit["id"]
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+
+            let submit = request(&mut lines).await;
+            let reply = format!(
+                "{{\"id\":{},\"result\":null,\"error\":[21,\"Job not found\",null]}}\n{}",
+                submit["id"],
+                notify("b", true)
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            let submit = request(&mut lines).await;
+            let reply = format!(
+                "{{\"id\":{},\"result\":false,\"error\":[23,\"Low difficulty share\",null]}}\n",
+                submit["id"]
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        });
+
+        let mut client = StratumClient::new(TcpTransport::new(&address), "rig.1", "x");
+        client.connect().await.unwrap();
+        let extranonce = client.get_extranonce().unwrap();
+        assert_eq!(extranonce.extranonce1, [0x08, 0, 0, 0x02]);
+        assert_eq!(extranonce.extranonce2_size, 4);
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Difficulty(2.0)
+        );
+        let StratumEvent::Job(job) = client.next_event().await.unwrap() else {
+            panic!("Pool sent a job first");
+        };
+        assert_eq!(job.job_id(), "a");
+        assert_eq!(client.get_difficulty(), 2.0);
+
+        assert!(client.submit(&share("a", 42)).await.unwrap());
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::ShareAccepted
+        );
+        assert!(client.submit(&share("a", 43)).await.unwrap());
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::ShareRejected("Job not found".to_string())
+        );
+        // Job b cleans out a, whose shares stay home
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+        assert!(!client.submit(&share("a", 44)).await.unwrap());
+        assert!(client.submit(&share("b", 45)).await.unwrap());
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::ShareRejected(_)
+        ));
+
+        assert_eq!(
+            *client.stats(),
+            ShareStats {
+                accepted: 1,
+                rejected: 1,
+                stale: 2,
+                lost: 0,
+                accepted_difficulty: 2.0,
+            }
+        );
+        pool.await.unwrap();
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Disconnected(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_resume_after_drops_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let pool = tokio::spawn(async move {
+            // Drops the share without an answer
+            let (mut lines, writer) = handshake(&listener, "a").await;
+            assert_eq!(request(&mut lines).await["method"], "mining.submit");
+            drop((lines, writer));
+
+            // Refuses the subscription once, the client backs off and retries
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            let subscribe = request(&mut lines).await;
+            let reply = format!(
+                "{{\"id\":{},\"result\":null,\"error\":[20,\"Maintenance\",null]}}\n",
+                subscribe["id"]
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            drop((lines, writer));
+
+            let (mut lines, mut writer) = handshake(&listener, "b").await;
+            assert_eq!(request(&mut lines).await["params"][1], "b");
+            writer
+                .write_all(b"{\"id\":null,\"method\":\"client.reconnect\",\"params\":[\"\",0,0]}\n")
+                .await
+                .unwrap();
+            // The duplicate never came, the reconnect did
+            let (mut lines, _writer) = handshake(&listener, "c").await;
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), request(&mut lines))
+                    .await
+                    .is_err()
+            );
+        });
+
+        let mut client = StratumClient::new(TcpTransport::new(&address), "rig.1", "x");
+        client.set_backoff(Backoff::new(
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+        ));
+        client.connect().await.unwrap();
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+        assert!(client.submit(&share("a", 1)).await.unwrap());
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Disconnected(_)
+        ));
+        assert!(client.get_job().is_none());
+        // Stale with its session, not sent to the next one
+        assert!(!client.submit(&share("a", 1)).await.unwrap());
+
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Reconnected
+        );
+        assert_eq!(client.get_backoff().attempts(), 0);
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+        assert!(client.submit(&share("b", 2)).await.unwrap());
+        assert!(!client.submit(&share("b", 2)).await.unwrap());
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Disconnected("Pool asked to reconnect.".to_string())
+        );
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Reconnected
+        );
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+
+        assert_eq!(client.stats().lost, 2);
+        assert_eq!(client.stats().stale, 1);
+        pool.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn nicehash_moves_extranonces_and_defers_difficulties() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let pool = tokio::spawn(async move {
+            let (mut lines, mut writer) = handshake(&listener, "a").await;
+            let subscribe = request(&mut lines).await;
+            assert_eq!(subscribe["method"], "mining.extranonce.subscribe");
+            let reply = format!(
+                "{{\"id\":{},\"result\":true,\"error\":null}}\n\
+                 {{\"id\":null,\"method\":\"mining.set_difficulty\",\"params\":[0.5]}}\n{}\
+                 {{\"id\":null,\"method\":\"mining.set_extranonce\",\"params\":[\"0a0b\",2]}}\n",
+                subscribe["id"],
+                notify("b", false)
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            // Nothing was submitted for the old extranonce
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), request(&mut lines))
+                    .await
+                    .is_err()
+            );
+        });
+
+        let mut client = StratumClient::new(TcpTransport::new(&address), "rig.1", "x");
+        client.set_nicehash(true);
+        client.connect().await.unwrap();
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Difficulty(0.5)
+        );
+        // Job a is still mined at the old difficulty, b at the new one
+        assert_eq!(client.get_difficulty(), 1.0);
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+        assert_eq!(client.get_difficulty(), 0.5);
+
+        let StratumEvent::Extranonce(extranonce) = client.next_event().await.unwrap() else {
+            panic!("Pool moved the extranonce");
+        };
+        assert_eq!(extranonce.extranonce1, [0x0a, 0x0b]);
+        assert_eq!(client.get_extranonce(), Some(&extranonce));
+        assert!(client.get_job().is_none());
+        assert!(!client.submit(&share("b", 1)).await.unwrap());
+        pool.await.unwrap();
+    }
+
+    // Accepts a client, subscribes and authorizes it and sends job_id
+    async fn handshake(
+        listener: &TcpListener,
+        job_id: &str,
+    ) -> (
+        Lines<BufReader<ReadHalf<tokio::net::TcpStream>>>,
+        WriteHalf<tokio::net::TcpStream>,
+    ) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        let subscribe = request(&mut lines).await;
+        assert_eq!(subscribe["method"], "mining.subscribe");
+        let reply = format!(
+            "{{\"id\":{},\"result\":[[],\"08000002\",4],\"error\":null}}\n",
+            subscribe["id"]
+        );
+        writer.write_all(reply.as_bytes()).await.unwrap();
+        let authorize = request(&mut lines).await;
+        let reply = format!(
+            "{{\"id\":{},\"result\":true,\"error\":null}}\n{}",
+            authorize["id"],
+            notify(job_id, true)
+        );
+        writer.write_all(reply.as_bytes()).await.unwrap();
+        (lines, writer)
+    }
+
+    #[tokio::test]
+    async fn version_rolling_is_negotiated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let pool = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = split(stream);
+            let mut lines = BufReader::new(reader).lines();
+
+            let configure = request(&mut lines).await;
+            assert_eq!(configure["method"], "mining.configure");
+            assert_eq!(configure["params"][1]["version-rolling.mask"], "1fffe000");
+            let reply = "{\"id\":1,\"result\":{\"version-rolling\":true,\
+                         \"version-rolling.mask\":\"0fffe000\"},\"error\":null}\n";
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            request(&mut lines).await;
+            writer
+                .write_all(b"{\"id\":2,\"result\":[[],\"08000002\",4],\"error\":null}\n")
+                .await
+                .unwrap();
+            request(&mut lines).await;
+            let reply = format!(
+                "{{\"id\":3,\"result\":true,\"error\":null}}\n{}",
+                notify("a", true)
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+
+            let submit = request(&mut lines).await;
+            assert_eq!(submit["params"][5], "00002000");
+            let reply = format!(
+                "{{\"id\":{},\"result\":true,\"error\":null}}\n\
+                 {{\"id\":null,\"method\":\"mining.set_version_mask\",\"params\":[\"00006000\"]}}\n",
+                submit["id"]
+            );
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        });
+
+        let mut client = StratumClient::new(TcpTransport::new(&address), "rig.1", "x");
+        client.set_version_rolling(Some(0x1fff_e000));
+        client.connect().await.unwrap();
+        assert_eq!(client.get_version_mask(), Some(0x0fff_e000));
+        assert!(matches!(
+            client.next_event().await.unwrap(),
+            StratumEvent::Job(_)
+        ));
+
+        let mut rolled = share("a", 42);
+        rolled.version_bits = Some(0x1000_0000);
+        assert!(client.submit(&rolled).await.is_err());
+        rolled.version_bits = Some(0x2000);
+        assert!(client.submit(&rolled).await.unwrap());
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::ShareAccepted
+        );
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            StratumEvent::VersionMask(0x6000)
+        );
+        assert_eq!(client.get_version_mask(), Some(0x6000));
+        pool.await.unwrap();
+    }
+
+    async fn request(lines: &mut Lines<BufReader<ReadHalf<tokio::net::TcpStream>>>) -> Value {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn share(job_id: &str, nonce: u32) -> Share {
+        Share {
+            job_id: job_id.to_string(),
+            extranonce2: vec![1, 0, 0, 0],
+            ntime: 0x4966bc61,
+            nonce,
+            version_bits: None,
+        }
+    }
+}
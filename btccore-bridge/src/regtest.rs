@@ -0,0 +1,215 @@
+//! A throwaway regtest bitcoind for integration tests.
+//!
+//! RegtestNode starts bitcoind with its own data directory and ports,
+//! funds a wallet and stops it again on drop. mine_block then runs the
+//! whole pipeline against it: getblocktemplate, assembly, a nonce ground
+//! on the CPU, which is enough at regtest difficulty, and submitblock.
+//! bitcoind comes from $BITCOIND or the PATH, tests skip without it.
+
+use std::{
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+
+use crate::{
+    merkle::sha256d, BlockHash, Bridge, NodeClient, RpcClient, SubmitResult, TcpTransport,
+};
+
+// How long bitcoind gets to answer RPC after it started
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Enough blocks for the first coinbase to mature
+const MATURITY: u32 = 101;
+
+// Tells apart the nodes of one test process
+static NODES: AtomicU32 = AtomicU32::new(0);
+
+/// bitcoind on regtest, killed and deleted on drop
+pub struct RegtestNode {
+    process: Child,
+    datadir: PathBuf,
+    rpc_address: String,
+}
+
+/// Path of bitcoind, from $BITCOIND or else the PATH
+pub fn bitcoind() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("BITCOIND") {
+        return Some(PathBuf::from(path));
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join("bitcoind"))
+        .find(|path| path.is_file())
+}
+
+impl RegtestNode {
+    /// Starts bitcoind in a fresh data directory and waits until it
+    /// answers RPC
+    pub async fn start() -> Result<Self> {
+        let bitcoind = bitcoind().context("bitcoind not found, set BITCOIND or the PATH.")?;
+        let datadir = std::env::temp_dir().join(format!(
+            "harvester-regtest-{}-{}",
+            std::process::id(),
+            NODES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&datadir);
+        std::fs::create_dir_all(&datadir)?;
+
+        let rpc_port = free_port()?;
+        let process = Command::new(&bitcoind)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={rpc_port}"))
+            .arg(format!("-port={}", free_port()?))
+            .args(["-listen=0", "-server", "-fallbackfee=0.0001"])
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Couldn't start {}.", bitcoind.display()))?;
+        let node = Self {
+            process,
+            datadir,
+            rpc_address: format!("127.0.0.1:{rpc_port}"),
+        };
+
+        let started = Instant::now();
+        loop {
+            let result = match node.client() {
+                Ok(client) => client.request::<u32>("getblockcount", json!([])).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(_) => return Ok(node),
+                Err(err) if started.elapsed() > STARTUP_TIMEOUT => {
+                    return Err(err.context("bitcoind didn't come up."))
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+
+    /// Getter for the address RPC is served on
+    pub fn get_rpc_address(&self) -> &str {
+        &self.rpc_address
+    }
+
+    /// Getter for the data directory
+    pub fn get_datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    /// Client authenticated with the cookie of the node
+    pub fn client(&self) -> Result<NodeClient<TcpTransport>> {
+        let mut client = NodeClient::new(TcpTransport::new(&self.rpc_address));
+        client.set_cookie_file(self.datadir.join("regtest").join(".cookie"))?;
+        Ok(client)
+    }
+
+    /// Creates a wallet, mines it a spendable coinbase and leaves a
+    /// payment to itself in the mempool, so templates have a transaction.
+    /// Returns an address of the wallet to pay the blocks to.
+    pub async fn fund_wallet(&self) -> Result<String> {
+        let client = self.client()?;
+        client
+            .request::<serde_json::Value>("createwallet", json!(["harvester"]))
+            .await?;
+        let address: String = client.request("getnewaddress", json!([])).await?;
+        client
+            .request::<Vec<String>>("generatetoaddress", json!([MATURITY, address]))
+            .await?;
+        client
+            .request::<String>("sendtoaddress", json!([address, 1.0]))
+            .await?;
+        Ok(address)
+    }
+}
+
+impl Drop for RegtestNode {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.datadir);
+    }
+}
+
+/// Fetches a template, assembles and mines the block and submits it.
+/// Returns the node's verdict and the hash of the block.
+pub async fn mine_block<T: RpcClient>(
+    bridge: &mut Bridge<T>,
+    payout_address: &str,
+) -> Result<(SubmitResult, BlockHash)> {
+    bridge.update_block(payout_address).await?;
+    let block = bridge.get_block().context("update_block left no block.")?;
+    let mut header = *block.header();
+    let hash = grind(&mut header, block.target())?;
+    Ok((bridge.submit_block(header).await?, hash))
+}
+
+// Tries nonces until the hash meets target, in display order
+fn grind(header: &mut [u8; 80], target: &[u8; 32]) -> Result<BlockHash> {
+    for nonce in 0..=u32::MAX {
+        header[76..].copy_from_slice(&nonce.to_le_bytes());
+        let mut hash = sha256d(header);
+        hash.reverse();
+        // Both big-endian now
+        if hash <= *target {
+            return Ok(BlockHash(hash));
+        }
+    }
+    bail!("No nonce meets the target, roll the extranonce.")
+}
+
+// Port nothing listens on right now
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Network;
+
+    #[test]
+    fn easy_targets_are_met() {
+        let mut header = [0u8; 80];
+        let hash = grind(&mut header, &[0x7f; 32]).unwrap();
+        assert!(hash.0[0] <= 0x7f);
+        let mut rehashed = sha256d(&header);
+        rehashed.reverse();
+        assert_eq!(rehashed, hash.0);
+    }
+
+    #[tokio::test]
+    async fn mined_blocks_extend_the_chain() {
+        if bitcoind().is_none() {
+            eprintln!("bitcoind not found, skipping the regtest run");
+            return;
+        }
+        let node = RegtestNode::start().await.unwrap();
+        let address = node.fund_wallet().await.unwrap();
+
+        let (mut bridge, _) = Bridge::new(node.client().unwrap());
+        bridge.set_network(Network::Regtest);
+        bridge.set_propose_blocks(true);
+        let (verdict, hash) = mine_block(&mut bridge, &address).await.unwrap();
+        assert_eq!(verdict, SubmitResult::Accepted);
+        // The payment to the wallet made it into the block
+        assert_eq!(bridge.get_block().unwrap().transactions().len(), 2);
+
+        let client = node.client().unwrap();
+        let best: String = client.request("getbestblockhash", json!([])).await.unwrap();
+        assert_eq!(best, hash.to_string());
+        let height: u32 = client.request("getblockcount", json!([])).await.unwrap();
+        assert_eq!(height, MATURITY + 1);
+
+        // The same block again is a duplicate
+        let mut solved = *bridge.get_block().unwrap().header();
+        grind(&mut solved, bridge.get_block().unwrap().target()).unwrap();
+        let verdict = bridge.submit_block(solved).await.unwrap();
+        assert!(!verdict.is_accepted());
+    }
+}
@@ -0,0 +1,159 @@
+//! Requests sent to Bitcoin Core over JSON-RPC.
+//!
+//! getblocktemplate takes a template request (BIP 22 and 23) naming the
+//! rules and capabilities of the client. Without "segwit" in the rules
+//! Bitcoin Core refuses to hand out a template, on signet without "signet"
+//! as well.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::Network;
+
+/// Rules the block assembly follows, a template may only require these
+pub const SUPPORTED_RULES: [&str; 4] = ["csv", "segwit", "signet", "taproot"];
+
+/// Parameters of a getblocktemplate call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TemplateRequest {
+    pub rules: Vec<String>,
+    /// Features of the client, the coinbase is always built locally
+    pub capabilities: Vec<String>,
+    /// longpollid of the last template, the node answers once it has a
+    /// newer one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longpollid: Option<String>,
+}
+
+impl Default for TemplateRequest {
+    fn default() -> Self {
+        Self {
+            rules: vec!["segwit".to_string()],
+            capabilities: vec!["coinbasevalue".to_string(), "longpoll".to_string()],
+            longpollid: None,
+        }
+    }
+}
+
+impl TemplateRequest {
+    /// Default request with the rules network needs, "signet" on signet
+    pub fn for_network(network: Network) -> Self {
+        let mut request = Self::default();
+        if network == Network::Signet {
+            request.rules.push("signet".to_string());
+        }
+        request
+    }
+}
+
+/// Body of a JSON-RPC 1.0 call, as bitcoind takes it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub id: String,
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+impl JsonRpcRequest {
+    pub fn new(id: &str, method: &str, params: serde_json::Value) -> Self {
+        Self {
+            jsonrpc: "1.0".to_string(),
+            id: id.to_string(),
+            method: method.to_string(),
+            params,
+        }
+    }
+
+    pub fn getblocktemplate(id: &str, request: &TemplateRequest) -> Self {
+        Self::new(id, "getblocktemplate", json!([request]))
+    }
+
+    pub fn submitblock(id: &str, block_hex: &str) -> Self {
+        Self::new(id, "submitblock", json!([block_hex]))
+    }
+
+    /// getblocktemplate in BIP 23 proposal mode, the node checks the block
+    /// without its proof of work
+    pub fn proposal(id: &str, block_hex: &str) -> Self {
+        let request = json!({"mode": "proposal", "data": block_hex});
+        Self::new(id, "getblocktemplate", json!([request]))
+    }
+
+    /// Signs and finalizes a base64 PSBT with the wallet of the node, with
+    /// SIGHASH_ALL as signets expect
+    pub fn walletprocesspsbt(id: &str, psbt: &str) -> Self {
+        Self::new(id, "walletprocesspsbt", json!([psbt, true, "ALL"]))
+    }
+}
+
+/// Fails on a rule marked with ! that isn't in SUPPORTED_RULES
+/// Rules without ! may be ignored, the block is valid either way.
+pub fn check_rules(rules: &[String]) -> Result<()> {
+    for rule in rules {
+        if let Some(required) = rule.strip_prefix('!') {
+            if !SUPPORTED_RULES.contains(&required) {
+                bail!("Template requires rule {required}, which this bridge doesn't support.");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_requests_ask_for_segwit() {
+        let body = JsonRpcRequest::getblocktemplate("1", &TemplateRequest::default());
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            json!({
+                "jsonrpc": "1.0",
+                "id": "1",
+                "method": "getblocktemplate",
+                "params": [{"rules": ["segwit"], "capabilities": ["coinbasevalue", "longpoll"]}]
+            })
+        );
+        let request = TemplateRequest {
+            longpollid: Some("tip1".to_string()),
+            ..Default::default()
+        };
+        let body = JsonRpcRequest::getblocktemplate("1", &request);
+        assert_eq!(body.params[0]["longpollid"], "tip1");
+
+        let body = JsonRpcRequest::submitblock("2", "00ff");
+        assert_eq!(body.params, json!(["00ff"]));
+        let body = JsonRpcRequest::proposal("3", "00ff");
+        assert_eq!(body.method, "getblocktemplate");
+        assert_eq!(body.params, json!([{"mode": "proposal", "data": "00ff"}]));
+    }
+
+    #[test]
+    fn unknown_required_rules_are_refused() {
+        let rules = |rules: &[&str]| {
+            rules
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(check_rules(&rules(&["csv", "!segwit", "taproot"])).is_ok());
+        assert!(check_rules(&rules(&["newfork"])).is_ok());
+        assert!(check_rules(&rules(&["!segwit", "!signet"])).is_ok());
+
+        let err = check_rules(&rules(&["!segwit", "!newfork"])).unwrap_err();
+        assert!(err.to_string().contains("newfork"));
+    }
+
+    #[test]
+    fn signet_requests_ask_for_signet() {
+        let request = TemplateRequest::for_network(Network::Signet);
+        assert_eq!(request.rules, ["segwit", "signet"]);
+        assert_eq!(
+            TemplateRequest::for_network(Network::Mainnet),
+            TemplateRequest::default()
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! Signing blocks for a signet (BIP 325).
+//!
+//! A signet only takes blocks signed for its challenge, the script
+//! getblocktemplate names in signet_challenge. What gets signed is to_sign,
+//! a virtual transaction spending an output of to_spend whose script is the
+//! challenge. to_spend commits to the version, previous block, merkle root
+//! and time of the unsigned block, so once signed only the nonce may roll.
+//! The solution, the scriptSig and witness of to_sign, is pushed after the
+//! witness commitment behind the header ecc7daa2.
+//!
+//! A wallet holding the keys of the challenge signs to_sign as a PSBT
+//! (BIP 174). OP_TRUE challenges take an empty solution.
+
+use std::ops::Range;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    client::{base64, decode_base64},
+    coinbase::write_compact_size,
+    merkle::sha256d,
+    transaction::Reader,
+};
+
+/// Starts the push of the solution in the witness commitment output
+pub const SIGNET_HEADER: [u8; 4] = [0xec, 0xc7, 0xda, 0xa2];
+
+// Challenge of signets anyone may mine on
+pub(crate) const OP_TRUE: u8 = 0x51;
+
+const OP_RETURN: u8 = 0x6a;
+
+const PSBT_MAGIC: [u8; 5] = *b"psbt\xff";
+
+// Key types of the PSBT maps
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_IN_NON_WITNESS_UTXO: u8 = 0x00;
+const PSBT_IN_SIGHASH_TYPE: u8 = 0x03;
+const PSBT_IN_FINAL_SCRIPTSIG: u8 = 0x07;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u8 = 0x08;
+
+const SIGHASH_ALL: u32 = 1;
+
+/// scriptSig and witness of to_sign that satisfy the challenge
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignetSolution {
+    pub script_sig: Vec<u8>,
+    pub witness: Vec<Vec<u8>>,
+}
+
+impl SignetSolution {
+    /// Takes the solution from the base64 PSBT a wallet signed and
+    /// finalized, see Block::signet_psbt
+    pub fn from_psbt(psbt: &str) -> Result<Self> {
+        let psbt = decode_base64(psbt).context("PSBT isn't base64.")?;
+        ensure!(psbt.starts_with(&PSBT_MAGIC), "Not a PSBT.");
+
+        let mut reader = Reader::new(&psbt[PSBT_MAGIC.len()..]);
+        read_map(&mut reader).context("Invalid PSBT.")?;
+        // to_sign has a single input
+        let input = read_map(&mut reader).context("Invalid PSBT.")?;
+        let find = |key_type: u8| {
+            input
+                .iter()
+                .find(|(key, _)| *key == [key_type])
+                .map(|(_, value)| *value)
+        };
+
+        let (script_sig, witness) = (
+            find(PSBT_IN_FINAL_SCRIPTSIG),
+            find(PSBT_IN_FINAL_SCRIPTWITNESS),
+        );
+        if script_sig.is_none() && witness.is_none() {
+            bail!("PSBT isn't finalized, the wallet couldn't sign for the challenge.");
+        }
+        let witness = match witness {
+            Some(witness) => {
+                let mut reader = Reader::new(witness);
+                let items = (0..reader.compact_size()?)
+                    .map(|_| {
+                        let len = reader.compact_size()?;
+                        reader.take(len).map(<[u8]>::to_vec)
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .context("Invalid final witness in the PSBT.")?;
+                ensure!(reader.is_empty(), "Invalid final witness in the PSBT.");
+                items
+            }
+            None => Vec::new(),
+        };
+        Ok(Self {
+            script_sig: script_sig.unwrap_or_default().to_vec(),
+            witness,
+        })
+    }
+
+    /// Serialization that follows the header: the scriptSig, then the
+    /// witness stack
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_compact_size(&mut bytes, self.script_sig.len() as u64);
+        bytes.extend_from_slice(&self.script_sig);
+        write_compact_size(&mut bytes, self.witness.len() as u64);
+        for item in &self.witness {
+            write_compact_size(&mut bytes, item.len() as u64);
+            bytes.extend_from_slice(item);
+        }
+        bytes
+    }
+}
+
+// Commitment script of an unsigned block, the header pushed without a
+// solution as the signature expects it
+pub(crate) fn with_header(commitment: &[u8]) -> Vec<u8> {
+    [commitment, &push(&SIGNET_HEADER)].concat()
+}
+
+// Base64 PSBT of to_sign for the unsigned block with header
+pub(crate) fn signing_psbt(header: &[u8; 80], challenge: &[u8]) -> String {
+    let to_spend = to_spend(header, challenge);
+    let mut psbt = PSBT_MAGIC.to_vec();
+    write_pair(&mut psbt, PSBT_GLOBAL_UNSIGNED_TX, &to_sign(&to_spend));
+    psbt.push(0);
+    write_pair(&mut psbt, PSBT_IN_NON_WITNESS_UTXO, &to_spend);
+    write_pair(&mut psbt, PSBT_IN_SIGHASH_TYPE, &SIGHASH_ALL.to_le_bytes());
+    psbt.push(0);
+    // to_sign's output needs nothing
+    psbt.push(0);
+    base64(&psbt)
+}
+
+// Coinbase raw with solution pushed in place of the bare header that ends
+// the commitment script, and where that script is now
+pub(crate) fn with_solution(
+    raw: &[u8],
+    commitment: Range<usize>,
+    solution: &SignetSolution,
+) -> Result<(Vec<u8>, Range<usize>)> {
+    let script = &raw[commitment.clone()];
+    let bare = push(&SIGNET_HEADER);
+    ensure!(
+        script.ends_with(&bare),
+        "Commitment doesn't end with the signet header, is the block signed already?"
+    );
+    let data = [&SIGNET_HEADER[..], &solution.to_bytes()].concat();
+    ensure!(
+        data.len() <= u16::MAX as usize,
+        "Signet solution of {} bytes is too long.",
+        data.len()
+    );
+    let signed = [&script[..script.len() - bare.len()], &push(&data)].concat();
+
+    let mut length = Vec::new();
+    write_compact_size(&mut length, script.len() as u64);
+    let mut coinbase = raw[..commitment.start - length.len()].to_vec();
+    write_compact_size(&mut coinbase, signed.len() as u64);
+    let start = coinbase.len();
+    coinbase.extend_from_slice(&signed);
+    let end = coinbase.len();
+    coinbase.extend_from_slice(&raw[commitment.end..]);
+    Ok((coinbase, start..end))
+}
+
+// Spends nothing, its scriptSig carries the header up to the time
+fn to_spend(header: &[u8; 80], challenge: &[u8]) -> Vec<u8> {
+    let script_sig = [&[0x00][..], &push(&header[..72])].concat();
+    transaction(&[0u8; 32], u32::MAX, &script_sig, challenge)
+}
+
+// Spends the challenge output, the transaction the solution signs
+fn to_sign(to_spend: &[u8]) -> Vec<u8> {
+    transaction(&sha256d(to_spend), 0, &[], &[OP_RETURN])
+}
+
+// Version 0 transaction with one input of sequence 0 and one output of no
+// value, lock time 0
+fn transaction(txid: &[u8; 32], index: u32, script_sig: &[u8], script_pubkey: &[u8]) -> Vec<u8> {
+    let mut tx = 0u32.to_le_bytes().to_vec();
+    write_compact_size(&mut tx, 1);
+    tx.extend_from_slice(txid);
+    tx.extend_from_slice(&index.to_le_bytes());
+    write_compact_size(&mut tx, script_sig.len() as u64);
+    tx.extend_from_slice(script_sig);
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    write_compact_size(&mut tx, 1);
+    tx.extend_from_slice(&0u64.to_le_bytes());
+    write_compact_size(&mut tx, script_pubkey.len() as u64);
+    tx.extend_from_slice(script_pubkey);
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx
+}
+
+// Smallest push of data, the way Bitcoin Core's CScript << data does it
+fn push(data: &[u8]) -> Vec<u8> {
+    let mut script = match data.len() {
+        0..=75 => vec![data.len() as u8],
+        76..=0xff => vec![0x4c, data.len() as u8],
+        _ => [&[0x4d][..], &(data.len() as u16).to_le_bytes()].concat(),
+    };
+    script.extend_from_slice(data);
+    script
+}
+
+fn write_pair(psbt: &mut Vec<u8>, key_type: u8, value: &[u8]) {
+    write_compact_size(psbt, 1);
+    psbt.push(key_type);
+    write_compact_size(psbt, value.len() as u64);
+    psbt.extend_from_slice(value);
+}
+
+// Key-value pairs of a PSBT map, up to its separator
+fn read_map<'a>(reader: &mut Reader<'a>) -> Result<Vec<(&'a [u8], &'a [u8])>> {
+    let mut map = Vec::new();
+    loop {
+        let key = reader.compact_size()?;
+        if key == 0 {
+            return Ok(map);
+        }
+        let key = reader.take(key)?;
+        let value = reader.compact_size()?;
+        map.push((key, reader.take(value)?));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{witness_commitment, Coinbase, Transaction};
+
+    #[test]
+    fn psbts_carry_the_block_and_the_solution() {
+        let mut header = [7u8; 80];
+        header[72..].copy_from_slice(&[0xff; 8]);
+        // 1-of-1 bare multisig, as custom signets use
+        let challenge = [&[0x51, 33][..], &[2; 33], &[0x51, 0xae]].concat();
+
+        let psbt = decode_base64(&signing_psbt(&header, &challenge)).unwrap();
+        assert!(psbt.starts_with(&PSBT_MAGIC));
+        let mut reader = Reader::new(&psbt[PSBT_MAGIC.len()..]);
+        let global = read_map(&mut reader).unwrap();
+        let input = read_map(&mut reader).unwrap();
+        assert!(read_map(&mut reader).unwrap().is_empty());
+        assert!(reader.is_empty());
+
+        // to_spend pushes the header without bits and nonce and pays to
+        // the challenge, to_sign spends it
+        let to_spend = input[0].1;
+        assert_eq!(input[0].0, [PSBT_IN_NON_WITNESS_UTXO]);
+        assert_eq!(to_spend[..5], [0, 0, 0, 0, 1]);
+        assert_eq!(to_spend[41..45], [74, 0x00, 72, 7]);
+        assert_eq!(to_spend[45..115], [7; 70]);
+        assert!(to_spend[..to_spend.len() - 4].ends_with(&challenge));
+        assert_eq!(input[1], (&[PSBT_IN_SIGHASH_TYPE][..], &[1, 0, 0, 0][..]));
+        let to_sign = global[0].1;
+        assert_eq!(to_sign[5..37], sha256d(to_spend));
+        assert_eq!(to_sign[37..42], [0, 0, 0, 0, 0]);
+
+        // Unsigned, there is no solution to take
+        assert!(SignetSolution::from_psbt(&base64(&psbt)).is_err());
+        let solution = SignetSolution {
+            script_sig: vec![0x00, 0x01, 0xaa],
+            witness: vec![vec![1, 2], Vec::new()],
+        };
+        assert_eq!(solution.to_bytes(), [3, 0, 1, 0xaa, 2, 2, 1, 2, 0]);
+        assert_eq!(SignetSolution::default().to_bytes(), [0, 0]);
+
+        let mut signed = PSBT_MAGIC.to_vec();
+        write_pair(&mut signed, PSBT_GLOBAL_UNSIGNED_TX, to_sign);
+        signed.push(0);
+        write_pair(&mut signed, PSBT_IN_FINAL_SCRIPTSIG, &solution.script_sig);
+        write_pair(&mut signed, PSBT_IN_FINAL_SCRIPTWITNESS, &[2, 2, 1, 2, 0]);
+        signed.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            SignetSolution::from_psbt(&base64(&signed)).unwrap(),
+            solution
+        );
+        assert!(SignetSolution::from_psbt("cHNidP8=").is_err());
+    }
+
+    #[test]
+    fn solutions_follow_the_commitment() {
+        let commitment = with_header(&witness_commitment(&[]));
+        let output = crate::CoinbaseOutput {
+            value: 50,
+            script_pubkey: vec![0x51],
+        };
+        let coinbase =
+            Coinbase::with_outputs(102, &[], &[output], &[0; 4], Some(&commitment)).unwrap();
+        let range = coinbase.commitment.clone().unwrap();
+        assert!(coinbase.raw[range.clone()].ends_with(&[4, 0xec, 0xc7, 0xda, 0xa2]));
+
+        let (raw, signed) =
+            with_solution(&coinbase.raw, range.clone(), &SignetSolution::default()).unwrap();
+        assert_eq!(raw[signed.start - 1] as usize, signed.len());
+        assert!(raw[signed.clone()].ends_with(&[6, 0xec, 0xc7, 0xda, 0xa2, 0, 0]));
+        assert!(Transaction::from_raw(raw.clone()).is_ok());
+        assert!(with_solution(&raw, signed, &SignetSolution::default()).is_err());
+
+        // Long solutions go in OP_PUSHDATA2, the script length grows a byte
+        let long = SignetSolution {
+            script_sig: Vec::new(),
+            witness: vec![vec![0xab; 300]],
+        };
+        let (raw, signed) = with_solution(&coinbase.raw, range, &long).unwrap();
+        assert_eq!(raw[signed.start - 3], 0xfd);
+        assert_eq!(
+            raw[signed.start + 38..signed.start + 41],
+            [0x4d, 0x35, 0x01]
+        );
+        assert!(raw[signed.clone()].ends_with(&long.to_bytes()));
+        assert!(Transaction::from_raw(raw).is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+//! SOCKS5 proxy transport (RFC 1928), e.g. Tor for a node on an onion
+//! address.
+//!
+//! Host names are passed to the proxy unresolved, so .onion addresses work
+//! and no DNS request leaves the rig. Tor isolates streams by the
+//! username and password (RFC 1929), any pair will do.
+
+use std::net::IpAddr;
+
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::Transport;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const CONNECT: u8 = 1;
+
+/// TCP to a node through a SOCKS5 proxy
+pub struct Socks5Transport {
+    proxy: String,
+    destination: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    // host:port for the Host header
+    authority: String,
+}
+
+impl Socks5Transport {
+    /// Reaches host:port through the proxy, e.g. "127.0.0.1:9050" for Tor
+    pub fn new(proxy: &str, host: &str, port: u16) -> Result<Self> {
+        ensure!(
+            !host.is_empty() && host.len() <= 255,
+            "SOCKS5 host names are 1 to 255 bytes."
+        );
+        Ok(Self {
+            proxy: proxy.to_string(),
+            destination: host.to_string(),
+            port,
+            credentials: None,
+            authority: format!("{host}:{port}"),
+        })
+    }
+
+    /// Getter for the proxy address
+    pub fn get_proxy(&self) -> &str {
+        &self.proxy
+    }
+
+    /// Authenticates to the proxy, each up to 255 bytes
+    pub fn set_credentials(&mut self, user: &str, password: &str) -> Result<()> {
+        ensure!(
+            (1..=255).contains(&user.len()) && (1..=255).contains(&password.len()),
+            "SOCKS5 user and password are 1 to 255 bytes."
+        );
+        self.credentials = Some((user.to_string(), password.to_string()));
+        Ok(())
+    }
+
+    // Method negotiation, authentication and the CONNECT request
+    async fn handshake(&self, stream: &mut TcpStream) -> Result<()> {
+        let methods: &[u8] = match self.credentials {
+            Some(_) => &[NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => &[NO_AUTHENTICATION],
+        };
+        stream
+            .write_all(&[&[VERSION, methods.len() as u8], methods].concat())
+            .await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        ensure!(choice[0] == VERSION, "Proxy doesn't speak SOCKS5.");
+        match (choice[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((user, password))) => {
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                ensure!(status[1] == 0, "Proxy refused the credentials.");
+            }
+            _ => bail!("Proxy accepts none of the authentication methods."),
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match self.destination.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                request.extend_from_slice(&[3, self.destination.len() as u8]);
+                request.extend_from_slice(self.destination.as_bytes());
+            }
+        }
+        request.extend_from_slice(&self.port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        ensure!(reply[0] == VERSION, "Proxy doesn't speak SOCKS5.");
+        if reply[1] != 0 {
+            bail!(
+                "Proxy couldn't reach {}: {}.",
+                self.authority,
+                reply_error(reply[1])
+            );
+        }
+        // The address the proxy bound, of no use here
+        let address = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            other => bail!("Proxy replied with address type {other}."),
+        };
+        let mut bound = vec![0u8; address + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for Socks5Transport {
+    type Stream = TcpStream;
+
+    async fn connect(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.proxy)
+            .await
+            .with_context(|| format!("Couldn't connect to the proxy {}.", self.proxy))?;
+        self.handshake(&mut stream).await?;
+        Ok(stream)
+    }
+
+    fn host(&self) -> &str {
+        &self.authority
+    }
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by the ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connections_go_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        // Accepts user:pw, then echoes to the client as if it were the node
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 9];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(auth, *b"\x01\x04user\x02pw");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut connect = [0u8; 5];
+            stream.read_exact(&mut connect).await.unwrap();
+            assert_eq!(connect, [5, 1, 0, 3, 11]);
+            let mut target = [0u8; 13];
+            stream.read_exact(&mut target).await.unwrap();
+            assert_eq!(target[..11], *b"node.onion.");
+            assert_eq!(target[11..], 8332u16.to_be_bytes());
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x20, 0x8c])
+                .await
+                .unwrap();
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            stream.write_all(&ping).await.unwrap();
+        });
+
+        let mut transport = Socks5Transport::new(&proxy, "node.onion.", 8332).unwrap();
+        assert!(transport.set_credentials("", "pw").is_err());
+        transport.set_credentials("user", "pw").unwrap();
+        assert_eq!(transport.host(), "node.onion.:8332");
+        let mut stream = transport.connect().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, *b"ping");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_failures_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            // The IP is sent as one
+            let mut connect = [0u8; 10];
+            stream.read_exact(&mut connect).await.unwrap();
+            assert_eq!(connect[3..8], [1, 10, 0, 0, 1]);
+            stream.write_all(&[5, 5, 0, 1]).await.unwrap();
+        });
+
+        let transport = Socks5Transport::new(&proxy, "10.0.0.1", 8332).unwrap();
+        let err = transport.connect().await.unwrap_err();
+        assert!(err.to_string().contains("connection refused"));
+        server.await.unwrap();
+        assert!(Socks5Transport::new(&proxy, "", 8332).is_err());
+    }
+}
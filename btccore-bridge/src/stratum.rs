@@ -0,0 +1,318 @@
+//! Work from a stratum pool (mining.notify).
+//!
+//! Pools hand out the coinbase in two halves, coinb1 and coinb2. The miner
+//! puts extranonce1, assigned by mining.subscribe, and an extranonce2 of its
+//! own between them, hashes the result and climbs the merkle branch to the
+//! root. Every extranonce2 gives a fresh header to mine.
+//!
+//! prevhash comes with the bytes of each 4 byte word swapped, version,
+//! nbits and ntime as big-endian hex. Branch hashes are in SHA256 order.
+
+use anyhow::{ensure, Context, Result};
+use serde_json::Value;
+
+use crate::{bits_to_target, decode_hex, merkle, strip_witness, Share};
+
+/// Extranonce1 of the session and the size of extranonce2, as
+/// mining.subscribe assigns them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extranonce {
+    pub extranonce1: Vec<u8>,
+    pub extranonce2_size: usize,
+}
+
+impl Extranonce {
+    /// Parses the result of mining.subscribe,
+    /// [subscriptions, extranonce1, extranonce2_size]
+    pub fn from_subscribe(result: &Value) -> Result<Self> {
+        Self::parse(result.get(1), result.get(2), "mining.subscribe result")
+    }
+
+    /// Parses the params of mining.set_extranonce, [extranonce1,
+    /// extranonce2_size]
+    pub fn from_set_extranonce(params: &Value) -> Result<Self> {
+        Self::parse(params.get(0), params.get(1), "mining.set_extranonce")
+    }
+
+    fn parse(extranonce1: Option<&Value>, size: Option<&Value>, source: &str) -> Result<Self> {
+        let extranonce1 = extranonce1
+            .and_then(Value::as_str)
+            .with_context(|| format!("{source} without extranonce1."))?;
+        let extranonce2_size = size
+            .and_then(Value::as_u64)
+            .with_context(|| format!("{source} without extranonce2_size."))?;
+        Ok(Self {
+            extranonce1: decode_hex(extranonce1).context("Invalid extranonce1.")?,
+            extranonce2_size: extranonce2_size as usize,
+        })
+    }
+
+    /// Extranonce2 for counter, little-endian in extranonce2_size
+    /// bytes. Fails once the counter doesn't fit.
+    pub fn extranonce2(&self, counter: u64) -> Result<Vec<u8>> {
+        let bytes = counter.to_le_bytes();
+        let used = 8 - counter.leading_zeros() as usize / 8;
+        ensure!(
+            used <= self.extranonce2_size,
+            "All extranonce2 of {} bytes are used.",
+            self.extranonce2_size
+        );
+        let mut extranonce2 = vec![0u8; self.extranonce2_size];
+        let len = self.extranonce2_size.min(8);
+        extranonce2[..len].copy_from_slice(&bytes[..len]);
+        Ok(extranonce2)
+    }
+}
+
+/// Job of a mining.notify
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StratumJob {
+    job_id: String,
+    // In header order, unswapped
+    prevhash: [u8; 32],
+    coinb1: Vec<u8>,
+    coinb2: Vec<u8>,
+    merkle_branch: Vec<[u8; 32]>,
+    version: u32,
+    bits: u32,
+    ntime: u32,
+    clean_jobs: bool,
+}
+
+/// Header to mine for a job, with the extranonce2 it was built with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StratumWork {
+    pub job_id: String,
+    pub extranonce2: Vec<u8>,
+    /// 80 bytes with a zero nonce
+    pub header: [u8; 80],
+}
+
+impl StratumWork {
+    /// Share for header, the solved header of this work
+    /// ntime and nonce are read from the header, little-endian as serialized,
+    /// and the version bits within version_mask if the pool allows rolling.
+    pub fn share(&self, header: &[u8; 80], version_mask: Option<u32>) -> Share {
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        Share {
+            job_id: self.job_id.clone(),
+            extranonce2: self.extranonce2.clone(),
+            ntime: field(68),
+            nonce: field(76),
+            version_bits: version_mask.map(|mask| field(0) & mask),
+        }
+    }
+}
+
+impl StratumJob {
+    /// Parses the params of mining.notify: job_id, prevhash, coinb1,
+    /// coinb2, merkle_branch, version, nbits, ntime, clean_jobs
+    pub fn from_notify(params: &Value) -> Result<Self> {
+        let field = |index: usize, name: &str| {
+            params
+                .get(index)
+                .with_context(|| format!("mining.notify without {name}."))
+        };
+        let hex = |index: usize, name: &str| {
+            let value = field(index, name)?
+                .as_str()
+                .with_context(|| format!("mining.notify {name} isn't a string."))?;
+            decode_hex(value).with_context(|| format!("Invalid mining.notify {name}."))
+        };
+        let word = |index: usize, name: &str| -> Result<u32> {
+            let bytes: [u8; 4] = hex(index, name)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("mining.notify {name} isn't 4 bytes."))?;
+            Ok(u32::from_be_bytes(bytes))
+        };
+
+        let job_id = field(0, "job_id")?
+            .as_str()
+            .context("mining.notify job_id isn't a string.")?
+            .to_string();
+        let swapped: [u8; 32] = hex(1, "prevhash")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("mining.notify prevhash isn't 32 bytes."))?;
+        let mut prevhash = [0u8; 32];
+        for (word, swapped) in prevhash.chunks_mut(4).zip(swapped.chunks(4)) {
+            word.copy_from_slice(swapped);
+            word.reverse();
+        }
+        let merkle_branch = field(4, "merkle_branch")?
+            .as_array()
+            .context("mining.notify merkle_branch isn't a list.")?
+            .iter()
+            .map(|hash| {
+                hash.as_str()
+                    .and_then(|hash| decode_hex(hash).ok())
+                    .and_then(|hash| hash.try_into().ok())
+                    .context("Invalid mining.notify merkle_branch hash.")
+            })
+            .collect::<Result<Vec<[u8; 32]>>>()?;
+
+        Ok(Self {
+            job_id,
+            prevhash,
+            coinb1: hex(2, "coinb1")?,
+            coinb2: hex(3, "coinb2")?,
+            merkle_branch,
+            version: word(5, "version")?,
+            bits: word(6, "nbits")?,
+            ntime: word(7, "ntime")?,
+            clean_jobs: field(8, "clean_jobs")?.as_bool().unwrap_or(false),
+        })
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    /// Hash of the block the job builds on, in header order
+    pub fn prevhash(&self) -> &[u8; 32] {
+        &self.prevhash
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn ntime(&self) -> u32 {
+        self.ntime
+    }
+
+    /// True if work on earlier jobs is worthless, usually a new block
+    pub fn clean_jobs(&self) -> bool {
+        self.clean_jobs
+    }
+
+    /// Big-endian target of a block, what nbits expands to
+    pub fn target(&self) -> Result<[u8; 32]> {
+        bits_to_target(self.bits)
+    }
+
+    /// Coinbase with extranonce1 and extranonce2 between the halves
+    pub fn coinbase(&self, extranonce: &Extranonce, extranonce2: &[u8]) -> Result<Vec<u8>> {
+        ensure!(
+            extranonce2.len() == extranonce.extranonce2_size,
+            "Extranonce2 of {} bytes, the pool asked for {}.",
+            extranonce2.len(),
+            extranonce.extranonce2_size
+        );
+        Ok([
+            &self.coinb1[..],
+            &extranonce.extranonce1,
+            extranonce2,
+            &self.coinb2,
+        ]
+        .concat())
+    }
+
+    /// Header of the job for extranonce2, with the merkle root of its
+    /// coinbase and a zero nonce
+    pub fn work(&self, extranonce: &Extranonce, extranonce2: &[u8]) -> Result<StratumWork> {
+        let coinbase = self.coinbase(extranonce, extranonce2)?;
+        // Pools send the coinbase without witness, but the txid never has it
+        let txid = merkle::sha256d(&strip_witness(&coinbase)?);
+        let root = merkle::root_from_branch(&txid, &self.merkle_branch);
+
+        let mut header = [0u8; 80];
+        header[..4].copy_from_slice(&self.version.to_le_bytes());
+        header[4..36].copy_from_slice(&self.prevhash);
+        header[36..68].copy_from_slice(&root);
+        header[68..72].copy_from_slice(&self.ntime.to_le_bytes());
+        header[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        Ok(StratumWork {
+            job_id: self.job_id.clone(),
+            extranonce2: extranonce2.to_vec(),
+            header,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Block 1 as a pool would hand it out, its scriptSig 04ffff001d0104
+    // split into extranonce1 ffff001d and extranonce2 0104
+    fn block_1() -> Value {
+        json!([
+            "b1",
+            "0a8ce26f72b3f1b646a2a6c14ff763ae65831e939c085ae10019d66800000000",
+            "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704",
+            "ffffffff0100f2052a0100000043410496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be63c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000",
+            [],
+            "00000001",
+            "1d00ffff",
+            "4966bc61",
+            true
+        ])
+    }
+
+    #[test]
+    fn jobs_rebuild_the_header_of_the_block() {
+        let extranonce =
+            Extranonce::from_subscribe(&json!([[["mining.notify", "ae6812eb"]], "ffff001d", 2]))
+                .unwrap();
+        assert_eq!(extranonce.extranonce1, [0xff, 0xff, 0x00, 0x1d]);
+        assert_eq!(extranonce.extranonce2(0x0401).unwrap(), [0x01, 0x04]);
+        assert!(extranonce.extranonce2(0x1_0000).is_err());
+        assert_eq!(
+            Extranonce::from_set_extranonce(&json!(["ffff001d", 2])).unwrap(),
+            extranonce
+        );
+        assert!(Extranonce::from_set_extranonce(&json!(["ffff001d"])).is_err());
+
+        let job = StratumJob::from_notify(&block_1()).unwrap();
+        assert_eq!(job.job_id(), "b1");
+        assert!(job.clean_jobs());
+        assert_eq!(job.target().unwrap()[4..6], [0xff, 0xff]);
+        let work = job.work(&extranonce, &[0x01, 0x04]).unwrap();
+        assert_eq!(work.extranonce2, [0x01, 0x04]);
+        assert!(job.work(&extranonce, &[0x01]).is_err());
+
+        let mut header = work.header;
+        header[76..].copy_from_slice(&2573394689u32.to_le_bytes());
+        assert_eq!(
+            crate::encode_hex(&header),
+            "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051\
+             fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299"
+        );
+
+        // mining.submit sends the nonce as the hex of the integer
+        let share = work.share(&header, None);
+        assert_eq!(format!("{:08x}", share.nonce), "9962e301");
+        assert_eq!(share.ntime, job.ntime());
+        assert_eq!(share.extranonce2, [0x01, 0x04]);
+        assert_eq!(share.version_bits, None);
+        assert_eq!(work.share(&header, Some(0x1fff_e000)).version_bits, Some(0));
+    }
+
+    #[test]
+    fn branches_lead_to_the_root() {
+        let mut params = block_1();
+        let sibling = "11".repeat(32);
+        params[4] = json!([sibling]);
+        let job = StratumJob::from_notify(&params).unwrap();
+        let extranonce = Extranonce {
+            extranonce1: vec![0xff, 0xff, 0x00, 0x1d],
+            extranonce2_size: 2,
+        };
+        let coinbase = job.coinbase(&extranonce, &[0x01, 0x04]).unwrap();
+        let txid = merkle::sha256d(&coinbase);
+        let root = merkle::merkle_root(&[txid, [0x11; 32]]);
+        assert_eq!(
+            job.work(&extranonce, &[0x01, 0x04]).unwrap().header[36..68],
+            root
+        );
+
+        params[4] = json!(["11"]);
+        assert!(StratumJob::from_notify(&params).is_err());
+        assert!(StratumJob::from_notify(&json!(["b1"])).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! ZeroMQ receiver for the blocks bitcoind announces.
+//!
+//! With -zmqpubhashblock=tcp://127.0.0.1:28332 bitcoind publishes three
+//! part messages for every new tip: the topic "hashblock", the 32 byte
+//! block hash in display order and a little-endian sequence number.
+//! ZeroMQ reconnects on its own while the node restarts, the socket is
+//! only rebuilt if it fails outright.
+//!
+//! Fees pile up between tips, so the subscriber can also follow hashtx
+//! (-zmqpubhashtx) and ask for a new template once enough transactions
+//! entered the mempool. Every transaction there pays a fee, the hashes
+//! alone can't tell how much without looking up the inputs.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use async_trait::async_trait;
+use async_zmq::{zmq, StreamExt, Subscribe};
+use tokio::sync::Mutex;
+
+use crate::{BlockHash, BridgeEvent, ZmqReceiver};
+
+/// Topic of the block announcements
+pub const HASHBLOCK_TOPIC: &str = "hashblock";
+
+/// Topic of the transaction announcements
+pub const HASHTX_TOPIC: &str = "hashtx";
+
+// Default wait before rebuilding a failed socket, also the longest
+// ZeroMQ waits between its own reconnects
+const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When new transactions call for rebuilding the template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TemplateRefresh {
+    /// Transactions announced since the last template
+    pub min_transactions: u32,
+    /// Shortest time between two rebuilds
+    pub min_interval: Duration,
+}
+
+/// Subscription to the hashblock topic of bitcoind
+pub struct ZmqSubscriber {
+    endpoint: String,
+    reconnect_interval: Duration,
+    refresh: Option<TemplateRefresh>,
+    state: Mutex<State>,
+}
+
+// What recv keeps between calls
+struct State {
+    // None after a failure until the next recv reconnects
+    socket: Option<Subscribe>,
+    transactions: u32,
+    last_template: Instant,
+}
+
+// Parsed message of one of the topics
+#[derive(Debug, PartialEq, Eq)]
+enum Announcement {
+    Block([u8; 32]),
+    Transaction,
+}
+
+impl ZmqSubscriber {
+    /// Connects to endpoint, e.g. "tcp://127.0.0.1:28332"
+    /// The node doesn't need to be up yet.
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let url = url::Url::parse(endpoint).context("Invalid ZMQ endpoint.")?;
+        ensure!(
+            matches!(url.scheme(), "tcp" | "ipc" | "inproc"),
+            "ZMQ endpoint {endpoint} should be tcp, ipc or inproc."
+        );
+        let socket = connect(endpoint, DEFAULT_RECONNECT_INTERVAL, false)?;
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            reconnect_interval: DEFAULT_RECONNECT_INTERVAL,
+            refresh: None,
+            state: Mutex::new(State {
+                socket: Some(socket),
+                transactions: 0,
+                last_template: Instant::now(),
+            }),
+        })
+    }
+
+    /// Getter for the endpoint
+    pub fn get_endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Getter for the wait before reconnecting
+    pub fn get_reconnect_interval(&self) -> Duration {
+        self.reconnect_interval
+    }
+
+    /// Sets how long to wait before rebuilding a failed socket, takes
+    /// effect on the next connection
+    pub fn set_reconnect_interval(&mut self, interval: Duration) {
+        self.reconnect_interval = interval;
+    }
+
+    /// Getter for the transaction based rebuilds
+    pub fn get_template_refresh(&self) -> Option<TemplateRefresh> {
+        self.refresh
+    }
+
+    /// Also subscribes to hashtx, recv then returns TemplateExpired once
+    /// refresh says a new template is worth it. The node needs
+    /// -zmqpubhashtx on the same endpoint.
+    pub fn set_template_refresh(&mut self, refresh: Option<TemplateRefresh>) -> Result<()> {
+        if let Some(socket) = &self.state.get_mut().socket {
+            let socket = socket.as_raw_socket();
+            match refresh {
+                Some(_) => socket.set_subscribe(HASHTX_TOPIC.as_bytes())?,
+                None => socket.set_unsubscribe(HASHTX_TOPIC.as_bytes())?,
+            }
+        }
+        self.refresh = refresh;
+        Ok(())
+    }
+}
+
+// SUB socket on the hashblock topic, and hashtx if transactions is set
+fn connect(endpoint: &str, reconnect_interval: Duration, transactions: bool) -> Result<Subscribe> {
+    let socket = zmq::Context::new()
+        .socket(zmq::SUB)
+        .context("Couldn't create ZMQ socket.")?;
+    let interval = reconnect_interval.as_millis().min(i32::MAX as u128) as i32;
+    socket.set_reconnect_ivl_max(interval)?;
+    socket.set_tcp_keepalive(1)?;
+    socket
+        .connect(endpoint)
+        .with_context(|| format!("Couldn't connect to {endpoint}."))?;
+    socket.set_subscribe(HASHBLOCK_TOPIC.as_bytes())?;
+    if transactions {
+        socket.set_subscribe(HASHTX_TOPIC.as_bytes())?;
+    }
+    Ok(Subscribe::from(socket))
+}
+
+#[async_trait]
+impl ZmqReceiver for ZmqSubscriber {
+    /// Waits for the next tip, or for enough transactions to expire the
+    /// template
+    async fn recv(&self) -> Result<BridgeEvent> {
+        let mut guard = self.state.lock().await;
+        let state = &mut *guard;
+        loop {
+            // Enough transactions came in while the last rebuild was too recent
+            let due = self
+                .refresh
+                .filter(|refresh| state.transactions >= refresh.min_transactions.max(1))
+                .map(|refresh| state.last_template + refresh.min_interval);
+            if due.is_some_and(|due| due <= Instant::now()) {
+                state.transactions = 0;
+                state.last_template = Instant::now();
+                return Ok(BridgeEvent::TemplateExpired);
+            }
+
+            let Some(subscribe) = state.socket.as_mut() else {
+                tokio::time::sleep(self.reconnect_interval).await;
+                state.socket = connect(
+                    &self.endpoint,
+                    self.reconnect_interval,
+                    self.refresh.is_some(),
+                )
+                .ok();
+                continue;
+            };
+
+            let next = match due {
+                Some(due) => match tokio::time::timeout_at(due.into(), subscribe.next()).await {
+                    Ok(next) => next,
+                    Err(_) => continue,
+                },
+                None => subscribe.next().await,
+            };
+            match next {
+                Some(Ok(parts)) => {
+                    let parts: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+                    match parse_announcement(&parts)? {
+                        Some(Announcement::Block(hash)) => {
+                            state.transactions = 0;
+                            state.last_template = Instant::now();
+                            return Ok(BridgeEvent::NewTip(BlockHash(hash)));
+                        }
+                        Some(Announcement::Transaction) => state.transactions += 1,
+                        None => {}
+                    }
+                }
+                // Dropping the socket reconnects on the next pass
+                Some(Err(_)) | None => state.socket = None,
+            }
+        }
+    }
+}
+
+// Hashblock or hashtx message, None for other topics that share the prefix
+fn parse_announcement(parts: &[&[u8]]) -> Result<Option<Announcement>> {
+    let Some(&topic) = parts.first() else {
+        anyhow::bail!("Empty ZMQ message.");
+    };
+    let topic = match topic {
+        b"hashblock" => HASHBLOCK_TOPIC,
+        b"hashtx" => HASHTX_TOPIC,
+        _ => return Ok(None),
+    };
+    ensure!(
+        parts.len() == 3,
+        "{topic} message of {} parts, expected 3.",
+        parts.len()
+    );
+    let hash = parts[1]
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("{topic} hash of {} bytes.", parts[1].len()))?;
+    Ok(Some(match topic {
+        HASHBLOCK_TOPIC => Announcement::Block(hash),
+        _ => Announcement::Transaction,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn hashblock_messages_are_validated() {
+        let hash = [0xab; 32];
+        let sequence = 7u32.to_le_bytes();
+        assert_eq!(
+            parse_announcement(&[b"hashblock", &hash, &sequence]).unwrap(),
+            Some(Announcement::Block(hash))
+        );
+        assert_eq!(
+            parse_announcement(&[b"hashtx", &hash, &sequence]).unwrap(),
+            Some(Announcement::Transaction)
+        );
+        assert_eq!(
+            parse_announcement(&[b"hashblockx", &hash, &sequence]).unwrap(),
+            None
+        );
+        assert!(parse_announcement(&[b"hashblock", &hash[..31], &sequence]).is_err());
+        assert!(parse_announcement(&[b"hashtx", &hash]).is_err());
+        assert!(parse_announcement(&[]).is_err());
+
+        assert!(ZmqSubscriber::new("http://127.0.0.1:28332").is_err());
+        assert!(ZmqSubscriber::new("not an endpoint").is_err());
+    }
+
+    #[tokio::test]
+    async fn blocks_published_by_the_node_are_received() {
+        let endpoint = "tcp://127.0.0.1:28391";
+        let publisher = zmq::Context::new().socket(zmq::PUB).unwrap();
+        publisher.bind(endpoint).unwrap();
+        let subscriber = ZmqSubscriber::new(endpoint).unwrap();
+        assert_eq!(subscriber.get_endpoint(), endpoint);
+
+        // Subscriptions take a moment to reach the publisher
+        let hash = [0x5f; 32];
+        let publish = tokio::spawn(async move {
+            loop {
+                publisher
+                    .send_multipart(
+                        [&b"hashblock"[..], &hash, &1u32.to_le_bytes()],
+                        zmq::DONTWAIT,
+                    )
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let received = tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(received, BridgeEvent::NewTip(BlockHash(tip)) if tip == hash));
+        publish.abort();
+    }
+
+    #[tokio::test]
+    async fn enough_transactions_ask_for_a_new_template() {
+        let endpoint = "tcp://127.0.0.1:28392";
+        let publisher = zmq::Context::new().socket(zmq::PUB).unwrap();
+        publisher.bind(endpoint).unwrap();
+        let mut subscriber = ZmqSubscriber::new(endpoint).unwrap();
+        let refresh = TemplateRefresh {
+            min_transactions: 3,
+            min_interval: Duration::from_millis(200),
+        };
+        subscriber.set_template_refresh(Some(refresh)).unwrap();
+        assert_eq!(subscriber.get_template_refresh(), Some(refresh));
+
+        // The block until it got through, then only transactions
+        let block = [0x5f; 32];
+        let announced = Arc::new(AtomicBool::new(false));
+        let publish = tokio::spawn({
+            let announced = announced.clone();
+            async move {
+                for sent in 0u32.. {
+                    let (topic, hash) = match announced.load(Ordering::Relaxed) {
+                        false => (&b"hashblock"[..], block),
+                        true => (&b"hashtx"[..], [sent as u8; 32]),
+                    };
+                    publisher
+                        .send_multipart([topic, &hash, &sent.to_le_bytes()], zmq::DONTWAIT)
+                        .unwrap();
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        });
+
+        let recv = || tokio::time::timeout(Duration::from_secs(5), subscriber.recv());
+        let first = recv().await.unwrap().unwrap();
+        assert!(matches!(first, BridgeEvent::NewTip(BlockHash(tip)) if tip == block));
+        announced.store(true, Ordering::Relaxed);
+        // Transactions keep coming, the next rebuild waits out the interval
+        let start = Instant::now();
+        let again = recv().await.unwrap().unwrap();
+        assert!(matches!(again, BridgeEvent::TemplateExpired));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        publish.abort();
+    }
+}
@@ -0,0 +1,310 @@
+//! Transactions of a block template.
+//!
+//! getblocktemplate lists every transaction with its serialization, txid,
+//! wtxid and the fee, weight and sigops it adds. The ids come in display
+//! order and are kept in SHA256 byte order, like merkle roots need them.
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{decode_hex, encode_hex, merkle::sha256d};
+
+// Weight units per byte of the serialization without witness
+const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// Transaction of a block with its ids and what it costs the block
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "TemplateTransaction", into = "TemplateTransaction")]
+pub struct Transaction {
+    raw: Vec<u8>,
+    txid: [u8; 32],
+    wtxid: [u8; 32],
+    fee: u64,
+    weight: u64,
+    sigops: u64,
+    depends: Vec<usize>,
+}
+
+impl Transaction {
+    /// Transaction built locally, such as the coinbase
+    /// Ids and weight come from the bytes, fee and sigops are zero.
+    pub fn from_raw(raw: Vec<u8>) -> Result<Self> {
+        let stripped = strip_witness(&raw)?;
+        Ok(Self {
+            txid: sha256d(&stripped),
+            wtxid: sha256d(&raw),
+            // Witness bytes count once, the rest four times
+            weight: stripped.len() as u64 * (WITNESS_SCALE_FACTOR - 1) + raw.len() as u64,
+            raw,
+            fee: 0,
+            sigops: 0,
+            depends: Vec::new(),
+        })
+    }
+
+    /// Consensus serialization, with the witness if it has one
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Hash without the witness, in SHA256 byte order
+    pub fn txid(&self) -> &[u8; 32] {
+        &self.txid
+    }
+
+    /// Hash with the witness, in SHA256 byte order
+    pub fn wtxid(&self) -> &[u8; 32] {
+        &self.wtxid
+    }
+
+    /// Fee in satoshis
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn weight(&self) -> u64 {
+        self.weight
+    }
+
+    pub fn sigops(&self) -> u64 {
+        self.sigops
+    }
+
+    /// Positions in the template, counting from 1, of the transactions
+    /// this one spends outputs of
+    pub fn depends(&self) -> &[usize] {
+        &self.depends
+    }
+
+    pub fn has_witness(&self) -> bool {
+        has_witness(&self.raw)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_depends(self, depends: Vec<usize>) -> Self {
+        Self { depends, ..self }
+    }
+}
+
+// Transaction object of getblocktemplate
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct TemplateTransaction {
+    data: String,
+    txid: String,
+    hash: String,
+    #[serde(default)]
+    depends: Vec<usize>,
+    #[serde(default)]
+    fee: u64,
+    #[serde(default)]
+    sigops: u64,
+    #[serde(default)]
+    weight: u64,
+}
+
+impl TryFrom<TemplateTransaction> for Transaction {
+    type Error = anyhow::Error;
+
+    fn try_from(tx: TemplateTransaction) -> Result<Self> {
+        let raw = decode_hex(&tx.data).context("Invalid transaction data.")?;
+        let computed = Transaction::from_raw(raw)?;
+        ensure!(
+            display_id(&tx.txid)? == computed.txid,
+            "Transaction data doesn't hash to txid {}",
+            tx.txid
+        );
+        ensure!(
+            display_id(&tx.hash)? == computed.wtxid,
+            "Transaction data doesn't hash to wtxid {}",
+            tx.hash
+        );
+        Ok(Self {
+            fee: tx.fee,
+            sigops: tx.sigops,
+            depends: tx.depends,
+            // Older nodes leave weight out
+            weight: if tx.weight == 0 {
+                computed.weight
+            } else {
+                tx.weight
+            },
+            ..computed
+        })
+    }
+}
+
+impl From<Transaction> for TemplateTransaction {
+    fn from(tx: Transaction) -> Self {
+        let display = |id: &[u8; 32]| {
+            let mut id = *id;
+            id.reverse();
+            encode_hex(&id)
+        };
+        Self {
+            data: encode_hex(&tx.raw),
+            txid: display(&tx.txid),
+            hash: display(&tx.wtxid),
+            depends: tx.depends,
+            fee: tx.fee,
+            sigops: tx.sigops,
+            weight: tx.weight,
+        }
+    }
+}
+
+// Parses an id in display order into SHA256 byte order
+fn display_id(hex: &str) -> Result<[u8; 32]> {
+    let mut id: [u8; 32] = decode_hex(hex)?
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow::anyhow!("Id of {} bytes: {hex}", bytes.len()))?;
+    id.reverse();
+    Ok(id)
+}
+
+/// Serialization of a transaction without its witness, which its txid
+/// hashes. Transactions without witness come back unchanged.
+pub fn strip_witness(raw: &[u8]) -> Result<Vec<u8>> {
+    if !has_witness(raw) {
+        return Ok(raw.to_vec());
+    }
+
+    let mut reader = Reader::new(raw);
+    reader.skip(6)?;
+    let inputs_start = reader.pos;
+    let inputs = reader.compact_size()?;
+    for _ in 0..inputs {
+        reader.skip(36)?;
+        let script = reader.compact_size()?;
+        reader.skip(script + 4)?;
+    }
+    let outputs = reader.compact_size()?;
+    for _ in 0..outputs {
+        reader.skip(8)?;
+        let script = reader.compact_size()?;
+        reader.skip(script)?;
+    }
+    let outputs_end = reader.pos;
+    for _ in 0..inputs {
+        for _ in 0..reader.compact_size()? {
+            let item = reader.compact_size()?;
+            reader.skip(item)?;
+        }
+    }
+    reader.skip(4)?;
+    ensure!(reader.pos == raw.len(), "Transaction has trailing bytes");
+
+    Ok([
+        &raw[..4],
+        &raw[inputs_start..outputs_end],
+        &raw[raw.len() - 4..],
+    ]
+    .concat())
+}
+
+// Marker 0 where the input count would be, no valid transaction without
+// witness has zero inputs
+fn has_witness(raw: &[u8]) -> bool {
+    raw.len() >= 6 && raw[4..6] == [0x00, 0x01]
+}
+
+// Walks a serialized transaction, or anything else of CompactSize
+// prefixed parts
+pub(crate) struct Reader<'a> {
+    raw: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(raw: &'a [u8]) -> Self {
+        Self { raw, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: u64) -> Result<&'a [u8]> {
+        let start = self.pos;
+        self.skip(len)?;
+        Ok(&self.raw[start..self.pos])
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.raw.len()
+    }
+
+    pub(crate) fn skip(&mut self, len: u64) -> Result<()> {
+        let end = self.pos.saturating_add(len as usize);
+        ensure!(end <= self.raw.len(), "Transaction ends early");
+        self.pos = end;
+        Ok(())
+    }
+
+    pub(crate) fn compact_size(&mut self) -> Result<u64> {
+        let start = self.pos;
+        self.skip(1)?;
+        let len = match self.raw[start] {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            n => return Ok(n as u64),
+        };
+        self.skip(len)?;
+        let mut bytes = [0u8; 8];
+        bytes[..len as usize].copy_from_slice(&self.raw[start + 1..self.pos]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{witness_commitment, Coinbase};
+
+    #[test]
+    fn witnesses_are_stripped_for_the_txid() {
+        let script_pubkey = [0x51];
+        let commitment = witness_commitment(&[]);
+        let segwit = Coinbase::new(500, 1, &script_pubkey, &[7; 4], Some(&commitment)).unwrap();
+        let legacy = Coinbase::new(500, 1, &script_pubkey, &[7; 4], None).unwrap();
+
+        let stripped = strip_witness(&segwit.raw).unwrap();
+        assert_eq!(sha256d(&stripped), segwit.txid);
+        assert_eq!(strip_witness(&legacy.raw).unwrap(), legacy.raw);
+        assert!(strip_witness(&segwit.raw[..segwit.raw.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn template_transactions_are_checked_against_their_ids() {
+        let commitment = witness_commitment(&[]);
+        let coinbase = Coinbase::new(500, 1, &[0x51], &[7; 4], Some(&commitment)).unwrap();
+        let tx = Transaction::from_raw(coinbase.raw.clone()).unwrap();
+        assert_eq!(tx.txid(), &coinbase.txid);
+        assert!(tx.has_witness());
+        assert_eq!(
+            tx.weight(),
+            (coinbase.raw.len() as u64 - 36) * 3 + coinbase.raw.len() as u64
+        );
+
+        let mut txid = coinbase.txid;
+        txid.reverse();
+        let mut wtxid = sha256d(&coinbase.raw);
+        wtxid.reverse();
+        let json = format!(
+            r#"{{"data":"{}","txid":"{}","hash":"{}","depends":[1],"fee":1234,"sigops":4,"weight":561}}"#,
+            encode_hex(&coinbase.raw),
+            encode_hex(&txid),
+            encode_hex(&wtxid)
+        );
+        let parsed: Transaction = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            (parsed.fee(), parsed.sigops(), parsed.weight()),
+            (1234, 4, 561)
+        );
+        assert_eq!(parsed.depends(), [1]);
+        assert_eq!(parsed.raw(), coinbase.raw);
+        let round_trip: Transaction =
+            serde_json::from_str(&serde_json::to_string(&parsed).unwrap()).unwrap();
+        assert_eq!(round_trip, parsed);
+
+        // Ids that don't match the data are rejected
+        let swapped = json.replace(&encode_hex(&txid), &encode_hex(&wtxid));
+        assert!(serde_json::from_str::<Transaction>(&swapped).is_err());
+    }
+}
@@ -1,77 +1,442 @@
 use std::{
     io::{self, Write},
-    time::Instant,
+    path::PathBuf,
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
+use btccore_bridge::{Network, StratumClient, StratumEvent, TcpTransport};
 use chrono::{TimeZone, Utc};
+use clap::Parser;
 
-use wgpu_sha256_miner::{hash_with_nonce, sha256_parse_words, sha256_preprocess, GpuMiner};
+use wgpu_sha256_miner::{
+    check_pow_target, difficulty_to_target, parse_backends, Backends, CpuMiner, GpuMiner,
+    HeaderWords, Miner, MinerBackend, MinerStats, RunOptions, RunOutcome, Solution, Throttle,
+    VanityPattern, Winner, VERSION_ROLLING_MASK,
+};
+
+/// GPU-accelerated Bitcoin miner
+#[derive(Debug, Parser)]
+struct Args {
+    /// Network to mine for (mainnet, testnet, signet or regtest), sets its easiest target
+    #[arg(long, default_value = "mainnet", value_parser = parse_network)]
+    network: Network,
+
+    /// wgpu backends to use, comma separated (vulkan, dx12, metal, gl or all)
+    #[arg(long, default_value = "all", value_parser = parse_backends)]
+    backend: Backends,
+
+    /// Directory to keep compiled shaders in between runs (Vulkan only)
+    #[arg(long)]
+    pipeline_cache: Option<PathBuf>,
+
+    /// Directory with sha256.wgsl and mine.wgsl to use instead of the built-in shaders
+    #[arg(long)]
+    shader_dir: Option<PathBuf>,
+
+    /// Fraction of the time the GPU may be busy, e.g. 0.5 to leave room for the desktop
+    #[arg(long)]
+    duty_cycle: Option<f64>,
+
+    /// Check the GPU kernel against known blocks and exit
+    #[arg(long)]
+    self_test: bool,
+
+    /// Time this many batches at every workgroup size and exit
+    #[arg(long)]
+    bench: Option<u32>,
+
+    /// Mine on the CPU instead of failing when no GPU can be set up
+    #[arg(long)]
+    cpu_fallback: bool,
+
+    /// Stop the run after this many hashes
+    #[arg(long)]
+    max_hashes: Option<u64>,
+
+    /// Stop the run after this many seconds
+    #[arg(long)]
+    max_seconds: Option<u64>,
+
+    /// Roll the 16 BIP 320 version bits before the timestamp, or those a pool allows
+    #[arg(long)]
+    version_rolling: bool,
+
+    /// Compute passes submitted together per batch, more can help fast cards
+    #[arg(long, default_value_t = 1)]
+    passes_per_batch: u32,
+
+    /// Seconds a batch may take before the GPU is rebuilt as hung, 0 to wait forever
+    #[arg(long, default_value_t = 30)]
+    batch_timeout: u64,
+
+    /// Only accept hashes that start with these hex digits, e.g. 0000dead
+    #[arg(long, value_parser = VanityPattern::from_hex_prefix)]
+    vanity: Option<VanityPattern>,
+
+    /// Mine shares for the stratum pool at host:port instead
+    #[arg(long)]
+    pool: Option<String>,
+
+    /// Worker name to authorize at the pool
+    #[arg(long, default_value = "harvester")]
+    user: String,
+
+    /// Password of the worker, most pools ignore it
+    #[arg(long, default_value = "x")]
+    password: String,
+
+    /// Follow NiceHash's stratum rules: extranonce changes, difficulty from the next job on
+    #[arg(long)]
+    nicehash: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+
     let header_bytes = [0u8; 80];
 
-    // Add padding to reach 128 bytes
-    let padded = sha256_preprocess(&header_bytes);
-    let mut words = sha256_parse_words(&padded);
+    // Padded to two SHA256 blocks
+    let words = HeaderWords::from_bytes(&header_bytes);
 
-    let mut miner = GpuMiner::new(None).await.context("Miner creation failed")?;
+    let mut builder = GpuMiner::builder()
+        .backends(args.backend)
+        .passes_per_batch(args.passes_per_batch);
+    if let Some(dir) = args.pipeline_cache.clone() {
+        builder = builder.pipeline_cache_dir(dir);
+    }
+    if let Some(dir) = args.shader_dir.clone() {
+        builder = builder.shader_dir(dir);
+    }
+    let batch_timeout = (args.batch_timeout > 0).then(|| Duration::from_secs(args.batch_timeout));
+    builder = builder.batch_timeout(batch_timeout);
+    if let Some(duty) = args.duty_cycle {
+        builder = builder.throttle(Throttle::DutyCycle(duty));
+    }
+    let miner = if args.cpu_fallback {
+        builder.build_or_cpu().await
+    } else {
+        builder
+            .build()
+            .await
+            .map(|miner| Miner::Gpu(Box::new(miner)))
+    }
+    .context("Miner creation failed")?;
+
+    let mut miner = match miner {
+        Miner::Gpu(miner) => *miner,
+        Miner::Cpu(_) if args.self_test => {
+            return Err(anyhow::anyhow!("No GPU to run the self-test on"));
+        }
+        Miner::Cpu(_) if args.bench.is_some() => {
+            return Err(anyhow::anyhow!("No GPU to benchmark"));
+        }
+        Miner::Cpu(_) if args.vanity.is_some() => {
+            return Err(anyhow::anyhow!("Vanity search needs a GPU"));
+        }
+        Miner::Cpu(_) if args.pool.is_some() => {
+            return Err(anyhow::anyhow!("Pool mining needs a GPU"));
+        }
+        Miner::Cpu(mut miner) => {
+            miner.set_target(&args.network.pow_limit());
+            return mine_on_cpu(&mut miner, &words).await;
+        }
+    };
+    println!("Adapter: {}", miner.adapter_info());
+
+    if args.self_test {
+        let report = miner.self_test().await.context("Self-test failed to run")?;
+        print!("{report}");
+        if !report.passed() {
+            return Err(anyhow::anyhow!("GPU self-test failed"));
+        }
+        return Ok(());
+    }
+
+    if let Some(batches) = args.bench {
+        let bench = miner.bench(batches).await.context("Benchmark failed")?;
+        print!("{bench}");
+        return Ok(());
+    }
 
     miner.autotune().await;
+    if let Some(pool) = &args.pool {
+        return mine_pool(&mut miner, pool, &args).await;
+    }
+    miner.set_target(&args.network.pow_limit());
+    if let Some(vanity) = &args.vanity {
+        miner.set_vanity(Some(vanity));
+        println!(
+            "Searching for a vanity hash, about {:.0} hashes per match",
+            vanity.expected_hashes()
+        );
+    }
     println!("Starting mining run...");
 
-    let mut count = 0;
-    let winning_nonce: u32;
-    let start = Instant::now();
+    let options = RunOptions {
+        max_hashes: args.max_hashes,
+        max_duration: args.max_seconds.map(Duration::from_secs),
+        version_rolling: args.version_rolling,
+        ..Default::default()
+    };
+    let outcome = miner
+        .run_until_found(&words, &options, |stats| {
+            print!(
+                "\rTried {} hashes at {:.2} MH/s, {:.1}% of the header's nonces, {} headers exhausted",
+                stats.hashes,
+                stats.hashrate() / 1_000_000.0,
+                stats.coverage() * 100.0,
+                stats.exhausted_headers
+            );
+            io::stdout().flush().unwrap();
+        })
+        .await
+        .context("Mining run failed.")?;
+
+    let solution = match outcome {
+        RunOutcome::Found(solution) => solution,
+        RunOutcome::Exhausted => {
+            println!("\nRan out of timestamps without a winner.");
+            print_best(&miner.stats());
+            return Ok(());
+        }
+        RunOutcome::LimitReached => {
+            println!("\nReached the run limit without a winner.");
+            print_best(&miner.stats());
+            return Ok(());
+        }
+        RunOutcome::Cancelled => return Ok(()),
+    };
+    // Checked on the CPU so a bad kernel never gets a block submitted
+    if !check_pow_target(&solution.words.to_bytes(), miner.get_target()) {
+        return Err(anyhow::anyhow!(
+            "The GPU returned a header above the target"
+        ));
+    }
+    report(&miner.stats(), &solution);
+    Ok(())
+}
+
+fn parse_network(name: &str) -> Result<Network> {
+    name.parse()
+}
 
-    loop {
-        count += miner.get_batch_size();
+// Degraded mode without a GPU, sweeps every nonce of the header once
+async fn mine_on_cpu(miner: &mut CpuMiner, words: &HeaderWords) -> Result<()> {
+    miner.autotune().await;
+    println!("Starting mining run on the CPU...");
 
-        let res = miner.run_batch(&words).await.context("Batch run failed.")?;
+    let step = miner.get_hashes_per_batch() as u64;
+    let mut base = 0;
+    while base < 1 << 32 {
+        let end = (base + step).min(1 << 32);
+        let res = miner
+            .run_batch_range(words, base..end)
+            .context("Mining run failed.")?;
 
-        if let Some(nonce) = res {
-            println!("\nStruck Gold!");
-            winning_nonce = nonce;
-            break;
+        let stats = miner.stats();
+        print!(
+            "\rTried {} hashes at {:.2} MH/s",
+            stats.total_hashes,
+            stats.hashrate / 1_000_000.0
+        );
+        io::stdout().flush().unwrap();
+
+        if let (Some(nonce), Some(hash)) = (res.nonce, res.hash) {
+            let words = words.with_nonce(nonce);
+            report(&stats, &Solution { words, nonce, hash });
+            return Ok(());
         }
+        base = end;
+    }
 
-        // Print out every 15 loops
-        if count % 15 * miner.get_batch_size() == 0 {
-            let time = start.elapsed().as_secs_f64();
+    println!("\nRan out of nonces without a winner.");
+    Ok(())
+}
 
-            let hashes_per_second = ((count as f64) / time) / 1_000_000.0;
+// Versions run_version_batch_all splits a batch over
+const VERSIONS_PER_BATCH: u32 = 16;
 
-            print!("\rTried {} hashes at {:.2} MH/s", count, hashes_per_second);
-            io::stdout().flush().unwrap();
+// Next work of the current pool job
+#[derive(Debug, Default)]
+struct PoolCursor {
+    extranonce2: u64,
+    version_bits: u32,
+}
+
+// Mines shares for a stratum pool until the connection drops, one
+// extranonce2 per batch or, rolling versions, per VERSIONS_PER_BATCH of them
+// The pool is listened to while the GPU runs, a clean job or a lost
+// connection cancels the batch in flight. The client reconnects on its own.
+async fn mine_pool(miner: &mut GpuMiner, pool: &str, args: &Args) -> Result<()> {
+    let mut client = StratumClient::new(TcpTransport::new(pool), &args.user, &args.password);
+    if args.version_rolling {
+        client.set_version_rolling(Some(VERSION_ROLLING_MASK));
+    }
+    client.set_nicehash(args.nicehash);
+    client.connect().await.context("Couldn't join the pool.")?;
+    if let Some(mask) = client.get_version_mask() {
+        println!("Rolling version bits {mask:08x}");
+    }
+    println!("Mining for {pool} as {}...", args.user);
+
+    let cancel = miner.cancel_handle();
+    let mut cursor = PoolCursor::default();
+    loop {
+        let Some(job) = client.get_job().cloned() else {
+            let event = client.next_event().await?;
+            handle_pool_event(event, &mut cursor);
+            continue;
+        };
+
+        // Both may change with every session
+        let extranonce = client
+            .get_extranonce()
+            .context("Pool sent no extranonce.")?
+            .clone();
+        if let Some(mask) = client.get_version_mask() {
+            miner.set_version_mask(mask)?;
+        }
+        let extranonce2 = extranonce.extranonce2(cursor.extranonce2)?;
+        let work = job.work(&extranonce, &extranonce2)?;
+        let words = HeaderWords::from_bytes(&work.header);
+        miner.set_target(&job.target()?);
+        miner.set_share_target(Some(&difficulty_to_target(client.get_difficulty())?));
+
+        cancel.reset();
+        let mask = client.get_version_mask();
+        let mut events = Vec::new();
+        let found = {
+            let batch = mine_pool_batch(miner, &words, mask, &mut cursor);
+            tokio::pin!(batch);
+            loop {
+                tokio::select! {
+                    found = &mut batch => break found?,
+                    event = client.next_event() => {
+                        let event = event?;
+                        // Whatever the GPU finds now is stale
+                        let stale = match &event {
+                            StratumEvent::Job(job) => job.clean_jobs(),
+                            StratumEvent::Disconnected(_) | StratumEvent::Extranonce(_) => true,
+                            _ => false,
+                        };
+                        if stale {
+                            cancel.cancel();
+                        }
+                        events.push(event);
+                    }
+                }
+            }
+        };
+        for event in events {
+            handle_pool_event(event, &mut cursor);
+        }
+
+        for (words, winner) in found {
+            // Winner::nonce is word 19, the header has it byte swapped
+            let header = words.with_nonce(winner.nonce).to_bytes();
+            let share = work.share(&header, client.get_version_mask());
+            client.submit(&share).await?;
         }
 
-        // Timestamp is at byte 68 in the original header
-        // 68 / 4 = 7
-        words[17] = words[17] + 1;
+        let shares = client.stats();
+        print!(
+            "\rShares: {} accepted, {} rejected, {} stale, {} lost at difficulty {}, {:.2} MH/s",
+            shares.accepted,
+            shares.rejected,
+            shares.stale,
+            shares.lost,
+            client.get_difficulty(),
+            miner.stats().hashrate / 1_000_000.0
+        );
+        io::stdout().flush().unwrap();
     }
+}
 
-    // Nonce at 76 / 4 = 19
-    words[19] = winning_nonce;
+// Runs the next batch of the job and moves the cursor past it
+// Returns the shares with the header they were found in.
+async fn mine_pool_batch(
+    miner: &mut GpuMiner,
+    words: &HeaderWords,
+    version_mask: Option<u32>,
+    cursor: &mut PoolCursor,
+) -> Result<Vec<(HeaderWords, Winner)>> {
+    let mut found = Vec::new();
+    match version_mask {
+        Some(mask) => {
+            let values = 1u64 << mask.count_ones();
+            let start = cursor.version_bits;
+            let end = (start as u64 + VERSIONS_PER_BATCH as u64).min(values) as u32;
+            let hits = miner
+                .run_version_batch_all(words, start..end)
+                .await
+                .context("Mining run failed.")?;
+            for (bits, hits) in (start..end).zip(hits) {
+                let rolled = words.with_masked_version_bits(mask, bits);
+                found.extend(hits.shares.into_iter().map(|share| (rolled, share)));
+            }
+            cursor.version_bits = end;
+            if end as u64 == values {
+                cursor.version_bits = 0;
+                cursor.extranonce2 += 1;
+            }
+        }
+        None => {
+            let hits = miner
+                .run_batch_all(words)
+                .await
+                .context("Mining run failed.")?;
+            found.extend(hits.shares.into_iter().map(|share| (*words, share)));
+            cursor.extranonce2 += 1;
+        }
+    }
+    Ok(found)
+}
 
-    // Reconstruct the 80-byte header
-    let mut header_bytes = [0u8; 80];
-    for i in 0..20 {
-        let word_bytes = words[i].to_be_bytes(); // Big-endian
-        let start = i * 4;
-        header_bytes[start..start + 4].copy_from_slice(&word_bytes);
+fn handle_pool_event(event: StratumEvent, cursor: &mut PoolCursor) {
+    match event {
+        // Every job starts over with the extranonce2
+        StratumEvent::Job(_) | StratumEvent::Extranonce(_) => *cursor = PoolCursor::default(),
+        StratumEvent::VersionMask(_) => cursor.version_bits = 0,
+        StratumEvent::ShareRejected(reason) => println!("\nShare rejected: {reason}"),
+        StratumEvent::Disconnected(reason) => println!("\nLost the pool, reconnecting: {reason}"),
+        StratumEvent::Reconnected => println!("Back at the pool"),
+        StratumEvent::Difficulty(_) | StratumEvent::ShareAccepted => {}
     }
+}
 
-    // Compute and print the hash
-    let hash = hash_with_nonce(&header_bytes);
-    let hash_hex = hex::encode(hash);
-    println!("{}", hash_hex);
+// Lowest hash of the session, useful to gauge progress without a winner
+fn print_best(stats: &MinerStats) {
+    if let Some(best) = stats.best {
+        println!(
+            "Best hash: {} (difficulty {:.2})",
+            hex::encode(best.hash.iter().rev().copied().collect::<Vec<_>>()),
+            best.difficulty()
+        );
+    }
+}
 
-    // Convert timestamp bytes to readable format
-    let timestamp = u32::from_be_bytes(header_bytes[68..72].try_into().unwrap());
-    let datetime = Utc.timestamp_opt(timestamp as i64, 0).unwrap();
+// Prints the winning header and a summary of the run
+fn report(stats: &MinerStats, solution: &Solution) {
+    println!("\nStruck Gold!");
 
-    println!("Nonce: {}\nTimestamp: {}", winning_nonce, datetime);
+    println!(
+        "{} hashes in {} batches over {:.1?}, {:.2} MH/s",
+        stats.total_hashes,
+        stats.batches,
+        stats.uptime,
+        stats.hashrate / 1_000_000.0
+    );
+    print_best(stats);
 
-    Ok(())
+    // Print the hash returned by the miner
+    let hash_hex = hex::encode(solution.hash);
+    println!("{}", hash_hex);
+
+    // Both little-endian in the header, the solution's nonce is word 19
+    let header = solution.words.to_header();
+    let datetime = Utc.timestamp_opt(solution.words.time() as i64, 0).unwrap();
+
+    println!("Nonce: {}\nTimestamp: {}", header.nonce, datetime);
 }
@@ -0,0 +1,79 @@
+// With the spirv feature the embedded WGSL is compiled ahead of time into
+// one SPIR-V kernel per launch configuration autotune can pick.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "spirv")]
+    spirv::compile();
+}
+
+#[cfg(feature = "spirv")]
+mod spirv {
+    use std::{env, fmt::Write, fs, path::Path};
+
+    use naga::{back, front, valid};
+
+    // Powers of two from 32 up to the largest workgroup size wgpu allows
+    const WG_SIZES: [u32; 6] = [32, 64, 128, 256, 512, 1024];
+    const NONCES_PER_THREAD: [u32; 4] = [1, 2, 4, 8];
+
+    pub fn compile() {
+        println!("cargo:rerun-if-changed=src/sha256.wgsl");
+        println!("cargo:rerun-if-changed=src/mine.wgsl");
+
+        let source = format!(
+            "{}\n{}",
+            fs::read_to_string("src/sha256.wgsl").unwrap(),
+            fs::read_to_string("src/mine.wgsl").unwrap()
+        );
+        let module = front::wgsl::parse_str(&source)
+            .unwrap_or_else(|err| panic!("{}", err.emit_to_string(&source)));
+        let info = valid::Validator::new(valid::ValidationFlags::all(), valid::Capabilities::all())
+            .validate(&module)
+            .expect("Mining shader doesn't validate");
+
+        let options = back::spv::Options {
+            bounds_check_policies: naga::proc::BoundsCheckPolicies {
+                index: naga::proc::BoundsCheckPolicy::Restrict,
+                buffer: naga::proc::BoundsCheckPolicy::Restrict,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pipeline_options = back::spv::PipelineOptions {
+            shader_stage: naga::ShaderStage::Compute,
+            entry_point: "main".to_string(),
+        };
+
+        let out_dir = env::var("OUT_DIR").unwrap();
+        let mut table = String::from("const SPIRV_KERNELS: &[(u32, u32, &[u8])] = &[\n");
+
+        for wg_size in WG_SIZES {
+            for nonces_per_thread in NONCES_PER_THREAD {
+                let constants = back::PipelineConstants::from([
+                    ("wgSize".to_string(), wg_size as f64),
+                    ("noncesPerThread".to_string(), nonces_per_thread as f64),
+                ]);
+                let (module, info) =
+                    back::pipeline_constants::process_overrides(&module, &info, &constants)
+                        .expect("Override constants don't apply");
+                let words = back::spv::write_vec(&module, &info, &options, Some(&pipeline_options))
+                    .expect("SPIR-V generation failed");
+
+                let name = format!("mine_{wg_size}_{nonces_per_thread}.spv");
+                let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+                fs::write(Path::new(&out_dir).join(&name), bytes).unwrap();
+
+                writeln!(
+                    table,
+                    "    ({wg_size}, {nonces_per_thread}, \
+                     include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{name}\"))),"
+                )
+                .unwrap();
+            }
+        }
+
+        table.push_str("];\n");
+        fs::write(Path::new(&out_dir).join("spirv_kernels.rs"), table).unwrap();
+    }
+}
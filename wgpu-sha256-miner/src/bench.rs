@@ -0,0 +1,207 @@
+//! Standardized benchmark across workgroup sizes.
+//!
+//! Every run hashes the same header at a target nothing meets, so results
+//! are comparable between machines, drivers and commits.
+
+use std::fmt;
+
+use crate::{GpuMiner, HeaderWords, Result};
+
+// Header hashed by every benchmark
+const BENCH_HEADER: [u8; 80] = [0u8; 80];
+
+// Batches run before measuring, to get shaders compiled and clocks up
+const BENCH_WARMUP_BATCHES: u32 = 1;
+
+/// Adapter the benchmark ran on
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BenchAdapter {
+    pub name: String,
+    pub backend: String,
+    pub device_type: String,
+    pub driver: String,
+    pub driver_info: String,
+}
+
+/// Hashrate of one workgroup size over all measured batches
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BenchRun {
+    pub wg_size: u32,
+    pub batches: u32,
+    /// Mean MH/s of the batches
+    pub mhs: f64,
+    /// Variance of the MH/s of the batches
+    pub variance: f64,
+    pub min_mhs: f64,
+    pub max_mhs: f64,
+}
+
+/// Results of GpuMiner::bench
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Benchmark {
+    pub adapter: BenchAdapter,
+    pub batch_size: u32,
+    pub nonces_per_thread: u32,
+    /// True if batches were timed on the GPU instead of the wall clock
+    pub gpu_timed: bool,
+    pub runs: Vec<BenchRun>,
+}
+
+impl Benchmark {
+    /// Fastest run, if any workgroup size could be measured
+    pub fn best(&self) -> Option<&BenchRun> {
+        self.runs.iter().max_by(|a, b| a.mhs.total_cmp(&b.mhs))
+    }
+}
+
+impl fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} ({}, {}), batch_size {}, nonces_per_thread {}",
+            self.adapter.name,
+            self.adapter.backend,
+            self.adapter.driver,
+            self.batch_size,
+            self.nonces_per_thread
+        )?;
+        for run in &self.runs {
+            writeln!(
+                f,
+                "wg_size {:>4}: {:.2} MH/s (sd {:.2}, min {:.2}, max {:.2})",
+                run.wg_size,
+                run.mhs,
+                run.variance.sqrt(),
+                run.min_mhs,
+                run.max_mhs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl GpuMiner {
+    /// Times the given number of batches at every supported workgroup size
+    /// Launch parameters, target, throttle and stats of the miner are
+    /// restored afterwards.
+    pub async fn bench(&mut self, batches: u32) -> Result<Benchmark> {
+        let wg_size = self.wg_size;
+        let target = self.target;
+        let share_target = self.share_target.take();
+        let vanity = self.vanity.take();
+        let throttle = self.throttle;
+        let stats = self.stats;
+        self.throttle = crate::Throttle::None;
+        // Nothing meets an all zero target, so no batch ends early
+        self.set_target(&[0u8; 32]);
+
+        let res = self.run_bench(batches.max(1)).await;
+
+        self.wg_size = wg_size;
+        self.reload_pipeline();
+        self.share_target = share_target;
+        self.vanity = vanity;
+        self.set_target(&target);
+        self.throttle = throttle;
+        self.stats = stats;
+        res
+    }
+
+    async fn run_bench(&mut self, batches: u32) -> Result<Benchmark> {
+        let words = HeaderWords::from_bytes(&BENCH_HEADER);
+        let max = self.device.limits().max_compute_workgroup_size_x;
+
+        let mut runs = Vec::new();
+        let mut wg_size = 32;
+        while wg_size <= max {
+            self.wg_size = wg_size;
+            if self.fits_dispatch(self.batch_size) {
+                self.reload_pipeline();
+                for _ in 0..BENCH_WARMUP_BATCHES {
+                    self.run_batch(&words).await?;
+                }
+
+                let mut rates = Vec::with_capacity(batches as usize);
+                for _ in 0..batches {
+                    let res = self.run_batch(&words).await?;
+                    rates.push(res.hashrate() / 1_000_000.0);
+                }
+                runs.push(summarize(wg_size, &rates));
+            }
+            wg_size *= 2;
+        }
+
+        let info = self.adapter_info();
+        Ok(Benchmark {
+            adapter: BenchAdapter {
+                name: info.name,
+                backend: info.backend,
+                device_type: info.device_type,
+                driver: info.driver,
+                driver_info: info.driver_info,
+            },
+            batch_size: self.batch_size,
+            nonces_per_thread: self.nonces_per_thread,
+            gpu_timed: self.supports_timestamps(),
+            runs,
+        })
+    }
+}
+
+// Mean, variance and range of the MH/s of every batch
+fn summarize(wg_size: u32, rates: &[f64]) -> BenchRun {
+    let n = rates.len() as f64;
+    let mhs = rates.iter().sum::<f64>() / n;
+    let variance = rates.iter().map(|rate| (rate - mhs).powi(2)).sum::<f64>() / n;
+    BenchRun {
+        wg_size,
+        batches: rates.len() as u32,
+        mhs,
+        variance,
+        min_mhs: rates.iter().copied().fold(f64::INFINITY, f64::min),
+        max_mhs: rates.iter().copied().fold(0.0, f64::max),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_are_summarized() {
+        let run = summarize(64, &[1.0, 2.0, 3.0]);
+        assert_eq!(run.batches, 3);
+        assert_eq!(run.mhs, 2.0);
+        assert!((run.variance - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!((run.min_mhs, run.max_mhs), (1.0, 3.0));
+    }
+
+    #[tokio::test]
+    async fn bench_covers_wg_sizes_and_restores_the_miner() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .wg_size(64)
+            .build()
+            .await
+            .unwrap();
+        let target = *miner.get_target();
+
+        let bench = miner.bench(2).await.unwrap();
+        assert!(!bench.runs.is_empty());
+        assert_eq!(bench.runs[0].wg_size, 32);
+        assert!(bench
+            .runs
+            .iter()
+            .all(|run| run.batches == 2 && run.mhs > 0.0));
+        assert!(bench.best().is_some());
+        assert!(!bench.adapter.name.is_empty());
+        assert!(bench.to_string().contains("wg_size"));
+
+        assert_eq!(miner.get_wg_size(), 64);
+        assert_eq!(miner.get_target(), &target);
+        assert_eq!(miner.stats().batches, 0);
+    }
+}
@@ -0,0 +1,66 @@
+//! Monotonic clock that also works in the browser
+//!
+//! std::time::Instant panics on wasm32-unknown-unknown, so the time is read
+//! from JavaScript there instead. On other targets this is the std type.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+
+#[cfg(target_arch = "wasm32")]
+pub use web::Instant;
+
+// Wall clock in Unix seconds, SystemTime panics in the browser as well
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn unix_time() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as u32)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn unix_time() -> u32 {
+    (js_sys::Date::now() / 1000.0) as u32
+}
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use std::{
+        ops::{Add, Sub},
+        time::Duration,
+    };
+
+    // Time since the epoch, only used for differences
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct Instant(Duration);
+
+    impl Instant {
+        pub fn now() -> Self {
+            Instant(Duration::from_secs_f64(js_sys::Date::now() / 1000.0))
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Self::now().saturating_duration_since(*self)
+        }
+
+        // Date::now can step backwards, so every difference saturates
+        pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+            self.0.saturating_sub(earlier.0)
+        }
+    }
+
+    impl Sub for Instant {
+        type Output = Duration;
+
+        fn sub(self, earlier: Instant) -> Duration {
+            self.saturating_duration_since(earlier)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, duration: Duration) -> Instant {
+            Instant(self.0 + duration)
+        }
+    }
+}
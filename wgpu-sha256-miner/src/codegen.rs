@@ -0,0 +1,274 @@
+//! Variants of the SHA-256 compression function for the mining kernel.
+//!
+//! sha256.wgsl holds the looped version with the message schedule in
+//! registers. The other variants replace its computeHash, which drivers
+//! compile into very different code, so autotune picks per device.
+
+use std::fmt::{self, Write};
+
+// computeHash sits between these lines of sha256.wgsl
+const COMPUTE_HASH_START: &str = "// Computes the hash state";
+const COMPUTE_HASH_END: &str = "// Bitcoin headers are 2 blocks (1024 bits)";
+
+// Largest workgroup whose rolling schedules fit in shared memory,
+// 16 words per invocation
+pub(crate) const MAX_SHARED_WG_SIZE: u32 = 256;
+
+// Bytes of workgroup memory used by the shared schedule
+const SHARED_SCHEDULE_BYTES: u32 = 16 * 4 * MAX_SHARED_WG_SIZE;
+
+// Round constants, same as K in sha256.wgsl
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Where the message schedule of each invocation lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schedule {
+    /// All 64 words in a private array
+    #[default]
+    Registers,
+    /// A rolling window of 16 words in workgroup memory, for workgroups of
+    /// up to 256 invocations
+    Shared,
+}
+
+/// How the compression function of the kernel is written out
+/// The default is the embedded sha256.wgsl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShaderOptions {
+    /// Writes out all 64 rounds with their constants instead of looping
+    pub unroll: bool,
+    pub schedule: Schedule,
+    /// Keeps the working variables in two vec4<u32>
+    pub vec4: bool,
+}
+
+impl ShaderOptions {
+    /// Every combination of options, the default first
+    pub fn all() -> impl Iterator<Item = Self> {
+        [false, true].into_iter().flat_map(|unroll| {
+            [Schedule::Registers, Schedule::Shared]
+                .into_iter()
+                .flat_map(move |schedule| {
+                    [false, true].map(move |vec4| ShaderOptions {
+                        unroll,
+                        schedule,
+                        vec4,
+                    })
+                })
+        })
+    }
+
+    // The shared schedule needs a slot per invocation of the workgroup
+    pub(crate) fn fits(self, wg_size: u32, limits: &wgpu::Limits) -> bool {
+        self.schedule == Schedule::Registers
+            || (wg_size <= MAX_SHARED_WG_SIZE
+                && SHARED_SCHEDULE_BYTES <= limits.max_compute_workgroup_storage_size)
+    }
+}
+
+impl fmt::Display for ShaderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rounds = if self.unroll { "unrolled" } else { "looped" };
+        let schedule = match self.schedule {
+            Schedule::Registers => "register schedule",
+            Schedule::Shared => "shared schedule",
+        };
+        let state = if self.vec4 { "vec4" } else { "scalar" };
+        write!(f, "{rounds}, {schedule}, {state}")
+    }
+}
+
+// sha256.wgsl with computeHash generated for the options
+pub(crate) fn sha256_source(options: ShaderOptions) -> String {
+    let source = include_str!("sha256.wgsl");
+    if options == ShaderOptions::default() {
+        return source.to_string();
+    }
+
+    let start = source
+        .find(COMPUTE_HASH_START)
+        .expect("sha256.wgsl has a computeHash section");
+    let end = source
+        .find(COMPUTE_HASH_END)
+        .expect("sha256.wgsl has a section after computeHash");
+    format!(
+        "{}{}\n{}",
+        &source[..start],
+        compute_hash(options),
+        &source[end..]
+    )
+}
+
+// Writes computeHash, same signature as in sha256.wgsl
+fn compute_hash(options: ShaderOptions) -> String {
+    let mut f = String::new();
+    writeln!(f, "// Computes the hash state, generated: {options}").unwrap();
+    if options.schedule == Schedule::Shared {
+        writeln!(
+            f,
+            "var<workgroup> sharedSchedule: array<u32, {}>;\n",
+            16 * MAX_SHARED_WG_SIZE
+        )
+        .unwrap();
+        f.push_str(
+            "// Slot of word t of this invocation, interleaved across the workgroup\n\
+             fn sharedSlot(t: u32) -> u32 {\n\
+             \treturn (t & 15u) * wgSize + lane;\n\
+             }\n\n",
+        );
+    }
+    f.push_str(
+        "fn computeHash(words: array<u32, 16>, hashState: array<u32, 8>)\n    -> array<u32, 8> {\n",
+    );
+
+    if options.schedule == Schedule::Registers {
+        f.push_str("    var w = expandMsgSchedule(words);\n");
+    }
+    if options.vec4 {
+        f.push_str(
+            "    var abcd = vec4<u32>(hashState[0], hashState[1], hashState[2], hashState[3]);\n\
+             \x20   var efgh = vec4<u32>(hashState[4], hashState[5], hashState[6], hashState[7]);\n",
+        );
+    } else {
+        for (i, name) in "abcdefgh".chars().enumerate() {
+            writeln!(f, "    var {name} = hashState[{i}];").unwrap();
+        }
+    }
+
+    if options.unroll {
+        for (t, k) in K.iter().enumerate() {
+            let t = t as u32;
+            f.push_str("    {\n");
+            f.push_str(&schedule_word(options.schedule, &format!("{t}u"), Some(t)));
+            f.push_str(&round(options.vec4, &format!("0x{k:08x}u")));
+            f.push_str("    }\n");
+        }
+    } else {
+        f.push_str("    for(var t = 0u; t < 64u; t = t + 1u) {\n");
+        f.push_str(&schedule_word(options.schedule, "t", None));
+        f.push_str(&round(options.vec4, "K[t]"));
+        f.push_str("    }\n");
+    }
+
+    let state = if options.vec4 {
+        [
+            "abcd.x", "abcd.y", "abcd.z", "abcd.w", "efgh.x", "efgh.y", "efgh.z", "efgh.w",
+        ]
+    } else {
+        ["a", "b", "c", "d", "e", "f", "g", "h"]
+    };
+    f.push_str("    return array<u32, 8>(\n");
+    for (i, var) in state.iter().enumerate() {
+        let comma = if i < 7 { "," } else { "" };
+        writeln!(f, "\t{var} + hashState[{i}]{comma}").unwrap();
+    }
+    f.push_str("    );\n}\n\n");
+    f
+}
+
+// Declares wt, word t of the schedule, expanding it first if shared
+// known is the round number if it is a constant
+fn schedule_word(schedule: Schedule, t: &str, known: Option<u32>) -> String {
+    match schedule {
+        Schedule::Registers => format!("\tlet wt = w[{t}];\n"),
+        Schedule::Shared => {
+            let first = format!("\tsharedSchedule[sharedSlot({t})] = words[{t}];\n");
+            let expand = format!(
+                "\tsharedSchedule[sharedSlot({t})] =\n\
+                 \t    littleSigma1(sharedSchedule[sharedSlot({t} - 2u)]) +\n\
+                 \t    sharedSchedule[sharedSlot({t} - 7u)] +\n\
+                 \t    littleSigma0(sharedSchedule[sharedSlot({t} - 15u)]) +\n\
+                 \t    sharedSchedule[sharedSlot({t})];\n"
+            );
+            let store = match known {
+                Some(t) if t < 16 => first,
+                Some(_) => expand,
+                None => format!(
+                    "\tif({t} < 16u) {{\n{}\t}} else {{\n{}\t}}\n",
+                    indent(&first),
+                    indent(&expand)
+                ),
+            };
+            format!("{store}\tlet wt = sharedSchedule[sharedSlot({t})];\n")
+        }
+    }
+}
+
+// One round of compression, k is the round constant
+fn round(vec4: bool, k: &str) -> String {
+    if vec4 {
+        format!(
+            "\tlet t1 = efgh.w + bigSigma1(efgh.x) + ch(efgh.x, efgh.y, efgh.z) + {k} + wt;\n\
+             \tlet t2 = bigSigma0(abcd.x) + maj(abcd.x, abcd.y, abcd.z);\n\
+             \tefgh = vec4<u32>(abcd.w + t1, efgh.xyz);\n\
+             \tabcd = vec4<u32>(t1 + t2, abcd.xyz);\n"
+        )
+    } else {
+        format!(
+            "\tlet t1 = h + bigSigma1(e) + ch(e, f, g) + {k} + wt;\n\
+             \tlet t2 = bigSigma0(a) + maj(a, b, c);\n\
+             \th = g;\n\
+             \tg = f;\n\
+             \tf = e;\n\
+             \te = d + t1;\n\
+             \td = c;\n\
+             \tc = b;\n\
+             \tb = a;\n\
+             \ta = t1 + t2;\n"
+        )
+    }
+}
+
+fn indent(code: &str) -> String {
+    code.lines().map(|line| format!("    {line}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_options_keep_the_embedded_shader() {
+        assert_eq!(
+            sha256_source(ShaderOptions::default()),
+            include_str!("sha256.wgsl")
+        );
+        assert_eq!(ShaderOptions::all().count(), 8);
+        assert_eq!(ShaderOptions::all().next(), Some(ShaderOptions::default()));
+    }
+
+    #[test]
+    fn variants_replace_compute_hash() {
+        for options in ShaderOptions::all().skip(1) {
+            let source = sha256_source(options);
+            assert_eq!(source.matches("fn computeHash(").count(), 1);
+            assert!(source.contains(&options.to_string()));
+            assert!(source.contains("fn doubleHashFromMidstate("));
+            assert_eq!(
+                source.contains("sharedSchedule"),
+                options.schedule == Schedule::Shared
+            );
+        }
+    }
+
+    #[test]
+    fn shared_schedule_limits_the_workgroup_size() {
+        let limits = wgpu::Limits::default();
+        let shared = ShaderOptions {
+            schedule: Schedule::Shared,
+            ..Default::default()
+        };
+        assert!(shared.fits(256, &limits));
+        assert!(!shared.fits(512, &limits));
+        assert!(ShaderOptions::default().fits(1024, &limits));
+    }
+}
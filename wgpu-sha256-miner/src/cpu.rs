@@ -0,0 +1,356 @@
+//! Multi-threaded CPU backend
+//!
+//! Orders of magnitude slower than the GPU, but runs on machines without
+//! one and gives an independent implementation to check the kernel against.
+//! sha2 picks SHA-NI at runtime when the CPU supports it.
+
+use std::{ops::Range, slice, time::Duration};
+
+use rayon::prelude::*;
+
+use crate::{
+    clock::Instant, hash_meets_target, lowest, sha256_midstate, BatchResult, HeaderWords,
+    MinerBackend, MinerError, MinerStats, Result, Winner, DEFAULT_TARGET, NONCE_SPACE,
+    SECOND_BLOCK_PADDING, SHA256_INITIAL_HASH,
+};
+
+// Nonces per batch before autotuning
+const CPU_DEFAULT_BATCH_SIZE: u32 = 1 << 16;
+
+// Autotune sizes batches to take about this long
+const CPU_BATCH_TIME: Duration = Duration::from_millis(200);
+
+// Bounds of the autotuned batch size
+const CPU_MIN_BATCH_SIZE: u32 = 1 << 12;
+const CPU_MAX_BATCH_SIZE: u32 = 1 << 26;
+
+/// Mines on every core with a rayon thread pool
+pub struct CpuMiner {
+    pool: rayon::ThreadPool,
+    batch_size: u32,
+    target: [u8; 32],
+    stats: MinerStats,
+    started: Instant,
+    last_batch: Option<Instant>,
+}
+
+impl CpuMiner {
+    /// Creates a miner hashing on the given number of threads, or one per
+    /// core if None
+    pub fn new(threads: Option<usize>) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.unwrap_or(0))
+            .thread_name(|i| format!("cpu-miner-{i}"))
+            .build()?;
+
+        Ok(Self {
+            pool,
+            batch_size: CPU_DEFAULT_BATCH_SIZE,
+            target: DEFAULT_TARGET,
+            stats: MinerStats::default(),
+            started: Instant::now(),
+            last_batch: None,
+        })
+    }
+
+    /// Number of threads hashing in parallel
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Sets how many nonces one batch tests, at least 1
+    pub fn set_batch_size(&mut self, batch_size: u32) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Getter for the current target
+    pub fn get_target(&self) -> &[u8; 32] {
+        &self.target
+    }
+
+    /// Mines the nonces in [start, end) of a header, end is at most 2^32
+    /// Runs as many batches as needed and stops at the first batch with a
+    /// winner, like GpuMiner::run_batch_range.
+    pub fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> Result<BatchResult> {
+        if nonces.start >= nonces.end || nonces.end > NONCE_SPACE {
+            return Err(MinerError::InvalidNonceRange {
+                start: nonces.start,
+                end: nonces.end,
+            });
+        }
+
+        let start = Instant::now();
+        let mut total = BatchResult {
+            nonce: None,
+            hash: None,
+            hits: 0,
+            shares: 0,
+            best: None,
+            hashes_tried: 0,
+            elapsed: Duration::ZERO,
+            gpu_time: None,
+            recovered: false,
+            rejected: false,
+        };
+
+        let mut base = nonces.start;
+        while base < nonces.end {
+            let count = (nonces.end - base).min(self.batch_size as u64);
+            let res = self.hash_span(words, base as u32, count as u32);
+
+            total.hits += res.hits;
+            total.shares += res.shares;
+            total.best = lowest(total.best, res.best);
+            total.hashes_tried += res.hashes_tried;
+            if res.is_found() {
+                total.nonce = res.nonce;
+                total.hash = res.hash;
+                break;
+            }
+            base += count;
+        }
+
+        total.elapsed = start.elapsed();
+        Ok(total)
+    }
+
+    // Hashes the nonces in [start, start + count) as one batch
+    // Reports the lowest winning nonce, so results are deterministic.
+    fn hash_span(&mut self, words: &[u32; 32], start: u32, count: u32) -> BatchResult {
+        let begin = Instant::now();
+        let midstate = sha256_midstate(words);
+        let target = self.target;
+
+        let (hits, nonce, best) = self.pool.install(|| {
+            (0..count)
+                .into_par_iter()
+                .map(|offset| {
+                    let nonce = start.wrapping_add(offset);
+                    let hash = double_hash(&midstate, words, nonce);
+                    let best = Some(Winner { nonce, hash });
+                    if hash_meets_target(&hash, &target) {
+                        (1, Some(nonce), best)
+                    } else {
+                        (0, None, best)
+                    }
+                })
+                .reduce(
+                    || (0u32, None, None),
+                    |(hits_a, a, best_a), (hits_b, b, best_b)| {
+                        let first = match (a, b) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
+                        (hits_a + hits_b, first, lowest(best_a, best_b))
+                    },
+                )
+        });
+
+        let elapsed = begin.elapsed();
+        let res = BatchResult {
+            nonce,
+            hash: nonce.map(|nonce| double_hash(&midstate, words, nonce)),
+            hits,
+            shares: hits,
+            best,
+            hashes_tried: count as u64,
+            elapsed,
+            gpu_time: None,
+            recovered: false,
+            rejected: false,
+        };
+        self.record_batch(&res);
+        res
+    }
+
+    // Updates the running totals after a batch
+    fn record_batch(&mut self, res: &BatchResult) {
+        let now = Instant::now();
+        let interval = self
+            .last_batch
+            .map_or(res.elapsed, |last| res.elapsed.min(now - last))
+            .as_secs_f64();
+        self.last_batch = Some(now);
+        self.stats.record(slice::from_ref(res), interval);
+    }
+}
+
+impl MinerBackend for CpuMiner {
+    /// Blocks the calling thread until the batch is done
+    async fn run_batch(&mut self, words: &HeaderWords) -> Result<BatchResult> {
+        Ok(self.hash_span(words, 0, self.batch_size))
+    }
+
+    /// Blocks the calling thread until the range is done
+    async fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> Result<BatchResult> {
+        CpuMiner::run_batch_range(self, words, nonces)
+    }
+
+    async fn autotune(&mut self) {
+        let start = Instant::now();
+        self.hash_span(
+            &HeaderWords::from_bytes(&[0u8; 80]),
+            0,
+            CPU_DEFAULT_BATCH_SIZE,
+        );
+        let rate = CPU_DEFAULT_BATCH_SIZE as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+
+        let size = (rate * CPU_BATCH_TIME.as_secs_f64()) as u32;
+        self.batch_size = size
+            .clamp(CPU_MIN_BATCH_SIZE, CPU_MAX_BATCH_SIZE)
+            .next_power_of_two();
+        println!(
+            "Running on {} CPU threads, batch_size: {}, {:.2} MH/s",
+            self.threads(),
+            self.batch_size,
+            rate / 1_000_000.0
+        );
+    }
+
+    fn stats(&self) -> MinerStats {
+        MinerStats {
+            uptime: self.started.elapsed(),
+            ..self.stats
+        }
+    }
+
+    fn set_target(&mut self, target: &[u8; 32]) {
+        self.target = *target;
+    }
+
+    fn get_hashes_per_batch(&self) -> u32 {
+        self.batch_size
+    }
+}
+
+// Double SHA256 of the header with the nonce, reusing the midstate of the
+// constant first block
+fn double_hash(midstate: &[u32; 8], words: &[u32; 32], nonce: u32) -> [u8; 32] {
+    let mut tail = [0u32; 16];
+    tail[..4].copy_from_slice(&words[16..20]);
+    tail[3] = nonce;
+    tail[4..].copy_from_slice(&SECOND_BLOCK_PADDING);
+
+    let mut state = *midstate;
+    compress(&mut state, &tail);
+
+    // The 32 byte digest fits in one block with its padding
+    let mut block = [0u32; 16];
+    block[..8].copy_from_slice(&state);
+    block[8] = 0x80000000;
+    block[15] = 256;
+
+    let mut state = SHA256_INITIAL_HASH;
+    compress(&mut state, &block);
+
+    let mut hash = [0u8; 32];
+    for (chunk, word) in hash.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+// Runs the compression function over one block of big-endian words
+fn compress(state: &mut [u32; 8], words: &[u32; 16]) {
+    let mut block = [0u8; 64];
+    for (chunk, word) in block.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    sha2::compress256(state, &[block.into()]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash_with_nonce, sha256_words_to_header, GpuMiner};
+
+    #[test]
+    fn double_hash_matches_sha2() {
+        let mut words = [0u32; 32];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = (i as u32).wrapping_mul(0x9e3779b9);
+        }
+        let midstate = sha256_midstate(&words);
+
+        for nonce in [0, 1, 0x1234_5678, u32::MAX] {
+            words[19] = nonce;
+            let expected = hash_with_nonce(&sha256_words_to_header(&words));
+            assert_eq!(double_hash(&midstate, &words, nonce), expected);
+        }
+    }
+
+    #[test]
+    fn lowest_winner_is_reported() {
+        let mut miner = CpuMiner::new(Some(2)).unwrap();
+        assert_eq!(miner.threads(), 2);
+
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let words = [0u32; 32];
+        let res = miner.hash_span(&words, 0, 1 << 12);
+
+        let winners: Vec<u32> = (0..1 << 12)
+            .filter(|&nonce| {
+                let mut words = words;
+                words[19] = nonce;
+                hash_meets_target(&hash_with_nonce(&sha256_words_to_header(&words)), &target)
+            })
+            .collect();
+        assert_eq!(res.nonce, winners.first().copied());
+        assert_eq!(res.hits as usize, winners.len());
+        assert_eq!(miner.stats().batches, 1);
+        assert_eq!(miner.stats().total_hashes, 1 << 12);
+    }
+
+    #[tokio::test]
+    async fn agrees_with_gpu() {
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        target[1] = 0x0F;
+        let words = HeaderWords::from_bytes(&[0u8; 80]).with_time(0x6549_2a3b);
+
+        let mut cpu = CpuMiner::new(None).unwrap();
+        cpu.set_target(&target);
+        let cpu_res = cpu.hash_span(&words, 0, 1 << 14);
+
+        let mut gpu = GpuMiner::new(None).await.unwrap();
+        gpu.set_target(&target);
+        let gpu_res = gpu.run_batch_range(&words, 0..1 << 14).await.unwrap();
+
+        assert!(cpu_res.is_found());
+        assert_eq!(cpu_res.hits, gpu_res.hits);
+        assert_eq!(cpu_res.best, gpu_res.best);
+    }
+
+    #[test]
+    fn range_stops_at_first_winning_batch() {
+        let mut miner = CpuMiner::new(None).unwrap();
+        miner.set_batch_size(1 << 10);
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let res = miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 0..1 << 16)
+            .unwrap();
+        let nonce = res.nonce.unwrap();
+        assert_eq!(res.hashes_tried, (nonce as u64 / (1 << 10) + 1) * (1 << 10));
+
+        assert!(miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 5..5)
+            .is_err());
+        assert!(miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 0..NONCE_SPACE + 1)
+            .is_err());
+    }
+}
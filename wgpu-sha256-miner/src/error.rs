@@ -0,0 +1,71 @@
+//! Error type of the miner
+//!
+//! Public functions return MinerError so callers can match on the kind of
+//! failure, e.g. to retry after a lost device but not after a bad setting.
+
+use std::{io, path::PathBuf, time::Duration};
+
+use thiserror::Error;
+
+/// Result with MinerError as the default error
+pub type Result<T, E = MinerError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum MinerError {
+    #[error("Couldn't find GPU adapter")]
+    AdapterNotFound,
+    /// Only a software adapter was found and those aren't allowed
+    #[error("Only found software adapter {0:?}, which is not allowed")]
+    SoftwareAdapter(String),
+    #[error("Request for device failed")]
+    DeviceRequest(#[from] wgpu::RequestDeviceError),
+    #[error("GPU ran out of memory")]
+    OutOfMemory,
+    #[error("Buffer creation failed")]
+    BufferCreation(#[source] wgpu::Error),
+    #[error("Mapping from GPU failed")]
+    MapFailed(#[from] wgpu::BufferAsyncError),
+    /// The device went away while a batch was in flight
+    #[error("GPU device was lost")]
+    DeviceLost,
+    #[error("Batch didn't finish within {0:?}")]
+    BatchTimeout(Duration),
+    #[error("Couldn't rebuild miner after device loss")]
+    RecoveryFailed(#[source] Box<MinerError>),
+    #[error("Invalid batch size: {0}")]
+    InvalidBatchSize(String),
+    #[error("Invalid nonce range {start}..{end}")]
+    InvalidNonceRange { start: u64, end: u64 },
+    #[error("Between 1 and {max} jobs can share a batch, got {count}")]
+    InvalidJobCount { count: usize, max: u32 },
+    #[error("Mining on several devices needs at least one")]
+    NoDevices,
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+    #[error("Invalid throttle: {0}")]
+    InvalidThrottle(String),
+    #[error("Invalid shader options: {0}")]
+    InvalidShaderOptions(String),
+    #[error("Invalid shader")]
+    InvalidShader(#[source] wgpu::Error),
+    #[error("Adapter doesn't support timestamp queries")]
+    TimestampsUnsupported,
+    #[error("Invalid bits: {0}")]
+    InvalidBits(String),
+    #[error("Invalid difficulty: {0}")]
+    InvalidDifficulty(String),
+    #[error("Invalid vanity pattern: {0}")]
+    InvalidPattern(String),
+    #[error("No valid backend in: {0}")]
+    UnknownBackend(String),
+    #[error("Couldn't read {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to start the device poll thread")]
+    PollThread(#[source] io::Error),
+    #[error("Failed to start the CPU mining threads")]
+    CpuThreads(#[from] rayon::ThreadPoolBuildError),
+}
@@ -0,0 +1,331 @@
+//! Bulk double SHA256 on the GPU
+//!
+//! Reuses the mining kernel's hash functions to return every digest
+//! instead of only the winners, e.g. for computing txids in bulk.
+
+use futures::channel::oneshot;
+use wgpu::util::DeviceExt;
+
+use crate::{sha256_midstate, GpuMiner, MinerError, Result, SHA256_INITIAL_HASH};
+
+// Messages hashed per dispatch, keeps buffers at a few MB
+// Smaller if the adapter limits require it or the GPU runs out of memory.
+const HASH_CHUNK_SIZE: usize = 1 << 16;
+
+// Matches @workgroup_size in hash.wgsl
+const HASH_WG_SIZE: u32 = 64;
+
+/// A message prepared for the GPU: the state after every block but the
+/// last, and the last block with its padding
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HashJob {
+    pub midstate: [u32; 8],
+    pub tail: [u32; 16],
+}
+
+impl HashJob {
+    /// Prepares an 80 byte header
+    pub fn from_header(header: &[u8; 80]) -> Self {
+        Self::from_message(header)
+    }
+
+    /// Prepares a message of any length, such as a serialized transaction
+    /// Every block but the last is compressed on the CPU.
+    pub fn from_message(message: &[u8]) -> Self {
+        // Message, the 0x80 byte and the 64-bit length, rounded up to blocks
+        let padded_len = (message.len() + 9).div_ceil(64) * 64;
+        let mut padded = vec![0u8; padded_len];
+        padded[..message.len()].copy_from_slice(message);
+        padded[message.len()] = 0x80;
+        padded[padded_len - 8..].copy_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+        let (blocks, last) = padded.split_at(padded_len - 64);
+        let mut midstate = SHA256_INITIAL_HASH;
+        for block in blocks.chunks_exact(64) {
+            let block: [u8; 64] = block.try_into().unwrap();
+            sha2::compress256(&mut midstate, &[block.into()]);
+        }
+
+        let mut tail = [0u32; 16];
+        for (word, chunk) in tail.iter_mut().zip(last.chunks_exact(4)) {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        Self { midstate, tail }
+    }
+
+    /// Uses the words of a padded header, as passed to run_batch
+    pub fn from_words(words: &[u32; 32]) -> Self {
+        let mut tail = [0u32; 16];
+        tail.copy_from_slice(&words[16..]);
+        Self {
+            midstate: sha256_midstate(words),
+            tail,
+        }
+    }
+}
+
+// Pipeline for the hashing kernel, built on first use
+pub(crate) struct Hasher {
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl Hasher {
+    fn new(device: &wgpu::Device) -> Self {
+        let source = format!(
+            "{}\n{}",
+            include_str!("sha256.wgsl"),
+            include_str!("hash.wgsl")
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Hash Shader"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Hash Pipe"),
+            layout: None,
+            module: &shader,
+            entry_point: Some("hashAll"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        Self { pipeline }
+    }
+}
+
+impl GpuMiner {
+    /// Double SHA256 of every header, in SHA256 byte order like
+    /// hash_with_nonce
+    pub async fn hash_headers(&mut self, headers: &[[u8; 80]]) -> Result<Vec<[u8; 32]>> {
+        let jobs: Vec<HashJob> = headers.iter().map(HashJob::from_header).collect();
+        self.hash_jobs(&jobs).await
+    }
+
+    /// Double SHA256 of messages of any length
+    /// Only the last block of each message is hashed on the GPU, so this
+    /// pays off for short messages like most transactions.
+    pub async fn hash_messages<M: AsRef<[u8]>>(&mut self, messages: &[M]) -> Result<Vec<[u8; 32]>> {
+        let jobs: Vec<HashJob> = messages
+            .iter()
+            .map(|message| HashJob::from_message(message.as_ref()))
+            .collect();
+        self.hash_jobs(&jobs).await
+    }
+
+    /// Double SHA256 of prepared jobs, one digest per job in order
+    pub async fn hash_jobs(&mut self, jobs: &[HashJob]) -> Result<Vec<[u8; 32]>> {
+        if self.is_device_lost() {
+            return Err(MinerError::DeviceLost);
+        }
+        if self.hasher.is_none() {
+            self.hasher = Some(Hasher::new(&self.device));
+        }
+
+        let mut chunk_size = self.max_hash_chunk();
+        let mut digests = Vec::with_capacity(jobs.len());
+        let mut done = 0;
+        while done < jobs.len() {
+            let chunk = &jobs[done..jobs.len().min(done + chunk_size)];
+            match self.hash_chunk(chunk, &mut digests).await {
+                Ok(()) => done += chunk.len(),
+                Err(MinerError::OutOfMemory) if chunk_size > 1 => {
+                    chunk_size /= 2;
+                    eprintln!(
+                        "Warning: GPU ran out of memory, hashing {chunk_size} messages at a time."
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(digests)
+    }
+
+    // Largest chunk whose buffers and dispatch fit the adapter limits
+    fn max_hash_chunk(&self) -> usize {
+        let limits = self.device.limits();
+        let buffer_size =
+            (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        let jobs = buffer_size / size_of::<HashJob>() as u64;
+        let dispatch = limits.max_compute_workgroups_per_dimension as u64 * HASH_WG_SIZE as u64;
+        (HASH_CHUNK_SIZE as u64).min(jobs).min(dispatch).max(1) as usize
+    }
+
+    // Hashes one dispatch worth of jobs and appends their digests
+    async fn hash_chunk(&self, jobs: &[HashJob], digests: &mut Vec<[u8; 32]>) -> Result<()> {
+        let hasher = self.hasher.as_ref().expect("Hasher is built before use");
+        let size = 32 * jobs.len() as u64;
+
+        self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let job_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Hash Job Buffer"),
+                contents: bytemuck::cast_slice(jobs),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let digest_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Digest Buffer"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Digest Staging Buffer"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let validation = self.device.pop_error_scope().await;
+        if self.device.pop_error_scope().await.is_some() {
+            return Err(MinerError::OutOfMemory);
+        }
+        if let Some(error) = validation {
+            return Err(MinerError::BufferCreation(error));
+        }
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Hash Bind Group"),
+            layout: &hasher.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: job_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: digest_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Hash Encoder"),
+            });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Hash Pass"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&hasher.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups((jobs.len() as u32).div_ceil(HASH_WG_SIZE), 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&digest_buffer, 0, &staging_buffer, 0, size);
+        let submission = self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging_buffer.slice(..);
+        let (sender, receiver) = oneshot::channel();
+        slice.map_async(wgpu::MapMode::Read, move |res| {
+            let _ = sender.send(res);
+        });
+        self.wait_for_map(submission, receiver).await?;
+
+        // Digest words are big-endian, like the state words of sha2
+        let data = slice.get_mapped_range();
+        for words in bytemuck::cast_slice::<u8, u32>(&data).chunks_exact(8) {
+            let mut digest = [0u8; 32];
+            for (chunk, word) in digest.chunks_exact_mut(4).zip(words) {
+                chunk.copy_from_slice(&word.to_be_bytes());
+            }
+            digests.push(digest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash_with_nonce, sha256_parse_words, sha256_preprocess};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn jobs_from_headers_and_words_agree() {
+        let header = [0x5a; 80];
+        let words = sha256_parse_words(&sha256_preprocess(&header));
+        assert_eq!(HashJob::from_header(&header), HashJob::from_words(&words));
+    }
+
+    #[tokio::test]
+    async fn chunks_fit_the_adapter_limits() {
+        let miner = GpuMiner::new(None).await.unwrap();
+        let chunk = miner.max_hash_chunk();
+        let limits = miner.device.limits();
+        assert!((1..=HASH_CHUNK_SIZE).contains(&chunk));
+        assert!((chunk * size_of::<HashJob>()) as u64 <= limits.max_buffer_size);
+    }
+
+    #[tokio::test]
+    async fn digests_match_the_cpu() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+
+        let headers: Vec<[u8; 80]> = (0..1000u32)
+            .map(|i| {
+                let mut header = [0u8; 80];
+                header[76..].copy_from_slice(&i.to_le_bytes());
+                header
+            })
+            .collect();
+        let digests = miner.hash_headers(&headers).await.unwrap();
+        assert_eq!(digests.len(), headers.len());
+        for (header, digest) in headers.iter().zip(&digests) {
+            assert_eq!(digest, &hash_with_nonce(header));
+        }
+
+        // Lengths around the block boundaries, including an empty message
+        let messages: Vec<Vec<u8>> = [0, 1, 55, 56, 63, 64, 119, 120, 250]
+            .iter()
+            .map(|&len| (0..len).map(|i| i as u8).collect())
+            .collect();
+        let digests = miner.hash_messages(&messages).await.unwrap();
+        for (message, digest) in messages.iter().zip(&digests) {
+            let expected: [u8; 32] = Sha256::digest(Sha256::digest(message)).into();
+            assert_eq!(digest, &expected);
+        }
+
+        assert!(miner.hash_jobs(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn nist_vectors_and_mainnet_headers() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+
+        // Messages of the FIPS 180-2 examples, with their double SHA256
+        let vectors: [(&[u8], &str); 4] = [
+            (
+                b"",
+                "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456",
+            ),
+            (
+                b"abc",
+                "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "0cffe17f68954dac3a84fb1458bd5ec99209449749b2b308b7cb55812f9563af",
+            ),
+            (
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                  hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+                "accd7bd1cb0fcbd85cf0ba5ba96945127776373a7d47891eb43ed6b1e2ee60fe",
+            ),
+        ];
+        let messages: Vec<&[u8]> = vectors.iter().map(|(message, _)| *message).collect();
+        let digests = miner.hash_messages(&messages).await.unwrap();
+        for ((message, expected), digest) in vectors.iter().zip(&digests) {
+            assert_eq!(hex::encode(digest), *expected, "{:?}", message);
+        }
+
+        // Block hashes display reversed
+        for (name, header, hash) in crate::self_test::KNOWN_BLOCKS {
+            let header: [u8; 80] = hex::decode(header).unwrap().try_into().unwrap();
+            let mut digest = miner.hash_headers(&[header]).await.unwrap()[0];
+            digest.reverse();
+            assert_eq!(hex::encode(digest), hash, "{name}");
+        }
+    }
+}
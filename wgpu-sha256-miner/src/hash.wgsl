@@ -0,0 +1,25 @@
+/// Concatenated after sha256.wgsl like mine.wgsl
+/// Double hashes every job and writes out the whole digest.
+@group(0) @binding(0) var<storage, read> hashJobs: array<HashJob>;
+@group(0) @binding(1) var<storage, read_write> digests: array<Digest>;
+
+// Everything up to the last block of the message is compressed on the CPU
+struct HashJob {
+    midstate: array<u32, 8>,
+    // Last block of the padded message
+    tail: array<u32, 16>,
+}
+
+struct Digest {
+    words: array<u32, 8>,
+}
+
+@compute @workgroup_size(64)
+fn hashAll(@builtin(global_invocation_id) id: vec3<u32>) {
+    // The digest buffer is sized to the number of jobs
+    if(id.x >= arrayLength(&digests)) {
+	return;
+    }
+    let job = hashJobs[id.x];
+    digests[id.x] = Digest(doubleHashFromMidstate(job.midstate, job.tail));
+}
@@ -0,0 +1,597 @@
+//! Block headers and the padded words the miners hash.
+//!
+//! Miners take HeaderWords instead of bare word arrays, so the padding of
+//! the second SHA256 block can't be left out or overwritten by accident.
+
+use std::ops::Deref;
+
+use crate::{
+    clock::unix_time, hash_with_nonce, sha256_parse_words, sha256_preprocess,
+    sha256_words_to_header, target::bits_to_target, MinerError, Result, SECOND_BLOCK_PADDING,
+};
+
+/// Seconds a block time may be ahead of the wall clock before nodes reject
+/// the block
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+
+/// Version bits 13 to 28, which BIP 320 leaves to miners as extra nonce
+/// space
+pub const VERSION_ROLLING_MASK: u32 = 0x1fff_e000;
+
+// Lowest bit of VERSION_ROLLING_MASK
+const VERSION_ROLLING_SHIFT: u32 = VERSION_ROLLING_MASK.trailing_zeros();
+
+/// Version with the low bits of bits spread over the set bits of mask,
+/// lowest first, and the bits outside mask kept
+/// Pools may allow other bits than BIP 320 through BIP 310.
+pub fn roll_version(version: u32, mask: u32, bits: u32) -> u32 {
+    let mut rolled = version & !mask;
+    let mut rest = mask;
+    let mut bits = bits;
+    while rest != 0 {
+        let lowest = rest & rest.wrapping_neg();
+        if bits & 1 == 1 {
+            rolled |= lowest;
+        }
+        bits >>= 1;
+        rest &= !lowest;
+    }
+    rolled
+}
+
+/// Fields of an 80 byte block header, serialized little-endian
+/// The hashes are kept in wire order, as SHA256 outputs them. RPCs and
+/// explorers display them reversed, new and the *_display methods convert.
+/// With serde the hashes are hex strings in wire order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockHeader {
+    pub version: i32,
+    /// Hash of the previous block in SHA256 byte order, reversed from how
+    /// explorers display it
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+    pub prev_block: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+    pub merkle_root: [u8; 32],
+    /// Unix time in seconds
+    pub time: u32,
+    /// Compact target
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// Header with a zero nonce from hashes in RPC display order, the hex
+    /// getblocktemplate returns for previousblockhash
+    /// Fails if a field couldn't be in a valid block, see validate.
+    pub fn new(
+        version: i32,
+        prev_hash: &str,
+        merkle_root: &str,
+        time: u32,
+        bits: u32,
+    ) -> Result<Self> {
+        Self::from_wire_order(
+            version,
+            display_hash("prev_hash", prev_hash)?,
+            display_hash("merkle_root", merkle_root)?,
+            time,
+            bits,
+        )
+    }
+
+    /// Header with a zero nonce from hashes in wire order, as SHA256
+    /// outputs them and they are serialized
+    pub fn from_wire_order(
+        version: i32,
+        prev_block: [u8; 32],
+        merkle_root: [u8; 32],
+        time: u32,
+        bits: u32,
+    ) -> Result<Self> {
+        let header = Self {
+            version,
+            prev_block,
+            merkle_root,
+            time,
+            bits,
+            nonce: 0,
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    /// Checks the fields a node would reject before looking at the hash:
+    /// a version that isn't positive, a time more than
+    /// MAX_FUTURE_BLOCK_TIME ahead of the wall clock and bits that don't
+    /// encode a positive target
+    /// Parsed headers aren't validated, old blocks break current rules.
+    pub fn validate(&self) -> Result<()> {
+        if self.version <= 0 {
+            return Err(MinerError::InvalidHeader(format!(
+                "version {:#010x} should be positive",
+                self.version
+            )));
+        }
+
+        let latest = unix_time().saturating_add(MAX_FUTURE_BLOCK_TIME);
+        if self.time > latest {
+            return Err(MinerError::InvalidHeader(format!(
+                "time {} is more than {MAX_FUTURE_BLOCK_TIME}s in the future, latest is {latest}",
+                self.time
+            )));
+        }
+
+        let target = bits_to_target(self.bits)
+            .map_err(|err| MinerError::InvalidHeader(format!("bits: {err}")))?;
+        if target == [0u8; 32] {
+            return Err(MinerError::InvalidHeader(format!(
+                "bits {:08x} encode a zero target",
+                self.bits
+            )));
+        }
+        Ok(())
+    }
+
+    /// Header with a zero nonce for a getblocktemplate result, merkle_root
+    /// in SHA256 byte order
+    /// Fails if the template's hashes don't parse or a field couldn't be in
+    /// a valid block.
+    #[cfg(feature = "bridge")]
+    pub fn from_template(
+        template: &btccore_bridge::BlockTemplate,
+        merkle_root: &[u8; 32],
+    ) -> Result<Self> {
+        let bytes = template
+            .to_header(merkle_root)
+            .map_err(|err| MinerError::InvalidHeader(format!("{err:#}")))?;
+        let header = Self::from_bytes(&bytes);
+        header.validate()?;
+        Ok(header)
+    }
+
+    /// Parses a serialized header, to_bytes gives the same bytes back
+    pub fn from_bytes(bytes: &[u8; 80]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self {
+            version: u32_at(0) as i32,
+            prev_block: bytes[4..36].try_into().unwrap(),
+            merkle_root: bytes[36..68].try_into().unwrap(),
+            time: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        }
+    }
+
+    /// Parses the 160 hex digits of a serialized header, as bitcoind's
+    /// getblockheader prints them with verbose=false
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = hex::decode(hex.trim())
+            .map_err(|err| MinerError::InvalidHeader(format!("{hex} isn't hex: {err}")))?;
+        let bytes: [u8; 80] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            MinerError::InvalidHeader(format!("{} bytes instead of 80", bytes.len()))
+        })?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    /// Serializes the header as it is hashed
+    pub fn to_bytes(&self) -> [u8; 80] {
+        let mut bytes = [0u8; 80];
+        bytes[..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&self.prev_block);
+        bytes[36..68].copy_from_slice(&self.merkle_root);
+        bytes[68..72].copy_from_slice(&self.time.to_le_bytes());
+        bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[76..].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    /// Serialized header as lowercase hex
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_bytes())
+    }
+
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.nonce = nonce;
+    }
+
+    /// Sets the timestamp, in Unix seconds
+    pub fn set_time(&mut self, time: u32) {
+        self.time = time;
+    }
+
+    /// Double SHA256 of the header, in SHA256 byte order
+    pub fn hash(&self) -> [u8; 32] {
+        hash_with_nonce(&self.to_bytes())
+    }
+
+    /// Same header with the BIP 320 bits of the version set to bits
+    pub fn with_version_bits(mut self, bits: u16) -> Self {
+        let version = (self.version as u32 & !VERSION_ROLLING_MASK)
+            | ((bits as u32) << VERSION_ROLLING_SHIFT);
+        self.version = version as i32;
+        self
+    }
+
+    /// BIP 320 bits of the version, 0 unless they were rolled
+    pub fn version_bits(&self) -> u16 {
+        ((self.version as u32 & VERSION_ROLLING_MASK) >> VERSION_ROLLING_SHIFT) as u16
+    }
+
+    /// Hash of the previous block as hex in RPC display order
+    pub fn prev_hash_display(&self) -> String {
+        to_display(&self.prev_block)
+    }
+
+    /// Merkle root as hex in RPC display order
+    pub fn merkle_root_display(&self) -> String {
+        to_display(&self.merkle_root)
+    }
+
+    /// Hash of this header as hex in RPC display order, the block hash
+    pub fn hash_display(&self) -> String {
+        to_display(&self.hash())
+    }
+}
+
+// Parses a hash in display order into wire order
+fn display_hash(field: &str, hex: &str) -> Result<[u8; 32]> {
+    if hex.len() != 64 {
+        return Err(MinerError::InvalidHeader(format!(
+            "{field} should be 64 hex digits, got {}",
+            hex.len()
+        )));
+    }
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(hex, &mut hash)
+        .map_err(|err| MinerError::InvalidHeader(format!("{field} {hex} isn't hex: {err}")))?;
+    hash.reverse();
+    Ok(hash)
+}
+
+fn to_display(hash: &[u8; 32]) -> String {
+    let mut hash = *hash;
+    hash.reverse();
+    hex::encode(hash)
+}
+
+/// Both SHA256 blocks of a header with their padding, as big-endian words
+/// Serialized as its BlockHeader, so the padding can't be tampered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "BlockHeader", into = "BlockHeader")
+)]
+pub struct HeaderWords([u32; 32]);
+
+impl HeaderWords {
+    /// Pads and parses a serialized header
+    pub fn from_bytes(header: &[u8; 80]) -> Self {
+        Self(sha256_parse_words(&sha256_preprocess(header)))
+    }
+
+    pub fn from_header(header: &BlockHeader) -> Self {
+        Self::from_bytes(&header.to_bytes())
+    }
+
+    /// Wraps words built by hand, e.g. by sha256_parse_words
+    /// Fails if words 20 to 31 aren't the padding of an 80 byte header.
+    pub fn from_words(words: [u32; 32]) -> Result<Self> {
+        if words[20..] != SECOND_BLOCK_PADDING {
+            return Err(MinerError::InvalidHeader(format!(
+                "words 20 to 31 should be the padding {SECOND_BLOCK_PADDING:x?}, got {:x?}",
+                &words[20..]
+            )));
+        }
+        Ok(Self(words))
+    }
+
+    /// Same header with another nonce, as word 19 like the miners report it
+    /// The header field is byte swapped from that.
+    pub fn with_nonce(mut self, nonce: u32) -> Self {
+        self.0[19] = nonce;
+        self
+    }
+
+    /// Same header with the BIP 320 bits of the version set to bits
+    /// The version is word 0, so every value gets its own midstate.
+    pub fn with_version_bits(mut self, bits: u16) -> Self {
+        let version = self.0[0].swap_bytes() & !VERSION_ROLLING_MASK;
+        self.0[0] = (version | ((bits as u32) << VERSION_ROLLING_SHIFT)).swap_bytes();
+        self
+    }
+
+    /// Same header with the version rolled within mask, see roll_version
+    pub fn with_masked_version_bits(mut self, mask: u32, bits: u32) -> Self {
+        let version = roll_version(self.0[0].swap_bytes(), mask, bits);
+        self.0[0] = version.swap_bytes();
+        self
+    }
+
+    /// Same header with another timestamp, in Unix seconds
+    pub fn with_time(mut self, time: u32) -> Self {
+        // Serialized little-endian at byte 68, word 17 reads it big-endian
+        self.0[17] = time.swap_bytes();
+        self
+    }
+
+    /// Timestamp of the header, in Unix seconds
+    pub fn time(&self) -> u32 {
+        self.0[17].swap_bytes()
+    }
+
+    /// Same header with the timestamp moved forward by seconds, None if
+    /// that overflows
+    /// Adding to word 17 itself would bump the most significant byte.
+    pub fn roll_time(self, seconds: u32) -> Option<Self> {
+        Some(self.with_time(self.time().checked_add(seconds)?))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 80] {
+        sha256_words_to_header(&self.0)
+    }
+
+    pub fn to_header(&self) -> BlockHeader {
+        BlockHeader::from_bytes(&self.to_bytes())
+    }
+
+    pub fn into_words(self) -> [u32; 32] {
+        self.0
+    }
+}
+
+impl Deref for HeaderWords {
+    type Target = [u32; 32];
+
+    fn deref(&self) -> &[u32; 32] {
+        &self.0
+    }
+}
+
+impl From<&BlockHeader> for HeaderWords {
+    fn from(header: &BlockHeader) -> Self {
+        Self::from_header(header)
+    }
+}
+
+impl From<BlockHeader> for HeaderWords {
+    fn from(header: BlockHeader) -> Self {
+        Self::from_header(&header)
+    }
+}
+
+impl From<HeaderWords> for BlockHeader {
+    fn from(words: HeaderWords) -> Self {
+        words.to_header()
+    }
+}
+
+impl From<&[u8; 80]> for HeaderWords {
+    fn from(header: &[u8; 80]) -> Self {
+        Self::from_bytes(header)
+    }
+}
+
+// Byte arrays as hex strings for serde
+#[cfg(feature = "serde")]
+pub(crate) mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let bytes = hex::decode(&hex).map_err(D::Error::custom)?;
+        bytes.try_into().map_err(|bytes: Vec<u8>| {
+            D::Error::custom(format!("{} bytes instead of {N}", bytes.len()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_round_trip_through_words() {
+        let bytes: [u8; 80] = std::array::from_fn(|i| i as u8);
+        let header = BlockHeader::from_bytes(&bytes);
+        assert_eq!(header.version, 0x0302_0100);
+        assert_eq!(header.nonce, 0x4f4e_4d4c);
+        assert_eq!(header.to_bytes(), bytes);
+        assert_eq!(header.hash(), hash_with_nonce(&bytes));
+
+        let words = HeaderWords::from_header(&header);
+        assert_eq!(words.to_header(), header);
+        assert_eq!(words.with_nonce(7).to_header().nonce, 0x0700_0000);
+        assert_eq!(
+            words.with_time(1_700_000_000).to_header().time,
+            1_700_000_000
+        );
+
+        let words = words.with_time(0x6549_2aff);
+        let rolled = words.roll_time(1).unwrap();
+        assert_eq!(rolled.time(), 0x6549_2b00);
+        assert_eq!(rolled.to_bytes()[68..72], [0x00, 0x2b, 0x49, 0x65]);
+        assert_eq!(words.with_time(u32::MAX).roll_time(1), None);
+
+        let mut rolled = header;
+        rolled.set_time(header.time + 1);
+        rolled.set_nonce(42);
+        assert_eq!(rolled.to_bytes()[68], bytes[68] + 1);
+        assert_eq!(rolled.to_bytes()[76..], [42, 0, 0, 0]);
+        assert_eq!(HeaderWords::from_words(words.into_words()).unwrap(), words);
+    }
+
+    #[test]
+    fn mainnet_headers_round_trip_through_hex() {
+        for (name, hex, hash) in crate::self_test::KNOWN_BLOCKS {
+            let header = BlockHeader::from_hex(hex).unwrap();
+            assert_eq!(header.to_hex(), hex, "{name}");
+            assert_eq!(BlockHeader::from_bytes(&header.to_bytes()), header);
+            assert_eq!(header.bits, 0x1d00_ffff);
+
+            let mut digest = header.hash();
+            digest.reverse();
+            assert_eq!(hex::encode(digest), hash, "{name}");
+        }
+
+        let genesis = BlockHeader::from_hex(crate::self_test::KNOWN_BLOCKS[0].1).unwrap();
+        assert_eq!((genesis.version, genesis.time), (1, 1_231_006_505));
+        assert_eq!(genesis.nonce, 2_083_236_893);
+
+        assert!(BlockHeader::from_hex("00").is_err());
+        assert!(BlockHeader::from_hex(&"zz".repeat(80)).is_err());
+    }
+
+    #[test]
+    fn display_order_hashes_are_reversed() {
+        let (_, hex, hash) = crate::self_test::KNOWN_BLOCKS[0];
+        let merkle_root = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let mut genesis =
+            BlockHeader::new(1, &"00".repeat(32), merkle_root, 1_231_006_505, 0x1d00_ffff).unwrap();
+        genesis.set_nonce(2_083_236_893);
+        assert_eq!(genesis.to_hex(), hex);
+        assert_eq!(genesis.merkle_root[0], 0x3b);
+        assert_eq!(genesis.merkle_root_display(), merkle_root);
+        assert_eq!(genesis.hash_display(), hash);
+
+        let wire =
+            BlockHeader::from_wire_order(1, [0; 32], genesis.merkle_root, 0, 0x1d00_ffff).unwrap();
+        assert_eq!(wire.merkle_root_display(), merkle_root);
+
+        let err = BlockHeader::new(1, "00", merkle_root, 0, 0).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("prev_hash should be 64 hex digits"));
+    }
+
+    #[test]
+    fn version_bits_roll_without_the_other_bits() {
+        let header = BlockHeader::from_hex(crate::self_test::KNOWN_BLOCKS[1].1).unwrap();
+        let header = BlockHeader {
+            version: 0x2000_0004,
+            ..header
+        };
+        assert_eq!(header.version_bits(), 0);
+
+        let rolled = header.with_version_bits(0xffff);
+        assert_eq!(rolled.version, 0x3fff_e004);
+        assert_eq!(rolled.version_bits(), 0xffff);
+        assert_eq!(rolled.with_version_bits(1).version, 0x2000_2004);
+
+        let words = HeaderWords::from_header(&header).with_version_bits(0xabcd);
+        assert_eq!(words.to_header(), header.with_version_bits(0xabcd));
+        assert_ne!(
+            crate::sha256_midstate(&words),
+            crate::sha256_midstate(&HeaderWords::from_header(&header))
+        );
+
+        // The BIP 320 mask rolls like with_version_bits, others spread out
+        let words = HeaderWords::from_header(&header);
+        assert_eq!(
+            words.with_masked_version_bits(VERSION_ROLLING_MASK, 0xabcd),
+            words.with_version_bits(0xabcd)
+        );
+        assert_eq!(roll_version(0x2000_0004, 0x0a00_6000, 0b1011), 0x2800_6004);
+        assert_eq!(roll_version(0x2fff_ffff, 0x0a00_6000, 0), 0x25ff_9fff);
+        assert_eq!(
+            words
+                .with_masked_version_bits(0x0000_6000, 0b11)
+                .to_header()
+                .version,
+            0x2000_6004
+        );
+    }
+
+    #[test]
+    fn implausible_fields_are_rejected() {
+        let zero = "00".repeat(32);
+        let now = unix_time();
+        let new = |version, time, bits| BlockHeader::new(version, &zero, &zero, time, bits);
+        assert!(new(0x2000_0000, now, 0x207f_ffff).is_ok());
+        assert!(new(0x2000_0000, now + MAX_FUTURE_BLOCK_TIME - 60, 0x1d00_ffff).is_ok());
+
+        let error = |res: Result<BlockHeader>| res.unwrap_err().to_string();
+        assert!(error(new(0, now, 0x1d00_ffff)).contains("version"));
+        assert!(error(new(-1, now, 0x1d00_ffff)).contains("version"));
+        assert!(error(new(4, now + 3 * 60 * 60, 0x1d00_ffff)).contains("future"));
+        assert!(error(new(4, now, 0x1d80_ffff)).contains("negative target"));
+        assert!(error(new(4, now, 0x2300_ffff)).contains("overflow"));
+        assert!(error(new(4, now, 0x1d00_0000)).contains("zero target"));
+
+        let err = error(BlockHeader::new(4, &zero, "zz", now, 0x1d00_ffff));
+        assert!(err.contains("merkle_root should be 64 hex digits, got 2"));
+        let err = error(BlockHeader::new(
+            4,
+            &"zz".repeat(32),
+            &zero,
+            now,
+            0x1d00_ffff,
+        ));
+        assert!(err.contains("prev_hash") && err.contains("isn't hex"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn headers_serialize_with_hex_hashes() {
+        let header = BlockHeader::from_hex(crate::self_test::KNOWN_BLOCKS[1].1).unwrap();
+        let json = serde_json::to_string(&header).unwrap();
+        assert!(json.contains(&format!(
+            "\"prev_block\":\"{}\"",
+            hex::encode(header.prev_block)
+        )));
+        assert_eq!(serde_json::from_str::<BlockHeader>(&json).unwrap(), header);
+
+        // Words go through their header, any padding is rebuilt
+        let words = HeaderWords::from_header(&header);
+        assert_eq!(serde_json::to_string(&words).unwrap(), json);
+        assert_eq!(serde_json::from_str::<HeaderWords>(&json).unwrap(), words);
+
+        assert!(serde_json::from_str::<BlockHeader>(
+            &json.replace(&hex::encode(header.merkle_root), "00")
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "bridge")]
+    #[test]
+    fn headers_are_built_from_templates() {
+        let block = BlockHeader::from_hex(crate::self_test::KNOWN_BLOCKS[1].1).unwrap();
+        let template = |prev_hash: &str| {
+            serde_json::from_value::<btccore_bridge::BlockTemplate>(serde_json::json!({
+                "bits": "1d00ffff",
+                "curtime": block.time,
+                "height": 1,
+                "previousblockhash": prev_hash,
+                "sigoplimit": 20000,
+                "sizelimit": 1000000,
+                "transactions": [],
+                "version": 1,
+                "coinbasevalue": 5000000000u64,
+            }))
+            .unwrap()
+        };
+
+        let genesis = template("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+        let header = BlockHeader::from_template(&genesis, &block.merkle_root).unwrap();
+        assert_eq!(header, BlockHeader { nonce: 0, ..block });
+        assert!(BlockHeader::from_template(&template("00"), &block.merkle_root).is_err());
+    }
+
+    #[test]
+    fn words_without_padding_are_rejected() {
+        assert!(HeaderWords::from_words([0u32; 32]).is_err());
+        let mut words = HeaderWords::from_bytes(&[0u8; 80]).into_words();
+        words[31] = 0;
+        assert!(HeaderWords::from_words(words).is_err());
+    }
+}
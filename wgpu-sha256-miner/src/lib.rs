@@ -6,78 +6,440 @@
 //! Works with any crypto that uses double SHA256 and has a 80 byte header.
 //! Most commonly used are Bitcoin, Bitcoin Cash and Bitcoin SV.
 
-use futures::channel::oneshot;
-use std::{convert::TryInto, time::Instant, u8};
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt, fs,
+    future::Future,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    task::Poll,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
 
+mod bench;
+mod clock;
+mod codegen;
+mod cpu;
+mod error;
+mod hash;
+mod header;
+mod merkle;
+mod multi;
+mod self_test;
+#[cfg(feature = "spirv")]
+mod spirv;
+pub mod target;
+mod timestamp;
+mod vanity;
+
+pub use wgpu::{Backends, PowerPreference};
+
+pub use bench::{BenchAdapter, BenchRun, Benchmark};
+pub use clock::Instant;
+pub use codegen::{Schedule, ShaderOptions};
+pub use cpu::CpuMiner;
+pub use error::{MinerError, Result};
+pub use hash::HashJob;
+pub use header::{
+    roll_version, BlockHeader, HeaderWords, MAX_FUTURE_BLOCK_TIME, VERSION_ROLLING_MASK,
+};
+pub use merkle::{merkle_parent, merkle_root, MerkleBranch};
+pub use multi::MultiMiner;
+pub use self_test::{SelfTestCheck, SelfTestReport};
+pub use target::{
+    bits_from_hex, bits_to_difficulty, bits_to_target, check_pow, check_pow_target,
+    difficulty_to_target, hash_meets_target, hash_to_difficulty, target_to_bits,
+    target_to_difficulty,
+};
+pub use timestamp::TimestampRoller;
+pub use vanity::VanityPattern;
+
+// Settings for picking the adapter
+#[derive(Debug, Clone, Copy)]
+struct AdapterOptions {
+    backends: Backends,
+    power_preference: PowerPreference,
+    allow_software: bool,
+}
+
+impl Default for AdapterOptions {
+    fn default() -> Self {
+        AdapterOptions {
+            backends: Backends::all(),
+            power_preference: PowerPreference::HighPerformance,
+            allow_software: true,
+        }
+    }
+}
+
+// Software rasterizers like llvmpipe and WARP report themselves as CPU
+fn is_software_adapter(info: &wgpu::AdapterInfo) -> bool {
+    info.device_type == wgpu::DeviceType::Cpu
+}
+
+/// Adapter a miner runs on, as reported by its driver
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdapterInfo {
+    pub name: String,
+    /// PCI vendor ID, or a backend specific ID, 0 if unknown
+    pub vendor: u32,
+    /// PCI device ID, 0 if unknown
+    pub device: u32,
+    /// Discrete, integrated, virtual GPU or CPU
+    pub device_type: String,
+    pub backend: String,
+    pub driver: String,
+    /// Driver version and other details, depends on the backend
+    pub driver_info: String,
+}
+
+impl AdapterInfo {
+    fn from_wgpu(info: &wgpu::AdapterInfo) -> Self {
+        Self {
+            name: info.name.clone(),
+            vendor: info.vendor,
+            device: info.device,
+            device_type: format!("{:?}", info.device_type),
+            backend: format!("{:?}", info.backend),
+            driver: info.driver.clone(),
+            driver_info: info.driver_info.clone(),
+        }
+    }
+}
+
+impl fmt::Display for AdapterInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] ({}, {}, {} {})",
+            self.name,
+            self.vendor,
+            self.device,
+            self.device_type,
+            self.backend,
+            self.driver,
+            self.driver_info
+        )
+    }
+}
+
 // Wgpu setup steps to get device and queue
-async fn setup_gpu() -> Result<(wgpu::Device, wgpu::Queue)> {
-    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+// The adapter's own limits are returned too, the device only gets the
+// default ones.
+async fn setup_gpu(
+    options: AdapterOptions,
+) -> Result<(wgpu::Device, wgpu::Queue, wgpu::AdapterInfo, wgpu::Limits)> {
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        backends: options.backends,
+        ..Default::default()
+    });
 
     let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions::default())
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: options.power_preference,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        })
         .await
-        .context("Couldn't find GPU adapter")?;
+        .ok_or(MinerError::AdapterNotFound)?;
+
+    let info = adapter.get_info();
+    if is_software_adapter(&info) {
+        if !options.allow_software {
+            return Err(MinerError::SoftwareAdapter(info.name));
+        }
+        eprintln!(
+            "Warning: {:?} is a software adapter, hashrate will be very low.",
+            info.name
+        );
+    }
+
+    // Timestamp queries are used for profiling and the pipeline cache
+    // for faster startup when available
+    let mut wanted_features = wgpu::Features::TIMESTAMP_QUERY | wgpu::Features::PIPELINE_CACHE;
+    // Precompiled kernels are loaded through SPIR-V passthrough
+    if cfg!(feature = "spirv") {
+        wanted_features |= wgpu::Features::SPIRV_SHADER_PASSTHROUGH;
+    }
+    let required_features = adapter.features() & wanted_features;
 
     let (device, queue) = adapter
-        .request_device(&wgpu::DeviceDescriptor::default(), None)
-        .await
-        .context("Request for device failed.")?;
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                required_features,
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
 
-    println!(
-        "Connected to the following GPU: {:?}",
-        adapter.get_info().name
-    );
+    println!("Connected to the following GPU: {:?}", info.name);
 
-    Ok((device, queue))
+    Ok((device, queue, info, adapter.limits()))
 }
 
-type Buffers = (wgpu::Buffer, wgpu::Buffer, wgpu::Buffer);
+// Buffers shared between CPU and GPU
+struct Buffers {
+    header: wgpu::Buffer,
+    output: wgpu::Buffer,
+    // Two staging buffers let one batch be read back while the next one runs
+    staging: [wgpu::Buffer; 2],
+    target: wgpu::Buffer,
+    params: wgpu::Buffer,
+    hits: wgpu::Buffer,
+    best: wgpu::Buffer,
+}
+
+impl Buffers {
+    // Frees the GPU memory now instead of once every handle is dropped
+    fn destroy(&self) {
+        for buffer in [&self.header, &self.output, &self.target, &self.params]
+            .into_iter()
+            .chain(&self.staging)
+            .chain([&self.hits, &self.best])
+        {
+            buffer.destroy();
+        }
+    }
+}
+
+// Hashes run per configuration during autotune, 20 batches of 2^20
+const AUTOTUNE_HASHES: u32 = 20 << 20;
+
+// Hashes run per shader variant during autotune, 4 batches of 2^18
+// Generated variants can be far slower than the embedded shader, so
+// they are compared on less work to keep batches short of the watchdog.
+const AUTOTUNE_SHADER_HASHES: u32 = 1 << 20;
+
+// Largest number of nonces per thread tried during autotune
+const AUTOTUNE_MAX_NONCES_PER_THREAD: u32 = 8;
+
+// Batch sizes tried during autotune, 2^18 to 2^22
+const AUTOTUNE_BATCH_SIZES: [u32; 3] = [1 << 18, 1 << 20, 1 << 22];
+
+// Default number of invocations per batch
+// Batch size should be a multiple of 2 to divide
+// with the workgroup size, 2^20 is a good base.
+const DEFAULT_BATCH_SIZE: u32 = 1 << 20;
+
+// Default batch sizes of discrete cards, which have many more compute
+// units, and integrated ones, which share the GPU with the desktop
+const DISCRETE_BATCH_SIZE: u32 = 1 << 22;
+const INTEGRATED_BATCH_SIZE: u32 = 1 << 19;
+
+// Workgroup size if the adapter doesn't report its subgroup size
+const DEFAULT_WG_SIZE: u32 = 64;
+
+// Subgroups per workgroup by default, enough for the scheduler to hide
+// latency without running out of registers
+const DEFAULT_SUBGROUPS_PER_WORKGROUP: u32 = 4;
+
+// Batches taking longer than this count as a hung GPU
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_BATCH_TIMEOUT: Option<Duration> = Some(Duration::from_secs(30));
+#[cfg(target_arch = "wasm32")]
+const DEFAULT_BATCH_TIMEOUT: Option<Duration> = None;
+
+/// Largest number of headers mined by one run_multi_batch dispatch
+pub const MAX_JOBS_PER_BATCH: u32 = 64;
+
+/// Largest number of compute passes submitted together as one batch
+pub const MAX_PASSES_PER_BATCH: u32 = 16;
+
+// Size of one job read by the shader, 24 u32 (midstate, second block)
+const JOB_SIZE: u64 = 96;
+
+// Block target, share target, vanity mask and vanity pattern, 8 u32 each
+const TARGETS_SIZE: u64 = 128;
+
+// Size of the output written by the shader for one job
+// Eight u32: found flag, first nonce, hit counter, share counter, best
+// score and padding
+const OUTPUT_SIZE: u64 = 32;
+
+// Outputs of every job of a batch
+const OUTPUTS_SIZE: u64 = OUTPUT_SIZE * MAX_JOBS_PER_BATCH as u64;
+
+/// Largest number of winners run_batch_all can report for one batch
+pub const MAX_HITS_PER_BATCH: u32 = 1024;
+
+// Size of the hit list, a counter and padding followed by (job, nonce) pairs
+const HITS_SIZE: u64 = 8 + 8 * MAX_HITS_PER_BATCH as u64;
+
+// Nonces that lowered the best hash of their job while the batch ran
+// About ln(batch size) per job, the lowest one is the best of the batch.
+const MAX_BEST_CANDIDATES: u32 = 256;
+
+// Size of the candidate list, laid out like the hit list
+const BEST_SIZE: u64 = 8 + 8 * MAX_BEST_CANDIDATES as u64;
+
+// Size of the per-pass parameters, five u32 (base nonce, count, job count,
+// threads, timestamp roll) padded to a multiple of 16 bytes
+const PARAMS_SIZE: u64 = 32;
+
+// Distance between the parameters of consecutive passes, the largest
+// uniform offset alignment an adapter may require
+const PARAMS_STRIDE: u64 = 256;
+
+// Time constant of the hashrate moving average
+const HASHRATE_EMA_WINDOW: Duration = Duration::from_secs(10);
+
+// Number of nonces for a single header, 2^32
+const NONCE_SPACE: u64 = 1 << 32;
+
+// Size of the resolved timestamps, two u64 (start and end of pass)
+const TIMESTAMPS_SIZE: u64 = 16;
+
+// Staging buffers hold the outputs, the timestamps, the hit list and the
+// best hash candidates
+const STAGING_SIZE: u64 = OUTPUTS_SIZE + TIMESTAMPS_SIZE + HITS_SIZE + BEST_SIZE;
+
+/// Target used until one is set, equal to difficulty 1 (bits 0x1d00ffff)
+pub const DEFAULT_TARGET: [u8; 32] = target::DIFF1_TARGET;
 
-// Create the three buffers neccessary for CPU-GPU communication
+// Create the buffers neccessary for CPU-GPU communication
 async fn create_buffers(device: &wgpu::Device, batch_size: u32) -> Result<Buffers> {
     // Protect against overflow
     batch_size
         .checked_mul(4)
-        .ok_or_else(|| anyhow::anyhow!("Batch size too large, caused overflow"))?;
+        .ok_or_else(|| MinerError::InvalidBatchSize("too large, caused overflow".into()))?;
 
     if batch_size == 0 {
-        return Err(anyhow::anyhow!("Batch size can't be zero."));
+        return Err(MinerError::InvalidBatchSize("can't be zero".into()));
     }
 
+    device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
     device.push_error_scope(wgpu::ErrorFilter::Validation);
-    // Buffer to hold header on the GPU
-    // Padded buffer is 128 bytes = 1024 bits
+    // Buffer to hold the jobs on the GPU
+    // Midstate (8 words) + second block of the padded header (16 words)
     let header_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Header Buffer"),
-        size: 128,
+        size: JOB_SIZE * MAX_JOBS_PER_BATCH as u64,
         mapped_at_creation: false,
         usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
     });
 
-    // Buffer to hold output on the gpu
+    // Buffer to hold output on the gpu, only the result of each job
     let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Output Buffer"),
-        size: (batch_size * 4) as u64,
+        size: OUTPUTS_SIZE,
         mapped_at_creation: false,
-        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
     });
 
-    // Staging buffer to map output from CPU
-    let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Staging Buffer"),
-        size: (batch_size * 4) as u64,
+    // Staging buffers to map output from CPU
+    let staging_buffers = ["Staging Buffer A", "Staging Buffer B"].map(|label| {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: STAGING_SIZE,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        })
+    });
+
+    // Buffer to hold the 256-bit target on the GPU
+    let target_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Target Buffer"),
+        size: TARGETS_SIZE,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    });
+
+    // Uniform with the nonce range covered by each pass of a batch
+    let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Params Buffer"),
+        size: PARAMS_STRIDE * MAX_PASSES_PER_BATCH as u64,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+
+    // Every winner of a batch, up to MAX_HITS_PER_BATCH
+    let hits_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Hits Buffer"),
+        size: HITS_SIZE,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
+    });
+
+    // Candidates for the lowest hash of a batch
+    let best_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Best Buffer"),
+        size: BEST_SIZE,
         mapped_at_creation: false,
-        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
     });
 
-    if let Some(error) = device.pop_error_scope().await {
-        Err(anyhow::anyhow!("Buffer creation failed: {:?}", error))
+    let validation = device.pop_error_scope().await;
+    if device.pop_error_scope().await.is_some() {
+        return Err(MinerError::OutOfMemory);
+    }
+    if let Some(error) = validation {
+        Err(MinerError::BufferCreation(error))
     } else {
-        Ok((header_buffer, output_buffer, staging_buffer))
+        Ok(Buffers {
+            header: header_buffer,
+            output: output_buffer,
+            staging: staging_buffers,
+            target: target_buffer,
+            params: params_buffer,
+            hits: hits_buffer,
+            best: best_buffer,
+        })
+    }
+}
+
+// Query set and resolve buffer for timing the compute pass on the GPU
+struct Timestamps {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    // Nanoseconds per timestamp tick
+    period: f32,
+}
+
+// Only available if the device has TIMESTAMP_QUERY enabled
+fn create_timestamps(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Timestamps> {
+    if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+        return None;
     }
+
+    let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+        label: Some("Timestamp Queries"),
+        ty: wgpu::QueryType::Timestamp,
+        count: 2,
+    });
+
+    let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Timestamp Resolve Buffer"),
+        size: TIMESTAMPS_SIZE,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+    });
+
+    Some(Timestamps {
+        query_set,
+        resolve_buffer,
+        period: queue.get_timestamp_period(),
+    })
 }
 
 // Bind group layout defines which resources our shader will use
@@ -105,6 +467,47 @@ fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 },
                 count: None,
             },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                // Every pass of a batch binds its own parameters
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(PARAMS_SIZE),
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
         ],
     })
 }
@@ -113,354 +516,4341 @@ fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
 fn create_bind_group(
     device: &wgpu::Device,
     layout: &wgpu::BindGroupLayout,
-    header_buffer: &wgpu::Buffer,
-    output_buffer: &wgpu::Buffer,
+    buffers: &Buffers,
 ) -> wgpu::BindGroup {
     device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("Bind Group"),
-        layout: &layout,
+        layout,
         entries: &[
             wgpu::BindGroupEntry {
                 binding: 0,
-                resource: header_buffer.as_entire_binding(),
+                resource: buffers.header.as_entire_binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 1,
-                resource: output_buffer.as_entire_binding(),
+                resource: buffers.output.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: buffers.target.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffers.params,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(PARAMS_SIZE),
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: buffers.hits.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: buffers.best.as_entire_binding(),
             },
         ],
     })
 }
 
+// Compiled pipelines kept on disk between runs
+struct PipelineCache {
+    cache: wgpu::PipelineCache,
+    path: PathBuf,
+}
+
+impl PipelineCache {
+    // Opens the cache file for this adapter in dir, starting empty if
+    // there is none yet. Only available with PIPELINE_CACHE (Vulkan).
+    fn load(device: &wgpu::Device, info: &wgpu::AdapterInfo, dir: &Path) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::PIPELINE_CACHE) {
+            return None;
+        }
+        let path = dir.join(wgpu::util::pipeline_cache_key(info)?);
+        let data = fs::read(&path).ok();
+
+        // SAFETY: the file is keyed by adapter and was written from get_data,
+        // fallback discards data wgpu or the driver doesn't accept
+        let cache = unsafe {
+            device.create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                label: Some("Pipeline Cache"),
+                data: data.as_deref(),
+                fallback: true,
+            })
+        };
+
+        Some(PipelineCache { cache, path })
+    }
+
+    // Writes the cache to a temporary file and moves it over the old one
+    fn save(&self) -> io::Result<()> {
+        let Some(data) = self.cache.get_data() else {
+            return Ok(());
+        };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = self.path.with_extension("temp");
+        fs::write(&temp, data)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
 // The pipeline describes which resources to use and the steps to take
 // in the computation
+// Launch parameters are override constants, so one module is specialized
+// per pipeline without recompiling the WGSL.
 fn create_compute_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::BindGroupLayout,
     shader: &wgpu::ShaderModule,
+    cache: Option<&PipelineCache>,
+    wg_size: u32,
+    nonces_per_thread: u32,
 ) -> wgpu::ComputePipeline {
+    let constants = HashMap::from([
+        ("wgSize".to_string(), wg_size as f64),
+        ("noncesPerThread".to_string(), nonces_per_thread as f64),
+    ]);
+
     device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
         label: Some("Compute Pipe I"),
         layout: Some(
             &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Pipeline Layout"),
-                bind_group_layouts: &[&layout],
+                bind_group_layouts: &[layout],
                 push_constant_ranges: &[],
             }),
         ),
-        module: &shader,
+        module: shader,
         entry_point: Some("main"),
-        compilation_options: Default::default(),
-        cache: None,
+        compilation_options: wgpu::PipelineCompilationOptions {
+            constants: &constants,
+            ..Default::default()
+        },
+        cache: cache.map(|cache| &cache.cache),
     })
 }
 
-/// A GPU based miner ready for batch jobs
-pub struct GpuMiner {
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-    compute_pipeline: wgpu::ComputePipeline,
-    header_buffer: wgpu::Buffer,
-    output_buffer: wgpu::Buffer,
-    staging_buffer: wgpu::Buffer,
-    bind_group: wgpu::BindGroup,
-    bind_group_layout: wgpu::BindGroupLayout,
-    batch_size: u32,
-    wg_size: u32,
+// Nonces covered by one dispatch
+#[derive(Debug, Clone, Copy)]
+struct NonceSpan {
+    base: u32,
+    count: u32,
 }
 
-impl GpuMiner {
-    /// Tries to create a GpuMiner
-    pub async fn new(wg_size: Option<u32>) -> Result<Self> {
-        // Batch size should be a multiple of 2 to divide
-        // with the workgroup size, 2^20 is a good base.
-        let batch_size: u32 = 1048576;
+/// Outcome of a single batch run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchResult {
+    /// Winning nonce, if one was found
+    pub nonce: Option<u32>,
+    /// Double SHA256 of the header with the winning nonce
+    pub hash: Option<[u8; 32]>,
+    /// Number of nonces in this batch that met the target
+    pub hits: u32,
+    /// Number of nonces that met the share target, equal to hits when no
+    /// share target is set
+    pub shares: u32,
+    /// Lowest hash of the batch, whether or not it met a target
+    pub best: Option<Winner>,
+    /// Number of nonces tested in this batch
+    pub hashes_tried: u64,
+    /// Wall-clock time spent on the batch
+    pub elapsed: Duration,
+    /// Time the dispatch took on the GPU, if timestamp queries are supported
+    /// Jobs sharing a dispatch report the time of the whole dispatch.
+    pub gpu_time: Option<Duration>,
+    /// True if the device was lost and rebuilt before this batch ran
+    pub recovered: bool,
+    /// True if the GPU reported a winner that failed verification on the
+    /// CPU, which points to a miscompiled kernel. The winner is dropped.
+    pub rejected: bool,
+}
+
+/// Winning nonce of a batch together with its hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Winner {
+    pub nonce: u32,
+    #[cfg_attr(feature = "serde", serde(with = "header::hex_bytes"))]
+    pub hash: [u8; 32],
+}
+
+impl Winner {
+    /// Difficulty the hash would have met, as shown for best shares
+    pub fn difficulty(&self) -> f64 {
+        target::hash_to_difficulty(&self.hash)
+    }
+}
+
+// The one with the lower hash, as SHA256 hashes compare little-endian
+fn lowest(a: Option<Winner>, b: Option<Winner>) -> Option<Winner> {
+    match (a, b) {
+        (Some(a), Some(b)) if b.hash.iter().rev().lt(a.hash.iter().rev()) => Some(b),
+        (a, b) => a.or(b),
+    }
+}
+
+// Reads (job, nonce) pairs after the counter of a hit or candidate list
+fn read_hit_list(data: &[u8], max: u32) -> Vec<(u32, u32)> {
+    let list = bytemuck::cast_slice::<u8, u32>(data);
+    let count = list[0].min(max) as usize;
+    list[2..2 + 2 * count]
+        .chunks_exact(2)
+        .map(|hit| (hit[0], hit[1]))
+        .collect()
+}
+
+/// Outcome of run_batch_all
+/// Only the first MAX_HITS_PER_BATCH winners are kept, so fewer winners
+/// than result.hits means the list was truncated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchHits {
+    pub result: BatchResult,
+    /// Every winner that fit in the hit list, ordered by nonce
+    pub winners: Vec<Winner>,
+    /// Every nonce that met the share target and fit in the hit list,
+    /// ordered by nonce. Blocks are usually shares as well.
+    pub shares: Vec<Winner>,
+}
+
+// Everything read back from one dispatch
+struct BatchOutput {
+    results: Vec<BatchResult>,
+    // Time the compute pass took on the GPU, if timestamps are supported
+    gpu_time: Option<Duration>,
+    // (job, nonce) of every winner or share that fit in the hit list
+    winners: Vec<(u32, u32)>,
+}
+
+impl BatchResult {
+    /// True if the batch produced a winner
+    pub fn is_found(&self) -> bool {
+        self.nonce.is_some()
+    }
+
+    /// Hashes per second of this batch, measured over the GPU time when
+    /// available and the wall-clock time otherwise
+    pub fn hashrate(&self) -> f64 {
+        let time = self.gpu_time.unwrap_or(self.elapsed);
+        self.hashes_tried as f64 / time.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Settings for run_until_found
+#[derive(Debug, Clone, Copy)]
+pub struct RunOptions {
+    /// Earliest timestamp the header may use, defaults to its own timestamp
+    pub min_time: Option<u32>,
+    /// Latest timestamp the header may be rolled to
+    pub max_time: Option<u32>,
+    /// Time between two stats callbacks
+    pub stats_interval: Duration,
+    /// Stop after this many hashes, the last batch is shortened to match
+    pub max_hashes: Option<u64>,
+    /// Stop once the run has taken this long
+    pub max_duration: Option<Duration>,
+    /// Stop at this point in time
+    pub deadline: Option<Instant>,
+    /// Roll the BIP 320 version bits through every value before moving on
+    /// to the next timestamp, 2^16 times the nonces per timestamp
+    pub version_rolling: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            min_time: None,
+            max_time: None,
+            stats_interval: Duration::from_secs(5),
+            max_hashes: None,
+            max_duration: None,
+            deadline: None,
+            version_rolling: false,
+        }
+    }
+}
+
+/// Limits how much of the time the GPU spends mining, so the desktop stays
+/// usable while the miner runs in the background
+/// Throttled runs don't overlap batches.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Throttle {
+    /// Run batches back to back
+    #[default]
+    None,
+    /// Keep the GPU busy for this fraction of the time, in (0, 1]
+    DutyCycle(f64),
+    /// Sleep for a fixed time after every batch
+    Pause(Duration),
+}
+
+impl Throttle {
+    // Fails for duty cycles outside (0, 1], and for any throttle in the
+    // browser where the pauses can't block the thread
+    fn validate(self) -> Result<Self> {
+        match self {
+            Throttle::DutyCycle(duty) if !(duty > 0.0 && duty <= 1.0) => Err(
+                MinerError::InvalidThrottle(format!("duty cycle must be in (0, 1], got {duty}")),
+            ),
+            Throttle::DutyCycle(_) | Throttle::Pause(_) if cfg!(target_arch = "wasm32") => Err(
+                MinerError::InvalidThrottle("throttling is not supported on wasm32".into()),
+            ),
+            throttle => Ok(throttle),
+        }
+    }
+
+    // Idle time needed after a batch that kept the GPU busy for busy
+    fn pause_after(self, busy: Duration) -> Duration {
+        match self {
+            Throttle::None => Duration::ZERO,
+            Throttle::DutyCycle(duty) => busy.mul_f64((1.0 - duty) / duty),
+            Throttle::Pause(pause) => pause,
+        }
+    }
+}
+
+/// How the miner waits for the GPU, none depends on a specific executor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollStrategy {
+    /// Blocks the calling thread in device.poll until the batch is done
+    /// A hung GPU blocks it for good, the batch timeout can't cut the wait
+    /// short.
+    Block,
+    /// Polls without blocking and yields to the executor in between, so
+    /// other tasks on the same thread keep running at the cost of a busy CPU
+    Yield,
+    /// A background thread polls the device while the task awaits the
+    /// result, the default everywhere but wasm32 which has no threads
+    Thread,
+}
+
+impl Default for PollStrategy {
+    fn default() -> Self {
+        if cfg!(target_arch = "wasm32") {
+            PollStrategy::Block
+        } else {
+            PollStrategy::Thread
+        }
+    }
+}
+
+// Thread for PollStrategy::Thread, polls until every submitted batch is
+// done each time it is woken
+struct PollThread {
+    wake: Option<mpsc::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl PollThread {
+    fn spawn(device: &wgpu::Device) -> Result<Self> {
+        let device = device.clone();
+        let (wake, requests) = mpsc::channel::<()>();
+        let handle = std::thread::Builder::new()
+            .name("gpu-poll".into())
+            .spawn(move || {
+                while requests.recv().is_ok() {
+                    device.poll(wgpu::Maintain::Wait);
+                }
+            })
+            .map_err(MinerError::PollThread)?;
+        Ok(PollThread {
+            wake: Some(wake),
+            handle: Some(handle),
+        })
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+
+    // Lets the thread run on its own, for when it can't be joined
+    fn detach(mut self) {
+        self.handle = None;
+    }
+}
+
+impl Drop for PollThread {
+    // Closing the channel ends the thread, joining it makes sure its handle
+    // to the device is gone once the miner is
+    fn drop(&mut self) {
+        self.wake = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Future that is pending once, letting the executor run other tasks
+fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    std::future::poll_fn(move |cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+}
+
+// Executor independent sleep, the timer runs on a short-lived thread
+async fn sleep(duration: Duration) {
+    if duration.is_zero() {
+        return;
+    }
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        std::thread::sleep(duration);
+        let _ = sender.send(());
+    });
+    let _ = receiver.await;
+}
+
+// Timer of the batch watchdog, fires after timeout unless dropped first
+// All miners share one thread. Timers of batches done in time are dropped
+// when the next one is armed, a thread per batch would sleep for the whole
+// timeout. Never fires if the thread couldn't be started.
+fn watchdog_timer(timeout: Duration) -> oneshot::Receiver<()> {
+    type Timer = (std::time::Instant, oneshot::Sender<()>);
+    static TIMERS: OnceLock<Option<Mutex<mpsc::Sender<Timer>>>> = OnceLock::new();
+    let timers = TIMERS.get_or_init(|| {
+        let (timers, requests) = mpsc::channel::<Timer>();
+        std::thread::Builder::new()
+            .name("gpu-watchdog".into())
+            .spawn(move || {
+                let mut armed: Vec<Timer> = Vec::new();
+                loop {
+                    let next = armed.iter().map(|(deadline, _)| *deadline).min();
+                    let request = match next {
+                        Some(deadline) => requests.recv_timeout(
+                            deadline.saturating_duration_since(std::time::Instant::now()),
+                        ),
+                        None => requests
+                            .recv()
+                            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                    };
+                    match request {
+                        Ok(timer) => armed.push(timer),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                    let now = std::time::Instant::now();
+                    let (due, pending) = std::mem::take(&mut armed)
+                        .into_iter()
+                        .filter(|(_, sender)| !sender.is_canceled())
+                        .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+                    armed = pending;
+                    for (_, sender) in due {
+                        let _ = sender.send(());
+                    }
+                }
+            })
+            .ok()
+            .map(|_| Mutex::new(timers))
+    });
+
+    let (sender, receiver) = oneshot::channel();
+    let deadline = std::time::Instant::now() + timeout;
+    if let Some(timers) = timers {
+        let _ = timers.lock().unwrap().send((deadline, sender));
+    }
+    receiver
+}
+
+/// Running totals kept by the miner across all batch runs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinerStats {
+    pub total_hashes: u64,
+    pub batches: u64,
+    /// Time since the miner was built
+    pub uptime: Duration,
+    /// Hashes per second, exponential moving average over about 10 seconds
+    pub hashrate: f64,
+    /// Lowest hash seen so far and its nonce, winner or not
+    pub best: Option<Winner>,
+    /// Winners reported by the GPU that failed CPU verification
+    pub rejected: u64,
+}
+
+impl MinerStats {
+    // Adds the results of one batch that took interval seconds
+    fn record(&mut self, results: &[BatchResult], interval: f64) {
+        let hashes: u64 = results.iter().map(|res| res.hashes_tried).sum();
+
+        if interval > 0.0 {
+            let rate = hashes as f64 / interval;
+            let alpha = if self.batches == 0 {
+                1.0
+            } else {
+                1.0 - (-interval / HASHRATE_EMA_WINDOW.as_secs_f64()).exp()
+            };
+            self.hashrate += alpha * (rate - self.hashrate);
+        }
+
+        self.total_hashes += hashes;
+        self.batches += 1;
+        self.rejected += results.iter().filter(|res| res.rejected).count() as u64;
+        for res in results {
+            self.best = lowest(self.best, res.best);
+        }
+    }
+}
+
+/// Progress reported periodically by run_until_found, and every time the
+/// nonce space of a header runs out
+#[derive(Debug, Clone, Copy)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+    /// Timestamp of the header currently mined
+    pub timestamp: u32,
+    /// BIP 320 version bits of the header currently mined
+    pub version_bits: u16,
+    /// Nonces of the current header tried so far, out of 2^32
+    pub header_nonces: u64,
+    /// Headers whose every nonce was tried, one per rolled timestamp and
+    /// version
+    pub exhausted_headers: u64,
+}
+
+impl MiningStats {
+    /// Average hashes per second since the start of the run
+    pub fn hashrate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Fraction of the nonce space of the current header covered, in [0, 1]
+    pub fn coverage(&self) -> f64 {
+        self.header_nonces as f64 / NONCE_SPACE as f64
+    }
+}
+
+/// Header words with the winning timestamp and nonce filled in
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Solution {
+    pub words: HeaderWords,
+    pub nonce: u32,
+    #[cfg_attr(feature = "serde", serde(with = "header::hex_bytes"))]
+    pub hash: [u8; 32],
+}
+
+/// How run_until_found ended
+#[derive(Debug, Clone, Copy)]
+pub enum RunOutcome {
+    Found(Solution),
+    /// Every nonce of every allowed timestamp and version was tried
+    Exhausted,
+    /// Stopped by max_hashes, max_duration or the deadline of RunOptions
+    LimitReached,
+    Cancelled,
+}
+
+/// Handle to stop a running mining loop from another task or thread
+/// Cancellation is sticky until reset, so a new run has to reset it first.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Requests the mining loop to stop after the in-flight batch
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Clears a previous cancellation
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+
+    /// True if cancellation was requested
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Builder for a GpuMiner with tunable launch parameters
+#[derive(Debug, Clone)]
+pub struct GpuMinerBuilder {
+    wg_size: Option<u32>,
+    nonces_per_thread: u32,
+    batch_size: Option<u32>,
+    passes_per_batch: u32,
+    adapter: AdapterOptions,
+    pipeline_cache_dir: Option<PathBuf>,
+    shader_dir: Option<PathBuf>,
+    prefer_spirv: bool,
+    throttle: Throttle,
+    poll_strategy: PollStrategy,
+    batch_timeout: Option<Duration>,
+    shader_options: ShaderOptions,
+}
+
+impl Default for GpuMinerBuilder {
+    fn default() -> Self {
+        GpuMinerBuilder {
+            wg_size: None,
+            nonces_per_thread: 1,
+            batch_size: None,
+            passes_per_batch: 1,
+            adapter: AdapterOptions::default(),
+            pipeline_cache_dir: None,
+            shader_dir: None,
+            prefer_spirv: false,
+            throttle: Throttle::None,
+            poll_strategy: PollStrategy::default(),
+            batch_timeout: DEFAULT_BATCH_TIMEOUT,
+            shader_options: ShaderOptions::default(),
+        }
+    }
+}
+
+impl GpuMinerBuilder {
+    /// Sets the workgroup size, by default a few subgroups of the adapter
+    /// or 64 if it doesn't report their size
+    pub fn wg_size(mut self, wg_size: u32) -> Self {
+        self.wg_size = Some(wg_size);
+        self
+    }
+
+    /// Sets how many consecutive nonces each invocation tests, default 1
+    pub fn nonces_per_thread(mut self, nonces_per_thread: u32) -> Self {
+        self.nonces_per_thread = nonces_per_thread;
+        self
+    }
+
+    /// Sets the number of invocations per batch, by default 2^22 on
+    /// discrete cards, 2^19 on integrated ones and 2^20 otherwise
+    pub fn batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// Sets how many compute passes of batch_size invocations one batch
+    /// submits at once, default 1
+    /// Fast cards otherwise spend much of their time on submission overhead.
+    pub fn passes_per_batch(mut self, passes_per_batch: u32) -> Self {
+        self.passes_per_batch = passes_per_batch;
+        self
+    }
+
+    /// Restricts which wgpu backends may be used, default all
+    pub fn backends(mut self, backends: Backends) -> Self {
+        self.adapter.backends = backends;
+        self
+    }
+
+    /// Sets which adapter is preferred, default HighPerformance
+    pub fn power_preference(mut self, power_preference: PowerPreference) -> Self {
+        self.adapter.power_preference = power_preference;
+        self
+    }
+
+    /// Allows or forbids software adapters like llvmpipe, default allowed
+    /// A warning is printed when one is used.
+    pub fn allow_software(mut self, allow_software: bool) -> Self {
+        self.adapter.allow_software = allow_software;
+        self
+    }
+
+    /// Keeps compiled pipelines in dir so later runs start faster
+    /// Only supported on Vulkan, ignored on other backends.
+    #[cfg(feature = "fs")]
+    pub fn pipeline_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.pipeline_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Loads sha256.wgsl and mine.wgsl from dir instead of the embedded
+    /// shaders, for experimenting without a rebuild. Missing files keep
+    /// the embedded version, invalid ones fall back to it with a warning.
+    #[cfg(feature = "fs")]
+    pub fn shader_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.shader_dir = Some(dir.into());
+        self
+    }
+
+    /// Uses the precompiled SPIR-V kernels instead of the WGSL shader,
+    /// default off. Needs SPIR-V passthrough (Vulkan), otherwise WGSL is
+    /// used. Custom shaders from shader_dir take precedence.
+    #[cfg(feature = "spirv")]
+    pub fn prefer_spirv(mut self, prefer_spirv: bool) -> Self {
+        self.prefer_spirv = prefer_spirv;
+        self
+    }
+
+    /// Picks how the kernel's SHA-256 rounds are generated, default the
+    /// looped embedded shader. Custom shaders from shader_dir take precedence.
+    pub fn shader_options(mut self, shader_options: ShaderOptions) -> Self {
+        self.shader_options = shader_options;
+        self
+    }
+
+    /// Limits GPU usage between batches, default none
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Sets how the miner waits for batches to finish, default Thread
+    pub fn poll_strategy(mut self, poll_strategy: PollStrategy) -> Self {
+        self.poll_strategy = poll_strategy;
+        self
+    }
+
+    /// Sets how long a batch may take before the GPU counts as hung and
+    /// the device is rebuilt, default 30 seconds or None to wait forever
+    /// Ignored on wasm32, where the browser deals with hung GPUs, and by
+    /// PollStrategy::Block.
+    pub fn batch_timeout(mut self, batch_timeout: Option<Duration>) -> Self {
+        self.batch_timeout = batch_timeout;
+        self
+    }
+
+    /// Builds the GPU miner, or a CpuMiner on every core if the GPU can't
+    /// be set up, e.g. on headless machines or with broken drivers
+    /// Invalid settings are still reported as errors.
+    pub async fn build_or_cpu(self) -> Result<Miner> {
+        match self.build().await {
+            Ok(miner) => Ok(Miner::Gpu(Box::new(miner))),
+            Err(
+                err @ (MinerError::AdapterNotFound
+                | MinerError::SoftwareAdapter(_)
+                | MinerError::DeviceRequest(_)),
+            ) => {
+                eprintln!("Warning: {err}, falling back to CPU mining.");
+                Ok(Miner::Cpu(CpuMiner::new(None)?))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Tries to create the GpuMiner
+    pub async fn build(self) -> Result<GpuMiner> {
+        self.throttle.validate()?;
+        if let Some(batch_size) = self.batch_size {
+            check_hashes_per_batch(batch_size, self.nonces_per_thread, self.passes_per_batch)?;
+        }
+
+        let (device, queue, adapter_info, adapter_limits) = setup_gpu(self.adapter).await?;
+        let (default_wg_size, default_batch_size) =
+            default_launch(&adapter_info, &adapter_limits, &device.limits());
+        let wg_size = self.wg_size.unwrap_or(default_wg_size);
+        let batch_size = self.batch_size.unwrap_or_else(|| {
+            cap_batch_size(
+                default_batch_size,
+                wg_size,
+                self.nonces_per_thread,
+                self.passes_per_batch,
+                &device.limits(),
+            )
+        });
+        check_hashes_per_batch(batch_size, self.nonces_per_thread, self.passes_per_batch)?;
+        let max_workgroups = device.limits().max_compute_workgroups_per_dimension;
+        if dispatch_size(batch_size.div_ceil(wg_size), max_workgroups).is_none() {
+            return Err(MinerError::InvalidBatchSize(format!(
+                "{batch_size} invocations don't fit the dispatch limits of the adapter"
+            )));
+        }
+        check_shader_options(self.shader_options, wg_size, &device.limits())?;
+
+        let buffers = create_buffers(&device, batch_size).await?;
+
+        let bind_group_layout = create_bind_group_layout(&device);
+        let bind_group = create_bind_group(&device, &bind_group_layout, &buffers);
+
+        let targets = [target_to_words(&DEFAULT_TARGET); 2];
+        queue.write_buffer(&buffers.target, 0, bytemuck::cast_slice(&targets));
+
+        let pipeline_cache = self
+            .pipeline_cache_dir
+            .as_deref()
+            .and_then(|dir| PipelineCache::load(&device, &adapter_info, dir));
+
+        // User supplied shaders are only used if they validate
+        let custom = match &self.shader_dir {
+            Some(dir) => create_custom_pipeline(
+                &device,
+                &bind_group_layout,
+                pipeline_cache.as_ref(),
+                dir,
+                wg_size,
+                self.nonces_per_thread,
+            )
+            .await
+            .inspect_err(|err| {
+                eprintln!("Warning: falling back to embedded shaders: {err:#}");
+            })
+            .ok(),
+            None => None,
+        };
+
+        let custom_shaders = custom.is_some();
+        let (shader, compute_pipeline) = custom.unwrap_or_else(|| {
+            // Load shader
+            let shader = create_shader(&device, &ShaderSources::generate(self.shader_options));
+            let pipeline = create_compute_pipeline(
+                &device,
+                &bind_group_layout,
+                &shader,
+                pipeline_cache.as_ref(),
+                wg_size,
+                self.nonces_per_thread,
+            );
+            (shader, pipeline)
+        });
+
+        let timestamps = create_timestamps(&device, &queue);
+
+        // Driver resets and timeouts are reported through this callback
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let lost = device_lost.clone();
+        device.set_device_lost_callback(move |reason, message| {
+            eprintln!("GPU device lost ({:?}): {}", reason, message);
+            lost.store(true, Ordering::Release);
+        });
+
+        let poll_thread = match self.poll_strategy {
+            PollStrategy::Thread => Some(PollThread::spawn(&device)?),
+            _ => None,
+        };
+
+        println!("Created GPU Miner.");
+
+        let mut miner = GpuMiner {
+            device,
+            queue,
+            compute_pipeline,
+            buffers,
+            timestamps,
+            bind_group,
+            bind_group_layout,
+            batch_size,
+            wg_size,
+            nonces_per_thread: self.nonces_per_thread,
+            passes_per_batch: self.passes_per_batch,
+            adapter: self.adapter,
+            adapter_info,
+            pipeline_cache,
+            pipeline_cache_dir: self.pipeline_cache_dir,
+            shader,
+            custom_shaders,
+            shader_options: if custom_shaders {
+                ShaderOptions::default()
+            } else {
+                self.shader_options
+            },
+            shader_dir: self.shader_dir,
+            prefer_spirv: self.prefer_spirv,
+            spirv: false,
+            stats: MinerStats::default(),
+            started: Instant::now(),
+            last_batch: None,
+            throttle: self.throttle,
+            resume_at: None,
+            poll_strategy: self.poll_strategy,
+            poll_thread,
+            batch_timeout: self.batch_timeout,
+            hung: AtomicBool::new(false),
+            #[cfg(test)]
+            injected_ooms: 0,
+            target: DEFAULT_TARGET,
+            share_target: None,
+            version_mask: VERSION_ROLLING_MASK,
+            vanity: None,
+            cancel: CancelHandle::default(),
+            device_lost,
+            hasher: None,
+            uploaded_jobs: Vec::new(),
+        };
+        if miner.prefer_spirv {
+            miner.reload_pipeline();
+        }
+        miner.save_pipeline_cache();
+
+        Ok(miner)
+    }
+}
+
+// Fails if a shared schedule wouldn't fit the workgroup
+fn check_shader_options(options: ShaderOptions, wg_size: u32, limits: &wgpu::Limits) -> Result<()> {
+    if options.fits(wg_size, limits) {
+        Ok(())
+    } else {
+        Err(MinerError::InvalidShaderOptions(format!(
+            "{options} needs a wg_size of at most {}, got {wg_size}",
+            codegen::MAX_SHARED_WG_SIZE
+        )))
+    }
+}
+
+/// Parses a comma separated list of backends, e.g. "vulkan,dx12"
+/// Accepts vulkan, dx12, metal, gl and webgpu, see Backends::from_comma_list
+pub fn parse_backends(list: &str) -> Result<Backends> {
+    let list = list.trim();
+    if list.eq_ignore_ascii_case("all") {
+        return Ok(Backends::all());
+    }
+
+    let backends = Backends::from_comma_list(list);
+    if backends.is_empty() {
+        return Err(MinerError::UnknownBackend(list.to_string()));
+    }
+    Ok(backends)
+}
+
+// Spreads workgroups over up to three dimensions of at most max each
+// Extra workgroups in the last row or layer are skipped by the shader.
+fn dispatch_size(workgroups: u32, max: u32) -> Option<(u32, u32, u32)> {
+    if workgroups <= max {
+        return Some((workgroups, 1, 1));
+    }
+
+    let rows = workgroups.div_ceil(max);
+    if rows <= max {
+        return Some((max, rows, 1));
+    }
+
+    let layers = rows.div_ceil(max);
+    (layers <= max).then_some((max, max, layers))
+}
+
+// Workgroup and batch size for builders that don't set them
+// Workgroups are a few subgroups wide where the adapter reports their size,
+// batches shrink until they fit the dispatch limits.
+fn default_launch(
+    info: &wgpu::AdapterInfo,
+    adapter_limits: &wgpu::Limits,
+    device_limits: &wgpu::Limits,
+) -> (u32, u32) {
+    let max_wg_size = device_limits
+        .max_compute_invocations_per_workgroup
+        .min(device_limits.max_compute_workgroup_size_x);
+    let wg_size = match adapter_limits.max_subgroup_size {
+        0 => DEFAULT_WG_SIZE,
+        subgroup_size => (subgroup_size * DEFAULT_SUBGROUPS_PER_WORKGROUP)
+            .next_power_of_two()
+            .max(DEFAULT_WG_SIZE),
+    }
+    .min(max_wg_size);
+
+    let batch_size = match info.device_type {
+        wgpu::DeviceType::DiscreteGpu => DISCRETE_BATCH_SIZE,
+        wgpu::DeviceType::IntegratedGpu => INTEGRATED_BATCH_SIZE,
+        _ => DEFAULT_BATCH_SIZE,
+    };
+    (
+        wg_size,
+        cap_batch_size(batch_size, wg_size, 1, 1, device_limits),
+    )
+}
+
+// Halves batch_size until a batch of it fits the dispatch limits and the
+// nonce space, but not below a single workgroup
+fn cap_batch_size(
+    mut batch_size: u32,
+    wg_size: u32,
+    nonces_per_thread: u32,
+    passes: u32,
+    limits: &wgpu::Limits,
+) -> u32 {
+    let max_workgroups = limits.max_compute_workgroups_per_dimension;
+    while batch_size > wg_size
+        && (dispatch_size(batch_size.div_ceil(wg_size), max_workgroups).is_none()
+            || check_hashes_per_batch(batch_size, nonces_per_thread, passes).is_err())
+    {
+        batch_size /= 2;
+    }
+    batch_size
+}
+
+// A batch can't cover more than the 2^32 nonces of a header
+fn check_hashes_per_batch(batch_size: u32, nonces_per_thread: u32, passes: u32) -> Result<u32> {
+    if nonces_per_thread == 0 {
+        return Err(MinerError::InvalidBatchSize(
+            "nonces per thread can't be zero".into(),
+        ));
+    }
+    if !(1..=MAX_PASSES_PER_BATCH).contains(&passes) {
+        return Err(MinerError::InvalidBatchSize(format!(
+            "passes per batch must be between 1 and {MAX_PASSES_PER_BATCH}"
+        )));
+    }
+
+    batch_size
+        .checked_mul(nonces_per_thread)
+        .and_then(|hashes| hashes.checked_mul(passes))
+        .ok_or_else(|| {
+            MinerError::InvalidBatchSize("batch covers more than the nonce space".into())
+        })
+}
+
+/// A GPU based miner ready for batch jobs
+pub struct GpuMiner {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    compute_pipeline: wgpu::ComputePipeline,
+    buffers: Buffers,
+    timestamps: Option<Timestamps>,
+    bind_group: wgpu::BindGroup,
+    bind_group_layout: wgpu::BindGroupLayout,
+    batch_size: u32,
+    wg_size: u32,
+    nonces_per_thread: u32,
+    passes_per_batch: u32,
+    adapter: AdapterOptions,
+    adapter_info: wgpu::AdapterInfo,
+    pipeline_cache: Option<PipelineCache>,
+    pipeline_cache_dir: Option<PathBuf>,
+    shader: wgpu::ShaderModule,
+    custom_shaders: bool,
+    shader_options: ShaderOptions,
+    shader_dir: Option<PathBuf>,
+    prefer_spirv: bool,
+    // True while a precompiled SPIR-V kernel is in use
+    spirv: bool,
+    stats: MinerStats,
+    started: Instant,
+    last_batch: Option<Instant>,
+    throttle: Throttle,
+    // Earliest time the next batch may start when throttled
+    resume_at: Option<Instant>,
+    poll_strategy: PollStrategy,
+    poll_thread: Option<PollThread>,
+    batch_timeout: Option<Duration>,
+    // Set by the watchdog, the poll thread may never return then
+    hung: AtomicBool,
+    // Submissions to fail as out of memory, for the retry tests
+    #[cfg(test)]
+    injected_ooms: u32,
+    target: [u8; 32],
+    share_target: Option<[u8; 32]>,
+    // Version bits run_version_batch rolls
+    version_mask: u32,
+    vanity: Option<VanityPattern>,
+    cancel: CancelHandle,
+    device_lost: Arc<AtomicBool>,
+    hasher: Option<hash::Hasher>,
+    // Jobs in the header buffer, as they were when last written
+    uploaded_jobs: Vec<[u32; 32]>,
+}
+
+impl Drop for GpuMiner {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl GpuMiner {
+    /// Tries to create a GpuMiner
+    pub async fn new(wg_size: Option<u32>) -> Result<Self> {
+        let mut builder = Self::builder();
+        if let Some(wg_size) = wg_size {
+            builder = builder.wg_size(wg_size);
+        }
+        builder.build().await
+    }
+
+    /// Returns a builder with default launch parameters
+    pub fn builder() -> GpuMinerBuilder {
+        GpuMinerBuilder::default()
+    }
+
+    // Helper function to set compute pipe with the current parameters
+    fn reload_pipeline(&mut self) {
+        let spirv = self.spirv_shader();
+        self.spirv = spirv.is_some();
+
+        self.compute_pipeline = create_compute_pipeline(
+            &self.device,
+            &self.bind_group_layout,
+            spirv.as_ref().unwrap_or(&self.shader),
+            self.pipeline_cache.as_ref(),
+            self.wg_size,
+            self.nonces_per_thread,
+        );
+    }
+
+    // Precompiled kernel for the current launch parameters, if preferred
+    // and available
+    fn spirv_shader(&self) -> Option<wgpu::ShaderModule> {
+        #[cfg(feature = "spirv")]
+        // The kernels are compiled from the embedded shader only
+        if self.prefer_spirv
+            && !self.custom_shaders
+            && self.shader_options == ShaderOptions::default()
+        {
+            return spirv::create_shader(&self.device, self.wg_size, self.nonces_per_thread);
+        }
+        None
+    }
+
+    /// True if a precompiled SPIR-V kernel is in use
+    pub fn uses_spirv(&self) -> bool {
+        self.spirv
+    }
+
+    /// True if the shaders from shader_dir validated and are in use
+    pub fn uses_custom_shaders(&self) -> bool {
+        self.custom_shaders
+    }
+
+    /// Getter for how the kernel is generated
+    pub fn get_shader_options(&self) -> ShaderOptions {
+        self.shader_options
+    }
+
+    /// Regenerates the kernel with the options
+    /// Fails with custom shaders or if the options don't fit the workgroup size.
+    pub fn set_shader_options(&mut self, shader_options: ShaderOptions) -> Result<()> {
+        if self.custom_shaders {
+            return Err(MinerError::InvalidShaderOptions(
+                "custom shaders are in use".into(),
+            ));
+        }
+        check_shader_options(shader_options, self.wg_size, &self.device.limits())?;
+        self.use_shader_options(shader_options);
+        Ok(())
+    }
+
+    // Rebuilds the shader and pipeline for options known to fit
+    fn use_shader_options(&mut self, shader_options: ShaderOptions) {
+        self.shader_options = shader_options;
+        self.shader = create_shader(&self.device, &ShaderSources::generate(shader_options));
+        self.reload_pipeline();
+    }
+
+    /// Waits for batches still on the GPU, then frees its buffers
+    /// Dropping the miner does the same, this only makes it explicit.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+
+    // Lets in-flight batches finish so their buffers aren't freed under them
+    // A hung device may never finish, its resources are left to the driver.
+    fn release(&self) {
+        if self.hung.load(Ordering::Acquire) {
+            return;
+        }
+        if !self.is_device_lost() {
+            self.device.poll(wgpu::Maintain::Wait);
+        }
+        self.buffers.destroy();
+        if let Some(timestamps) = &self.timestamps {
+            timestamps.resolve_buffer.destroy();
+        }
+    }
+
+    // Persists the pipeline cache, failing to do so only costs startup time
+    fn save_pipeline_cache(&self) {
+        if let Some(cache) = &self.pipeline_cache {
+            if let Err(err) = cache.save() {
+                eprintln!("Warning: couldn't save pipeline cache: {err:#}");
+            }
+        }
+    }
+
+    // Getter for worgroup size
+    pub fn get_wg_size(&self) -> u32 {
+        self.wg_size
+    }
+
+    /// Getter for nonces tested by each invocation
+    pub fn get_nonces_per_thread(&self) -> u32 {
+        self.nonces_per_thread
+    }
+
+    /// Number of nonces tested by one batch
+    pub fn get_hashes_per_batch(&self) -> u32 {
+        self.batch_size * self.nonces_per_thread * self.passes_per_batch
+    }
+
+    // Nonces of every job covered by one pass, a full dispatch
+    fn pass_window(&self, jobs: usize) -> u32 {
+        (self.batch_size * self.nonces_per_thread / jobs as u32).max(1)
+    }
+
+    /// Getter for compute passes submitted per batch
+    pub fn get_passes_per_batch(&self) -> u32 {
+        self.passes_per_batch
+    }
+
+    /// Sets how many compute passes one batch submits at once
+    pub fn set_passes_per_batch(&mut self, passes_per_batch: u32) -> Result<()> {
+        check_hashes_per_batch(self.batch_size, self.nonces_per_thread, passes_per_batch)?;
+        self.passes_per_batch = passes_per_batch;
+        Ok(())
+    }
+
+    /// Name, IDs, type, backend and driver of the adapter
+    pub fn adapter_info(&self) -> AdapterInfo {
+        AdapterInfo::from_wgpu(&self.adapter_info)
+    }
+
+    /// True if the miner runs on a software adapter like llvmpipe
+    pub fn is_software(&self) -> bool {
+        is_software_adapter(&self.adapter_info)
+    }
+
+    /// True if batches can be timed with GPU timestamp queries
+    pub fn supports_timestamps(&self) -> bool {
+        self.timestamps.is_some()
+    }
+
+    /// Getter for batch size
+    pub fn get_batch_size(&self) -> u32 {
+        self.batch_size
+    }
+
+    /// Sets the number of invocations per batch
+    pub fn set_batch_size(&mut self, batch_size: u32) -> Result<()> {
+        if batch_size == 0 {
+            return Err(MinerError::InvalidBatchSize("can't be zero".into()));
+        }
+        check_hashes_per_batch(batch_size, self.nonces_per_thread, self.passes_per_batch)?;
+
+        self.batch_size = batch_size;
+        Ok(())
+    }
+
+    // True if a batch can be dispatched with the current workgroup size
+    // and nonces per thread
+    fn fits_dispatch(&self, batch_size: u32) -> bool {
+        let max_workgroups = self.device.limits().max_compute_workgroups_per_dimension;
+        dispatch_size(batch_size.div_ceil(self.wg_size), max_workgroups).is_some()
+            && check_hashes_per_batch(batch_size, self.nonces_per_thread, self.passes_per_batch)
+                .is_ok()
+            && self
+                .shader_options
+                .fits(self.wg_size, &self.device.limits())
+    }
+
+    /// True if the device was lost and the miner has to be recovered
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Acquire)
+    }
+
+    /// Tears down and rebuilds the device, buffers and pipeline
+    /// Launch parameters, target and cancel handle are kept.
+    pub async fn recover(&mut self) -> Result<()> {
+        let mut miner = GpuMinerBuilder {
+            wg_size: Some(self.wg_size),
+            nonces_per_thread: self.nonces_per_thread,
+            batch_size: Some(self.batch_size),
+            passes_per_batch: self.passes_per_batch,
+            adapter: self.adapter,
+            pipeline_cache_dir: self.pipeline_cache_dir.clone(),
+            shader_dir: self.shader_dir.clone(),
+            prefer_spirv: self.prefer_spirv,
+            throttle: self.throttle,
+            poll_strategy: self.poll_strategy,
+            batch_timeout: self.batch_timeout,
+            shader_options: self.shader_options,
+        }
+        .build()
+        .await
+        .map_err(|err| MinerError::RecoveryFailed(Box::new(err)))?;
+
+        // Joining a poll thread stuck on the hung device would hang as well
+        if self.hung.load(Ordering::Acquire) {
+            if let Some(poll_thread) = self.poll_thread.take() {
+                poll_thread.detach();
+            }
+        }
+
+        miner.share_target = self.share_target;
+        miner.vanity = self.vanity;
+        miner.set_target(&self.target);
+        miner.cancel = self.cancel.clone();
+        miner.stats = self.stats;
+        miner.started = self.started;
+        *self = miner;
+
+        println!("Recovered GPU Miner.");
+        Ok(())
+    }
+
+    // Rebuilds the miner if the device was lost, returns true if it was
+    async fn recover_if_lost(&mut self) -> Result<bool> {
+        if !self.is_device_lost() {
+            return Ok(false);
+        }
+        self.recover().await?;
+        Ok(true)
+    }
+
+    /// Getter for the throttle
+    pub fn get_throttle(&self) -> Throttle {
+        self.throttle
+    }
+
+    /// Changes how much the GPU rests between batches
+    pub fn set_throttle(&mut self, throttle: Throttle) -> Result<()> {
+        self.throttle = throttle.validate()?;
+        if throttle == Throttle::None {
+            self.resume_at = None;
+        }
+        Ok(())
+    }
+
+    // Sleeps until the throttle lets the next batch start
+    async fn wait_for_throttle(&self) {
+        if let Some(resume_at) = self.resume_at {
+            sleep(resume_at.saturating_duration_since(Instant::now())).await;
+        }
+    }
+
+    /// Getter for the poll strategy
+    pub fn get_poll_strategy(&self) -> PollStrategy {
+        self.poll_strategy
+    }
+
+    /// Changes how the miner waits for batches to finish
+    /// Fails if the poll thread can't be started.
+    pub fn set_poll_strategy(&mut self, poll_strategy: PollStrategy) -> Result<()> {
+        if poll_strategy == PollStrategy::Thread && self.poll_thread.is_none() {
+            self.poll_thread = Some(PollThread::spawn(&self.device)?);
+        }
+        self.poll_strategy = poll_strategy;
+        Ok(())
+    }
+
+    // Started with the Thread strategy and kept when switching away
+    fn poll_thread(&self) -> &PollThread {
+        self.poll_thread
+            .as_ref()
+            .expect("Poll thread is started with the Thread strategy")
+    }
+
+    // Waits until the submission is done and the staging buffer is mapped
+    async fn wait_for_map(
+        &self,
+        submission: wgpu::SubmissionIndex,
+        mut receiver: oneshot::Receiver<Result<(), wgpu::BufferAsyncError>>,
+    ) -> Result<()> {
+        // The callback is dropped unanswered when the device goes away
+        let timeout = self
+            .batch_timeout
+            .filter(|_| cfg!(not(target_arch = "wasm32")));
+        match self.poll_strategy {
+            PollStrategy::Block => {
+                self.device
+                    .poll(wgpu::Maintain::WaitForSubmissionIndex(submission));
+                receiver.await.map_err(|_| MinerError::DeviceLost)??;
+            }
+            PollStrategy::Yield => {
+                let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                loop {
+                    self.device.poll(wgpu::Maintain::Poll);
+                    if let Some(res) = receiver.try_recv().map_err(|_| MinerError::DeviceLost)? {
+                        res?;
+                        break;
+                    }
+                    if let (Some(timeout), Some(deadline)) = (timeout, deadline) {
+                        if Instant::now() >= deadline {
+                            return Err(self.batch_timed_out(timeout));
+                        }
+                    }
+                    yield_now().await;
+                }
+            }
+            PollStrategy::Thread => {
+                self.poll_thread().wake();
+                let Some(timeout) = timeout else {
+                    receiver.await.map_err(|_| MinerError::DeviceLost)??;
+                    return Ok(());
+                };
+                match future::select(receiver, watchdog_timer(timeout)).await {
+                    Either::Left((res, _)) => res.map_err(|_| MinerError::DeviceLost)??,
+                    Either::Right((Ok(()), _)) => return Err(self.batch_timed_out(timeout)),
+                    // No watchdog thread, the batch is waited for without one
+                    Either::Right((Err(_), receiver)) => {
+                        receiver.await.map_err(|_| MinerError::DeviceLost)??
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Marks the device as lost after a batch ran into the watchdog, so the
+    // usual recovery rebuilds it and reruns the batch
+    fn batch_timed_out(&self, timeout: Duration) -> MinerError {
+        eprintln!(
+            "Warning: batch didn't finish within {:?} on {:?} ({:?}, driver {:?}), \
+             batch_size {}, wg_size {}, nonces_per_thread {}, {} batches done. \
+             Assuming the GPU hung, rebuilding the device.",
+            timeout,
+            self.adapter_info.name,
+            self.adapter_info.backend,
+            self.adapter_info.driver_info,
+            self.batch_size,
+            self.wg_size,
+            self.nonces_per_thread,
+            self.stats.batches
+        );
+        self.hung.store(true, Ordering::Release);
+        self.device_lost.store(true, Ordering::Release);
+        MinerError::BatchTimeout(timeout)
+    }
+
+    /// Getter for the batch timeout of the watchdog
+    pub fn get_batch_timeout(&self) -> Option<Duration> {
+        self.batch_timeout
+    }
+
+    /// Changes how long a batch may take before the GPU counts as hung
+    pub fn set_batch_timeout(&mut self, batch_timeout: Option<Duration>) {
+        self.batch_timeout = batch_timeout;
+    }
+
+    // Waits until every submitted batch is done
+    async fn wait_for_idle(&self, submission: wgpu::SubmissionIndex) {
+        match self.poll_strategy {
+            PollStrategy::Block => {
+                self.device
+                    .poll(wgpu::Maintain::WaitForSubmissionIndex(submission));
+            }
+            PollStrategy::Yield => {
+                while !self.device.poll(wgpu::Maintain::Poll).is_queue_empty() {
+                    yield_now().await;
+                }
+            }
+            PollStrategy::Thread => {
+                let (sender, receiver) = oneshot::channel();
+                self.queue.on_submitted_work_done(move || {
+                    let _ = sender.send(());
+                });
+                self.poll_thread().wake();
+                let _ = receiver.await;
+            }
+        }
+    }
+
+    /// Snapshot of the hashes, batches and hashrate so far
+    /// Autotune and profile runs are not counted.
+    pub fn stats(&self) -> MinerStats {
+        MinerStats {
+            uptime: self.started.elapsed(),
+            ..self.stats
+        }
+    }
+
+    /// Moving average of the hashrate in hashes per second
+    pub fn get_hashrate(&self) -> f64 {
+        self.stats.hashrate
+    }
+
+    // Adds the results of one dispatch to the stats
+    fn record_batch(&mut self, results: &[BatchResult]) {
+        let now = Instant::now();
+        let elapsed = results
+            .iter()
+            .map(|res| res.elapsed)
+            .max()
+            .unwrap_or_default();
+
+        // Pipelined batches overlap, so the time since the previous batch
+        // is used when it is shorter than the batch itself
+        let interval = self
+            .last_batch
+            .map_or(elapsed, |last| elapsed.min(now - last))
+            .as_secs_f64();
+        self.last_batch = Some(now);
+
+        let pause = self.throttle.pause_after(elapsed);
+        self.resume_at = (!pause.is_zero()).then(|| now + pause);
+
+        self.stats.record(results, interval);
+    }
+
+    /// Handle that stops run_batches from another task
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Getter for the current target
+    pub fn get_target(&self) -> &[u8; 32] {
+        &self.target
+    }
+
+    /// Sets the 256-bit target hashes are compared against
+    /// The target is big-endian, as displayed by getblocktemplate
+    pub fn set_target(&mut self, target: &[u8; 32]) {
+        self.target = *target;
+        self.write_targets();
+    }
+
+    /// Getter for the share target, if one is set
+    pub fn get_share_target(&self) -> Option<&[u8; 32]> {
+        self.share_target.as_ref()
+    }
+
+    /// Also counts and lists hashes that meet the easier share target, as
+    /// pools ask for. Without one the block target counts as share target.
+    pub fn set_share_target(&mut self, share_target: Option<&[u8; 32]>) {
+        self.share_target = share_target.copied();
+        self.write_targets();
+    }
+
+    /// Getter for the version bits run_version_batch rolls
+    pub fn get_version_mask(&self) -> u32 {
+        self.version_mask
+    }
+
+    /// Rolls the bits of mask instead of the BIP 320 ones, e.g. what a
+    /// pool allowed through BIP 310
+    pub fn set_version_mask(&mut self, mask: u32) -> Result<()> {
+        if mask == 0 {
+            return Err(MinerError::InvalidHeader(
+                "the version mask leaves no bits to roll".to_string(),
+            ));
+        }
+        self.version_mask = mask;
+        Ok(())
+    }
+
+    /// Getter for the vanity pattern, if one is set
+    pub fn get_vanity(&self) -> Option<&VanityPattern> {
+        self.vanity.as_ref()
+    }
+
+    /// Only counts hashes that also match the pattern as winners or shares
+    /// Set the target to all 0xFF to search for the pattern alone.
+    pub fn set_vanity(&mut self, vanity: Option<&VanityPattern>) {
+        self.vanity = vanity.copied();
+        self.write_targets();
+    }
+
+    // Sends the block and share target and the vanity pattern to the GPU
+    // An empty mask matches every hash.
+    fn write_targets(&self) {
+        let share_target = self.share_target.unwrap_or(self.target);
+        let (mask, pattern) = self
+            .vanity
+            .map_or(([0; 8], [0; 8]), VanityPattern::to_words);
+        let targets = [
+            target_to_words(&self.target),
+            target_to_words(&share_target),
+            mask,
+            pattern,
+        ];
+        self.queue
+            .write_buffer(&self.buffers.target, 0, bytemuck::cast_slice(&targets));
+    }
+
+    // CPU side of the kernel's winner check
+    fn is_winner(&self, hash: &[u8; 32]) -> bool {
+        hash_meets_target(hash, &self.target) && self.vanity.is_none_or(|v| v.matches(hash))
+    }
+
+    // CPU side of the kernel's share check
+    fn is_share(&self, hash: &[u8; 32]) -> bool {
+        let share_target = self.share_target.unwrap_or(self.target);
+        hash_meets_target(hash, &share_target) && self.vanity.is_none_or(|v| v.matches(hash))
+    }
+
+    /// Sets the target from the compact bits field of a header
+    pub fn set_target_from_bits(&mut self, bits: u32) -> Result<()> {
+        let target = bits_to_target(bits)?;
+        self.set_target(&target);
+        Ok(())
+    }
+
+    /// Automatically sets optimal workgroup size, batch size, nonces
+    /// per thread and shader options
+    pub async fn autotune(&mut self) {
+        // Largest supported workgroup size
+        let max = self.device.limits().max_compute_workgroup_size_x;
+
+        // Launch parameters are tuned on the embedded shader
+        if !self.custom_shaders && self.shader_options != ShaderOptions::default() {
+            self.use_shader_options(ShaderOptions::default());
+        }
+
+        // Start checking from 2^5 (32) with one nonce per thread
+        self.nonces_per_thread = 1;
+        self.batch_size = DEFAULT_BATCH_SIZE;
+        let mut best_size = 32;
+        let mut best_rate = 0.0;
+
+        // We test workgroup sizes as different powers of 2
+        let mut wg_size: u32 = 32;
+        while wg_size <= max {
+            self.wg_size = wg_size;
+            if self.fits_dispatch(self.batch_size) {
+                self.reload_pipeline();
+
+                let rate = self.measure_hashrate(AUTOTUNE_HASHES).await;
+                println!("Tested wg_size {}, {:.2} MH/s", wg_size, rate / 1_000_000.0);
+                if rate > best_rate {
+                    best_rate = rate;
+                    best_size = wg_size;
+                }
+            }
+            wg_size *= 2;
+        }
+        self.wg_size = best_size;
+
+        // Batch size and nonces per thread both decide how much work one
+        // dispatch covers, so they are explored together
+        let mut best_batch = DEFAULT_BATCH_SIZE;
+        let mut best_nonces = 1;
+        let mut nonces_per_thread = 1;
+        while nonces_per_thread <= AUTOTUNE_MAX_NONCES_PER_THREAD {
+            self.nonces_per_thread = nonces_per_thread;
+            self.reload_pipeline();
+
+            for batch_size in AUTOTUNE_BATCH_SIZES {
+                // Already measured in the workgroup sweep
+                let is_baseline = nonces_per_thread == 1 && batch_size == DEFAULT_BATCH_SIZE;
+                if is_baseline || !self.fits_dispatch(batch_size) {
+                    continue;
+                }
+                self.batch_size = batch_size;
+
+                let rate = self.measure_hashrate(AUTOTUNE_HASHES).await;
+                println!(
+                    "Tested batch_size {}, nonces_per_thread {}, {:.2} MH/s",
+                    batch_size,
+                    nonces_per_thread,
+                    rate / 1_000_000.0
+                );
+                if rate > best_rate {
+                    best_rate = rate;
+                    best_batch = batch_size;
+                    best_nonces = nonces_per_thread;
+                }
+            }
+            nonces_per_thread *= 2;
+        }
+
+        // Generated variants last, at the workgroup size picked above and
+        // against the embedded shader on the same work
+        // Software adapters run them orders of magnitude slower, down to
+        // batches that would trip the watchdog, so they keep the default.
+        let mut best_options = ShaderOptions::default();
+        let sweep = !self.custom_shaders && !self.is_software();
+        if sweep && self.fits_dispatch(AUTOTUNE_BATCH_SIZES[0]) {
+            self.batch_size = AUTOTUNE_BATCH_SIZES[0];
+            self.nonces_per_thread = 1;
+            self.reload_pipeline();
+            let mut best_shader_rate = 0.0;
+            for options in ShaderOptions::all() {
+                if !options.fits(self.wg_size, &self.device.limits()) {
+                    continue;
+                }
+                if options != self.shader_options {
+                    self.use_shader_options(options);
+                }
+
+                let rate = self.measure_hashrate(AUTOTUNE_SHADER_HASHES).await;
+                println!("Tested shader {options}, {:.2} MH/s", rate / 1_000_000.0);
+                if rate > best_shader_rate {
+                    best_shader_rate = rate;
+                    best_options = options;
+                }
+            }
+        }
+
+        self.batch_size = best_batch;
+        self.nonces_per_thread = best_nonces;
+
+        println!(
+            "Running with wg_size: {best_size}, batch_size: {best_batch}, \
+             nonces_per_thread: {best_nonces}, shader: {best_options}"
+        );
+        if best_options == self.shader_options {
+            self.reload_pipeline();
+        } else {
+            self.use_shader_options(best_options);
+        }
+        self.save_pipeline_cache();
+    }
+
+    // Hashes per second with the current pipeline, over at least the given
+    // number of hashes so configurations are timed on the same work
+    // GPU time is used when available, since wall-clock includes map
+    // latency and host jitter.
+    async fn measure_hashrate(&mut self, hashes: u32) -> f64 {
+        let runs = (hashes / self.get_hashes_per_batch()).max(1);
+        let hashes = (runs as u64 * self.get_hashes_per_batch() as u64) as f64;
+
+        let start_time = Instant::now();
+        let mut gpu_time = Some(Duration::ZERO);
+        for _ in 0..runs {
+            let start = Instant::now();
+            let span = self.full_span(0);
+            let jobs = [[0u32; 32]];
+            let Ok(submission) = self.submit_batch(&jobs, 0, span) else {
+                gpu_time = None;
+                continue;
+            };
+            match self.read_batch(&jobs, 0, span, submission, start).await {
+                Ok(output) => {
+                    gpu_time = gpu_time.zip(output.gpu_time).map(|(sum, time)| sum + time)
+                }
+                Err(_) => gpu_time = None,
+            }
+        }
+
+        let time = match gpu_time {
+            Some(time) if !time.is_zero() => time,
+            _ => start_time.elapsed(),
+        };
+        hashes / time.as_secs_f64()
+    }
+
+    /// Runs batches on the header and reports the GPU time of each one
+    /// Fails if the adapter doesn't support timestamp queries
+    pub async fn profile(&mut self, words: &HeaderWords, batches: u32) -> Result<Vec<Duration>> {
+        if !self.supports_timestamps() {
+            return Err(MinerError::TimestampsUnsupported);
+        }
+
+        let mut times = Vec::with_capacity(batches as usize);
+        for _ in 0..batches {
+            let start = Instant::now();
+            let span = self.full_span(0);
+            let jobs = slice::from_ref(&**words);
+            let submission = self.submit_batch(jobs, 0, span)?;
+            let output = self.read_batch(jobs, 0, span, submission, start).await?;
+            times.push(output.gpu_time.ok_or(MinerError::TimestampsUnsupported)?);
+        }
+
+        Ok(times)
+    }
+
+    /// Runs one batch of nonces
+    /// If a winner is found the nonce and its hash are part of the result
+    /// A lost device is rebuilt and the batch retried once, which is
+    /// reported through BatchResult::recovered.
+    pub async fn run_batch(&mut self, words: &HeaderWords) -> Result<BatchResult> {
+        let span = self.full_span(0);
+        self.run_span(words, span).await
+    }
+
+    /// Sets the target and runs one batch of the header from nonce 0
+    pub async fn run_batch_header(
+        &mut self,
+        header: &BlockHeader,
+        target: &[u8; 32],
+    ) -> Result<BatchResult> {
+        self.set_target(target);
+        self.run_batch(&HeaderWords::from_header(header)).await
+    }
+
+    /// Mines the nonces in [start, end) of a header, end is at most 2^32
+    /// Runs as many batches as needed and stops at the first winner or
+    /// when cancelled. hashes_tried reports how much of the range was covered.
+    pub async fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> Result<BatchResult> {
+        if nonces.start >= nonces.end || nonces.end > NONCE_SPACE {
+            return Err(MinerError::InvalidNonceRange {
+                start: nonces.start,
+                end: nonces.end,
+            });
+        }
+
+        let start = Instant::now();
+        let mut total = BatchResult {
+            nonce: None,
+            hash: None,
+            hits: 0,
+            shares: 0,
+            best: None,
+            hashes_tried: 0,
+            elapsed: Duration::ZERO,
+            gpu_time: self.supports_timestamps().then_some(Duration::ZERO),
+            recovered: false,
+            rejected: false,
+        };
+
+        let mut base = nonces.start;
+        while base < nonces.end && !self.cancel.is_cancelled() {
+            let count = (nonces.end - base).min(self.get_hashes_per_batch() as u64);
+            let span = NonceSpan {
+                base: base as u32,
+                count: count as u32,
+            };
+            let res = self.run_span(words, span).await?;
+
+            total.hits += res.hits;
+            total.shares += res.shares;
+            total.best = lowest(total.best, res.best);
+            total.hashes_tried += res.hashes_tried;
+            total.gpu_time = total
+                .gpu_time
+                .zip(res.gpu_time)
+                .map(|(sum, time)| sum + time);
+            total.recovered |= res.recovered;
+            total.rejected |= res.rejected;
+            if res.is_found() {
+                total.nonce = res.nonce;
+                total.hash = res.hash;
+                break;
+            }
+            base += res.hashes_tried;
+        }
+
+        total.elapsed = start.elapsed();
+        Ok(total)
+    }
+
+    /// Mines a header until it meets the target
+    /// Walks the whole nonce space, of every version with version_rolling,
+    /// then rolls the timestamp with a TimestampRoller, between min_time
+    /// and max_time and never past the 2 hour future rule. A timestamp the
+    /// wall clock overtook is replaced by the current time mid-search.
+    /// on_stats is called every stats_interval. The cancel handle stops the
+    /// run between batches.
+    pub async fn run_until_found<F>(
+        &mut self,
+        words: &HeaderWords,
+        options: &RunOptions,
+        mut on_stats: F,
+    ) -> Result<RunOutcome>
+    where
+        F: FnMut(&MiningStats),
+    {
+        let mut header = words.to_header();
+        let mut roller = TimestampRoller::new(header.time, options.min_time.unwrap_or(0));
+        if let Some(max_time) = options.max_time {
+            roller.set_max_time(max_time);
+        }
+
+        let start = Instant::now();
+        let mut last_stats = start;
+        let mut hashes = 0;
+        let mut exhausted_headers = 0;
+
+        let max_hashes = options.max_hashes.unwrap_or(u64::MAX);
+        let deadline = match (options.max_duration, options.deadline) {
+            (Some(duration), Some(deadline)) => Some(deadline.min(start + duration)),
+            (duration, deadline) => deadline.or(duration.map(|duration| start + duration)),
+        };
+
+        // The header's own version bits come first, then every other value
+        let first_bits = header.version_bits();
+        let other_bits =
+            (0..=u16::MAX).filter(|&bits| options.version_rolling && bits != first_bits);
+        let version_bits: Vec<u16> = std::iter::once(first_bits).chain(other_bits).collect();
+
+        'time: while roller.is_allowed() {
+            let timestamp = roller.time();
+            header.set_time(timestamp);
+
+            for &bits in &version_bits {
+                let words = HeaderWords::from_header(&header.with_version_bits(bits));
+                let mut base = 0;
+                while base < NONCE_SPACE {
+                    if self.cancel.is_cancelled() {
+                        return Ok(RunOutcome::Cancelled);
+                    }
+                    let past_deadline = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                    if hashes >= max_hashes || past_deadline {
+                        return Ok(RunOutcome::LimitReached);
+                    }
+
+                    let count = (NONCE_SPACE - base)
+                        .min(self.get_hashes_per_batch() as u64)
+                        .min(max_hashes - hashes);
+                    let span = NonceSpan {
+                        base: base as u32,
+                        count: count as u32,
+                    };
+                    let res = self.run_span(&words, span).await?;
+                    // Less than count if the batch size was halved on the way
+                    hashes += res.hashes_tried;
+
+                    if let (Some(nonce), Some(hash)) = (res.nonce, res.hash) {
+                        let words = words.with_nonce(nonce);
+                        return Ok(RunOutcome::Found(Solution { words, nonce, hash }));
+                    }
+                    base += res.hashes_tried;
+
+                    // Running out of nonces is always reported, the header
+                    // is rolled right after
+                    let exhausted = base >= NONCE_SPACE;
+                    exhausted_headers += exhausted as u64;
+                    if exhausted || last_stats.elapsed() >= options.stats_interval {
+                        last_stats = Instant::now();
+                        on_stats(&MiningStats {
+                            hashes,
+                            elapsed: start.elapsed(),
+                            timestamp,
+                            version_bits: bits,
+                            header_nonces: base,
+                            exhausted_headers,
+                        });
+                    }
+
+                    if roller.refresh().is_some() {
+                        continue 'time;
+                    }
+                }
+            }
+
+            if roller.roll().is_none() {
+                break;
+            }
+        }
+
+        Ok(RunOutcome::Exhausted)
+    }
+
+    // Span covering a full batch starting at base
+    fn full_span(&self, base: u32) -> NonceSpan {
+        NonceSpan {
+            base,
+            count: self.get_hashes_per_batch(),
+        }
+    }
+
+    /// Mines the same nonce range of several headers in a single dispatch
+    /// Meant for jobs with small nonce budgets that would not fill a batch
+    /// on their own. The range length times the number of jobs has to fit
+    /// in one batch. Results are returned in job order.
+    pub async fn run_multi_batch(
+        &mut self,
+        jobs: &[HeaderWords],
+        nonces: Range<u64>,
+    ) -> Result<Vec<BatchResult>> {
+        if jobs.is_empty() || jobs.len() > MAX_JOBS_PER_BATCH as usize {
+            return Err(MinerError::InvalidJobCount {
+                count: jobs.len(),
+                max: MAX_JOBS_PER_BATCH,
+            });
+        }
+        if nonces.start >= nonces.end || nonces.end > NONCE_SPACE {
+            return Err(MinerError::InvalidNonceRange {
+                start: nonces.start,
+                end: nonces.end,
+            });
+        }
+
+        // Every pass covers the same window of each job
+        let count = nonces.end - nonces.start;
+        let max_count = self.pass_window(jobs.len()) as u64 * self.passes_per_batch as u64;
+        if count > max_count {
+            return Err(MinerError::InvalidBatchSize(format!(
+                "{} jobs of {count} nonces don't fit in a batch of {} hashes",
+                jobs.len(),
+                self.get_hashes_per_batch()
+            )));
+        }
+
+        let span = NonceSpan {
+            base: nonces.start as u32,
+            count: count as u32,
+        };
+        let jobs: Vec<[u32; 32]> = jobs.iter().map(|words| **words).collect();
+        Ok(self.run_jobs(&jobs, span).await?.results)
+    }
+
+    /// Mines the same nonce range of the header at several versions in one
+    /// dispatch, versions being values of the bits of the version mask
+    /// Every version is a job of its own with its own midstate, like in
+    /// run_multi_batch. Results are returned in version order.
+    pub async fn run_version_batch(
+        &mut self,
+        words: &HeaderWords,
+        versions: Range<u32>,
+        nonces: Range<u64>,
+    ) -> Result<Vec<BatchResult>> {
+        let jobs = self.version_jobs(words, versions)?;
+        self.run_multi_batch(&jobs, nonces).await
+    }
+
+    /// Runs one batch split evenly over several versions, like
+    /// run_version_batch, and returns every hit of each in version order
+    /// Hits are dropped like in run_batch_all once cancelled.
+    pub async fn run_version_batch_all(
+        &mut self,
+        words: &HeaderWords,
+        versions: Range<u32>,
+    ) -> Result<Vec<BatchHits>> {
+        let jobs = self.version_jobs(words, versions)?;
+        if jobs.is_empty() || jobs.len() > MAX_JOBS_PER_BATCH as usize {
+            return Err(MinerError::InvalidJobCount {
+                count: jobs.len(),
+                max: MAX_JOBS_PER_BATCH,
+            });
+        }
+        let span = NonceSpan {
+            base: 0,
+            count: self.pass_window(jobs.len()) * self.passes_per_batch,
+        };
+        let raw: Vec<[u32; 32]> = jobs.iter().map(|words| **words).collect();
+        let output = self.run_jobs(&raw, span).await?;
+
+        Ok(jobs
+            .iter()
+            .zip(output.results)
+            .enumerate()
+            .map(|(job, (words, result))| {
+                let nonces = output
+                    .winners
+                    .iter()
+                    .filter(|&&(hit_job, _)| hit_job as usize == job)
+                    .map(|&(_, nonce)| nonce);
+                self.sort_hits(words, nonces, result)
+            })
+            .collect())
+    }
+
+    // The header at every value of versions, rolled within the version mask
+    fn version_jobs(&self, words: &HeaderWords, versions: Range<u32>) -> Result<Vec<HeaderWords>> {
+        let values = 1u64 << self.version_mask.count_ones();
+        if versions.end as u64 > values {
+            return Err(MinerError::InvalidHeader(format!(
+                "the version mask {:#010x} leaves {values} versions to roll, got {versions:?}",
+                self.version_mask
+            )));
+        }
+        Ok(versions
+            .map(|bits| words.with_masked_version_bits(self.version_mask, bits))
+            .collect())
+    }
+
+    /// Runs one batch and returns every nonce that met the target
+    /// Useful at share difficulty, where a batch often holds several shares.
+    /// A batch cancelled while in flight still runs to the end on the GPU,
+    /// but returns no hits, they belong to work that was replaced.
+    pub async fn run_batch_all(&mut self, words: &HeaderWords) -> Result<BatchHits> {
+        let span = self.full_span(0);
+        let mut output = self.run_jobs(slice::from_ref(&**words), span).await?;
+        let nonces: Vec<u32> = output.winners.iter().map(|&(_, nonce)| nonce).collect();
+        Ok(self.sort_hits(words, nonces.into_iter(), output.results.remove(0)))
+    }
+
+    // Sorts the hits of one job into winners and shares, checked on the CPU
+    fn sort_hits(
+        &self,
+        words: &HeaderWords,
+        nonces: impl Iterator<Item = u32>,
+        result: BatchResult,
+    ) -> BatchHits {
+        let cancelled = self.cancel.is_cancelled();
+        let mut hits: Vec<Winner> = nonces
+            .filter(|_| !cancelled)
+            .map(|nonce| Winner {
+                nonce,
+                hash: hash_with_nonce(&words.with_nonce(nonce).to_bytes()),
+            })
+            .collect();
+        hits.sort_unstable_by_key(|winner| winner.nonce);
+
+        let (winners, shares) =
+            hits.iter()
+                .fold((vec![], vec![]), |(mut winners, mut shares), hit| {
+                    if self.is_winner(&hit.hash) {
+                        winners.push(*hit);
+                    }
+                    if self.is_share(&hit.hash) {
+                        shares.push(*hit);
+                    }
+                    (winners, shares)
+                });
+
+        let rejected = hits
+            .iter()
+            .filter(|hit| !self.is_winner(&hit.hash) && !self.is_share(&hit.hash))
+            .count();
+        if rejected > 0 {
+            eprintln!(
+                "Warning: dropped {rejected} GPU hits that don't meet the target, \
+                 the kernel may be miscompiled."
+            );
+        }
+
+        BatchHits {
+            result,
+            winners,
+            shares,
+        }
+    }
+
+    // Runs a single dispatch for one header
+    async fn run_span(&mut self, words: &[u32; 32], span: NonceSpan) -> Result<BatchResult> {
+        let mut output = self.run_jobs(slice::from_ref(words), span).await?;
+        Ok(output.results.remove(0))
+    }
+
+    // Runs a single dispatch, rebuilding the device once if it was lost
+    // The span shrinks with the batch size if the GPU runs out of memory,
+    // hashes_tried of the results tells how much was covered.
+    async fn run_jobs(&mut self, jobs: &[[u32; 32]], mut span: NonceSpan) -> Result<BatchOutput> {
+        let recovered = self.recover_if_lost().await?;
+
+        self.wait_for_throttle().await;
+        let start = Instant::now();
+        let submission = self.submit_shrinking(jobs, &mut span).await?;
+        let mut output = match self.read_batch(jobs, 0, span, submission, start).await {
+            Ok(output) => output,
+            Err(_) if !recovered && self.is_device_lost() => {
+                self.recover().await?;
+
+                let start = Instant::now();
+                let submission = self.submit_shrinking(jobs, &mut span).await?;
+                let mut output = self.read_batch(jobs, 0, span, submission, start).await?;
+                for res in &mut output.results {
+                    res.recovered = true;
+                }
+                output
+            }
+            Err(err) => return Err(err),
+        };
+
+        for res in &mut output.results {
+            res.recovered |= recovered;
+        }
+        self.record_batch(&output.results);
+        Ok(output)
+    }
+
+    /// Runs a stream of batches, one per job
+    /// The next batch is already computing while the previous result is
+    /// read back. on_result receives the words and result of every batch
+    /// in order and returns false to stop early.
+    /// Stops without queueing more work once the cancel handle is triggered.
+    pub async fn run_batches<I, F>(&mut self, jobs: I, mut on_result: F) -> Result<()>
+    where
+        I: IntoIterator<Item = HeaderWords>,
+        F: FnMut(&HeaderWords, BatchResult) -> bool,
+    {
+        let mut jobs = jobs.into_iter();
+        if self.cancel.is_cancelled() {
+            return Ok(());
+        }
+        let Some(mut current) = jobs.next() else {
+            return Ok(());
+        };
+
+        let mut recovered = self.recover_if_lost().await?;
+        // Job that was queued when the device got lost
+        let mut requeued: Option<HeaderWords> = None;
+
+        // Throttling needs the GPU idle between batches
+        let pipelined = self.throttle == Throttle::None;
+
+        let span = self.full_span(0);
+        let mut slot = 0;
+        self.wait_for_throttle().await;
+        let mut start = Instant::now();
+        let mut submission = self.submit_batch(slice::from_ref(&*current), slot, span)?;
+
+        loop {
+            // Queue up the next batch before waiting for the current one
+            let mut next = if self.cancel.is_cancelled() || !pipelined {
+                None
+            } else {
+                let words = requeued.take().or_else(|| jobs.next());
+                self.queue_next(words, 1 - slot, span).await?
+            };
+
+            let read = self.read_batch(slice::from_ref(&*current), slot, span, submission, start);
+            let mut res = match read.await {
+                Ok(mut output) => output.results.remove(0),
+                Err(_) if !recovered && self.is_device_lost() => {
+                    // Both in-flight batches went down with the device
+                    self.recover().await?;
+                    recovered = true;
+                    requeued = next.map(|(words, _, _)| words);
+
+                    start = Instant::now();
+                    submission = self.submit_batch(slice::from_ref(&*current), slot, span)?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            res.recovered = recovered;
+            recovered = false;
+            self.record_batch(slice::from_ref(&res));
+
+            let keep_going = on_result(&current, res) && !self.cancel.is_cancelled();
+            if keep_going && !pipelined {
+                let words = requeued.take().or_else(|| jobs.next());
+                next = self.queue_next(words, 1 - slot, span).await?;
+            }
+
+            let Some((words, next_start, next_submission)) = next else {
+                return Ok(());
+            };
+
+            if !keep_going {
+                // Let the queued batch finish so its staging buffer is free
+                self.wait_for_idle(next_submission).await;
+                return Ok(());
+            }
+
+            current = words;
+            start = next_start;
+            submission = next_submission;
+            slot = 1 - slot;
+        }
+    }
+
+    // Submits the next job of run_batches once the throttle allows it
+    async fn queue_next(
+        &mut self,
+        words: Option<HeaderWords>,
+        slot: usize,
+        span: NonceSpan,
+    ) -> Result<Option<(HeaderWords, Instant, wgpu::SubmissionIndex)>> {
+        let Some(words) = words else {
+            return Ok(None);
+        };
+        self.wait_for_throttle().await;
+        let start = Instant::now();
+        let submission = self.submit_batch(slice::from_ref(&*words), slot, span)?;
+        Ok(Some((words, start, submission)))
+    }
+
+    // Submits a batch to the first staging buffer, halving the batch size
+    // and retrying for as long as the GPU runs out of memory
+    async fn submit_shrinking(
+        &mut self,
+        jobs: &[[u32; 32]],
+        span: &mut NonceSpan,
+    ) -> Result<wgpu::SubmissionIndex> {
+        loop {
+            self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+            let submission = self.submit_batch(jobs, 0, *span);
+            let out_of_memory =
+                self.device.pop_error_scope().await.is_some() | self.inject_out_of_memory();
+            let submission = submission?;
+            if !out_of_memory {
+                return Ok(submission);
+            }
+
+            // Every pass covers less of each job now
+            self.halve_batch_size()?;
+            span.count = span
+                .count
+                .min(self.pass_window(jobs.len()) * self.passes_per_batch);
+        }
+    }
+
+    // True for the next injected_ooms submissions
+    #[cfg(test)]
+    fn inject_out_of_memory(&mut self) -> bool {
+        let inject = self.injected_ooms > 0;
+        self.injected_ooms = self.injected_ooms.saturating_sub(1);
+        inject
+    }
+
+    #[cfg(not(test))]
+    fn inject_out_of_memory(&mut self) -> bool {
+        false
+    }
+
+    // Halves the batch size after the GPU ran out of memory, fails once it
+    // is down to a single workgroup
+    fn halve_batch_size(&mut self) -> Result<()> {
+        if self.batch_size <= self.wg_size {
+            return Err(MinerError::OutOfMemory);
+        }
+        self.batch_size /= 2;
+        eprintln!(
+            "Warning: GPU ran out of memory, batch_size halved to {}.",
+            self.batch_size
+        );
+        Ok(())
+    }
+
+    // Sends midstate and second block of every job to the header buffer
+    // Writes are ordered with submissions, so the in-flight batch is unaffected
+    fn upload_jobs(&mut self, jobs: &[[u32; 32]]) {
+        let mut data = Vec::with_capacity(jobs.len() * 24);
+        for words in jobs {
+            data.extend_from_slice(&sha256_midstate(words));
+            data.extend_from_slice(&words[16..20]);
+            // Padding is the same for every 80 byte header, so the GPU always
+            // hashes exactly what the CPU verifies
+            data.extend_from_slice(&SECOND_BLOCK_PADDING);
+        }
+        self.queue
+            .write_buffer(&self.buffers.header, 0, bytemuck::cast_slice(&data));
+
+        self.uploaded_jobs.clear();
+        self.uploaded_jobs.extend_from_slice(jobs);
+    }
+
+    // Seconds every job's timestamp moved since the jobs were uploaded,
+    // None if anything else changed and they need uploading
+    // The nonce is replaced on the GPU anyway. Custom shaders may not
+    // apply the roll, so they always get the jobs uploaded.
+    fn time_roll(&self, jobs: &[[u32; 32]]) -> Option<u32> {
+        if self.custom_shaders || jobs.len() != self.uploaded_jobs.len() {
+            return None;
+        }
+        // Word 17 holds the little-endian timestamp read big-endian, so the
+        // difference is taken between the timestamps and not the words
+        let seconds = |words: &[u32; 32]| words[17].swap_bytes();
+        let time_roll = seconds(&jobs[0]).wrapping_sub(seconds(&self.uploaded_jobs[0]));
+        jobs.iter()
+            .zip(&self.uploaded_jobs)
+            .all(|(words, uploaded)| {
+                words[..17] == uploaded[..17]
+                    && words[18] == uploaded[18]
+                    && seconds(words).wrapping_sub(seconds(uploaded)) == time_roll
+            })
+            .then_some(time_roll)
+    }
+
+    // Encodes and submits a batch writing its results to the given staging buffer
+    // Every job is mined over the same span of nonces. Fails if the span
+    // needs more passes than the params buffer has room for.
+    fn submit_batch(
+        &mut self,
+        jobs: &[[u32; 32]],
+        slot: usize,
+        span: NonceSpan,
+    ) -> Result<wgpu::SubmissionIndex> {
+        // The span is split into consecutive windows, one pass each
+        let window = self.pass_window(jobs.len());
+        let passes = span.count.div_ceil(window).max(1);
+        if passes > MAX_PASSES_PER_BATCH {
+            return Err(MinerError::InvalidBatchSize(format!(
+                "{} jobs of {} nonces need {passes} passes, at most {MAX_PASSES_PER_BATCH} fit",
+                jobs.len(),
+                span.count
+            )));
+        }
+
+        // Jobs that only rolled their timestamp get it patched in the shader
+        let time_roll = match self.time_roll(jobs) {
+            Some(time_roll) => time_roll,
+            None => {
+                self.upload_jobs(jobs);
+                0
+            }
+        };
+
+        let max_workgroups = self.device.limits().max_compute_workgroups_per_dimension;
+
+        let mut params = vec![0u32; (passes as u64 * PARAMS_STRIDE / 4) as usize];
+        let mut dispatches = Vec::with_capacity(passes as usize);
+        for (pass, params) in params
+            .chunks_exact_mut((PARAMS_STRIDE / 4) as usize)
+            .enumerate()
+        {
+            let offset = pass as u32 * window;
+            let count = window.min(span.count - offset);
+
+            // Only launch enough workgroups to cover the window of every job
+            let hashes = count as u64 * jobs.len() as u64;
+            let threads = hashes.div_ceil(self.nonces_per_thread as u64) as u32;
+            dispatches.push(
+                dispatch_size(threads.div_ceil(self.wg_size), max_workgroups)
+                    .expect("Batch sizes are checked to fit a dispatch"),
+            );
+            params[..5].copy_from_slice(&[
+                span.base + offset,
+                count,
+                jobs.len() as u32,
+                threads,
+                time_roll,
+            ]);
+        }
+        self.queue
+            .write_buffer(&self.buffers.params, 0, bytemuck::cast_slice(&params));
+        let outputs_size = OUTPUT_SIZE * jobs.len() as u64;
+
+        // Command encoder
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Command Encoder"),
+            });
+
+        // Reset the found flag and hit counter from the previous batch
+        encoder.clear_buffer(&self.buffers.output, 0, None);
+        encoder.clear_buffer(&self.buffers.hits, 0, Some(8));
+        encoder.clear_buffer(&self.buffers.best, 0, Some(8));
+
+        // Run the compute shader, results of every pass add up in the
+        // same buffers and the timestamps span all of them
+        let last = dispatches.len() - 1;
+        for (pass, (x, y, z)) in dispatches.into_iter().enumerate() {
+            let timestamp_writes = self
+                .timestamps
+                .as_ref()
+                .filter(|_| pass == 0 || pass == last)
+                .map(|timestamps| wgpu::ComputePassTimestampWrites {
+                    query_set: &timestamps.query_set,
+                    beginning_of_pass_write_index: (pass == 0).then_some(0),
+                    end_of_pass_write_index: (pass == last).then_some(1),
+                });
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Compute Pass"),
+                timestamp_writes,
+            });
+            compute_pass.set_pipeline(&self.compute_pipeline);
+            let offset = (pass as u64 * PARAMS_STRIDE) as u32;
+            compute_pass.set_bind_group(0, &self.bind_group, &[offset]);
+            compute_pass.dispatch_workgroups(x, y, z);
+        }
+
+        // Copy results to staging buffer to read from CPU
+        encoder.copy_buffer_to_buffer(
+            &self.buffers.output,
+            0,
+            &self.buffers.staging[slot],
+            0,
+            outputs_size,
+        );
+
+        // Timestamps go right after the space for every output
+        if let Some(timestamps) = &self.timestamps {
+            encoder.resolve_query_set(&timestamps.query_set, 0..2, &timestamps.resolve_buffer, 0);
+            encoder.copy_buffer_to_buffer(
+                &timestamps.resolve_buffer,
+                0,
+                &self.buffers.staging[slot],
+                OUTPUTS_SIZE,
+                TIMESTAMPS_SIZE,
+            );
+        }
+
+        // Hit list goes last
+        encoder.copy_buffer_to_buffer(
+            &self.buffers.hits,
+            0,
+            &self.buffers.staging[slot],
+            OUTPUTS_SIZE + TIMESTAMPS_SIZE,
+            HITS_SIZE,
+        );
+        encoder.copy_buffer_to_buffer(
+            &self.buffers.best,
+            0,
+            &self.buffers.staging[slot],
+            OUTPUTS_SIZE + TIMESTAMPS_SIZE + HITS_SIZE,
+            BEST_SIZE,
+        );
+
+        Ok(self.queue.submit(Some(encoder.finish())))
+    }
+
+    // Waits for a submitted batch and maps its staging buffer
+    // Returns one result per job, the hit list and the GPU time of the
+    // batch if timestamps are supported
+    async fn read_batch(
+        &self,
+        jobs: &[[u32; 32]],
+        slot: usize,
+        span: NonceSpan,
+        submission: wgpu::SubmissionIndex,
+        start: Instant,
+    ) -> Result<BatchOutput> {
+        let staging_buffer = &self.buffers.staging[slot];
+        let slice = staging_buffer.slice(..);
+
+        let (sender, receiver) = oneshot::channel();
+
+        slice.map_async(wgpu::MapMode::Read, move |res| {
+            let _ = sender.send(res);
+        });
+        self.wait_for_map(submission, receiver).await?;
+
+        // Everything is read straight from the mapped range, only the few
+        // words of each job's output are copied out before unmapping
+        let data = slice.get_mapped_range();
+        let outputs: Vec<(u32, u32, u32, u32)> = data[..OUTPUT_SIZE as usize * jobs.len()]
+            .chunks_exact(OUTPUT_SIZE as usize)
+            .map(|output| match bytemuck::cast_slice::<u8, u32>(output) {
+                &[found, nonce, hits, shares, ..] => (found, nonce, hits, shares),
+                _ => unreachable!("Each output holds eight words"),
+            })
+            .collect();
+
+        let gpu_time = self.timestamps.as_ref().map(|timestamps| {
+            let timestamps_range = OUTPUTS_SIZE as usize..(OUTPUTS_SIZE + TIMESTAMPS_SIZE) as usize;
+            let ticks = match bytemuck::cast_slice::<u8, u64>(&data[timestamps_range]) {
+                &[begin, end] => end.saturating_sub(begin),
+                _ => unreachable!("Two timestamps are resolved"),
+            };
+            Duration::from_nanos((ticks as f64 * timestamps.period as f64) as u64)
+        });
+
+        let hits_start = (OUTPUTS_SIZE + TIMESTAMPS_SIZE) as usize;
+        let best_start = hits_start + HITS_SIZE as usize;
+        let winners = read_hit_list(&data[hits_start..best_start], MAX_HITS_PER_BATCH);
+        let candidates = read_hit_list(&data[best_start..], MAX_BEST_CANDIDATES);
+
+        drop(data);
+        staging_buffer.unmap();
+
+        let elapsed = start.elapsed();
+        let results = jobs
+            .iter()
+            .zip(outputs)
+            .enumerate()
+            .map(|(job, (words, (found, nonce, hits, shares)))| {
+                let mut nonce = (found != 0).then_some(nonce);
+                let mut hash = nonce.map(|nonce| {
+                    let mut words = *words;
+                    words[19] = nonce;
+                    hash_with_nonce(&sha256_words_to_header(&words))
+                });
+
+                // Never report a winner the CPU doesn't agree with
+                let rejected = hash.is_some_and(|hash| !self.is_winner(&hash));
+                if rejected {
+                    eprintln!(
+                        "Warning: GPU reported nonce {:#010x} which doesn't meet the target, \
+                         the kernel may be miscompiled.",
+                        nonce.unwrap_or_default()
+                    );
+                    nonce = None;
+                    hash = None;
+                }
+
+                // Lowest of the candidates recorded for this job
+                let best = candidates
+                    .iter()
+                    .filter(|&&(candidate_job, _)| candidate_job == job as u32)
+                    .map(|&(_, nonce)| {
+                        let mut words = *words;
+                        words[19] = nonce;
+                        Winner {
+                            nonce,
+                            hash: hash_with_nonce(&sha256_words_to_header(&words)),
+                        }
+                    })
+                    .min_by(|a, b| a.hash.iter().rev().cmp(b.hash.iter().rev()));
+
+                BatchResult {
+                    nonce,
+                    hash,
+                    hits,
+                    shares,
+                    best,
+                    hashes_tried: span.count as u64,
+                    elapsed,
+                    gpu_time,
+                    recovered: false,
+                    rejected,
+                }
+            })
+            .collect();
+
+        Ok(BatchOutput {
+            results,
+            gpu_time,
+            winners,
+        })
+    }
+}
+
+// WGSL sources the mining shader is assembled from
+#[derive(Debug, Clone)]
+struct ShaderSources {
+    sha256: String,
+    mine: String,
+}
+
+impl Default for ShaderSources {
+    fn default() -> Self {
+        ShaderSources::generate(ShaderOptions::default())
+    }
+}
+
+impl ShaderSources {
+    // Embedded shaders with computeHash generated for the options
+    fn generate(options: ShaderOptions) -> Self {
+        ShaderSources {
+            sha256: codegen::sha256_source(options),
+            mine: include_str!("mine.wgsl").to_string(),
+        }
+    }
+
+    // sha256.wgsl and mine.wgsl in dir replace the embedded ones,
+    // a missing file keeps the embedded version
+    fn load(dir: &Path) -> Result<Self> {
+        let mut sources = ShaderSources::default();
+        for (name, source) in [
+            ("sha256.wgsl", &mut sources.sha256),
+            ("mine.wgsl", &mut sources.mine),
+        ] {
+            let path = dir.join(name);
+            if path.exists() {
+                *source = fs::read_to_string(&path).map_err(|source| MinerError::Io {
+                    path: path.clone(),
+                    source,
+                })?;
+                println!("Loaded shader from {}", path.display());
+            }
+        }
+        Ok(sources)
+    }
+}
+
+/// Operations shared by the GPU and CPU miners
+/// Lets callers swap backends, e.g. to validate one against the other.
+pub trait MinerBackend {
+    /// Mines one batch of the header starting at nonce 0
+    fn run_batch(&mut self, words: &HeaderWords) -> impl Future<Output = Result<BatchResult>>;
+
+    /// Mines the nonces in [start, end) until a batch has a winner
+    fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> impl Future<Output = Result<BatchResult>>;
+
+    /// Picks the fastest settings for the hardware
+    fn autotune(&mut self) -> impl Future<Output = ()>;
+
+    /// Running totals across all batches
+    fn stats(&self) -> MinerStats;
+
+    /// Sets the big-endian 256-bit target
+    fn set_target(&mut self, target: &[u8; 32]);
+
+    /// Number of nonces tested by one batch
+    fn get_hashes_per_batch(&self) -> u32;
+}
+
+impl MinerBackend for GpuMiner {
+    async fn run_batch(&mut self, words: &HeaderWords) -> Result<BatchResult> {
+        GpuMiner::run_batch(self, words).await
+    }
+
+    async fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> Result<BatchResult> {
+        GpuMiner::run_batch_range(self, words, nonces).await
+    }
+
+    async fn autotune(&mut self) {
+        GpuMiner::autotune(self).await
+    }
+
+    fn stats(&self) -> MinerStats {
+        GpuMiner::stats(self)
+    }
+
+    fn set_target(&mut self, target: &[u8; 32]) {
+        GpuMiner::set_target(self, target)
+    }
+
+    fn get_hashes_per_batch(&self) -> u32 {
+        GpuMiner::get_hashes_per_batch(self)
+    }
+}
+
+/// Miner built with a CPU fallback, see GpuMinerBuilder::build_or_cpu
+pub enum Miner {
+    Gpu(Box<GpuMiner>),
+    Cpu(CpuMiner),
+}
+
+impl Miner {
+    /// True if no GPU was available and the miner runs on the CPU
+    pub fn is_cpu(&self) -> bool {
+        matches!(self, Miner::Cpu(_))
+    }
+}
+
+impl MinerBackend for Miner {
+    async fn run_batch(&mut self, words: &HeaderWords) -> Result<BatchResult> {
+        match self {
+            Miner::Gpu(miner) => MinerBackend::run_batch(miner.as_mut(), words).await,
+            Miner::Cpu(miner) => MinerBackend::run_batch(miner, words).await,
+        }
+    }
+
+    async fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> Result<BatchResult> {
+        match self {
+            Miner::Gpu(miner) => MinerBackend::run_batch_range(miner.as_mut(), words, nonces).await,
+            Miner::Cpu(miner) => MinerBackend::run_batch_range(miner, words, nonces).await,
+        }
+    }
+
+    async fn autotune(&mut self) {
+        match self {
+            Miner::Gpu(miner) => MinerBackend::autotune(miner.as_mut()).await,
+            Miner::Cpu(miner) => MinerBackend::autotune(miner).await,
+        }
+    }
+
+    fn stats(&self) -> MinerStats {
+        match self {
+            Miner::Gpu(miner) => MinerBackend::stats(miner.as_ref()),
+            Miner::Cpu(miner) => MinerBackend::stats(miner),
+        }
+    }
+
+    fn set_target(&mut self, target: &[u8; 32]) {
+        match self {
+            Miner::Gpu(miner) => MinerBackend::set_target(miner.as_mut(), target),
+            Miner::Cpu(miner) => MinerBackend::set_target(miner, target),
+        }
+    }
+
+    fn get_hashes_per_batch(&self) -> u32 {
+        match self {
+            Miner::Gpu(miner) => MinerBackend::get_hashes_per_batch(miner.as_ref()),
+            Miner::Cpu(miner) => MinerBackend::get_hashes_per_batch(miner),
+        }
+    }
+}
+
+// Builds the pipeline from shaders in dir, failing if they don't load,
+// parse or validate
+async fn create_custom_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    cache: Option<&PipelineCache>,
+    dir: &Path,
+    wg_size: u32,
+    nonces_per_thread: u32,
+) -> Result<(wgpu::ShaderModule, wgpu::ComputePipeline)> {
+    let sources = ShaderSources::load(dir)?;
+
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let shader = create_shader(device, &sources);
+    let pipeline =
+        create_compute_pipeline(device, layout, &shader, cache, wg_size, nonces_per_thread);
+
+    match device.pop_error_scope().await {
+        Some(error) => Err(MinerError::InvalidShader(error)),
+        None => Ok((shader, pipeline)),
+    }
+}
+
+fn create_shader(device: &wgpu::Device, sources: &ShaderSources) -> wgpu::ShaderModule {
+    let combined_shader = format!("{}\n{}", sources.sha256, sources.mine);
+
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Mining Shader"),
+        source: wgpu::ShaderSource::Wgsl(combined_shader.into()),
+    })
+}
+
+/// Hashes a full 80 byte bitcoin header on CPU for verification
+pub fn hash_with_nonce(header: &[u8; 80]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(header)).into()
+}
+
+/// Adds padding to the header to make it 128 bytes (1024 bits)
+pub fn sha256_preprocess(header: &[u8; 80]) -> [u8; 128] {
+    // Initialize to 128 bytes of 0
+    let mut padded = [0u8; 128];
+    // First 80 bytes are from the original header
+    padded[0..80].copy_from_slice(header);
+
+    // Add a byte with 1
+    padded[80] = 0x80;
+
+    // Add the length 640 which fits in 2 bytes
+    padded[126] = 0x02;
+    padded[127] = 0x80;
+
+    padded
+}
+
+/// Parse the 32x32-bit words, expects 128 byte header
+pub fn sha256_parse_words(header: &[u8; 128]) -> [u32; 32] {
+    let mut words = [0u32; 32];
+    // Words are chunks of 4 byte = 32 bit
+    for (i, chunk) in header.chunks_exact(4).enumerate() {
+        words[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+// Splits the big-endian target into words, most significant first
+fn target_to_words(target: &[u8; 32]) -> [u32; 8] {
+    let mut words = [0u32; 8];
+    for (word, chunk) in words.iter_mut().zip(target.chunks_exact(4)) {
+        *word = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// Compresses the first 64 bytes of the header
+/// The result is constant for every nonce and only has to be computed
+/// once per header.
+pub fn sha256_midstate(words: &[u32; 32]) -> [u32; 8] {
+    let mut block = [0u8; 64];
+    for (chunk, word) in block.chunks_exact_mut(4).zip(words.iter()) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+
+    let mut state = SHA256_INITIAL_HASH;
+    sha2::compress256(&mut state, &[block.into()]);
+    state
+}
+
+// Initial hash values for sha256, same as in sha256.wgsl
+const SHA256_INITIAL_HASH: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// Words 20-31 of every padded header: the 0x80 marker and the 640 bit length
+const SECOND_BLOCK_PADDING: [u32; 12] = [0x80000000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 640];
+
+/// Reconstructs the 80 byte header from parsed words
+pub fn sha256_words_to_header(words: &[u32; 32]) -> [u8; 80] {
+    let mut header = [0u8; 80];
+    for (chunk, word) in header.chunks_exact_mut(4).zip(words.iter()) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn gpu_setup_works() {
+        let res = setup_gpu(AdapterOptions::default()).await;
+        assert!(res.is_ok());
+
+        let (device, ..) = res.unwrap();
+        assert!(
+            device.limits().max_buffer_size > 0,
+            "Successfully got limts"
+        )
+    }
+
+    #[tokio::test]
+    async fn buffers_created_correct_size() {
+        let (device, ..) = setup_gpu(AdapterOptions::default()).await.unwrap();
+        let batch_size = 2048;
+        let buffers = create_buffers(&device, batch_size)
+            .await
+            .expect("Buffer creation failed.");
+
+        assert_eq!(buffers.header.size(), JOB_SIZE * MAX_JOBS_PER_BATCH as u64);
+        assert_eq!(buffers.target.size(), TARGETS_SIZE);
+        assert_eq!(buffers.output.size(), OUTPUTS_SIZE);
+        assert_eq!(
+            buffers.params.size(),
+            PARAMS_STRIDE * MAX_PASSES_PER_BATCH as u64
+        );
+        assert_eq!(buffers.hits.size(), HITS_SIZE);
+        for staging_buffer in &buffers.staging {
+            assert_eq!(staging_buffer.size(), STAGING_SIZE);
+        }
+    }
+
+    #[tokio::test]
+    async fn buffer_creation_fails_invalid_batch_size() {
+        let (device, ..) = setup_gpu(AdapterOptions::default()).await.unwrap();
+
+        let res = create_buffers(&device, u32::MAX).await;
+        assert!(res.is_err(), "u32 MAX should cause an error.");
+
+        let res = create_buffers(&device, 0).await;
+        assert!(
+            res.is_err(),
+            "Buffer creation should fail with 0 batch size."
+        );
+    }
+
+    #[tokio::test]
+    async fn buffers_have_correct_flags() {
+        let (device, ..) = setup_gpu(AdapterOptions::default()).await.unwrap();
+
+        let buffers = create_buffers(&device, 4096)
+            .await
+            .expect("Bufer creation failed.");
+
+        assert!(buffers.header.usage().contains(wgpu::BufferUsages::STORAGE));
+        assert!(buffers
+            .output
+            .usage()
+            .contains(wgpu::BufferUsages::COPY_SRC));
+        assert!(buffers.params.usage().contains(wgpu::BufferUsages::UNIFORM));
+        assert!(buffers.staging[0]
+            .usage()
+            .contains(wgpu::BufferUsages::MAP_READ));
+    }
+
+    #[cfg(feature = "fs")]
+    #[tokio::test]
+    async fn pipeline_cache_is_persisted() {
+        let dir = std::env::temp_dir().join(format!("harvester-cache-{}", std::process::id()));
+
+        let miner = GpuMiner::builder()
+            .pipeline_cache_dir(&dir)
+            .build()
+            .await
+            .unwrap();
+        if !miner
+            .device
+            .features()
+            .contains(wgpu::Features::PIPELINE_CACHE)
+        {
+            assert!(miner.pipeline_cache.is_none());
+            return;
+        }
+
+        let path = miner.pipeline_cache.as_ref().unwrap().path.clone();
+        assert!(path.starts_with(&dir));
+        assert!(path.exists(), "Cache is written after build.");
+
+        // A second miner starts from the saved data
+        let mut miner = GpuMiner::builder()
+            .pipeline_cache_dir(&dir)
+            .build()
+            .await
+            .unwrap();
+        assert!(miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "fs")]
+    #[tokio::test]
+    async fn shaders_are_loaded_from_dir() {
+        let dir = std::env::temp_dir().join(format!("harvester-shaders-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Valid override of mine.wgsl only
+        let mine = format!("// Custom kernel\n{}", include_str!("mine.wgsl"));
+        fs::write(dir.join("mine.wgsl"), &mine).unwrap();
+        let mut miner = GpuMiner::builder().shader_dir(&dir).build().await.unwrap();
+        assert!(miner.uses_custom_shaders());
+        assert!(miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .is_ok());
+
+        // Broken shaders fall back to the embedded ones
+        fs::write(dir.join("mine.wgsl"), "fn main( {").unwrap();
+        let mut miner = GpuMiner::builder().shader_dir(&dir).build().await.unwrap();
+        assert!(!miner.uses_custom_shaders());
+        assert!(miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "spirv")]
+    #[tokio::test]
+    async fn spirv_kernel_matches_wgsl() {
+        let mut spirv_miner = GpuMiner::builder()
+            .prefer_spirv(true)
+            .build()
+            .await
+            .unwrap();
+        if !spirv_miner
+            .device
+            .features()
+            .contains(wgpu::Features::SPIRV_SHADER_PASSTHROUGH)
+        {
+            assert!(!spirv_miner.uses_spirv());
+            return;
+        }
+        assert!(spirv_miner.uses_spirv());
+
+        let mut wgsl_miner = GpuMiner::new(None).await.unwrap();
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+
+        for miner in [&mut spirv_miner, &mut wgsl_miner] {
+            miner.set_target(&target);
+        }
+        let spirv = spirv_miner.run_batch(&words).await.unwrap();
+        let wgsl = wgsl_miner.run_batch(&words).await.unwrap();
+        assert_eq!(spirv.hits, wgsl.hits);
+        assert!(spirv.is_found());
+    }
+
+    #[tokio::test]
+    async fn miner_works() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        assert!(miner.get_batch_size() != 0, "It gets created.");
+
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+
+        assert!(!res.is_found(), "We probably won't find a valid hash.");
+        assert!(res.hash.is_none());
+        assert_eq!(res.hits, 0);
+        assert_eq!(res.hashes_tried, miner.get_hashes_per_batch() as u64);
+    }
+
+    #[tokio::test]
+    async fn miner_finds_hash_below_target() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+
+        // Roughly one in 256 hashes has a zero most significant byte
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let res = miner.run_batch(&words).await.unwrap();
+        let hash = res.hash.expect("Easy target should be met.");
+
+        let header_words = words.with_nonce(res.nonce.unwrap());
+        assert_eq!(
+            hash,
+            hash_with_nonce(&sha256_words_to_header(&header_words))
+        );
+        assert_eq!(hash[31], 0x00, "Hash is compared as little-endian.");
+        assert!(hash_meets_target(&hash, &target));
+
+        // 2^20 nonces at 1/256 odds
+        let expected = miner.get_batch_size() / 256;
+        assert!(res.hits > expected / 2 && res.hits < expected * 2);
+    }
+
+    #[tokio::test]
+    async fn miner_respects_impossible_target() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+
+        miner.set_target(&[0x00; 32]);
+        let res = miner.run_batch(&words).await.unwrap();
+
+        assert!(!res.is_found());
+    }
+
+    #[tokio::test]
+    async fn run_batch_header_mines_from_nonce_zero() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .build()
+            .await
+            .unwrap();
+        let header = BlockHeader {
+            version: 0x2000_0000,
+            time: 1_700_000_000,
+            bits: 0x1d00_ffff,
+            ..Default::default()
+        };
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+
+        let res = miner.run_batch_header(&header, &target).await.unwrap();
+        assert_eq!(miner.get_target(), &target);
+        let winner = BlockHeader {
+            nonce: res.nonce.expect("Easy target should be met.").swap_bytes(),
+            ..header
+        };
+        assert_eq!(res.hash, Some(winner.hash()));
+    }
+
+    #[test]
+    fn target_words_are_most_significant_first() {
+        let words = target_to_words(&DEFAULT_TARGET);
+        assert_eq!(words[0], 0x00000000);
+        assert_eq!(words[1], 0xffff0000);
+        assert_eq!(&words[2..], &[0u32; 6]);
+    }
+
+    #[tokio::test]
+    async fn miner_with_several_nonces_per_thread() {
+        let mut miner = GpuMiner::builder()
+            .nonces_per_thread(4)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(miner.get_hashes_per_batch(), 4 * miner.get_batch_size());
+
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let res = miner.run_batch(&words).await.unwrap();
+        let header_words = words.with_nonce(res.nonce.expect("Easy target should be met."));
+        assert_eq!(
+            res.hash.unwrap(),
+            hash_with_nonce(&sha256_words_to_header(&header_words))
+        );
+
+        let expected = miner.get_hashes_per_batch() / 256;
+        assert!(res.hits > expected / 2 && res.hits < expected * 2);
+    }
+
+    #[tokio::test]
+    async fn run_batch_range_finds_nonce_in_range() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        // Spans several batches and starts at an unaligned nonce
+        let range = 3_000_000_123..3_000_100_000;
+        let res = miner.run_batch_range(&words, range.clone()).await.unwrap();
+        let nonce = res.nonce.expect("Easy target should be met.") as u64;
+        assert!(range.contains(&nonce));
+
+        let header_words = words.with_nonce(nonce as u32);
+        assert_eq!(
+            res.hash.unwrap(),
+            hash_with_nonce(&sha256_words_to_header(&header_words))
+        );
+
+        // Mining only the winner is enough to find it again
+        let res = miner
+            .run_batch_range(&words, nonce..nonce + 1)
+            .await
+            .unwrap();
+        assert_eq!(res.nonce, Some(nonce as u32));
+        assert_eq!(res.hashes_tried, 1);
+
+        // The range just before the first winner has no hits
+        let res = miner.run_batch_range(&words, range.start..nonce).await;
+        if range.start < nonce {
+            let res = res.unwrap();
+            assert!(!res.is_found());
+            assert_eq!(res.hashes_tried, nonce - range.start);
+        }
+    }
+
+    #[tokio::test]
+    async fn run_until_found_returns_solution() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let options = RunOptions {
+            min_time: Some(1_700_000_000),
+            ..Default::default()
+        };
+        let outcome = miner.run_until_found(&words, &options, |_| {}).await;
+        let RunOutcome::Found(solution) = outcome.unwrap() else {
+            panic!("Easy target should be met.");
+        };
+
+        // Rolled in seconds, not as a raw word
+        assert_eq!(solution.words.to_header().time, 1_700_000_000);
+        assert_eq!(solution.words[19], solution.nonce);
+        assert_eq!(
+            solution.hash,
+            hash_with_nonce(&sha256_words_to_header(&solution.words))
+        );
+    }
+
+    #[tokio::test]
+    async fn run_until_found_stops_at_limits() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        miner.set_target(&[0x00; 32]);
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+
+        // One and a half batches, the second one is cut short
+        let max_hashes = miner.get_hashes_per_batch() as u64 * 3 / 2;
+        let options = RunOptions {
+            max_hashes: Some(max_hashes),
+            ..Default::default()
+        };
+        let outcome = miner.run_until_found(&words, &options, |_| {}).await;
+        assert!(matches!(outcome.unwrap(), RunOutcome::LimitReached));
+        assert_eq!(miner.stats().total_hashes, max_hashes);
+        assert_eq!(miner.stats().batches, 2);
+
+        for options in [
+            RunOptions {
+                max_duration: Some(Duration::ZERO),
+                ..Default::default()
+            },
+            RunOptions {
+                deadline: Some(Instant::now()),
+                max_duration: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        ] {
+            let outcome = miner.run_until_found(&words, &options, |_| {}).await;
+            assert!(matches!(outcome.unwrap(), RunOutcome::LimitReached));
+        }
+        assert_eq!(miner.stats().batches, 2, "No batch runs past the limit.");
+    }
+
+    #[tokio::test]
+    async fn run_until_found_stops_on_cancel_and_bad_time_window() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        miner.set_target(&[0x00; 32]);
+
+        // The window closes before the header's timestamp
+        let words = HeaderWords::from_bytes(&[0u8; 80]).with_time(100);
+        let options = RunOptions {
+            max_time: Some(99),
+            ..Default::default()
+        };
+        let outcome = miner.run_until_found(&words, &options, |_| {}).await;
+        assert!(matches!(outcome.unwrap(), RunOutcome::Exhausted));
+
+        // Cancel from the first stats report
+        let cancel = miner.cancel_handle();
+        let options = RunOptions {
+            stats_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut reports = 0;
+        let outcome = miner
+            .run_until_found(&words, &options, |stats| {
+                reports += 1;
+                assert_eq!(stats.timestamp, 100);
+                assert!(stats.hashes > 0);
+                assert_eq!(stats.header_nonces, stats.hashes);
+                assert_eq!((stats.version_bits, stats.exhausted_headers), (0, 0));
+                assert!(stats.coverage() > 0.0 && stats.coverage() < 1.0);
+                cancel.cancel();
+            })
+            .await;
+        assert!(matches!(outcome.unwrap(), RunOutcome::Cancelled));
+        assert_eq!(reports, 1);
+    }
+
+    #[tokio::test]
+    async fn run_version_batch_mines_each_rolled_version() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .wg_size(64)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_header(&BlockHeader {
+            version: 0x2000_0000,
+            ..Default::default()
+        });
+
+        // Every hash meets the maximum target, so each version wins at once
+        miner.set_target(&[0xFF; 32]);
+        let results = miner.run_version_batch(&words, 3..7, 0..256).await.unwrap();
+        assert_eq!(results.len(), 4);
+        for (bits, res) in (3..7).zip(&results) {
+            let rolled = words.with_version_bits(bits).with_nonce(res.nonce.unwrap());
+            assert_eq!(rolled.to_header().version_bits(), bits);
+            assert_eq!(res.hash.unwrap(), hash_with_nonce(&rolled.to_bytes()));
+        }
+        assert_ne!(results[0].hash, results[1].hash);
+
+        assert!(miner
+            .run_version_batch(&words, 0..(1 << 16) + 1, 0..256)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn run_version_batch_all_rolls_the_version_mask() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .wg_size(64)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_header(&BlockHeader {
+            version: 0x2000_0000,
+            ..Default::default()
+        });
+        // Two bits a pool might allow, 4 versions
+        miner.set_version_mask(0x0000_6000).unwrap();
+        assert!(miner.set_version_mask(0).is_err());
+
+        let mut target = [0u8; 32];
+        target[0] = 0x01;
+        miner.set_target(&[0; 32]);
+        miner.set_share_target(Some(&target));
+        let rolled = miner.run_version_batch_all(&words, 0..4).await.unwrap();
+        assert_eq!(rolled.len(), 4);
+        for (bits, hits) in (0..4).zip(&rolled) {
+            assert!(!hits.shares.is_empty());
+            assert!(hits.winners.is_empty());
+            for share in &hits.shares {
+                let header = words
+                    .with_masked_version_bits(0x0000_6000, bits)
+                    .with_nonce(share.nonce);
+                assert_eq!(
+                    header.to_header().version,
+                    0x2000_0000 | (bits as i32) << 13
+                );
+                assert_eq!(share.hash, hash_with_nonce(&header.to_bytes()));
+            }
+        }
+        assert!(miner.run_version_batch_all(&words, 0..5).await.is_err());
+        assert!(miner.run_version_batch(&words, 3..5, 0..256).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn run_multi_batch_reports_each_job() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .build()
+            .await
+            .unwrap();
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let jobs: Vec<HeaderWords> = (0..4u8)
+            .map(|i| HeaderWords::from_bytes(&[i; 80]))
+            .collect();
+        let range = 1000..5000;
+        let results = miner.run_multi_batch(&jobs, range.clone()).await.unwrap();
+        assert_eq!(results.len(), jobs.len());
+
+        for (words, res) in jobs.iter().zip(&results) {
+            assert_eq!(res.hashes_tried, 4000);
+
+            // Each job finds as many winners as when mined alone
+            let single = miner.run_batch_range(words, range.clone()).await.unwrap();
+            assert_eq!(res.hits, single.hits);
+            assert_eq!(res.is_found(), single.is_found());
+
+            if let (Some(nonce), Some(hash)) = (res.nonce, res.hash) {
+                assert!(range.contains(&(nonce as u64)));
+                let header_words = words.with_nonce(nonce);
+                assert_eq!(
+                    hash,
+                    hash_with_nonce(&sha256_words_to_header(&header_words))
+                );
+            }
+        }
+        assert!(results.iter().any(|res| res.is_found()));
+
+        // Jobs have to fit in a single batch
+        assert!(miner.run_multi_batch(&jobs, 0..1 << 13).await.is_err());
+        assert!(miner.run_multi_batch(&[], 0..10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn passes_of_a_batch_cover_consecutive_windows() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 10)
+            .passes_per_batch(4)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(miner.get_hashes_per_batch(), 1 << 12);
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+        let words = HeaderWords::from_bytes(&[0x11; 80]);
+
+        let expected: Vec<u32> = (0..1 << 12)
+            .filter(|&nonce| {
+                let words = words.with_nonce(nonce);
+                hash_meets_target(&hash_with_nonce(&sha256_words_to_header(&words)), &target)
+            })
+            .collect();
+        let hits = miner.run_batch_all(&words).await.unwrap();
+        let found: Vec<u32> = hits.winners.iter().map(|winner| winner.nonce).collect();
+        assert_eq!(found, expected);
+        assert_eq!(hits.result.hits as usize, expected.len());
+        assert_eq!(hits.result.hashes_tried, 1 << 12);
+
+        // Three jobs get 341 nonces per pass each
+        let jobs = [words; 3];
+        let results = miner.run_multi_batch(&jobs, 0..1364).await.unwrap();
+        let in_range = expected.iter().filter(|&&nonce| nonce < 1364).count();
+        assert!(results.iter().all(|res| res.hits as usize == in_range));
+        assert!(miner.run_multi_batch(&jobs, 0..1365).await.is_err());
+
+        assert!(miner.set_passes_per_batch(0).is_err());
+        assert!(miner
+            .set_passes_per_batch(MAX_PASSES_PER_BATCH + 1)
+            .is_err());
+        miner.set_passes_per_batch(1).unwrap();
+        assert_eq!(miner.get_hashes_per_batch(), 1 << 10);
+    }
+
+    #[test]
+    fn dispatch_is_split_over_dimensions() {
+        assert_eq!(dispatch_size(1000, 65535), Some((1000, 1, 1)));
+        assert_eq!(dispatch_size(65536, 65535), Some((65535, 2, 1)));
+        assert_eq!(dispatch_size(u32::MAX, 65535), Some((65535, 65535, 2)));
+        assert_eq!(dispatch_size(1 << 20, 256), Some((256, 256, 16)));
+        assert_eq!(dispatch_size(u32::MAX, 256), None);
+    }
+
+    #[tokio::test]
+    async fn batches_beyond_workgroup_limit_are_not_truncated() {
+        let mut miner = GpuMiner::builder().wg_size(32).build().await.unwrap();
+        let max_workgroups = miner.device.limits().max_compute_workgroups_per_dimension;
+
+        // One more workgroup than a single dimension holds
+        let batch_size = (max_workgroups + 1) * 32;
+        miner.set_batch_size(batch_size).unwrap();
+
+        // Every hash meets the largest target, so hits count the work done
+        miner.set_target(&[0xFF; 32]);
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert_eq!(res.hits, batch_size);
+        assert_eq!(res.hashes_tried, batch_size as u64);
+    }
+
+    #[tokio::test]
+    async fn stats_track_batches_and_best_hash() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        assert_eq!(miner.stats().batches, 0);
+        assert_eq!(miner.get_hashrate(), 0.0);
+
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let mut hashes = Vec::new();
+        let mut bests = Vec::new();
+        for i in 0..3 {
+            let words = HeaderWords::from_bytes(&[0u8; 80]).with_time(i);
+            let res = miner.run_batch(&words).await.unwrap();
+            hashes.extend(res.hash);
+            bests.extend(res.best);
+        }
+        miner
+            .run_batches([HeaderWords::from_bytes(&[1u8; 80]); 2], |_, res| {
+                hashes.extend(res.hash);
+                bests.extend(res.best);
+                true
+            })
+            .await
+            .unwrap();
+
+        let stats = miner.stats();
+        assert_eq!(stats.batches, 5);
+        assert_eq!(stats.total_hashes, 5 * miner.get_hashes_per_batch() as u64);
+        assert!(stats.hashrate > 0.0);
+        assert_eq!(stats.hashrate, miner.get_hashrate());
+        assert!(stats.uptime > Duration::ZERO);
+
+        let best = bests
+            .iter()
+            .min_by(|a, b| a.hash.iter().rev().cmp(b.hash.iter().rev()))
+            .copied();
+        assert_eq!(bests.len(), 5);
+        assert_eq!(stats.best, best);
+
+        // No winner beats the best hash
+        let best = best.unwrap();
+        assert!(hashes
+            .iter()
+            .all(|hash| !hash.iter().rev().lt(best.hash.iter().rev())));
+        assert!(best.difficulty() >= target_to_difficulty(&target));
+    }
+
+    #[tokio::test]
+    async fn shader_variants_agree_with_the_cpu() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .build()
+            .await
+            .unwrap();
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+        let words = HeaderWords::from_bytes(&[0u8; 80]).with_time(0x6549_2a3b);
+
+        let expected: Vec<u32> = (0..1 << 12)
+            .filter(|&nonce| {
+                let words = words.with_nonce(nonce);
+                hash_meets_target(&hash_with_nonce(&sha256_words_to_header(&words)), &target)
+            })
+            .collect();
+        assert!(!expected.is_empty());
+
+        for options in ShaderOptions::all() {
+            miner.set_shader_options(options).unwrap();
+            assert_eq!(miner.get_shader_options(), options);
+            // llvmpipe takes minutes to compile the shared schedule, it is
+            // still validated above
+            if miner.is_software() && options.schedule == Schedule::Shared {
+                continue;
+            }
+            let hits = miner.run_batch_all(&words).await.unwrap();
+            let found: Vec<u32> = hits.winners.iter().map(|winner| winner.nonce).collect();
+            assert_eq!(found, expected, "{options}");
+        }
+
+        let shared = ShaderOptions {
+            schedule: Schedule::Shared,
+            ..Default::default()
+        };
+        let err = GpuMiner::builder()
+            .wg_size(512)
+            .shader_options(shared)
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, MinerError::InvalidShaderOptions(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn rolled_timestamps_skip_the_header_upload() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .build()
+            .await
+            .unwrap();
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+        // Rolling by a second carries into the next byte of the timestamp
+        let words = HeaderWords::from_bytes(&[0u8; 80]).with_time(0x6549_2aff);
+        miner.run_batch_all(&words).await.unwrap();
+
+        for time_roll in [1, 2, u32::MAX] {
+            let rolled = words.with_time(words.time().wrapping_add(time_roll));
+            assert_eq!(miner.time_roll(&[*rolled]), Some(time_roll));
+            let expected: Vec<u32> = (0..1 << 12)
+                .filter(|&nonce| {
+                    let rolled = rolled.with_nonce(nonce);
+                    hash_meets_target(&hash_with_nonce(&sha256_words_to_header(&rolled)), &target)
+                })
+                .collect();
+
+            let hits = miner.run_batch_all(&rolled).await.unwrap();
+            let found: Vec<u32> = hits.winners.iter().map(|winner| winner.nonce).collect();
+            assert_eq!(found, expected);
+            assert_eq!(miner.uploaded_jobs, [*words]);
+        }
+
+        // Any other change to the header uploads it again
+        let mut changed = words.into_words();
+        changed[18] = 0x1d00_ffff;
+        miner
+            .run_batch_all(&HeaderWords::from_words(changed).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(miner.uploaded_jobs, [changed]);
+    }
+
+    #[tokio::test]
+    async fn vanity_pattern_filters_winners() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .build()
+            .await
+            .unwrap();
+        miner.set_target(&[0xFF; 32]);
+        let pattern = VanityPattern::from_hex_prefix("a5").unwrap();
+        miner.set_vanity(Some(&pattern));
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
 
-        let (device, queue) = setup_gpu().await.context("Test")?;
+        let expected = (0..1 << 14)
+            .filter(|&nonce| {
+                let words = words.with_nonce(nonce);
+                pattern.matches(&hash_with_nonce(&sha256_words_to_header(&words)))
+            })
+            .count();
+        let hits = miner.run_batch_all(&words).await.unwrap();
+        assert_eq!(hits.result.hits as usize, expected);
+        assert_eq!(hits.winners.len(), expected);
+        assert!(hits
+            .winners
+            .iter()
+            .all(|winner| pattern.matches(&winner.hash)));
+        assert!(!hits.result.rejected);
 
-        let (header_buffer, output_buffer, staging_buffer) = create_buffers(&device, batch_size)
+        // The pattern applies on top of the target, "a5" can't start with 00
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+        let res = miner.run_batch(&words).await.unwrap();
+        assert_eq!(res.hits, 0);
+
+        miner.set_vanity(None);
+        assert!(miner.get_vanity().is_none());
+        let res = miner.run_batch(&words).await.unwrap();
+        assert!(res.hits > 0);
+    }
+
+    #[tokio::test]
+    async fn best_hash_matches_cpu() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .build()
             .await
-            .context("Buffer creation failed")?;
+            .unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]).with_time(0x6549_2a3b);
 
-        let bind_group_layout = create_bind_group_layout(&device);
-        let bind_group =
-            create_bind_group(&device, &bind_group_layout, &header_buffer, &output_buffer);
-
-        // Load shader
-        // Default workgroup size of 64
-        let wg_size = match wg_size {
-            Some(x) => x,
-            None => 64,
-        };
-        let shader = create_shader_with_wg_size(&device, wg_size as u16);
+        let res = miner.run_batch(&words).await.unwrap();
+        let expected = (0..1 << 12)
+            .map(|nonce| Winner {
+                nonce,
+                hash: hash_with_nonce(&words.with_nonce(nonce).to_bytes()),
+            })
+            .min_by(|a, b| a.hash.iter().rev().cmp(b.hash.iter().rev()));
+        assert_eq!(res.best, expected);
+    }
 
-        let compute_pipeline = create_compute_pipeline(&device, &bind_group_layout, &shader);
+    #[tokio::test]
+    async fn near_misses_get_the_full_compare() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+        let best = miner.run_batch(&words).await.unwrap().best.unwrap();
 
-        println!("Created GPU Miner.");
+        // The lowest hash as target, so it only passes on equal low bits
+        let mut target = best.hash;
+        target.reverse();
+        miner.set_target(&target);
+        let res = miner.run_batch(&words).await.unwrap();
+        assert_eq!(res.nonce, Some(best.nonce));
+        assert_eq!(res.hits, 1);
 
-        Ok(GpuMiner {
-            device,
-            queue,
-            compute_pipeline,
-            header_buffer,
-            output_buffer,
-            staging_buffer,
-            bind_group,
-            bind_group_layout,
-            batch_size,
-            wg_size,
-        })
+        // One below it, with the same top 64 bits
+        let borrow = target.iter().rposition(|&byte| byte != 0).unwrap();
+        assert!(borrow >= 8);
+        target[borrow] -= 1;
+        target[borrow + 1..].fill(0xFF);
+        miner.set_target(&target);
+        assert!(!miner.run_batch(&words).await.unwrap().is_found());
     }
 
-    // Helper function to set compute pipe
-    fn set_pipeline(&mut self, shader: &wgpu::ShaderModule) {
-        self.compute_pipeline =
-            self.device
-                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                    label: Some("Compute Pipe I"),
-                    layout: Some(&self.device.create_pipeline_layout(
-                        &wgpu::PipelineLayoutDescriptor {
-                            label: Some("Pipeline Layout"),
-                            bind_group_layouts: &[&self.bind_group_layout],
-                            push_constant_ranges: &[],
-                        },
-                    )),
-                    module: shader,
-                    entry_point: Some("main"),
-                    compilation_options: Default::default(),
-                    cache: None,
-                });
+    #[test]
+    fn throttle_pauses_are_computed() {
+        let busy = Duration::from_millis(100);
+        assert_eq!(Throttle::None.pause_after(busy), Duration::ZERO);
+        assert_eq!(
+            Throttle::DutyCycle(0.25).pause_after(busy),
+            Duration::from_millis(300)
+        );
+        assert_eq!(Throttle::DutyCycle(1.0).pause_after(busy), Duration::ZERO);
+        assert_eq!(
+            Throttle::Pause(Duration::from_secs(1)).pause_after(busy),
+            Duration::from_secs(1)
+        );
+
+        assert!(Throttle::DutyCycle(0.0).validate().is_err());
+        assert!(Throttle::DutyCycle(1.5).validate().is_err());
+        assert!(Throttle::DutyCycle(f64::NAN).validate().is_err());
     }
 
-    // Getter for worgroup size
-    pub fn get_wg_size(&self) -> u32 {
-        self.wg_size
+    #[tokio::test]
+    async fn throttle_rests_between_batches() {
+        let pause = Duration::from_millis(100);
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .throttle(Throttle::Pause(pause))
+            .build()
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            miner
+                .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+                .await
+                .unwrap();
+        }
+        miner
+            .run_batches([HeaderWords::from_bytes(&[0u8; 80]); 3], |_, _| true)
+            .await
+            .unwrap();
+        // Every batch but the first waits for the previous pause
+        assert!(start.elapsed() >= pause * 5);
+
+        assert!(miner.set_throttle(Throttle::DutyCycle(2.0)).is_err());
+        miner.set_throttle(Throttle::None).unwrap();
+        assert_eq!(miner.get_throttle(), Throttle::None);
     }
 
-    /// Getter for batch size
-    pub fn get_batch_size(&self) -> u32 {
-        self.batch_size
+    #[tokio::test]
+    async fn shutdown_waits_for_batches_in_flight() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+        miner.run_batch(&words).await.unwrap();
+
+        // Submitted but never read back
+        let span = miner.full_span(0);
+        miner
+            .submit_batch(slice::from_ref(&*words), 0, span)
+            .unwrap();
+        let device = miner.device.clone();
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        miner.shutdown();
+        assert!(device.poll(wgpu::Maintain::Poll).is_queue_empty());
+        assert!(device.pop_error_scope().await.is_none());
     }
 
-    /// Automatically sets optimal workgroup size
-    pub async fn autotune(&mut self) {
-        // Largest supported workgroup size
-        let max = self.device.limits().max_compute_workgroup_size_x;
+    #[tokio::test]
+    async fn poll_thread_is_the_default() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        assert_eq!(miner.get_poll_strategy(), PollStrategy::Thread);
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
 
-        let mut best_size = 32;
-        let mut best_time = u128::MAX;
+        assert!(miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap()
+            .is_found());
+        // Stopping early waits for the queued batch on the poll thread
+        let mut batches = 0;
+        miner
+            .run_batches([HeaderWords::from_bytes(&[1u8; 80]); 3], |_, _| {
+                batches += 1;
+                false
+            })
+            .await
+            .unwrap();
+        assert_eq!(batches, 1);
 
-        // Start checking from 2^5 (32)
-        let base: u32 = 2;
-        let mut n = 5;
+        let mut miner = GpuMiner::builder()
+            .poll_strategy(PollStrategy::Block)
+            .build()
+            .await
+            .unwrap();
+        miner.set_poll_strategy(PollStrategy::Thread).unwrap();
+        miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+    }
 
-        // We test workgroup sizes as different powers of 2
-        while base.pow(n) <= max {
-            let shader = create_shader_with_wg_size(&self.device, base.pow(n) as u16);
+    #[test]
+    fn runs_on_any_executor() {
+        futures::executor::block_on(async {
+            let mut miner = GpuMiner::builder()
+                .poll_strategy(PollStrategy::Yield)
+                .throttle(Throttle::Pause(Duration::from_millis(5)))
+                .build()
+                .await
+                .unwrap();
+            let mut target = [0xFF; 32];
+            target[0] = 0x00;
+            miner.set_target(&target);
 
-            self.wg_size = base.pow(n);
-            self.set_pipeline(&shader);
+            let res = miner
+                .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+                .await
+                .unwrap();
+            assert!(res.is_found());
+            miner
+                .run_batches([HeaderWords::from_bytes(&[1u8; 80]); 2], |_, res| {
+                    res.is_found()
+                })
+                .await
+                .unwrap();
 
-            let start_time = Instant::now();
-            for _ in 0..20 {
-                _ = self.run_batch(&[0u32; 32]).await;
-            }
-            let time = start_time.elapsed().as_millis();
+            miner.set_poll_strategy(PollStrategy::Block).unwrap();
+            assert!(miner
+                .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+                .await
+                .unwrap()
+                .is_found());
+        });
+    }
 
-            println!("Tested {}, ran in {} ms", base.pow(n), time);
-            if time < best_time {
-                best_time = time;
-                best_size = base.pow(n);
-            }
-            n += 1;
+    #[tokio::test]
+    async fn run_batch_all_returns_every_winner() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 16)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+
+        // About 256 winners, well within the hit list
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        let hits = miner.run_batch_all(&words).await.unwrap();
+        assert!(hits.result.hits > 1);
+        assert_eq!(hits.winners.len(), hits.result.hits as usize);
+        assert!(hits
+            .winners
+            .windows(2)
+            .all(|pair| pair[0].nonce < pair[1].nonce));
+        assert!(hits
+            .winners
+            .iter()
+            .any(|winner| Some(winner.nonce) == hits.result.nonce));
+        for winner in &hits.winners {
+            assert!(hash_meets_target(&winner.hash, &target));
         }
 
-        println!("Running with wg_size: {best_size}");
-        let shader = create_shader_with_wg_size(&self.device, best_size as u16);
-        self.set_pipeline(&shader);
-        self.wg_size = best_size;
+        // Every nonce wins, only the first MAX_HITS_PER_BATCH are kept
+        miner.set_target(&[0xFF; 32]);
+        let hits = miner.run_batch_all(&words).await.unwrap();
+        assert_eq!(hits.result.hits, 1 << 16);
+        assert_eq!(hits.winners.len(), MAX_HITS_PER_BATCH as usize);
     }
 
-    /// Runs one batch of nonces
-    /// If a winner is found the nonce is returned inside an option
-    pub async fn run_batch(&mut self, words: &[u32; 32]) -> Result<Option<u32>> {
-        // Send header words to buffer
-        self.queue
-            .write_buffer(&self.header_buffer, 0, bytemuck::cast_slice(words));
+    #[tokio::test]
+    async fn shares_are_reported_next_to_blocks() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 16)
+            .build()
+            .await
+            .unwrap();
+        let mut target = [0xFF; 32];
+        target[..2].fill(0x00);
+        let mut share_target = [0xFF; 32];
+        share_target[0] = 0x00;
+        miner.set_target(&target);
+        miner.set_share_target(Some(&share_target));
 
-        // Command encoder
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Command Encoder"),
-            });
+        // About 256 shares and a single block
+        let hits = miner
+            .run_batch_all(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert!(hits.result.shares > hits.result.hits);
+        assert_eq!(hits.shares.len(), hits.result.shares as usize);
+        assert_eq!(hits.winners.len(), hits.result.hits as usize);
+        assert!(hits
+            .shares
+            .iter()
+            .all(|share| hash_meets_target(&share.hash, &share_target)));
+        assert!(hits
+            .winners
+            .iter()
+            .all(|winner| hits.shares.contains(winner)));
 
-        // Run the compute shader
-        {
-            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: Some("Compute Pass"),
-                timestamp_writes: None,
-            });
-            compute_pass.set_pipeline(&self.compute_pipeline);
-            compute_pass.set_bind_group(0, &self.bind_group, &[]);
-            compute_pass.dispatch_workgroups(&self.batch_size / &self.wg_size, 1, 1);
-        }
+        miner.set_share_target(None);
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert_eq!(res.shares, res.hits);
+    }
 
-        // Copy results to staging buffer to read from CPU
-        encoder.copy_buffer_to_buffer(
-            &self.output_buffer,
-            0,
-            &self.staging_buffer,
+    #[tokio::test]
+    async fn false_winners_are_rejected() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        miner.set_target(&[0x00; 32]);
+
+        // The GPU sees a target every hash meets, the CPU an impossible one
+        miner.queue.write_buffer(
+            &miner.buffers.target,
             0,
-            (&self.batch_size * 4) as u64,
+            bytemuck::cast_slice(&target_to_words(&[0xFF; 32])),
         );
-        self.queue.submit(Some(encoder.finish()));
 
-        let slice = self.staging_buffer.slice(..);
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert!(res.rejected);
+        assert!(!res.is_found());
+        assert!(res.hash.is_none());
+        assert_eq!(miner.stats().rejected, 1);
 
-        let (sender, receiver) = oneshot::channel();
+        let hits = miner
+            .run_batch_all(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert!(hits.result.rejected);
+        assert!(hits.winners.is_empty());
+    }
 
-        slice.map_async(wgpu::MapMode::Read, move |res| {
-            let _ = sender.send(res);
-        });
-        self.device.poll(wgpu::Maintain::Wait);
+    #[tokio::test]
+    async fn run_batch_range_rejects_invalid_ranges() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
 
-        receiver.await.context("Mapping from GPU failed.")??;
+        assert!(miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 10..10)
+            .await
+            .is_err());
+        assert!(miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 0..NONCE_SPACE + 1)
+            .await
+            .is_err());
+    }
 
-        let data = slice.get_mapped_range();
-        let res: Vec<u32> = bytemuck::cast_slice(&data).to_vec();
+    #[tokio::test]
+    async fn errors_report_their_kind() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
 
-        drop(data);
-        self.staging_buffer.unmap();
+        let err = miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 10..10)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MinerError::InvalidNonceRange { start: 10, end: 10 }
+        ));
+        assert!(matches!(
+            miner.set_batch_size(0),
+            Err(MinerError::InvalidBatchSize(_))
+        ));
+        assert!(matches!(
+            miner.run_multi_batch(&[], 0..1).await,
+            Err(MinerError::InvalidJobCount { count: 0, .. })
+        ));
 
-        for &nonce in res.iter() {
-            if nonce != 0 {
-                return Ok(Some(nonce));
-            }
+        let res = GpuMiner::builder()
+            .backends(Backends::empty())
+            .build()
+            .await;
+        assert!(matches!(res, Err(MinerError::AdapterNotFound)));
+    }
+
+    #[tokio::test]
+    async fn run_batches_streams_results_in_order() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+
+        // Different timestamps give different winners
+        let jobs: Vec<HeaderWords> = (0..4u8)
+            .map(|i| {
+                let mut header = [0u8; 80];
+                header[68] = i;
+                HeaderWords::from_bytes(&header)
+            })
+            .collect();
+
+        let mut seen = Vec::new();
+        miner
+            .run_batches(jobs.clone(), |words, res| {
+                let header_words = words.with_nonce(res.nonce.unwrap());
+                assert_eq!(
+                    res.hash.unwrap(),
+                    hash_with_nonce(&sha256_words_to_header(&header_words))
+                );
+                seen.push(*words);
+                true
+            })
+            .await
+            .unwrap();
+        assert_eq!(seen, jobs);
+
+        // Stopping early skips the remaining jobs
+        let mut count = 0;
+        miner
+            .run_batches(jobs, |_, _| {
+                count += 1;
+                false
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn batch_size_can_be_configured() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 16)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(miner.get_batch_size(), 1 << 16);
+
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert_eq!(res.hashes_tried, 1 << 16);
+
+        assert!(miner.set_batch_size(0).is_err());
+        miner.set_batch_size(1 << 18).unwrap();
+        assert_eq!(miner.get_hashes_per_batch(), 1 << 18);
+    }
+
+    #[tokio::test]
+    async fn batch_reports_gpu_time_and_hashrate() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert_eq!(res.gpu_time.is_some(), miner.supports_timestamps());
+        if let Some(gpu_time) = res.gpu_time {
+            let expected = res.hashes_tried as f64 / gpu_time.as_secs_f64();
+            assert_eq!(res.hashrate(), expected);
         }
+        assert!(res.hashrate() > 0.0);
+
+        let res = miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 0..3 << 20)
+            .await
+            .unwrap();
+        assert_eq!(res.gpu_time.is_some(), miner.supports_timestamps());
+    }
+
+    #[tokio::test]
+    async fn profile_reports_gpu_time_per_batch() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
 
-        Ok(None)
+        let res = miner.profile(&HeaderWords::from_bytes(&[0u8; 80]), 3).await;
+        if miner.supports_timestamps() {
+            let times = res.unwrap();
+            assert_eq!(times.len(), 3);
+        } else {
+            assert!(res.is_err(), "Profiling needs timestamp queries.");
+        }
     }
-}
 
-fn create_shader_with_wg_size(device: &wgpu::Device, size: u16) -> wgpu::ShaderModule {
-    let sha256_shader = include_str!("sha256.wgsl");
+    #[tokio::test]
+    async fn cancel_handle_stops_run_batches() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 16)
+            .build()
+            .await
+            .unwrap();
+        let cancel = miner.cancel_handle();
+        let jobs = vec![HeaderWords::from_bytes(&[0u8; 80]); 10];
 
-    let mine_shader = include_str!("mine.wgsl");
-    let mine_shader = mine_shader.replace("{{wg_size}}", &size.to_string());
+        // Cancelled from "another task" after the second batch
+        let mut count = 0;
+        miner
+            .run_batches(jobs.clone(), |_, _| {
+                count += 1;
+                if count == 2 {
+                    cancel.cancel();
+                }
+                true
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
 
-    let combined_shader = format!("{}\n{}", sha256_shader, mine_shader);
+        // Stays cancelled until reset
+        count = 0;
+        miner
+            .run_batches(jobs.clone(), |_, _| {
+                count += 1;
+                true
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
 
-    device.create_shader_module(wgpu::ShaderModuleDescriptor {
-        label: Some("Mining Shader"),
-        source: wgpu::ShaderSource::Wgsl(combined_shader.into()),
-    })
-}
+        cancel.reset();
+        miner
+            .run_batches(jobs, |_, _| {
+                count += 1;
+                true
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 10);
+    }
 
-/// Hashes a full 80 byte bitcoin header on CPU for verification
-pub fn hash_with_nonce(header: &[u8; 80]) -> [u8; 32] {
-    Sha256::digest(Sha256::digest(header)).into()
-}
+    #[tokio::test]
+    async fn cancelled_batches_drop_their_hits() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 14)
+            .build()
+            .await
+            .unwrap();
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+        miner.set_target(&[0xFF; 32]);
 
-/// Adds padding to the header to make it 128 bytes (1024 bits)
-pub fn sha256_preprocess(header: &[u8; 80]) -> [u8; 128] {
-    // Initialize to 128 bytes of 0
-    let mut padded = [0u8; 128];
-    // First 80 bytes are from the original header
-    padded[0..80].copy_from_slice(header);
+        let cancel = miner.cancel_handle();
+        cancel.cancel();
+        let hits = miner.run_batch_all(&words).await.unwrap();
+        assert!(hits.winners.is_empty() && hits.shares.is_empty());
+        assert!(hits.result.hits > 0);
+        let rolled = miner.run_version_batch_all(&words, 0..2).await.unwrap();
+        assert!(rolled.iter().all(|hits| hits.winners.is_empty()));
 
-    // Add a byte with 1
-    padded[80] = 0x80;
+        cancel.reset();
+        assert!(!miner
+            .run_batch_all(&words)
+            .await
+            .unwrap()
+            .winners
+            .is_empty());
+    }
 
-    // Add the length 640 which fits in 2 bytes
-    padded[126] = 0x02;
-    padded[127] = 0x80;
+    #[tokio::test]
+    async fn miner_recovers_from_device_loss() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 16)
+            .build()
+            .await
+            .unwrap();
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        miner.set_target(&target);
+        assert!(!miner.is_device_lost());
 
-    padded
-}
+        let res = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert!(!res.recovered);
 
-/// Parse the 32x32-bit words, expects 128 byte header
-pub fn sha256_parse_words(header: &[u8; 128]) -> [u32; 32] {
-    let mut words = [0u32; 32];
-    // Words are chunks of 4 byte = 32 bit
-    for (i, chunk) in header.chunks_exact(4).enumerate() {
-        words[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+        // Simulates a driver reset
+        miner.device.destroy();
+        miner.device.poll(wgpu::Maintain::Poll);
+        assert!(miner.is_device_lost());
+
+        let recovered = miner
+            .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+            .await
+            .unwrap();
+        assert!(recovered.recovered);
+        assert!(!miner.is_device_lost());
+        assert_eq!(miner.get_target(), &target, "Target survives recovery.");
+        assert_eq!(recovered.nonce, res.nonce);
     }
-    words
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn watchdog_timers_fire_past_dropped_ones() {
+        drop(watchdog_timer(Duration::from_secs(3600)));
+        let start = Instant::now();
+        watchdog_timer(Duration::from_millis(10)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
 
     #[tokio::test]
-    async fn gpu_setup_works() {
-        let res = setup_gpu().await;
-        assert!(res.is_ok());
+    async fn watchdog_rebuilds_hung_device() {
+        // Block can't time out, the wait is in the driver
+        for poll_strategy in [PollStrategy::Thread, PollStrategy::Yield] {
+            let mut miner = GpuMiner::builder()
+                .batch_size(1 << 16)
+                .poll_strategy(poll_strategy)
+                .batch_timeout(Some(Duration::from_millis(20)))
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(miner.get_batch_timeout(), Some(Duration::from_millis(20)));
 
-        let (device, _) = res.unwrap();
-        assert_eq!(
-            device.limits().max_buffer_size > 0,
-            true,
-            "Successfully got limts"
-        )
+            // A map callback that never runs looks like a hung batch
+            let (_sender, receiver) = oneshot::channel();
+            let submission = miner.queue.submit([]);
+            let err = miner.wait_for_map(submission, receiver).await.unwrap_err();
+            assert!(matches!(err, MinerError::BatchTimeout(_)), "{err}");
+            assert!(miner.is_device_lost());
+
+            let res = miner
+                .run_batch(&HeaderWords::from_bytes(&[0u8; 80]))
+                .await
+                .unwrap();
+            assert!(res.recovered);
+            assert!(!miner.is_device_lost());
+
+            miner.set_batch_timeout(None);
+            assert_eq!(miner.get_batch_timeout(), None);
+        }
     }
 
     #[tokio::test]
-    async fn buffers_created_correct_size() {
-        let (device, _) = setup_gpu().await.unwrap();
-        let batch_size = 2048;
-        let (header_buffer, output_buffer, staging_buffer) = create_buffers(&device, batch_size)
+    async fn out_of_memory_halves_the_batch_size() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 10)
+            .wg_size(256)
+            .build()
             .await
-            .expect("Buffer creation failed.");
+            .unwrap();
+
+        miner.halve_batch_size().unwrap();
+        assert_eq!(miner.get_batch_size(), 1 << 9);
+        miner.halve_batch_size().unwrap();
+        assert_eq!(miner.get_batch_size(), 1 << 8);
+        assert!(matches!(
+            miner.halve_batch_size(),
+            Err(MinerError::OutOfMemory)
+        ));
+        assert_eq!(miner.get_batch_size(), 1 << 8);
 
-        assert_eq!(header_buffer.size(), 128);
-        assert_eq!(output_buffer.size(), (4 * batch_size) as u64);
-        assert_eq!(staging_buffer.size(), (4 * batch_size) as u64);
+        // Ranges still advance by what each batch covered
+        let res = miner
+            .run_batch_range(&HeaderWords::from_bytes(&[0u8; 80]), 0..1000)
+            .await
+            .unwrap();
+        assert_eq!(res.hashes_tried, 1000);
     }
 
     #[tokio::test]
-    async fn buffer_creation_fails_invalid_batch_size() {
-        let (device, _) = setup_gpu().await.unwrap();
+    async fn out_of_memory_shrinks_the_span_of_every_job() {
+        let mut miner = GpuMiner::builder()
+            .batch_size(1 << 12)
+            .wg_size(64)
+            .passes_per_batch(MAX_PASSES_PER_BATCH)
+            .build()
+            .await
+            .unwrap();
+        let jobs = [0u8, 1, 2, 3].map(|byte| HeaderWords::from_bytes(&[byte; 80]));
+        let count = miner.pass_window(jobs.len()) as u64 * MAX_PASSES_PER_BATCH as u64;
 
-        let res = create_buffers(&device, u32::MAX).await;
-        assert!(res.is_err(), "u32 MAX should cause an error.");
+        // The batch is retried at a quarter of the size with all the passes
+        miner.injected_ooms = 2;
+        let results = miner.run_multi_batch(&jobs, 0..count).await.unwrap();
+        assert_eq!(miner.get_batch_size(), 1 << 10);
+        let covered = miner.pass_window(jobs.len()) * MAX_PASSES_PER_BATCH;
+        assert!(results.iter().all(|res| res.hashes_tried == covered as u64));
+        for (words, res) in jobs.iter().zip(&results) {
+            let single = miner
+                .run_batch_range(words, 0..covered as u64)
+                .await
+                .unwrap();
+            assert_eq!(res.nonce, single.nonce);
+        }
 
-        let res = create_buffers(&device, 0).await;
-        assert!(
-            res.is_err(),
-            "Buffer creation should fail with 0 batch size."
+        // Spans the params buffer has no room for are refused
+        let span = NonceSpan {
+            base: 0,
+            count: covered * 2,
+        };
+        let raw: Vec<[u32; 32]> = jobs.iter().map(|words| **words).collect();
+        assert!(matches!(
+            miner.submit_batch(&raw, 0, span),
+            Err(MinerError::InvalidBatchSize(_))
+        ));
+    }
+
+    #[test]
+    fn backends_are_parsed() {
+        assert_eq!(parse_backends("vulkan").unwrap(), Backends::VULKAN);
+        assert_eq!(
+            parse_backends("Vulkan, dx12").unwrap(),
+            Backends::VULKAN | Backends::DX12
         );
+        assert_eq!(parse_backends("all").unwrap(), Backends::all());
+        assert!(parse_backends("cuda").is_err());
     }
 
     #[tokio::test]
-    async fn buffers_have_correct_flags() {
-        let (device, _) = setup_gpu().await.unwrap();
+    async fn builder_fails_without_backends() {
+        let res = GpuMiner::builder()
+            .backends(Backends::empty())
+            .build()
+            .await;
+        assert!(res.is_err(), "No backend means no adapter.");
 
-        let (header_buffer, output_buffer, staging_buffer) = create_buffers(&device, 4096)
+        let miner = GpuMiner::builder()
+            .backends(Backends::empty())
+            .build_or_cpu()
             .await
-            .expect("Bufer creation failed.");
+            .unwrap();
+        assert!(miner.is_cpu());
 
-        assert!(header_buffer.usage().contains(wgpu::BufferUsages::STORAGE));
-        assert!(output_buffer.usage().contains(wgpu::BufferUsages::COPY_SRC));
-        assert!(staging_buffer
-            .usage()
-            .contains(wgpu::BufferUsages::MAP_READ));
+        let miner = GpuMiner::builder().build_or_cpu().await.unwrap();
+        assert!(!miner.is_cpu());
     }
 
     #[tokio::test]
-    async fn miner_works() {
-        let mut miner = GpuMiner::new(None).await.unwrap();
-        assert!(miner.get_batch_size() != 0, "It gets created.");
+    async fn software_adapters_can_be_forbidden() {
+        let res = GpuMiner::builder()
+            .power_preference(PowerPreference::LowPower)
+            .allow_software(false)
+            .build()
+            .await;
+
+        match res {
+            Ok(miner) => assert!(!miner.is_software()),
+            Err(err) => assert!(format!("{err:#}").contains("software adapter")),
+        }
+    }
+
+    #[tokio::test]
+    async fn adapter_info_matches_the_adapter() {
+        let miner = GpuMiner::new(None).await.unwrap();
+        let info = miner.adapter_info();
+        assert_eq!(info.name, miner.adapter_info.name);
+        assert_eq!(info.device_type == "Cpu", miner.is_software());
+        assert!(info.to_string().starts_with(&info.name));
+        assert!(info.to_string().contains(&info.backend));
+    }
+
+    #[test]
+    fn hashes_per_batch_is_checked() {
+        assert_eq!(check_hashes_per_batch(1024, 4, 1).unwrap(), 4096);
+        assert_eq!(check_hashes_per_batch(1024, 4, 2).unwrap(), 8192);
+        assert!(check_hashes_per_batch(1024, 0, 1).is_err());
+        assert!(check_hashes_per_batch(1024, 1, 0).is_err());
+        assert!(check_hashes_per_batch(1024, 1, MAX_PASSES_PER_BATCH + 1).is_err());
+        assert!(check_hashes_per_batch(1 << 20, 1 << 12, 1).is_err());
+        assert!(check_hashes_per_batch(1 << 20, 1 << 8, 1 << 4).is_err());
+    }
+
+    #[test]
+    fn default_launch_follows_the_adapter() {
+        let adapter = |device_type| wgpu::AdapterInfo {
+            name: String::new(),
+            vendor: 0,
+            device: 0,
+            device_type,
+            driver: String::new(),
+            driver_info: String::new(),
+            backend: wgpu::Backend::Vulkan,
+        };
+        let subgroups = |size| wgpu::Limits {
+            min_subgroup_size: size,
+            max_subgroup_size: size,
+            ..Default::default()
+        };
+        let limits = wgpu::Limits::default();
+
+        let discrete = adapter(wgpu::DeviceType::DiscreteGpu);
+        assert_eq!(
+            default_launch(&discrete, &subgroups(32), &limits),
+            (128, DISCRETE_BATCH_SIZE)
+        );
+        assert_eq!(default_launch(&discrete, &subgroups(64), &limits).0, 256);
+        let integrated = adapter(wgpu::DeviceType::IntegratedGpu);
+        assert_eq!(
+            default_launch(&integrated, &subgroups(8), &limits),
+            (DEFAULT_WG_SIZE, INTEGRATED_BATCH_SIZE)
+        );
+        let other = adapter(wgpu::DeviceType::Other);
+        assert_eq!(
+            default_launch(&other, &subgroups(0), &limits),
+            (DEFAULT_WG_SIZE, DEFAULT_BATCH_SIZE)
+        );
 
-        let res = miner.run_batch(&[0u32; 32]).await.unwrap();
+        // Small limits cap the workgroup and the batch
+        let small = wgpu::Limits {
+            max_compute_invocations_per_workgroup: 64,
+            max_compute_workgroups_per_dimension: 16,
+            ..Default::default()
+        };
+        let (wg_size, batch_size) = default_launch(&discrete, &subgroups(64), &small);
+        assert_eq!(wg_size, 64);
+        assert!(dispatch_size(batch_size / wg_size, 16).is_some());
+        assert!(dispatch_size(batch_size * 2 / wg_size, 16).is_none());
 
-        assert!(res.is_none(), "We probably won't find a valid hash.");
+        // As do passes and nonces per thread that would overflow the nonces
+        let batch_size = cap_batch_size(DISCRETE_BATCH_SIZE, 64, 1 << 8, 16, &limits);
+        assert!(check_hashes_per_batch(batch_size, 1 << 8, 16).is_ok());
+        assert!(check_hashes_per_batch(batch_size * 2, 1 << 8, 16).is_err());
+        assert_eq!(cap_batch_size(1 << 12, 256, 1, 1, &limits), 1 << 12);
     }
 
     #[tokio::test]
     async fn autotune_sets_reasonable_value() {
-        let (device, _) = setup_gpu().await.unwrap();
+        let (device, ..) = setup_gpu(AdapterOptions::default()).await.unwrap();
         let mut miner = GpuMiner::new(Some(4)).await.unwrap();
         assert!(miner.get_wg_size() == 4, "wg_size is set to chosen value.");
 
@@ -469,7 +4859,9 @@ mod tests {
         assert!(
             miner.get_wg_size() <= device.limits().max_compute_workgroup_size_x,
             "wg_size is within limits."
-        )
+        );
+        assert!(miner.get_nonces_per_thread() <= AUTOTUNE_MAX_NONCES_PER_THREAD);
+        assert!(AUTOTUNE_BATCH_SIZES.contains(&miner.get_batch_size()));
     }
 
     #[test]
@@ -490,8 +4882,8 @@ mod tests {
     fn preprocess_zero_padding_is_correct() {
         let header = [0xFF; 80];
         let padded = sha256_preprocess(&header);
-        for i in 81..120 {
-            assert_eq!(padded[i], 0x00);
+        for byte in &padded[81..120] {
+            assert_eq!(*byte, 0x00);
         }
     }
 
@@ -531,8 +4923,8 @@ mod tests {
     fn parse_words_incremental() {
         let mut header = [0u8; 128];
 
-        for i in 0..128 {
-            header[i] = i as u8;
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = i as u8;
         }
 
         let words = sha256_parse_words(&header);
@@ -543,6 +4935,37 @@ mod tests {
         //[80,81,82,83] = hex [50,51,52,53]
     }
 
+    #[test]
+    fn words_to_header_round_trip() {
+        let mut header = [0u8; 80];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let words = HeaderWords::from_bytes(&header);
+        assert_eq!(sha256_words_to_header(&words), header);
+    }
+
+    #[test]
+    fn midstate_finishes_to_full_hash() {
+        let mut header = [0u8; 80];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        let padded = sha256_preprocess(&header);
+        let words = sha256_parse_words(&padded);
+
+        let mut state = sha256_midstate(&words);
+        let block: [u8; 64] = padded[64..].try_into().unwrap();
+        sha2::compress256(&mut state, &[block.into()]);
+
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        assert_eq!(digest, <[u8; 32]>::from(Sha256::digest(header)));
+    }
+
     #[test]
     fn double_sha256_zeros() {
         let res = hash_with_nonce(&[0u8; 80]);
@@ -0,0 +1,185 @@
+//! Merkle roots of the transactions of a block.
+//!
+//! Txids are in SHA256 byte order, reversed from how explorers display
+//! them, and so is the root, ready for the header. Every level hashes
+//! pairs of the level below, duplicating the last hash of odd levels.
+//!
+//! The coinbase changes with every extranonce while the other txids stay
+//! put, so a MerkleBranch keeps the hashes along its path and gets the
+//! root from a new coinbase txid in one hash per level, like stratum does.
+
+use sha2::{Digest, Sha256};
+
+use crate::{GpuMiner, Result};
+
+/// Double SHA256 of two hashes, their parent in the tree
+pub fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(concat(left, right))).into()
+}
+
+/// Merkle root of the txids, the coinbase first
+/// All zero without txids, like Bitcoin Core.
+pub fn merkle_root(txids: &[[u8; 32]]) -> [u8; 32] {
+    let Some(mut level) = first_level(txids) else {
+        return [0u8; 32];
+    };
+    while level.len() > 1 {
+        level = pairs(&level)
+            .map(|(left, right)| merkle_parent(left, right))
+            .collect();
+    }
+    level[0]
+}
+
+/// Siblings along the path of the coinbase to the root, lowest level first
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MerkleBranch {
+    hashes: Vec<[u8; 32]>,
+}
+
+impl MerkleBranch {
+    /// Branch of a block with these txids after its coinbase
+    pub fn new(txids: &[[u8; 32]]) -> Self {
+        // The level without its first hash, which depends on the coinbase
+        let mut rest = txids.to_vec();
+        let mut hashes = Vec::new();
+        while let Some(&sibling) = rest.first() {
+            hashes.push(sibling);
+            rest = pairs(&rest[1..])
+                .map(|(left, right)| merkle_parent(left, right))
+                .collect();
+        }
+        Self { hashes }
+    }
+
+    /// Uses hashes received from elsewhere, e.g. in a stratum job
+    pub fn from_hashes(hashes: Vec<[u8; 32]>) -> Self {
+        Self { hashes }
+    }
+
+    pub fn hashes(&self) -> &[[u8; 32]] {
+        &self.hashes
+    }
+
+    /// Merkle root of the block with this coinbase txid
+    pub fn root(&self, coinbase: &[u8; 32]) -> [u8; 32] {
+        self.hashes
+            .iter()
+            .fold(*coinbase, |node, sibling| merkle_parent(&node, sibling))
+    }
+}
+
+impl GpuMiner {
+    /// Merkle root of the txids like merkle_root, hashing each level of
+    /// the tree in one batch on the GPU
+    /// Only worth it for blocks with many thousand transactions.
+    pub async fn merkle_root(&mut self, txids: &[[u8; 32]]) -> Result<[u8; 32]> {
+        let Some(mut level) = first_level(txids) else {
+            return Ok([0u8; 32]);
+        };
+        while level.len() > 1 {
+            let messages: Vec<[u8; 64]> = pairs(&level)
+                .map(|(left, right)| concat(left, right))
+                .collect();
+            level = self.hash_messages(&messages).await?;
+        }
+        Ok(level[0])
+    }
+}
+
+fn concat(left: &[u8; 32], right: &[u8; 32]) -> [u8; 64] {
+    let mut pair = [0u8; 64];
+    pair[..32].copy_from_slice(left);
+    pair[32..].copy_from_slice(right);
+    pair
+}
+
+fn first_level(txids: &[[u8; 32]]) -> Option<Vec<[u8; 32]>> {
+    (!txids.is_empty()).then(|| txids.to_vec())
+}
+
+// Pairs of a level, the last hash paired with itself if it has no partner
+fn pairs(level: &[[u8; 32]]) -> impl Iterator<Item = (&[u8; 32], &[u8; 32])> {
+    level
+        .chunks(2)
+        .map(|pair| (&pair[0], pair.get(1).unwrap_or(&pair[0])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Displayed txid to SHA256 byte order
+    fn txid(display: &str) -> [u8; 32] {
+        let mut txid: [u8; 32] = hex::decode(display).unwrap().try_into().unwrap();
+        txid.reverse();
+        txid
+    }
+
+    #[test]
+    fn roots_of_known_blocks() {
+        // Coinbase only, the root is its txid
+        let genesis = txid("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        assert_eq!(merkle_root(&[genesis]), genesis);
+        let header = crate::BlockHeader::from_hex(crate::self_test::KNOWN_BLOCKS[0].1).unwrap();
+        assert_eq!(header.merkle_root, genesis);
+
+        // Block 100000
+        let txids = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]
+        .map(txid);
+        assert_eq!(
+            merkle_root(&txids),
+            txid("f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766")
+        );
+
+        // Odd levels pair their last hash with itself
+        assert_eq!(
+            merkle_root(&txids[..3]),
+            merkle_root(&[txids[0], txids[1], txids[2], txids[2]])
+        );
+        assert_eq!(merkle_root(&[]), [0u8; 32]);
+    }
+
+    #[test]
+    fn branches_give_the_root_for_any_coinbase() {
+        for count in 0..20u32 {
+            let txids: Vec<[u8; 32]> = (0..count)
+                .map(|i| Sha256::digest(i.to_le_bytes()).into())
+                .collect();
+            let branch = MerkleBranch::new(&txids);
+            // One sibling per level above the txids
+            assert_eq!(
+                branch.hashes().len(),
+                (count + 1).next_power_of_two().trailing_zeros() as usize
+            );
+
+            for coinbase in [[0u8; 32], [0xC0; 32]] {
+                let all: Vec<[u8; 32]> = std::iter::once(coinbase).chain(txids.clone()).collect();
+                assert_eq!(branch.root(&coinbase), merkle_root(&all), "{count} txids");
+            }
+        }
+
+        let branch = MerkleBranch::new(&[[1; 32], [2; 32]]);
+        assert_eq!(MerkleBranch::from_hashes(branch.hashes().to_vec()), branch);
+    }
+
+    #[tokio::test]
+    async fn gpu_roots_match_the_cpu() {
+        let mut miner = crate::GpuMiner::new(None).await.unwrap();
+        for count in [0, 1, 2, 3, 1001] {
+            let txids: Vec<[u8; 32]> = (0..count as u32)
+                .map(|i| Sha256::digest(i.to_le_bytes()).into())
+                .collect();
+            assert_eq!(
+                miner.merkle_root(&txids).await.unwrap(),
+                merkle_root(&txids),
+                "{count} txids"
+            );
+        }
+    }
+}
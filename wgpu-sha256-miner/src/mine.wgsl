@@ -1,53 +1,174 @@
 /// Naga doesn't support import yet
 /// import "sha256.wgsl" as sha256;
 /// So we have to manually concat files for now.
-@group(0) @binding(0) var<storage, read> headerWords: array<u32, 32>;
-@group(0) @binding(1) var<storage, read_write> output: array<u32, 1048576>;
-
-// wg_size needs to be set manually from CPU-side
-@compute @workgroup_size({{wg_size}})
-fn main(@builtin(global_invocation_id) id: vec3<u32>) {
-    let mTarget: array<u32, 8> = array<u32, 8>(
-	0x00000000u,  // 2 zero bytes 
-	0xFFFFFFFFu,
-	0xFFFFFFFFu,
-	0xFFFFFFFFu,
-	0xFFFFFFFFu,
-	0xFFFFFFFFu,
-	0xFFFFFFFFu,
-	0xFFFFFFFFu 
-    );
-
-    let thId = id.x;
-    // Lucky Number?
-    let baseNonce = 777777u;
-    
-    // Nonce on this invocation: base + id
-    let nonce: u32 = baseNonce + thId;
+@group(0) @binding(0) var<storage, read> jobs: array<Job>;
+@group(0) @binding(1) var<storage, read_write> output: array<MineResult>;
+@group(0) @binding(2) var<storage, read> targets: Targets;
+@group(0) @binding(3) var<uniform> params: Params;
+@group(0) @binding(4) var<storage, read_write> hits: HitList;
+@group(0) @binding(5) var<storage, read_write> bestCandidates: HitList;
 
-    var words: array<u32, 32>;
-    // The words should be copied
-    for(var i = 0u; i < 32u; i = i + 1u) {
-	words[i] = headerWords[i];
-    }
+// Nonces covered by this dispatch: baseNonce .. baseNonce + count
+// for each of the first jobCount jobs, spread over threadCount invocations
+// timeRoll is the number of seconds added to the timestamp of every job,
+// so rolling it doesn't need the jobs uploaded again.
+struct Params {
+    baseNonce: u32,
+    count: u32,
+    jobCount: u32,
+    threadCount: u32,
+    timeRoll: u32,
+}
+
+// 256-bit targets, most significant word first
+// The share target is the block target unless the CPU sets an easier one.
+// Winners and shares must also match the vanity pattern under its mask,
+// which is all zero and matches everything unless a pattern is set.
+struct Targets {
+    block: array<u32, 8>,
+    share: array<u32, 8>,
+    mask: array<u32, 8>,
+    pattern: array<u32, 8>,
+}
+
+// The first 64 bytes of the header are constant for every nonce,
+// so their compressed state is computed once on the CPU.
+struct Job {
+    midstate: array<u32, 8>,
+    // Second block of the padded header, words 16-31
+    tail: array<u32, 16>,
+}
+
+// One per job, written by the first invocation that meets the target.
+// The flag makes every nonce value representable, including 0.
+// hits counts every invocation that met the target, shares every one
+// that met the share target.
+// best is the inverted most significant word of the lowest hash so far,
+// so the cleared buffer starts out worse than any hash.
+struct MineResult {
+    found: atomic<u32>,
+    nonce: u32,
+    hits: atomic<u32>,
+    shares: atomic<u32>,
+    best: atomic<u32>,
+    padding: array<u32, 3>,
+}
 
-    // The nonce is in bytes 76-80 in the btc header
-    // 76 / 4 = 19 (each location in words is 4 bytes)
-    words[19] = nonce;
+// Every winner and share of the batch, as long as there is room
+// Also used for the nonces that raised best, the CPU picks the lowest.
+struct Hit {
+    job: u32,
+    nonce: u32,
+}
+
+struct HitList {
+    count: atomic<u32>,
+    padding: u32,
+    // Sized by the buffer, MAX_HITS_PER_BATCH on the CPU side
+    entries: array<Hit>,
+}
+
+// Launch parameters, specialized per pipeline from the CPU side
+override wgSize: u32 = 64u;
+override noncesPerThread: u32 = 1u;
+
+// Index of the invocation in its workgroup, for generated kernels that
+// keep per-invocation state in workgroup memory
+var<private> lane: u32;
+
+@compute @workgroup_size(wgSize)
+fn main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) localIndex: u32,
+    @builtin(num_workgroups) workgroups: vec3<u32>,
+) {
+    lane = localIndex;
+
+    // Large batches are dispatched as rows and layers of workgroups
+    let width = workgroups.x * wgSize;
+    let thId = id.x + (id.y + id.z * workgroups.y) * width;
+    // Padding workgroups of the last row or layer
+    if(thId >= params.threadCount) {
+	return;
+    }
     
-    var finalHash = doubleHash(words);
-    var meetsTarget = true;
+    // First offset on this invocation: id * nonces per thread
+    let firstIndex: u32 = thId * noncesPerThread;
 
-    for(var i = 0u; i < 8u; i = i + 1u) {
-	if(finalHash[i] > mTarget[i]) {
-	    meetsTarget = false;
+    // Nonce invariant parts of the current job, redone when the loop
+    // reaches the next one
+    var preJob = 0xffffffffu;
+    var pre: NonceInvariant;
+
+    for(var k = 0u; k < noncesPerThread; k = k + 1u) {
+	// Jobs are laid out back to back, count nonces each
+	let index = firstIndex + k;
+	let jobIndex = index / params.count;
+	// The last workgroup can reach past the end of the last job
+	if(jobIndex >= params.jobCount) {
 	    break;
 	}
+	// The nonce is in bytes 76-80 in the btc header
+	// 76 / 4 = 19 (each location in words is 4 bytes)
+	// 19 - 16 = 3 in the second block
+	let nonce = params.baseNonce + index % params.count;
+	let midstate = jobs[jobIndex].midstate;
+	if(jobIndex != preJob) {
+	    var tail = jobs[jobIndex].tail;
+	    // Timestamp is word 17 of the header, 1 in the second block
+	    // The header stores it little-endian, so it is swapped to add
+	    tail[1] = swapEndian(swapEndian(tail[1]) + params.timeRoll);
+	    pre = precomputeNonceInvariant(midstate, tail);
+	    preJob = jobIndex;
+	}
+
+	var finalHash = doubleHashWithNonce(midstate, pre, nonce);
+
+	// Ties on the top word are recorded too, the CPU compares full hashes
+	let score = ~swapEndian(finalHash[7]);
+	if(score >= atomicLoad(&output[jobIndex].best)
+	    && score >= atomicMax(&output[jobIndex].best, score)) {
+	    let slot = atomicAdd(&bestCandidates.count, 1u);
+	    if(slot < arrayLength(&bestCandidates.entries)) {
+		bestCandidates.entries[slot] = Hit(jobIndex, nonce);
+	    }
+	}
+
+	// Nearly every hash is above both targets in its top 64 bits, those
+	// skip the vanity check and the full comparisons
+	if(!nearTarget(finalHash, targets.block) && !nearTarget(finalHash, targets.share)) {
+	    continue;
+	}
+
+	let isVanity = matchesPattern(finalHash);
+	let isBlock = isVanity && meetsTarget(finalHash, targets.block);
+	let isShare = isVanity && meetsTarget(finalHash, targets.share);
+
+	if(isBlock || isShare) {
+	    let slot = atomicAdd(&hits.count, 1u);
+	    if(slot < arrayLength(&hits.entries)) {
+		hits.entries[slot] = Hit(jobIndex, nonce);
+	    }
+	}
+	if(isShare) {
+	    atomicAdd(&output[jobIndex].shares, 1u);
+	}
+	if(isBlock) {
+	    atomicAdd(&output[jobIndex].hits, 1u);
+	    // Only the first winner of each job gets to write its nonce
+	    if(atomicExchange(&output[jobIndex].found, 1u) == 0u) {
+		output[jobIndex].nonce = nonce;
+	    }
+	}
     }
+}
 
-    if(meetsTarget) {
-	output[thId] = nonce;
-    } else {
-	output[thId] = 0u;
+// Compares the hash in display order against the vanity pattern
+fn matchesPattern(hash: array<u32, 8>) -> bool {
+    for(var i = 0u; i < 8u; i = i + 1u) {
+	if((swapEndian(hash[7u - i]) & targets.mask[i]) != targets.pattern[i]) {
+	    return false;
+	}
     }
+    return true;
 }
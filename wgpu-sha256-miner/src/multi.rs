@@ -0,0 +1,311 @@
+//! Mining one header on several GPUs at once.
+//!
+//! Every device keeps its own launch parameters. Nonces are handed out in
+//! rounds, each device getting a share proportional to the hashrate it
+//! measured so far, so a slow integrated GPU doesn't hold up a fast one.
+
+use std::{ops::Range, time::Duration};
+
+use futures::future;
+
+use crate::{lowest, BatchResult, GpuMiner, HeaderWords, MinerError, Result, NONCE_SPACE};
+
+// Default length of a round, after which the shares are rebalanced
+const DEFAULT_REBALANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Weight of the latest round in the hashrate of a device
+const RATE_SMOOTHING: f64 = 0.5;
+
+/// Several GPU miners sharing the nonce space of a header
+pub struct MultiMiner {
+    miners: Vec<GpuMiner>,
+    // Hashes per second of each device, None until it ran a round
+    rates: Vec<Option<f64>>,
+    rebalance_interval: Duration,
+}
+
+impl MultiMiner {
+    /// Mines with every miner, which keep their own batch sizes
+    pub fn new(miners: Vec<GpuMiner>) -> Result<Self> {
+        if miners.is_empty() {
+            return Err(MinerError::NoDevices);
+        }
+        Ok(Self {
+            rates: vec![None; miners.len()],
+            miners,
+            rebalance_interval: DEFAULT_REBALANCE_INTERVAL,
+        })
+    }
+
+    pub fn miners(&self) -> &[GpuMiner] {
+        &self.miners
+    }
+
+    /// Miners in the order they were passed to new
+    pub fn miners_mut(&mut self) -> &mut [GpuMiner] {
+        &mut self.miners
+    }
+
+    pub fn into_miners(self) -> Vec<GpuMiner> {
+        self.miners
+    }
+
+    /// Getter for the length of a round
+    pub fn get_rebalance_interval(&self) -> Duration {
+        self.rebalance_interval
+    }
+
+    /// Sets how long a round should take, shares are rebalanced between
+    /// rounds. Devices stop at the end of a round when another one found a
+    /// winner, so shorter rounds waste less work but sync more often.
+    pub fn set_rebalance_interval(&mut self, interval: Duration) {
+        self.rebalance_interval = interval;
+    }
+
+    /// Measured hashes per second of each device, None before its first round
+    pub fn rates(&self) -> &[Option<f64>] {
+        &self.rates
+    }
+
+    /// Fraction of a round each device gets, summing to 1
+    /// Devices without a measured rate count as the average of the others.
+    pub fn shares(&self) -> Vec<f64> {
+        let rates = self.filled_rates();
+        let total: f64 = rates.iter().sum();
+        rates.iter().map(|rate| rate / total).collect()
+    }
+
+    /// Autotunes every device in turn and forgets the measured rates
+    pub async fn autotune(&mut self) {
+        for miner in &mut self.miners {
+            miner.autotune().await;
+        }
+        self.rates.fill(None);
+    }
+
+    /// Sets the big-endian 256-bit target of every device
+    pub fn set_target(&mut self, target: &[u8; 32]) {
+        for miner in &mut self.miners {
+            miner.set_target(target);
+        }
+    }
+
+    /// Mines the nonces in [start, end) of a header, end is at most 2^32
+    /// Runs rounds until a device finds a winner or the range is covered.
+    /// hashes_tried adds up all devices, elapsed is the wall-clock time.
+    pub async fn run_batch_range(
+        &mut self,
+        words: &HeaderWords,
+        nonces: Range<u64>,
+    ) -> Result<BatchResult> {
+        if nonces.start >= nonces.end || nonces.end > NONCE_SPACE {
+            return Err(MinerError::InvalidNonceRange {
+                start: nonces.start,
+                end: nonces.end,
+            });
+        }
+
+        let start = crate::Instant::now();
+        let mut total = BatchResult {
+            nonce: None,
+            hash: None,
+            hits: 0,
+            shares: 0,
+            best: None,
+            hashes_tried: 0,
+            elapsed: Duration::ZERO,
+            gpu_time: None,
+            recovered: false,
+            rejected: false,
+        };
+
+        let mut base = nonces.start;
+        while base < nonces.end {
+            let spans = self.plan_round(base..nonces.end);
+            let round_end = spans.last().map_or(base, |span| span.end);
+            let results = future::join_all(self.miners.iter_mut().zip(spans).map(
+                |(miner, span)| async move {
+                    if span.is_empty() {
+                        Ok(None)
+                    } else {
+                        miner.run_batch_range(words, span).await.map(Some)
+                    }
+                },
+            ))
+            .await;
+
+            for (rate, res) in self.rates.iter_mut().zip(results) {
+                let Some(res) = res? else {
+                    continue;
+                };
+                if res.elapsed > Duration::ZERO {
+                    let measured = res.hashes_tried as f64 / res.elapsed.as_secs_f64();
+                    *rate = Some(match *rate {
+                        Some(old) => old + RATE_SMOOTHING * (measured - old),
+                        None => measured,
+                    });
+                }
+
+                total.hits += res.hits;
+                total.shares += res.shares;
+                total.best = lowest(total.best, res.best);
+                total.hashes_tried += res.hashes_tried;
+                total.recovered |= res.recovered;
+                total.rejected |= res.rejected;
+                if res.is_found() && !total.is_found() {
+                    total.nonce = res.nonce;
+                    total.hash = res.hash;
+                }
+            }
+
+            let cancelled = self
+                .miners
+                .iter()
+                .any(|miner| miner.cancel_handle().is_cancelled());
+            if total.is_found() || cancelled {
+                break;
+            }
+            base = round_end;
+        }
+
+        total.elapsed = start.elapsed();
+        Ok(total)
+    }
+
+    // Rates with the unmeasured devices filled in
+    fn filled_rates(&self) -> Vec<f64> {
+        let measured: Vec<f64> = self.rates.iter().flatten().copied().collect();
+        let fallback = if measured.is_empty() {
+            1.0
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+        self.rates
+            .iter()
+            .map(|rate| rate.unwrap_or(fallback).max(f64::MIN_POSITIVE))
+            .collect()
+    }
+
+    // Nonces each device should take this round, before clamping to the
+    // range: a rebalance interval worth at its rate in whole batches, or a
+    // single batch to measure it
+    fn wanted(&self) -> Vec<u64> {
+        self.miners
+            .iter()
+            .zip(&self.rates)
+            .map(|(miner, rate)| {
+                let batch = miner.get_hashes_per_batch() as u64;
+                match rate {
+                    Some(rate) => {
+                        let nonces = rate * self.rebalance_interval.as_secs_f64();
+                        (nonces as u64).div_ceil(batch).max(1) * batch
+                    }
+                    None => batch,
+                }
+            })
+            .collect()
+    }
+
+    // Consecutive spans of the range, one per device
+    // If the range is shorter than a round it is split by the shares.
+    fn plan_round(&self, remaining: Range<u64>) -> Vec<Range<u64>> {
+        let len = remaining.end - remaining.start;
+        let wanted = self.wanted();
+        let counts: Vec<u64> = if wanted.iter().sum::<u64>() <= len {
+            wanted
+        } else {
+            let shares = self.shares();
+            let mut counts: Vec<u64> = shares
+                .iter()
+                .map(|share| (share * len as f64) as u64)
+                .collect();
+            // Rounding leftovers go to the fastest device
+            let fastest = (0..shares.len())
+                .max_by(|&a, &b| shares[a].total_cmp(&shares[b]))
+                .unwrap_or(0);
+            counts[fastest] += len - counts.iter().sum::<u64>().min(len);
+            counts
+        };
+
+        let mut base = remaining.start;
+        counts
+            .into_iter()
+            .map(|count| {
+                let span = base..(base + count).min(remaining.end);
+                base = span.end;
+                span
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn miners(batch_sizes: &[u32]) -> MultiMiner {
+        let mut miners = Vec::new();
+        for &batch_size in batch_sizes {
+            let miner = GpuMiner::builder()
+                .batch_size(batch_size)
+                .wg_size(64)
+                .build()
+                .await
+                .unwrap();
+            miners.push(miner);
+        }
+        MultiMiner::new(miners).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rounds_follow_the_measured_rates() {
+        assert!(matches!(
+            MultiMiner::new(Vec::new()),
+            Err(MinerError::NoDevices)
+        ));
+
+        let mut multi = miners(&[1 << 12, 1 << 14]).await;
+        // Unmeasured devices run one batch of their own size
+        assert_eq!(
+            multi.plan_round(0..NONCE_SPACE),
+            vec![0..1 << 12, 1 << 12..(1 << 12) + (1 << 14)]
+        );
+
+        multi.rates = vec![Some(1e6), Some(3e6)];
+        assert_eq!(multi.shares(), vec![0.25, 0.75]);
+        let spans = multi.plan_round(0..NONCE_SPACE);
+        let lens: Vec<u64> = spans.iter().map(|span| span.end - span.start).collect();
+        // A second at each rate, rounded up to whole batches
+        assert_eq!(lens, vec![1_003_520, 3_014_656]);
+
+        // Short ranges are split by the shares and covered exactly
+        let spans = multi.plan_round(100..1100);
+        assert_eq!(spans, vec![100..350, 350..1100]);
+
+        // New devices count as the average until measured
+        multi.rates[0] = None;
+        assert_eq!(multi.shares(), vec![0.5, 0.5]);
+    }
+
+    #[tokio::test]
+    async fn devices_split_a_range_and_find_the_winner() {
+        let mut multi = miners(&[1 << 12, 1 << 13]).await;
+        let words = HeaderWords::from_bytes(&[0u8; 80]);
+
+        // Nothing meets an all zero target, so the whole range is covered
+        multi.set_target(&[0u8; 32]);
+        let res = multi.run_batch_range(&words, 0..1 << 16).await.unwrap();
+        assert!(!res.is_found());
+        assert_eq!(res.hashes_tried, 1 << 16);
+        assert!(multi.rates().iter().all(|rate| rate.unwrap() > 0.0));
+        let batches: u64 = multi.miners().iter().map(|m| m.stats().batches).sum();
+        assert!(batches >= 2);
+
+        // Every hash meets the maximum target
+        multi.set_target(&[0xFF; 32]);
+        let res = multi.run_batch_range(&words, 0..1 << 16).await.unwrap();
+        assert!(res.is_found());
+
+        assert!(multi.run_batch_range(&words, 5..5).await.is_err());
+    }
+}
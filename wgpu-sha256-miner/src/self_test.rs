@@ -0,0 +1,217 @@
+//! GPU self-test against headers with known hashes.
+//!
+//! A driver that miscompiles the kernel either misses real winners or
+//! reports nonces that don't meet the target. Both show up here.
+
+use std::{fmt, ops::Range};
+
+use crate::Result;
+
+use crate::{
+    hash_meets_target, hash_with_nonce, sha256_parse_words, sha256_preprocess,
+    sha256_words_to_header, GpuMiner, HeaderWords,
+};
+
+// Mainnet headers with their hashes in display order
+pub(crate) const KNOWN_BLOCKS: [(&str, &str, &str); 2] = [
+    (
+        "genesis block",
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3ed\
+         fd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+        "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    ),
+    (
+        "block 1",
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051\
+         fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+        "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048",
+    ),
+];
+
+// Nonces searched on either side of a known winner
+const KNOWN_BLOCK_MARGIN: u64 = 1 << 12;
+
+// Nonces compared one by one against the CPU at the easy target
+const EASY_TARGET_NONCES: Range<u64> = 0..1 << 14;
+
+/// Outcome of a single self-test check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    /// What was compared, or what went wrong
+    pub detail: String,
+}
+
+/// All checks run by GpuMiner::self_test
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// True if every check passed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = if check.passed { "ok" } else { "FAILED" };
+            writeln!(f, "{} ... {}: {}", check.name, status, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl GpuMiner {
+    /// Mines known headers on the GPU and checks the results on the CPU
+    /// The target and stats of the miner are restored afterwards. Errors
+    /// are only returned if the GPU can't run batches at all.
+    pub async fn self_test(&mut self) -> Result<SelfTestReport> {
+        let target = self.target;
+        let share_target = self.share_target.take();
+        let vanity = self.vanity.take();
+        let throttle = self.throttle;
+        let stats = self.stats;
+        self.throttle = crate::Throttle::None;
+
+        let res = self.run_self_test().await;
+
+        self.share_target = share_target;
+        self.vanity = vanity;
+        self.set_target(&target);
+        self.throttle = throttle;
+        self.stats = stats;
+        res
+    }
+
+    async fn run_self_test(&mut self) -> Result<SelfTestReport> {
+        let mut checks = Vec::new();
+
+        for (name, header, hash) in KNOWN_BLOCKS {
+            // Known blocks are constants, so bad hex is a bug in this file
+            let header: [u8; 80] = hex::decode(header)
+                .ok()
+                .and_then(|header| header.try_into().ok())
+                .expect("Known header is 80 bytes of hex");
+            let mut expected: [u8; 32] = hex::decode(hash)
+                .ok()
+                .and_then(|hash| hash.try_into().ok())
+                .expect("Known hash is 32 bytes of hex");
+            expected.reverse();
+
+            checks.push(self.check_known_block(name, &header, &expected).await?);
+        }
+
+        checks.push(self.check_easy_target().await?);
+
+        Ok(SelfTestReport { checks })
+    }
+
+    // Searches around the real nonce at the block's own target, only the
+    // real nonce may win
+    async fn check_known_block(
+        &mut self,
+        name: &str,
+        header: &[u8; 80],
+        expected: &[u8; 32],
+    ) -> Result<SelfTestCheck> {
+        let words = HeaderWords::from_bytes(header);
+        let nonce = words[19] as u64;
+        self.set_target_from_bits(words[18].swap_bytes())?;
+
+        let start = nonce.saturating_sub(KNOWN_BLOCK_MARGIN);
+        let end = (nonce + KNOWN_BLOCK_MARGIN).min(u32::MAX as u64 + 1);
+        let hits = self.run_batch_range(&words, start..end).await?;
+
+        let (passed, detail) = match (hits.nonce, hits.hash) {
+            (Some(found), Some(hash)) if found as u64 == nonce && &hash == expected => {
+                (true, format!("found nonce {found:#010x}"))
+            }
+            (Some(found), _) => (
+                false,
+                format!("found nonce {found:#010x}, expected {nonce:#010x}"),
+            ),
+            (None, _) => (false, format!("missed nonce {nonce:#010x}")),
+        };
+
+        Ok(SelfTestCheck {
+            name: name.to_string(),
+            passed,
+            detail,
+        })
+    }
+
+    // Compares every winner at a trivial target with the CPU
+    async fn check_easy_target(&mut self) -> Result<SelfTestCheck> {
+        let name = "easy target".to_string();
+        let words = sha256_parse_words(&sha256_preprocess(&[0x5a; 80]));
+
+        // Roughly one in 256 hashes wins
+        let mut target = [0xFF; 32];
+        target[0] = 0x00;
+        self.set_target(&target);
+
+        let mut expected = Vec::new();
+        for nonce in EASY_TARGET_NONCES {
+            let mut words = words;
+            words[19] = nonce as u32;
+            if hash_meets_target(&hash_with_nonce(&sha256_words_to_header(&words)), &target) {
+                expected.push(nonce as u32);
+            }
+        }
+
+        let mut found = Vec::new();
+        let mut base = EASY_TARGET_NONCES.start;
+        while base < EASY_TARGET_NONCES.end {
+            let count = (EASY_TARGET_NONCES.end - base).min(self.get_hashes_per_batch() as u64);
+            let span = crate::NonceSpan {
+                base: base as u32,
+                count: count as u32,
+            };
+            let output = self.run_jobs(std::slice::from_ref(&words), span).await?;
+            found.extend(output.winners.iter().map(|&(_, nonce)| nonce));
+            base += count;
+        }
+        found.sort_unstable();
+
+        let passed = found == expected;
+        let detail = if passed {
+            format!("{} winners match the CPU", found.len())
+        } else {
+            let missing = expected.iter().filter(|n| !found.contains(n)).count();
+            let extra = found.iter().filter(|n| !expected.contains(n)).count();
+            format!(
+                "{missing} winners missed and {extra} false winners of {}",
+                expected.len()
+            )
+        };
+
+        Ok(SelfTestCheck {
+            name,
+            passed,
+            detail,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn self_test_passes() {
+        let mut miner = GpuMiner::new(None).await.unwrap();
+        let target = *miner.get_target();
+
+        let report = miner.self_test().await.unwrap();
+        assert!(report.passed(), "{report}");
+        assert_eq!(report.checks.len(), 3);
+
+        assert_eq!(miner.get_target(), &target);
+        assert_eq!(miner.stats().batches, 0);
+    }
+}
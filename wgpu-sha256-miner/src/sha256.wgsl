@@ -161,3 +161,123 @@ fn doubleHash(blocks: array<u32, 32>) -> array<u32, 8> {
     var finalHash = computeHash(padded, SHA256_INITIAL_HASH);
     return finalHash;
 }
+
+// Same as doubleHash but starts from the state after the first block
+fn doubleHashFromMidstate(midstate: array<u32, 8>, block2: array<u32, 16>)
+    -> array<u32, 8> {
+    var firstHash = computeHash(block2, midstate);
+    var padded = pad256to512(firstHash);
+
+    var finalHash = computeHash(padded, SHA256_INITIAL_HASH);
+    return finalHash;
+}
+
+// Padding of the second block of every 80 byte header, words 4-15
+// Constant so drivers can fold it into the message schedule.
+const HEADER_PADDING: array<u32, 12> = array<u32, 12>(
+    0x80000000u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0u, 0x00000280u
+);
+
+// Parts of the first compression of a header that don't depend on the
+// nonce (word 3 of the second block), shared by every nonce of a job
+struct NonceInvariant {
+    // State after rounds 0-2, which only use words 0-2
+    state: array<u32, 8>,
+    // Schedule words 16 and 17, and 18 and 19 without their nonce terms
+    schedule: array<u32, 4>,
+}
+
+// One round of compression, wt is word t of the message schedule
+fn compressionRound(s: array<u32, 8>, t: u32, wt: u32) -> array<u32, 8> {
+    let t1 = s[7] + bigSigma1(s[4]) + ch(s[4], s[5], s[6]) + K[t] + wt;
+    let t2 = bigSigma0(s[0]) + maj(s[0], s[1], s[2]);
+    return array<u32, 8>(t1 + t2, s[0], s[1], s[2], s[3] + t1, s[4], s[5], s[6]);
+}
+
+// Words 0-2 of block2 are the end of the merkle root, time and bits
+fn precomputeNonceInvariant(midstate: array<u32, 8>, block2: array<u32, 16>)
+    -> NonceInvariant {
+    var state = midstate;
+    for(var t = 0u; t < 3u; t = t + 1u) {
+	state = compressionRound(state, t, block2[t]);
+    }
+
+    // w[t] = s1(w[t - 2]) + w[t - 7] + s0(w[t - 15]) + w[t - 16], with the
+    // nonce as w[3] and HEADER_PADDING from w[4]
+    let w16 = littleSigma1(HEADER_PADDING[10]) + HEADER_PADDING[5] +
+	littleSigma0(block2[1]) + block2[0];
+    let w17 = littleSigma1(HEADER_PADDING[11]) + HEADER_PADDING[6] +
+	littleSigma0(block2[2]) + block2[1];
+    let w18 = littleSigma1(w16) + HEADER_PADDING[7] + block2[2];
+    let w19 = littleSigma1(w17) + HEADER_PADDING[8] + littleSigma0(HEADER_PADDING[0]);
+    return NonceInvariant(state, array<u32, 4>(w16, w17, w18, w19));
+}
+
+// First compression of a header for one nonce, continuing from the
+// nonce invariant parts of its job
+fn computeHashWithNonce(midstate: array<u32, 8>, pre: NonceInvariant, nonce: u32)
+    -> array<u32, 8> {
+    var w: array<u32, 64>;
+    w[3] = nonce;
+    for(var t = 4u; t < 16u; t = t + 1u) {
+	w[t] = HEADER_PADDING[t - 4u];
+    }
+    w[16] = pre.schedule[0];
+    w[17] = pre.schedule[1];
+    w[18] = pre.schedule[2] + littleSigma0(nonce);
+    w[19] = pre.schedule[3] + nonce;
+    for(var t = 20u; t < 64u; t = t + 1u) {
+	w[t] = littleSigma1(w[t - 2u]) + w[t - 7u] +
+	    littleSigma0(w[t - 15u]) + w[t - 16u];
+    }
+
+    var state = pre.state;
+    for(var t = 3u; t < 64u; t = t + 1u) {
+	state = compressionRound(state, t, w[t]);
+    }
+    for(var i = 0u; i < 8u; i = i + 1u) {
+	state[i] = state[i] + midstate[i];
+    }
+    return state;
+}
+
+// Same as doubleHashFromMidstate for a header with the given nonce
+fn doubleHashWithNonce(midstate: array<u32, 8>, pre: NonceInvariant, nonce: u32)
+    -> array<u32, 8> {
+    var firstHash = computeHashWithNonce(midstate, pre, nonce);
+    var padded = pad256to512(firstHash);
+
+    var finalHash = computeHash(padded, SHA256_INITIAL_HASH);
+    return finalHash;
+}
+
+// Reverses the byte order of a word. The digest is read as a
+// little-endian 256-bit number when compared against the target.
+fn swapEndian(x: u32) -> u32 {
+    return ((x & 0xffu) << 24u) | ((x & 0xff00u) << 8u) |
+	((x >> 8u) & 0xff00u) | (x >> 24u);
+}
+
+// Bitcoin compares the hash as a little-endian number, so the last
+// digest word holds the most significant bits.
+// tgt is given most significant word first.
+fn meetsTarget(hash: array<u32, 8>, tgt: array<u32, 8>) -> bool {
+    for(var i = 0u; i < 8u; i = i + 1u) {
+	let h = swapEndian(hash[7u - i]);
+	if(h < tgt[i]) {
+	    return true;
+	}
+	if(h > tgt[i]) {
+	    return false;
+	}
+    }
+    // Hash equal to target is valid
+    return true;
+}
+
+// False if the top 64 bits of the hash already exceed the target's, true
+// for hashes that need the full comparison of meetsTarget
+fn nearTarget(hash: array<u32, 8>, tgt: array<u32, 8>) -> bool {
+    let top = swapEndian(hash[7]);
+    return top < tgt[0] || (top == tgt[0] && swapEndian(hash[6]) <= tgt[1]);
+}
@@ -0,0 +1,58 @@
+//! Precompiled SPIR-V kernels for drivers where WGSL compilation is slow
+//! or buggy.
+//!
+//! build.rs specializes the embedded WGSL for every launch configuration
+//! autotune can pick, since override constants don't apply to passthrough
+//! modules.
+
+include!(concat!(env!("OUT_DIR"), "/spirv_kernels.rs"));
+
+// Creates the passthrough module for a launch configuration, if the device
+// supports passthrough and the configuration was precompiled
+pub(crate) fn create_shader(
+    device: &wgpu::Device,
+    wg_size: u32,
+    nonces_per_thread: u32,
+) -> Option<wgpu::ShaderModule> {
+    if !device
+        .features()
+        .contains(wgpu::Features::SPIRV_SHADER_PASSTHROUGH)
+    {
+        return None;
+    }
+
+    let (_, _, bytes) = SPIRV_KERNELS
+        .iter()
+        .find(|(wg, nonces, _)| *wg == wg_size && *nonces == nonces_per_thread)?;
+
+    // SAFETY: kernels are generated by naga from the validated mining shader
+    // with bounds checks, and use the same bind group layout
+    let shader = unsafe {
+        device.create_shader_module_spirv(&wgpu::ShaderModuleDescriptorSpirV {
+            label: Some("Mining Shader (SPIR-V)"),
+            source: wgpu::util::make_spirv_raw(bytes),
+        })
+    };
+    Some(shader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_autotune_configuration_is_precompiled() {
+        for wg_size in [32, 64, 128, 256, 512, 1024] {
+            for nonces_per_thread in [1, 2, 4, 8] {
+                assert!(SPIRV_KERNELS
+                    .iter()
+                    .any(|&(wg, nonces, _)| wg == wg_size && nonces == nonces_per_thread));
+            }
+        }
+
+        // SPIR-V magic number
+        for (_, _, bytes) in SPIRV_KERNELS {
+            assert_eq!(&bytes[..4], &0x0723_0203u32.to_le_bytes());
+        }
+    }
+}
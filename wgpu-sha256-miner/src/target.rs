@@ -0,0 +1,243 @@
+//! Conversions between the compact nBits field, 256-bit targets and
+//! difficulty.
+//!
+//! Targets are big-endian [u8; 32], the same order getblocktemplate uses
+//! to display them. Hashes are in the byte order produced by SHA256, which
+//! Bitcoin reads as a little-endian number.
+
+use crate::{hash_with_nonce, MinerError, Result};
+
+/// Target of difficulty 1 (bits 0x1d00ffff)
+pub const DIFF1_TARGET: [u8; 32] = [
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Parses the hex encoded bits field, e.g. "1700e526"
+pub fn bits_from_hex(bits: &str) -> Result<u32> {
+    u32::from_str_radix(bits, 16).map_err(|err| MinerError::InvalidBits(format!("{bits}: {err}")))
+}
+
+/// Expands compact bits into a 256-bit target
+pub fn bits_to_target(bits: u32) -> Result<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+
+    if bits & 0x0080_0000 != 0 && mantissa != 0 {
+        return Err(MinerError::InvalidBits(format!(
+            "{bits:08x} encode a negative target"
+        )));
+    }
+
+    let mut target = [0u8; 32];
+    let mantissa = mantissa.to_be_bytes();
+
+    // The three mantissa bytes start exponent bytes from the end
+    for (i, &byte) in mantissa[1..].iter().enumerate() {
+        let index = 32 + i as isize - exponent as isize;
+        if index < 0 {
+            if byte != 0 {
+                return Err(MinerError::InvalidBits(format!(
+                    "{bits:08x} overflow 256 bits"
+                )));
+            }
+        } else if index < 32 {
+            target[index as usize] = byte;
+        }
+    }
+
+    Ok(target)
+}
+
+/// Compresses a 256-bit target into compact bits, losing precision
+pub fn target_to_bits(target: &[u8; 32]) -> u32 {
+    let Some(first) = target.iter().position(|&byte| byte != 0) else {
+        return 0;
+    };
+
+    let mut size = (32 - first) as u32;
+    let mut mantissa = [0u8; 4];
+    for (i, byte) in mantissa[1..].iter_mut().enumerate() {
+        *byte = target.get(first + i).copied().unwrap_or(0);
+    }
+    let mut mantissa = u32::from_be_bytes(mantissa);
+
+    // Keep the sign bit clear
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
+/// Difficulty relative to the difficulty 1 target
+pub fn target_to_difficulty(target: &[u8; 32]) -> f64 {
+    to_f64(&DIFF1_TARGET) / to_f64(target)
+}
+
+/// Difficulty encoded by compact bits
+pub fn bits_to_difficulty(bits: u32) -> Result<f64> {
+    Ok(target_to_difficulty(&bits_to_target(bits)?))
+}
+
+/// Target for a given difficulty, rounded down
+pub fn difficulty_to_target(difficulty: f64) -> Result<[u8; 32]> {
+    if !(difficulty.is_finite() && difficulty > 0.0) {
+        return Err(MinerError::InvalidDifficulty(format!(
+            "must be positive, got {difficulty}"
+        )));
+    }
+
+    let mut value = to_f64(&DIFF1_TARGET) / difficulty;
+    if value >= 256f64.powi(32) {
+        return Err(MinerError::InvalidDifficulty(format!(
+            "{difficulty} exceeds 256 bits"
+        )));
+    }
+
+    let mut target = [0u8; 32];
+    for (i, byte) in target.iter_mut().enumerate() {
+        let place = 256f64.powi(31 - i as i32);
+        let digit = (value / place).floor().min(255.0);
+        *byte = digit as u8;
+        value -= digit * place;
+    }
+
+    Ok(target)
+}
+
+/// Difficulty of the lowest target a hash (SHA256 byte order) meets
+pub fn hash_to_difficulty(hash: &[u8; 32]) -> f64 {
+    let mut value = *hash;
+    value.reverse();
+    target_to_difficulty(&value)
+}
+
+/// True if a hash (SHA256 byte order) is less than or equal to the target
+pub fn hash_meets_target(hash: &[u8; 32], target: &[u8; 32]) -> bool {
+    hash.iter().rev().le(target.iter())
+}
+
+/// True if the double SHA256 of a serialized header meets compact bits,
+/// as consensus checks it. Bits that don't encode a positive target fail.
+pub fn check_pow(header: &[u8; 80], bits: u32) -> bool {
+    match bits_to_target(bits) {
+        Ok(target) if target != [0u8; 32] => check_pow_target(header, &target),
+        _ => false,
+    }
+}
+
+/// True if the double SHA256 of a serialized header meets a big-endian
+/// 256-bit target
+pub fn check_pow_target(header: &[u8; 80], target: &[u8; 32]) -> bool {
+    hash_meets_target(&hash_with_nonce(header), target)
+}
+
+// Approximates a big-endian 256-bit number as f64
+fn to_f64(value: &[u8; 32]) -> f64 {
+    value
+        .iter()
+        .fold(0.0, |acc, &byte| acc * 256.0 + byte as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_bits_expand_to_diff1() {
+        assert_eq!(bits_to_target(0x1d00ffff).unwrap(), DIFF1_TARGET);
+        assert_eq!(bits_to_difficulty(0x1d00ffff).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        for bits in [0x1d00ffff, 0x1700e526, 0x1b0404cb, 0x207fffff, 0x03123456] {
+            let target = bits_to_target(bits).unwrap();
+            assert_eq!(target_to_bits(&target), bits);
+        }
+    }
+
+    #[test]
+    fn bits_parse_from_hex() {
+        assert_eq!(bits_from_hex("1700e526").unwrap(), 0x1700e526);
+        assert!(bits_from_hex("not hex").is_err());
+    }
+
+    #[test]
+    fn regtest_bits_are_near_max() {
+        let target = bits_to_target(0x207fffff).unwrap();
+        assert_eq!(&target[0..3], &[0x7f, 0xff, 0xff]);
+        assert!(target[3..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn known_difficulty() {
+        let difficulty = bits_to_difficulty(0x1b0404cb).unwrap();
+        assert!((difficulty - 16307.420938523983).abs() < 1e-6);
+    }
+
+    #[test]
+    fn invalid_bits_are_rejected() {
+        assert!(bits_to_target(0x04923456).is_err(), "Negative target.");
+        assert!(bits_to_target(0xff123456).is_err(), "Overflows 256 bits.");
+    }
+
+    #[test]
+    fn difficulty_converts_back_to_target() {
+        let target = difficulty_to_target(1.0).unwrap();
+        assert_eq!(target, DIFF1_TARGET);
+        assert!(difficulty_to_target(0.0).is_err());
+    }
+
+    #[test]
+    fn hash_compared_little_endian() {
+        let mut hash = [0xff; 32];
+        hash[31] = 0x00;
+        hash[30] = 0x00;
+        hash[29] = 0x00;
+        hash[28] = 0x00;
+
+        // Most significant bytes are zero, remaining bytes too large
+        assert!(!hash_meets_target(&hash, &DIFF1_TARGET));
+
+        hash[27] = 0x00;
+        hash[26] = 0x00;
+        assert!(hash_meets_target(&hash, &DIFF1_TARGET));
+        assert!(hash_meets_target(&DIFF1_TARGET, &[0xff; 32]));
+    }
+
+    #[test]
+    fn known_headers_have_valid_pow() {
+        let genesis: [u8; 80] = hex::decode(crate::self_test::KNOWN_BLOCKS[0].1)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(check_pow(&genesis, 0x1d00ffff));
+        assert!(check_pow_target(&genesis, &DIFF1_TARGET));
+
+        // Another nonce almost surely misses, and bad bits never pass
+        let mut other = genesis;
+        other[76] ^= 1;
+        assert!(!check_pow(&other, 0x1d00ffff));
+        assert!(!check_pow(&genesis, 0x1800ffff));
+        assert!(!check_pow(&genesis, 0));
+        assert!(!check_pow(&genesis, 0x04923456));
+    }
+
+    #[test]
+    fn hash_difficulty_is_relative_to_diff1() {
+        let mut hash = DIFF1_TARGET;
+        hash.reverse();
+        assert_eq!(hash_to_difficulty(&hash), 1.0);
+
+        // Genesis block hash has one more zero byte than diff 1 requires
+        let mut genesis =
+            hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        genesis.reverse();
+        let difficulty = hash_to_difficulty(&genesis.try_into().unwrap());
+        assert!((difficulty - 2536.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,148 @@
+//! Timestamps a header may be rolled to once its nonces run out.
+//!
+//! Nodes reject blocks older than the template's mintime and blocks more
+//! than MAX_FUTURE_BLOCK_TIME ahead of their clock. Within that window
+//! the roller counts up and catches up with the wall clock now and then,
+//! so rolled headers don't drift away from the real time.
+
+use std::time::Duration;
+
+use crate::{clock::unix_time, Instant, MAX_FUTURE_BLOCK_TIME};
+
+// Default time between two checks of the wall clock
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Timestamp of the header being mined, kept within the rules of a template
+#[derive(Debug, Clone)]
+pub struct TimestampRoller {
+    time: u32,
+    min_time: u32,
+    max_time: Option<u32>,
+    refresh_interval: Duration,
+    last_refresh: Instant,
+}
+
+impl TimestampRoller {
+    /// Starts at time, or at min_time if that is later
+    /// For a template that is its curtime and mintime.
+    pub fn new(time: u32, min_time: u32) -> Self {
+        Self {
+            time: time.max(min_time),
+            min_time,
+            max_time: None,
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+            last_refresh: Instant::now(),
+        }
+    }
+
+    /// Current timestamp, in Unix seconds
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    pub fn get_min_time(&self) -> u32 {
+        self.min_time
+    }
+
+    /// Caps the timestamp below the 2 hour future rule, e.g. to stay close
+    /// to the time of a job
+    pub fn set_max_time(&mut self, max_time: u32) {
+        self.max_time = Some(max_time);
+    }
+
+    /// Getter for the time between two checks of the wall clock
+    pub fn get_refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+
+    pub fn set_refresh_interval(&mut self, interval: Duration) {
+        self.refresh_interval = interval;
+    }
+
+    /// Latest timestamp allowed right now, MAX_FUTURE_BLOCK_TIME past the
+    /// wall clock or max_time if that is earlier
+    pub fn latest(&self) -> u32 {
+        let latest = unix_time().saturating_add(MAX_FUTURE_BLOCK_TIME);
+        self.max_time
+            .map_or(latest, |max_time| max_time.min(latest))
+    }
+
+    /// True if the current timestamp may be mined
+    pub fn is_allowed(&self) -> bool {
+        (self.min_time..=self.latest()).contains(&self.time)
+    }
+
+    /// Moves to the next second, or to the wall clock if that is later
+    /// Returns None and keeps the timestamp if the next one isn't allowed.
+    pub fn roll(&mut self) -> Option<u32> {
+        let next = self.time.checked_add(1)?.max(unix_time());
+        if next > self.latest() {
+            return None;
+        }
+        self.time = next;
+        self.last_refresh = Instant::now();
+        Some(next)
+    }
+
+    /// Catches up with the wall clock once every refresh interval
+    /// Returns the new timestamp if it moved, the header then has to be
+    /// mined from the first nonce again.
+    pub fn refresh(&mut self) -> Option<u32> {
+        if self.last_refresh.elapsed() < self.refresh_interval {
+            return None;
+        }
+        self.last_refresh = Instant::now();
+        let now = unix_time();
+        if now <= self.time || now > self.latest() {
+            return None;
+        }
+        self.time = now;
+        Some(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolls_within_the_template_window() {
+        let now = unix_time();
+        let roller = TimestampRoller::new(now - 100, now - 50);
+        assert_eq!(roller.time(), now - 50);
+        assert!(roller.is_allowed());
+
+        // Behind the wall clock, the next timestamp catches up with it
+        let mut roller = TimestampRoller::new(now - 100, 0);
+        assert!(roller.roll().unwrap() >= now);
+
+        // Ahead of it, the timestamp counts up to the cap
+        let mut roller = TimestampRoller::new(now + 60, 0);
+        roller.set_max_time(now + 62);
+        assert_eq!(roller.roll(), Some(now + 61));
+        assert_eq!(roller.roll(), Some(now + 62));
+        assert_eq!(roller.roll(), None);
+        assert_eq!(roller.time(), now + 62);
+
+        // Never past the 2 hour future rule
+        let mut roller = TimestampRoller::new(now + MAX_FUTURE_BLOCK_TIME + 60, 0);
+        assert!(!roller.is_allowed());
+        assert_eq!(roller.roll(), None);
+    }
+
+    #[test]
+    fn refresh_catches_up_with_the_wall_clock() {
+        let now = unix_time();
+        let mut roller = TimestampRoller::new(now - 100, 0);
+        assert_eq!(roller.refresh(), None, "Not due yet.");
+
+        roller.set_refresh_interval(Duration::ZERO);
+        assert!(roller.refresh().unwrap() >= now);
+
+        // Rolled ahead of the clock, refreshing doesn't go back
+        let mut roller = TimestampRoller::new(now + 60, 0);
+        roller.set_refresh_interval(Duration::ZERO);
+        assert_eq!(roller.refresh(), None);
+        assert_eq!(roller.time(), now + 60);
+    }
+}
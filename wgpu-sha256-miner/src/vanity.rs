@@ -0,0 +1,117 @@
+//! Bit patterns for vanity hash searches.
+//!
+//! Patterns are in display order, the big-endian form block explorers
+//! show, so a prefix of "0000dead" matches hashes that print that way.
+
+use crate::{MinerError, Result};
+
+/// Hashes match if their masked bits equal the pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VanityPattern {
+    pattern: [u8; 32],
+    mask: [u8; 32],
+}
+
+impl VanityPattern {
+    /// Pattern and mask in display order, bits outside the mask are ignored
+    pub fn new(pattern: &[u8; 32], mask: &[u8; 32]) -> Self {
+        let mut masked = *pattern;
+        for (byte, mask) in masked.iter_mut().zip(mask) {
+            *byte &= mask;
+        }
+        Self {
+            pattern: masked,
+            mask: *mask,
+        }
+    }
+
+    /// Matches hashes that start with the hex digits, e.g. "00000beef"
+    pub fn from_hex_prefix(prefix: &str) -> Result<Self> {
+        if prefix.len() > 64 {
+            return Err(MinerError::InvalidPattern(format!(
+                "{prefix} is longer than a hash"
+            )));
+        }
+
+        let mut pattern = [0u8; 32];
+        let mut mask = [0u8; 32];
+        for (i, digit) in prefix.chars().enumerate() {
+            let nibble = digit
+                .to_digit(16)
+                .ok_or_else(|| MinerError::InvalidPattern(format!("{prefix} isn't hex")))?
+                as u8;
+            // High nibble first
+            let shift = if i % 2 == 0 { 4 } else { 0 };
+            pattern[i / 2] |= nibble << shift;
+            mask[i / 2] |= 0xF << shift;
+        }
+        Ok(Self { pattern, mask })
+    }
+
+    /// True if the hash (SHA256 byte order) matches
+    pub fn matches(&self, hash: &[u8; 32]) -> bool {
+        hash.iter()
+            .rev()
+            .zip(self.pattern.iter().zip(&self.mask))
+            .all(|(byte, (pattern, mask))| byte & mask == *pattern)
+    }
+
+    /// Average number of hashes to find one match
+    pub fn expected_hashes(&self) -> f64 {
+        let bits: u32 = self.mask.iter().map(|byte| byte.count_ones()).sum();
+        2f64.powi(bits as i32)
+    }
+
+    // Mask and pattern as words, most significant first like the targets
+    pub(crate) fn to_words(self) -> ([u32; 8], [u32; 8]) {
+        let mut mask = [0u32; 8];
+        let mut pattern = [0u32; 8];
+        for i in 0..8 {
+            let bytes = i * 4..i * 4 + 4;
+            mask[i] = u32::from_be_bytes(self.mask[bytes.clone()].try_into().unwrap());
+            pattern[i] = u32::from_be_bytes(self.pattern[bytes].try_into().unwrap());
+        }
+        (mask, pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_prefix_matches_display_order() {
+        let pattern = VanityPattern::from_hex_prefix("000000000019d").unwrap();
+        assert_eq!(pattern.expected_hashes(), 2f64.powi(52));
+
+        // Genesis block hash, reversed into SHA256 byte order
+        let mut genesis: [u8; 32] =
+            hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        genesis.reverse();
+        assert!(pattern.matches(&genesis));
+        assert!(!VanityPattern::from_hex_prefix("000000000019e")
+            .unwrap()
+            .matches(&genesis));
+
+        assert!(VanityPattern::from_hex_prefix("")
+            .unwrap()
+            .matches(&genesis));
+        assert!(VanityPattern::from_hex_prefix("xyz").is_err());
+        assert!(VanityPattern::from_hex_prefix(&"0".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn bits_outside_the_mask_are_ignored() {
+        let mut mask = [0u8; 32];
+        mask[31] = 0x0F;
+        let pattern = VanityPattern::new(&[0xAB; 32], &mask);
+        assert_eq!(pattern.to_words().1[7], 0x0B);
+
+        let mut hash = [0u8; 32];
+        hash[0] = 0xFB;
+        assert!(pattern.matches(&hash));
+    }
+}